@@ -0,0 +1,39 @@
+// Compares hashing many small files one at a time and concurrently by
+// running the binary with and without --no-parallel-files. Run with
+// `cargo bench --bench parallel_files`.
+use std::fs;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::time::Instant;
+
+fn small_files(dir: &Path, n: usize) {
+    for i in 0..n {
+        let sub = dir.join(format!("{}", i % 10));
+        fs::create_dir_all(&sub).unwrap();
+        fs::write(sub.join(format!("{}", i)), vec![i as u8; i % 300 + 1]).unwrap();
+    }
+}
+
+fn main() {
+    let dir = tempfile::tempdir().unwrap();
+    small_files(dir.path(), 50_000);
+
+    for parallel in [false, true] {
+        let mut cmd = Command::new(env!("CARGO_BIN_EXE_mktorrent-rs"));
+        cmd.args([
+            "--announce=",
+            "--piece-length=14",
+            "--check-only",
+            "--no-progress",
+        ]);
+        if !parallel {
+            cmd.arg("--no-parallel-files");
+        }
+        cmd.arg(dir.path()).stdout(Stdio::null());
+
+        let start = Instant::now();
+        let status = cmd.status().unwrap();
+        assert!(status.success(), "{}", status);
+        println!("parallel_files={}: {:?}", parallel, start.elapsed());
+    }
+}
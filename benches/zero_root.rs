@@ -0,0 +1,43 @@
+// Compares the cost of padding pieces with and without the zero_root cache.
+// Run with `cargo bench --bench zero_root`.
+use std::hint::black_box;
+use std::time::Instant;
+
+use mktorrent_rs::checksum::merkle::{root_hash, Hasher, Sha256, TreeHash};
+use mktorrent_rs::checksum::sha256;
+
+// Computes zero_root without the cache.
+fn zero_root_uncached(layer: u8) -> sha256::Digest {
+    let mut d = sha256::Digest::default();
+    for _ in 0..layer {
+        d = Sha256::combine(&d, &d);
+    }
+    d
+}
+
+fn main() {
+    // A 16MiB piece has 10 layers of blocks.
+    const LAYER: u8 = 10;
+    const PIECES: u32 = 100_000;
+    let d = [b'a'; sha256::Digest::LENGTH].into();
+
+    let start = Instant::now();
+    for _ in 0..PIECES {
+        black_box(root_hash(LAYER, [&d]));
+    }
+    let cached = start.elapsed();
+
+    let start = Instant::now();
+    for _ in 0..PIECES {
+        let mut h = Hasher::new();
+        h.add_block(&d);
+        black_box(h.finish_tree(&zero_root_uncached(LAYER)));
+    }
+    let uncached = start.elapsed();
+
+    println!(
+        "per piece: cached {:?}, uncached {:?}",
+        cached / PIECES,
+        uncached / PIECES
+    );
+}
@@ -0,0 +1,53 @@
+const ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+// Encodes data as base32 with padding as defined in RFC 4648.
+pub fn encode(data: &[u8]) -> String {
+    let mut ret = String::with_capacity(data.len().div_ceil(5) * 8);
+
+    for chunk in data.chunks(5) {
+        let mut block = [0u8; 5];
+        block[..chunk.len()].copy_from_slice(chunk);
+        let bits = block.iter().fold(0u64, |acc, &b| (acc << 8) | b as u64);
+
+        // Each character holds 5 bits, so a partial chunk of n bytes needs
+        // ceil(n * 8 / 5) characters and the rest are padding.
+        let chars = (chunk.len() * 8).div_ceil(5);
+        for i in 0..8 {
+            if i < chars {
+                let idx = (bits >> (35 - i * 5)) & 0x1f;
+                ret.push(ALPHABET[idx as usize] as char);
+            } else {
+                ret.push('=');
+            }
+        }
+    }
+
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rfc4648_vectors() {
+        let cases = [
+            ("", ""),
+            ("f", "MY======"),
+            ("fo", "MZXQ===="),
+            ("foo", "MZXW6==="),
+            ("foob", "MZXW6YQ="),
+            ("fooba", "MZXW6YTB"),
+            ("foobar", "MZXW6YTBOI======"),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(encode(input.as_bytes()), expected, "input {:?}", input);
+        }
+    }
+
+    #[test]
+    fn digest() {
+        let d: Vec<u8> = (0..20).collect();
+        assert_eq!(encode(&d), "AAAQEAYEAUDAOCAJBIFQYDIOB4IBCEQT");
+    }
+}
@@ -0,0 +1,2398 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::iter;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Error, Result};
+use positioned_io::Slice;
+use zip::ZipArchive;
+
+use crate::checksum::{self, sha256};
+use crate::files::{check_relative_path, filtered_files, trim_common_prefix};
+use crate::hash::{hash_checked, hash_files, FileOutcome, HashOptions};
+use crate::manifest::Manifest;
+use crate::metainfo::{self, v1, FileEntry, PieceLength, Torrent};
+use crate::output::{
+    emit_encoded, emit_torrent, format_infohash, print_infohash, torrent_file_name, unique_path,
+};
+use crate::source::{ContentSource, Filesystem};
+use crate::summary::{file_pieces, write_file_table, LayerStats, Summary, TreeStats};
+use crate::{validate_name, Cli, MetaVersion};
+
+// Writes the torrent built by main to outputs, along with anything else the
+// options ask for, such as the manifest. With --check-only only the infohash
+// is printed. listed is the file list read from disk, which is only needed
+// for --manifest and --delta-against. single_file is the same as for
+// build_torrent.
+pub fn write_build(
+    cli: &Cli,
+    outputs: &[PathBuf],
+    torrent: &Torrent,
+    mtimes: &Mtimes,
+    listed: &[(String, u64)],
+    single_file: bool,
+) -> Result<()> {
+    if cli.check_only {
+        return print_infohash(io::stdout().lock(), torrent, cli.infohash_encoding);
+    }
+
+    if cli.manifest.is_some() || cli.delta_against.is_some() {
+        let name = single_file.then_some(torrent.info.name.as_str());
+        let listed: Vec<_> = listed
+            .iter()
+            .map(|(p, l)| (entry_path(cli, name, p), *l))
+            .collect();
+        let manifest = Manifest::new(torrent, mtimes).with_disk_lengths(&listed);
+        if let Some(p) = &cli.manifest {
+            manifest.write(p)?;
+        }
+        if let (Some(prev), Some(p)) = (&cli.delta_against, &cli.delta_output) {
+            manifest.delta(&Manifest::load(prev)?).write(p)?;
+        }
+    }
+
+    if let Some(p) = &cli.pieces_sidecar {
+        let f =
+            fs::File::create(p).context(format!("failed to create `{}`", p.to_string_lossy()))?;
+        serde_json::to_writer_pretty(io::BufWriter::new(f), &file_pieces(torrent))?;
+    }
+
+    // Encode once and take the infohash from the encoded info dictionary.
+    let (data, infohash) = torrent
+        .encode_with_infohash()
+        .map_err(|e| Error::msg(e.to_string()))?;
+    emit_encoded(cli, outputs, &data)?;
+
+    if cli.verbose >= 2 && !cli.json_summary {
+        write_file_table(io::stderr().lock(), torrent)?;
+    }
+
+    if cli.dump_piece_layers_stats {
+        eprintln!("{}", LayerStats::new(torrent));
+    }
+
+    if cli.print_tree_stats {
+        eprintln!("{}", TreeStats::new(torrent));
+    }
+
+    if cli.print_infohash {
+        println!("{}", format_infohash(&infohash, cli.infohash_encoding));
+    }
+
+    if cli.print_content_fingerprint {
+        let fingerprint = torrent
+            .info
+            .content_fingerprint()
+            .map_err(|e| Error::msg(e.to_string()))?;
+        println!("{}", fingerprint);
+    }
+
+    if cli.content_id {
+        println!("{}", torrent.content_id());
+    }
+
+    if cli.json_summary {
+        // The summary names the first output, which is the only one unless
+        // --output is given more than once.
+        let summary = Summary::new(torrent, outputs.first().cloned())
+            .map_err(|e| Error::msg(e.to_string()))?;
+        serde_json::to_writer(io::stdout(), &summary)?;
+        println!();
+    }
+
+    Ok(())
+}
+
+// Modification times in nanoseconds since the unix epoch by path.
+pub type Mtimes = HashMap<String, u64>;
+
+// Hashes files, which are relative to dir, and builds a torrent from them.
+// Also returns the modification time of each file that has one and anything
+// worth warning about. If single_file is set files is the file of a single
+// file root, which is named after the torrent rather than the file on disk.
+pub fn build_torrent(
+    cli: &Cli,
+    hash_options: &HashOptions,
+    name: String,
+    dir: &Path,
+    files: Vec<(String, u64)>,
+    single_file: bool,
+) -> Result<(Torrent, Mtimes, Vec<BuildWarning>)> {
+    let mut warnings = content_warnings(cli, &files);
+    // The shape of the tree is known from the file list, so a build that
+    // --strict would fail does not hash anything first.
+    if let Some(n) = cli.warn_wide_dirs {
+        let wide = TreeStats::from_paths(files.iter().map(|(p, _)| tree_path(cli, p))).wide_dirs(n);
+        if cli.strict && !wide.is_empty() {
+            for (path, entries) in &wide {
+                eprintln!(
+                    "error: {}",
+                    BuildWarning::WideDirectory(path.clone(), *entries)
+                );
+            }
+            return Err(Error::msg(format!(
+                "{} directories have more than {} entries",
+                wide.len(),
+                n
+            )));
+        }
+        warnings.extend(
+            wide.into_iter()
+                .map(|(path, entries)| BuildWarning::WideDirectory(path, entries)),
+        );
+    }
+
+    let single_name = single_file.then_some(name.as_str());
+    let (entries, mtimes, changed) = hash_files(cli, hash_options, dir, files, single_name)?;
+    warnings.extend(changed);
+
+    let mut torrent =
+        Torrent::from_entries(announce(cli), name, hash_options.piece_length, entries)
+            .map_err(|e| Error::msg(format!("conflicting file: {}", e)))?;
+    set_torrent_options(cli, &mut torrent);
+
+    Ok((torrent, mtimes, warnings))
+}
+
+// Returns the path of a file in the file tree given its path on disk. With
+// --group-by-extension the file is moved under a directory named by its
+// extension.
+pub fn tree_path(cli: &Cli, path: &str) -> String {
+    if !cli.group_by_extension {
+        return path.to_owned();
+    }
+    let name = path.rsplit('/').next().unwrap_or(path);
+    let group = match Path::new(name).extension() {
+        Some(ext) => ext.to_string_lossy().to_lowercase(),
+        None => "other".to_owned(),
+    };
+    format!("{}/{}", group, name)
+}
+
+// Returns the path of a listed file in the file tree. name is set when the
+// content is a single file, which is listed under the torrent's name rather
+// than its name on disk.
+pub fn entry_path(cli: &Cli, name: Option<&str>, path: &str) -> String {
+    match name {
+        Some(name) => name.to_owned(),
+        None => tree_path(cli, path),
+    }
+}
+
+// Sets the parts of a v2 torrent that come straight from the command line
+// rather than from the content.
+fn set_torrent_options(cli: &Cli, torrent: &mut Torrent) {
+    torrent.preserve_order = cli.preserve_order;
+    torrent.httpseeds = cli.http_seed.clone();
+    torrent.url_list = cli.web_seed.clone();
+    torrent.nodes = cli.node.clone();
+    torrent.announce_list = announce_list(cli);
+    torrent.comment = cli.comment.clone();
+    torrent.created_by = created_by(cli);
+    torrent.creation_date = cli.creation_date;
+    torrent.info.private = cli.private;
+    torrent.info.source = cli.source.clone();
+}
+
+pub const CREATED_BY: &str = concat!("mktorrent-rs ", env!("CARGO_PKG_VERSION"));
+
+// Returns the piece length for total bytes of content when --piece-length is
+// not given and writes which was chosen to log, unless --json-summary is set
+// since the summary includes it.
+pub fn auto_piece_length(cli: &Cli, total: u64, mut log: impl Write) -> Result<PieceLength> {
+    let piece_length = PieceLength::for_content(total);
+    if !cli.json_summary {
+        writeln!(
+            log,
+            "using --piece-length={} for {} bytes of content",
+            piece_length.layers + 14,
+            total
+        )?;
+    }
+    Ok(piece_length)
+}
+
+// Returns --created-by, or None if it is empty.
+pub fn created_by(cli: &Cli) -> Option<String> {
+    Some(cli.created_by.clone()).filter(|s| !s.is_empty())
+}
+
+// Returns the first announce url, or an empty string for a trackerless
+// torrent.
+pub fn announce(cli: &Cli) -> String {
+    cli.announce
+        .iter()
+        .chain(cli.announce_tier.iter().flatten())
+        .next()
+        .cloned()
+        .unwrap_or_default()
+}
+
+// Returns the announce-list: the --announce urls as the first tier followed
+// by each --announce-tier. It is empty when there is only one announce url.
+fn announce_list(cli: &Cli) -> Vec<Vec<String>> {
+    let tiers: Vec<Vec<String>> = iter::once(cli.announce.clone())
+        .chain(cli.announce_tier.iter().cloned())
+        .filter(|t| !t.is_empty())
+        .collect();
+    if matches!(tiers.as_slice(), [t] if t.len() == 1) {
+        return Vec::new();
+    }
+    tiers
+}
+
+// Something unusual about the content that does not stop the build.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BuildWarning {
+    // A file whose name starts with '.'.
+    HiddenFile(String),
+    EmptyFile(String),
+    // A .torrent file. Only reported with --warn-nested-torrents.
+    NestedTorrent(String),
+    // Two paths that only differ by case, which collide on case insensitive
+    // filesystems.
+    CaseCollision(String, String),
+    // A file whose length or mtime changed while it was hashed.
+    ChangedFile(String),
+    // A file that was removed after it was listed. Only reported with
+    // --skip-errors; otherwise it is an error.
+    VanishedFile(String),
+    // A piece length outside the range most clients support.
+    PieceLength(String),
+    // A directory with more entries than --warn-wide-dirs. The top level
+    // directory's path is empty.
+    WideDirectory(String, usize),
+}
+
+impl BuildWarning {
+    // Returns true if the warning is only printed with --verbose since it is
+    // common in ordinary content.
+    fn is_minor(&self) -> bool {
+        matches!(
+            self,
+            BuildWarning::HiddenFile(_) | BuildWarning::EmptyFile(_)
+        )
+    }
+}
+
+impl fmt::Display for BuildWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BuildWarning::HiddenFile(p) => write!(f, "hidden file: {}", p),
+            BuildWarning::EmptyFile(p) => write!(f, "empty file: {}", p),
+            BuildWarning::NestedTorrent(p) => write!(f, "content contains a torrent file: {}", p),
+            BuildWarning::CaseCollision(a, b) => {
+                write!(f, "paths differ only by case: {} and {}", a, b)
+            }
+            BuildWarning::ChangedFile(p) => write!(f, "file changed while hashing: {}", p),
+            BuildWarning::VanishedFile(p) => {
+                write!(f, "file disappeared during processing, skipping: {}", p)
+            }
+            BuildWarning::PieceLength(w) => write!(f, "{}", w),
+            BuildWarning::WideDirectory(p, n) if p.is_empty() => {
+                write!(f, "top level directory has {} entries", n)
+            }
+            BuildWarning::WideDirectory(p, n) => write!(f, "directory has {} entries: {}", n, p),
+        }
+    }
+}
+
+// Prints warnings to stderr. Minor warnings are only printed if verbose.
+fn print_warnings(warnings: &[BuildWarning], verbose: bool) {
+    for w in warnings {
+        if verbose || !w.is_minor() {
+            eprintln!("warning: {}", w);
+        }
+    }
+}
+
+// Prints warnings as print_warnings does. With --fail-on-warning every
+// warning is printed and any warning is an error.
+pub fn report_warnings(cli: &Cli, warnings: &[BuildWarning]) -> Result<()> {
+    print_warnings(warnings, cli.verbose > 0 || cli.fail_on_warning);
+    if cli.fail_on_warning && !warnings.is_empty() {
+        return Err(Error::msg(format!(
+            "found {} warnings with --fail-on-warning",
+            warnings.len()
+        )));
+    }
+    Ok(())
+}
+
+// Returns the warnings that only depend on the list of files.
+fn content_warnings(cli: &Cli, files: &[(String, u64)]) -> Vec<BuildWarning> {
+    let mut ret = Vec::new();
+
+    for (p, l) in files {
+        if p.split('/').any(|c| c.starts_with('.')) {
+            ret.push(BuildWarning::HiddenFile(p.clone()));
+        }
+        if *l == 0 {
+            ret.push(BuildWarning::EmptyFile(p.clone()));
+        }
+    }
+
+    if cli.warn_nested_torrents {
+        for p in nested_torrents(files) {
+            ret.push(BuildWarning::NestedTorrent(p.to_owned()));
+        }
+    }
+
+    let mut lower: HashMap<String, &str> = HashMap::new();
+    for (p, _) in files {
+        if let Some(prev) = lower.insert(p.to_lowercase(), p) {
+            ret.push(BuildWarning::CaseCollision(prev.to_owned(), p.clone()));
+        }
+    }
+
+    ret
+}
+
+// Builds a torrent from files hashed elsewhere.
+pub fn build_from_manifest(
+    cli: &Cli,
+    piece_length: PieceLength,
+    entries: Vec<FileEntry>,
+) -> Result<Torrent> {
+    let name = validate_name(cli.name.clone().unwrap())?;
+    let mut torrent = Torrent::new(announce(cli), name, piece_length);
+    set_torrent_options(cli, &mut torrent);
+
+    for e in entries {
+        torrent
+            .add_precomputed(&e.path, e.file, e.pieces_layer)
+            .map_err(|err| Error::msg(format!("{}: {}", e.path, err)))?;
+    }
+
+    Ok(torrent)
+}
+
+// Builds a torrent from the file entries of the zip archive at p. Entries
+// are compressed individually so each is streamed through the hasher. If
+// piece_length is None it is chosen from the uncompressed size of the entries.
+pub fn build_from_zip(cli: &Cli, piece_length: Option<PieceLength>, p: &Path) -> Result<Torrent> {
+    let f = fs::File::open(p).context(format!("failed to open `{}`", p.to_string_lossy()))?;
+    let mut archive = ZipArchive::new(io::BufReader::new(f))
+        .context(format!("failed to read `{}`", p.to_string_lossy()))?;
+
+    let piece_length = match piece_length {
+        Some(pl) => pl,
+        None => {
+            let mut total = 0;
+            for i in 0..archive.len() {
+                total += archive.by_index_raw(i)?.size();
+            }
+            auto_piece_length(cli, total, io::stderr())?
+        }
+    };
+
+    let name = validate_name(cli.name.clone().unwrap())?;
+    let mut torrent = Torrent::new(announce(cli), name, piece_length);
+    set_torrent_options(cli, &mut torrent);
+
+    for i in 0..archive.len() {
+        let mut entry = archive.by_index(i)?;
+        if entry.is_dir() {
+            continue;
+        }
+
+        // enclosed_name rejects absolute paths and paths that leave the
+        // archive.
+        let path = entry
+            .enclosed_name()
+            .and_then(|p| {
+                let components: Option<Vec<_>> = p.iter().map(|c| c.to_str()).collect();
+                components.map(|c| c.join("/"))
+            })
+            .filter(|p| !p.is_empty())
+            .ok_or_else(|| Error::msg(format!("invalid path in zip: {}", entry.name())))?;
+
+        let (file, pieces_layer) = checksum::torrent2::checksum_file(piece_length, &mut entry)
+            .context(format!("failed to checksum `{}`", path))?;
+        if !torrent.add_file(&path, file, pieces_layer) {
+            return Err(Error::msg(format!(
+                "conflicting file: {}: {}",
+                path,
+                torrent.conflict(&path)
+            )));
+        }
+    }
+
+    Ok(torrent)
+}
+
+// Reads lines of a path, length and hex pieces root, optionally followed by
+// the hex piece layer, separated by tabs.
+pub fn read_precomputed(p: &Path) -> Result<Vec<FileEntry>> {
+    let f = fs::File::open(p).context(format!("failed to open `{}`", p.to_string_lossy()))?;
+    let mut ret = Vec::new();
+
+    for (i, line) in io::BufReader::new(f).lines().enumerate() {
+        let line = line?;
+        if line.is_empty() {
+            continue;
+        }
+
+        let err = |what: &str| Error::msg(format!("line {}: {}", i + 1, what));
+        let mut fields = line.split('\t');
+        let path = fields.next().unwrap().to_owned();
+        check_relative_path(&path).map_err(|e| err(&e.to_string()))?;
+        let length = fields
+            .next()
+            .and_then(|l| l.parse().ok())
+            .ok_or_else(|| err("invalid length"))?;
+        let pieces_root = fields
+            .next()
+            .and_then(|r| r.parse().ok())
+            .ok_or_else(|| err("invalid pieces root"))?;
+        let pieces_layer = fields
+            .map(|d| d.parse())
+            .collect::<Result<Vec<sha256::Digest>, _>>()
+            .map_err(|_| err("invalid piece layer hash"))?;
+
+        ret.push(FileEntry {
+            path,
+            file: metainfo::File {
+                length,
+                pieces_root,
+                crc32: None,
+            },
+            pieces_layer,
+        });
+    }
+
+    Ok(ret)
+}
+
+// Returns the files with pieces that only contain zeros and the indices of
+// those pieces. Files without a piece layer are skipped.
+pub fn zero_pieces(torrent: &Torrent) -> Vec<(String, Vec<u64>)> {
+    let piece_length = torrent.info.piece_length;
+    let full = checksum::torrent2::zero_piece(piece_length, piece_length.bytes());
+    let mut ret = Vec::new();
+
+    for (path, f) in torrent.files() {
+        let layer = match torrent.piece_layers.get(&f.pieces_root) {
+            Some(l) => l,
+            None => continue,
+        };
+
+        let last_length = match f.length % piece_length.bytes() {
+            0 => piece_length.bytes(),
+            n => n,
+        };
+        let last = checksum::torrent2::zero_piece(piece_length, last_length);
+
+        let pieces: Vec<u64> = layer
+            .iter()
+            .enumerate()
+            .filter(|&(i, d)| *d == if i == layer.len() - 1 { last } else { full })
+            .map(|(i, _)| i as u64)
+            .collect();
+        if !pieces.is_empty() {
+            ret.push((path, pieces));
+        }
+    }
+
+    ret
+}
+
+// The builds an option cannot be used with. Every option supports a single v2
+// torrent.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Unsupported {
+    V1,
+    MultiplePieceLengths,
+    Both,
+}
+
+// Returns each option that some builds do not support as its flag, whether it
+// is set and the builds that do not support it.
+fn limited_options(cli: &Cli) -> Vec<(&'static str, bool, Unsupported)> {
+    use Unsupported::*;
+
+    vec![
+        (
+            "--meta-version=v1",
+            cli.meta_version == MetaVersion::V1,
+            MultiplePieceLengths,
+        ),
+        (
+            "--from-manifest",
+            cli.from_manifest.is_some(),
+            MultiplePieceLengths,
+        ),
+        ("--manifest", cli.manifest.is_some(), Both),
+        ("--pieces-sidecar", cli.pieces_sidecar.is_some(), Both),
+        ("--incremental", cli.incremental.is_some(), Both),
+        (
+            "--resume-from-checkpoint",
+            cli.resume_from_checkpoint.is_some(),
+            Both,
+        ),
+        ("--delta-against", cli.delta_against.is_some(), Both),
+        ("--verify-after-build", cli.verify_after_build, Both),
+        ("--store-crc32", cli.store_crc32, Both),
+        (
+            "--max-inflight-pieces",
+            cli.max_inflight_pieces.is_some(),
+            Both,
+        ),
+        (
+            "--max-buffer-memory",
+            cli.max_buffer_memory.is_some(),
+            MultiplePieceLengths,
+        ),
+        ("--progress-pipe", cli.progress_pipe.is_some(), Both),
+        ("--warn-wide-dirs", cli.warn_wide_dirs.is_some(), Both),
+        ("--digest-bytes", cli.digest_bytes.is_some(), Both),
+        ("--json-summary", cli.json_summary, Both),
+        ("--print-infohash", cli.print_infohash, MultiplePieceLengths),
+        (
+            "--print-content-fingerprint",
+            cli.print_content_fingerprint,
+            Both,
+        ),
+        ("--content-id", cli.content_id, Both),
+        ("--warn-zero-pieces", cli.warn_zero_pieces, Both),
+        ("--check-only", cli.check_only, Both),
+        (
+            "--dump-piece-layers-stats",
+            cli.dump_piece_layers_stats,
+            Both,
+        ),
+        ("--print-tree-stats", cli.print_tree_stats, Both),
+        ("--per-subdir", cli.per_subdir, Both),
+        ("--always-emit-layer", cli.always_emit_layer, V1),
+        ("--adaptive-threads", cli.adaptive_threads, Both),
+        (
+            "--single-threaded",
+            cli.single_threaded,
+            MultiplePieceLengths,
+        ),
+        ("--start-offset", cli.start_offset.is_some(), V1),
+        ("--length", cli.length.is_some(), V1),
+        (
+            "--normalize-newlines",
+            !cli.normalize_newlines.is_empty(),
+            Both,
+        ),
+        ("--direct-io", cli.direct_io, Both),
+    ]
+}
+
+// Returns the first set option that is unsupported by build, which is V1 or
+// MultiplePieceLengths.
+fn unsupported_option(cli: &Cli, build: Unsupported) -> Option<&'static str> {
+    limited_options(cli)
+        .into_iter()
+        .find(|&(_, set, unsupported)| {
+            set && (unsupported == build || unsupported == Unsupported::Both)
+        })
+        .map(|(flag, _, _)| flag)
+}
+
+// Returns an error if an option that needs a single piece length is set or
+// --output is missing.
+pub fn check_multiple_piece_lengths_options(cli: &Cli) -> Result<()> {
+    if cli.output.is_empty() {
+        return Err(Error::msg(
+            "multiple --piece-length values require --output",
+        ));
+    }
+
+    match unsupported_option(cli, Unsupported::MultiplePieceLengths) {
+        Some(flag) => Err(Error::msg(format!(
+            "{} requires a single --piece-length",
+            flag
+        ))),
+        None => Ok(()),
+    }
+}
+
+// Hashes files, which are relative to dir, once and builds a torrent for each
+// piece length from the block hashes. single_file is the same as for
+// build_torrent.
+pub fn build_torrents(
+    cli: &Cli,
+    hash_options: &HashOptions,
+    piece_lengths: &[PieceLength],
+    name: String,
+    dir: &Path,
+    files: Vec<(String, u64)>,
+    single_file: bool,
+) -> Result<Vec<Torrent>> {
+    check_suspicious_files(cli, dir, &files)?;
+    let mut warnings = content_warnings(cli, &files);
+
+    let source = Filesystem::new(dir);
+    let mut entries = vec![Vec::with_capacity(files.len()); piece_lengths.len()];
+    for (path, l) in files {
+        let outcome = hash_checked(cli, dir, &path, |_| {
+            let f = source.open(&path)?;
+            let r = Slice::new(&f, hash_options.start_offset, Some(l));
+            Ok((checksum::torrent2::block_hashes(l, &r)?, true))
+        })?;
+        let blocks = match outcome {
+            FileOutcome::Hashed {
+                result, changed, ..
+            } => {
+                if changed {
+                    warnings.push(BuildWarning::ChangedFile(path.clone()));
+                }
+                result
+            }
+            FileOutcome::Vanished(path) => {
+                warnings.push(BuildWarning::VanishedFile(path));
+                continue;
+            }
+        };
+
+        for (&piece_length, entries) in piece_lengths.iter().zip(&mut entries) {
+            let (f, mut pieces_layer) =
+                checksum::torrent2::from_block_hashes(piece_length, l, &blocks);
+            if cli.always_emit_layer && pieces_layer.is_empty() && f.length > 0 {
+                pieces_layer = vec![checksum::single_piece_layer(piece_length, &f)];
+            }
+            entries.push(FileEntry {
+                path: if single_file {
+                    name.clone()
+                } else {
+                    tree_path(cli, &path)
+                },
+                file: f,
+                pieces_layer,
+            });
+        }
+    }
+    report_warnings(cli, &warnings)?;
+
+    piece_lengths
+        .iter()
+        .zip(entries)
+        .map(|(&piece_length, entries)| {
+            let mut t = Torrent::from_entries(announce(cli), name.clone(), piece_length, entries)
+                .map_err(|e| Error::msg(format!("conflicting file: {}", e)))?;
+            set_torrent_options(cli, &mut t);
+            Ok(t)
+        })
+        .collect()
+}
+
+// Adds the piece length exponent before the extension of p, e.g. name.torrent
+// becomes name.18.torrent.
+pub fn with_piece_length(p: &Path, exponent: u8) -> PathBuf {
+    let stem = p.file_stem().unwrap_or_default().to_string_lossy();
+    match p.extension() {
+        Some(ext) => p.with_file_name(format!("{}.{}.{}", stem, exponent, ext.to_string_lossy())),
+        None => p.with_file_name(format!("{}.{}", stem, exponent)),
+    }
+}
+
+// Returns an error if any option that only applies to v2 torrents is set.
+pub fn check_v1_options(cli: &Cli) -> Result<()> {
+    match unsupported_option(cli, Unsupported::V1) {
+        Some(flag) => Err(Error::msg(format!("{} requires --meta-version=v2", flag))),
+        None => Ok(()),
+    }
+}
+
+// Hashes files, which are relative to dir, and builds a v1 torrent from them.
+// Also returns anything worth warning about. Files are hashed in sorted order
+// unless --preserve-order is set since v1 pieces span file boundaries.
+pub fn build_torrent_v1(
+    cli: &Cli,
+    piece_length: PieceLength,
+    name: String,
+    dir: &Path,
+    files: Vec<(String, u64)>,
+    single_file: bool,
+) -> Result<(v1::Torrent, Vec<BuildWarning>)> {
+    check_suspicious_files(cli, dir, &files)?;
+    let warnings = content_warnings(cli, &files);
+
+    // Files are ordered by their path in the torrent but read from disk.
+    let mut files: Vec<_> = files
+        .into_iter()
+        .map(|(p, l)| (tree_path(cli, &p), p, l))
+        .collect();
+    if !cli.preserve_order {
+        files.sort_unstable();
+    }
+    let mut seen = HashSet::new();
+    if let Some((path, _, _)) = files.iter().find(|(path, _, _)| !seen.insert(path)) {
+        return Err(Error::msg(format!(
+            "conflicting file: {}: added more than once",
+            path
+        )));
+    }
+
+    let readers = files
+        .iter()
+        .map(|(_, p, l)| fs::File::open(dir.join(p)).map(|f| (f, *l)));
+    let pieces = checksum::checksum_files_v1(piece_length.bytes(), readers)
+        .context("failed to checksum files")?;
+
+    let files = if single_file {
+        v1::Files::Single { length: files[0].2 }
+    } else {
+        v1::Files::Multi(
+            files
+                .into_iter()
+                .map(|(path, _, length)| v1::File {
+                    length,
+                    path: path.split('/').map(str::to_owned).collect(),
+                })
+                .collect(),
+        )
+    };
+
+    let torrent = v1::Torrent {
+        announce: announce(cli),
+        announce_list: announce_list(cli),
+        comment: cli.comment.clone(),
+        created_by: created_by(cli),
+        creation_date: cli.creation_date,
+        httpseeds: cli.http_seed.clone(),
+        info: v1::Info {
+            name,
+            piece_length: piece_length.bytes(),
+            pieces,
+            files,
+            private: cli.private,
+            source: cli.source.clone(),
+        },
+        nodes: cli.node.clone(),
+        url_list: cli.web_seed.clone(),
+    };
+
+    Ok((torrent, warnings))
+}
+
+// Builds one torrent for each immediate subdirectory of the root, named after
+// the subdirectory, and one for any files directly in the root, named after
+// the root. The torrents are written to each of the out directories.
+pub fn build_per_subdir(
+    cli: &Cli,
+    hash_options: &HashOptions,
+    torrent_name: &str,
+    outs: &[PathBuf],
+) -> Result<()> {
+    let paths = |file_name: &str| -> Vec<PathBuf> {
+        outs.iter()
+            .map(|out| {
+                let p = out.join(file_name);
+                if cli.output_unique {
+                    unique_path(&p)
+                } else {
+                    p
+                }
+            })
+            .collect()
+    };
+
+    let root = cli.root.as_ref().unwrap();
+
+    // Files are selected as for a single torrent of the root, so every filter
+    // applies with paths relative to the root, and then split by the
+    // subdirectory they are in.
+    let mut subdirs: BTreeMap<String, Vec<(String, u64)>> = BTreeMap::new();
+    let mut loose_files = Vec::new();
+    for (path, l) in filtered_files(cli, root)? {
+        match path.split_once('/') {
+            Some((subdir, rest)) => subdirs
+                .entry(subdir.to_owned())
+                .or_default()
+                .push((rest.to_owned(), l)),
+            None => loose_files.push((path, l)),
+        }
+    }
+
+    let mut entries = fs::read_dir(root)
+        .context(format!("failed to read `{}`", root.to_string_lossy()))?
+        .collect::<io::Result<Vec<_>>>()?;
+    entries.sort_unstable_by_key(|e| e.file_name());
+    for entry in entries {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if entry.file_type()?.is_dir() && !subdirs.contains_key(&name) {
+            eprintln!("warning: skipping directory with no files: {}", name);
+        }
+    }
+
+    // Torrents named after the directory their files are in.
+    let mut builds = Vec::new();
+    for (name, files) in subdirs {
+        let dir = root.join(&name);
+        let (dir, files) = if cli.trim_common_prefix {
+            let (prefix, files) = trim_common_prefix(files);
+            (dir.join(prefix), files)
+        } else {
+            (dir, files)
+        };
+        builds.push((name, dir, files));
+    }
+    if !loose_files.is_empty() {
+        builds.push((torrent_name.to_owned(), root.clone(), loose_files));
+    }
+
+    // Check every name before writing anything so one torrent never replaces
+    // another, as a subdirectory named like the root would.
+    let mut file_names = HashSet::new();
+    for (name, _, _) in &builds {
+        let file_name = torrent_file_name(name, cli.sanitize_output_name);
+        if !file_names.insert(file_name.clone()) {
+            return Err(Error::msg(format!(
+                "more than one torrent would be written to `{}`",
+                file_name
+            )));
+        }
+    }
+
+    for (name, dir, files) in builds {
+        let file_name = torrent_file_name(&name, cli.sanitize_output_name);
+        let (torrent, _, warnings) = build_torrent(cli, hash_options, name, &dir, files, false)?;
+        report_warnings(cli, &warnings)?;
+        emit_torrent(cli, &paths(&file_name), &torrent)?;
+    }
+
+    Ok(())
+}
+
+// Returns the paths in files with a .torrent extension.
+fn nested_torrents(files: &[(String, u64)]) -> Vec<&str> {
+    files
+        .iter()
+        .map(|(p, _)| p.as_str())
+        .filter(|p| {
+            Path::new(p)
+                .extension()
+                .is_some_and(|e| e.eq_ignore_ascii_case("torrent"))
+        })
+        .collect()
+}
+
+// With --refuse-suspicious, returns an error if any of files, which are
+// relative to dir, is setuid, setgid or world-writable.
+#[cfg(unix)]
+pub fn check_suspicious_files(cli: &Cli, dir: &Path, files: &[(String, u64)]) -> Result<()> {
+    if cli.refuse_suspicious {
+        for (file, _) in files {
+            let p = dir.join(file);
+            let metadata = fs::metadata(&p).context(format!("failed to stat `{}`", file))?;
+            check_suspicious(&p, &metadata)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn check_suspicious_files(_cli: &Cli, _dir: &Path, _files: &[(String, u64)]) -> Result<()> {
+    Ok(())
+}
+
+// Returns an error if the file is setuid, setgid or world-writable.
+#[cfg(unix)]
+fn check_suspicious(path: &Path, metadata: &fs::Metadata) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let mode = metadata.permissions().mode();
+    let reason = if mode & 0o4000 != 0 {
+        "setuid"
+    } else if mode & 0o2000 != 0 {
+        "setgid"
+    } else if mode & 0o002 != 0 {
+        "world-writable"
+    } else {
+        return Ok(());
+    };
+
+    Err(Error::msg(format!(
+        "refusing {} file: {}",
+        reason,
+        path.to_string_lossy()
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hash::{hash_file, hash_options, self_check};
+    use crate::metainfo::raw::check_canonical;
+    use crate::metainfo::PathElement;
+    use crate::output::{emit_encoded, print_infohash};
+    use crate::test_util::get_file_list;
+    use crate::{
+        check_peer_sources, display_name, normalize_announce, parse_node, read_torrent,
+        torrent_name_from_path,
+    };
+    use bendy::decoding::FromBencode;
+    use bendy::encoding::ToBencode;
+    use clap::Parser;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn warn_nested_torrents() {
+        let files = [
+            ("a.txt".to_owned(), 1),
+            ("b/c.torrent".to_owned(), 1),
+            ("d.TORRENT".to_owned(), 1),
+            ("torrent".to_owned(), 1),
+        ];
+        assert_eq!(nested_torrents(&files), vec!["b/c.torrent", "d.TORRENT"]);
+    }
+
+    #[test]
+    fn single_file_name() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("a.txt");
+        fs::write(&root, vec![1u8; 40 << 10]).unwrap();
+
+        let cli = Cli::parse_from([
+            "mktorrent-rs",
+            "--announce=",
+            "--piece-length=14",
+            "--verify-after-build",
+            "--name=Other",
+            root.to_str().unwrap(),
+        ]);
+        let name = display_name(&cli, &torrent_name_from_path(&root).unwrap()).unwrap();
+        let opts = HashOptions::new(PieceLength { layers: 0 });
+        let files = vec![("a.txt".to_owned(), 40 << 10)];
+        let (t, _, _) = build_torrent(&cli, &opts, name, dir.path(), files.clone(), true).unwrap();
+        assert_eq!(t.info.name, "Other");
+        let paths: Vec<_> = t.files().into_iter().map(|(p, _)| p).collect();
+        assert_eq!(paths, vec!["Other"]);
+
+        // the file is still read again under its name on disk
+        assert!(self_check(&cli, &opts, dir.path(), &files, &t, true)
+            .unwrap()
+            .is_empty());
+        fs::write(&root, vec![2u8; 40 << 10]).unwrap();
+        assert_eq!(
+            self_check(&cli, &opts, dir.path(), &files, &t, true).unwrap(),
+            vec!["a.txt"]
+        );
+    }
+
+    #[test]
+    fn single_file_name_manifest() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("a.txt");
+        fs::write(&root, "a\r\nb\r\n").unwrap();
+        let manifest = dir.path().join("m.json");
+        let out = dir.path().join("out.torrent");
+
+        let parse = |args: &[&str]| {
+            let mut argv = vec![
+                "mktorrent-rs",
+                "--announce=",
+                "--piece-length=14",
+                "--name=Other",
+                "--normalize-newlines=txt",
+                "--force",
+                "-o",
+                out.to_str().unwrap(),
+            ];
+            argv.extend_from_slice(args);
+            argv.push(root.to_str().unwrap());
+            Cli::parse_from(argv)
+        };
+        let mut opts = HashOptions::new(PieceLength { layers: 0 });
+        opts.normalize_newlines = vec!["txt".to_owned()];
+        let files = vec![("a.txt".to_owned(), 6)];
+        let build = |cli: &Cli| {
+            let name = display_name(cli, &torrent_name_from_path(&root).unwrap()).unwrap();
+            build_torrent(cli, &opts, name, dir.path(), files.clone(), true).unwrap()
+        };
+
+        let cli = parse(&["--manifest", manifest.to_str().unwrap()]);
+        let (t, mtimes, _) = build(&cli);
+        write_build(&cli, std::slice::from_ref(&out), &t, &mtimes, &files, true).unwrap();
+
+        // the file is recorded under the torrent's name
+        let m = Manifest::load(&manifest).unwrap();
+        assert_eq!(m.files.len(), 1);
+        let e = &m.files[0];
+        assert_eq!(
+            (e.path.as_str(), e.length, e.disk_length),
+            ("Other", 4, Some(6))
+        );
+        assert!(e.mtime_ns.is_some());
+
+        // and its hashes are reused while the size and mtime match
+        let mtime = fs::metadata(&root).unwrap().modified().unwrap();
+        fs::write(&root, "c\r\nd\r\n").unwrap();
+        fs::File::options()
+            .write(true)
+            .open(&root)
+            .unwrap()
+            .set_modified(mtime)
+            .unwrap();
+        let (cached, _, _) = build(&parse(&["--incremental", manifest.to_str().unwrap()]));
+        assert_eq!(cached.info.infohash().unwrap(), t.info.infohash().unwrap());
+    }
+
+    #[test]
+    fn per_subdir() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("root");
+        let out = dir.path().join("out");
+        fs::create_dir_all(root.join("show1/season1")).unwrap();
+        fs::create_dir_all(root.join("show2")).unwrap();
+        fs::create_dir_all(root.join("empty")).unwrap();
+        fs::create_dir_all(&out).unwrap();
+        fs::write(root.join("show1/season1/ep1"), "ep1").unwrap();
+        fs::write(root.join("show2/ep1"), "ep1").unwrap();
+        fs::write(root.join("show2/ep2"), "ep2").unwrap();
+
+        let cli = Cli::parse_from([
+            "mktorrent-rs",
+            "--announce=http://example.com",
+            "--piece-length=14",
+            "--per-subdir",
+            "-o",
+            out.to_str().unwrap(),
+            root.to_str().unwrap(),
+        ]);
+        let opts = HashOptions::new(PieceLength { layers: 0 });
+        build_per_subdir(&cli, &opts, "root", std::slice::from_ref(&out)).unwrap();
+
+        let mut written: Vec<_> = fs::read_dir(&out)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect();
+        written.sort();
+        assert_eq!(written, vec!["show1.torrent", "show2.torrent"]);
+        for name in &written {
+            check_canonical(&fs::read(out.join(name)).unwrap()).unwrap();
+        }
+
+        let show1 =
+            String::from_utf8_lossy(&fs::read(out.join("show1.torrent")).unwrap()).into_owned();
+        assert!(show1.contains("4:name5:show1"), "{}", show1);
+        assert!(show1.contains("7:season1d3:ep1"), "{}", show1);
+
+        let show2 =
+            String::from_utf8_lossy(&fs::read(out.join("show2.torrent")).unwrap()).into_owned();
+        assert!(show2.contains("4:name5:show2"), "{}", show2);
+        assert!(show2.contains("3:ep1d0:d6:lengthi3e"), "{}", show2);
+        assert!(show2.contains("3:ep2d0:d6:lengthi3e"), "{}", show2);
+
+        // existing torrents are only replaced with --force
+        fs::write(root.join("loose"), "loose").unwrap();
+        assert!(build_per_subdir(&cli, &opts, "root", std::slice::from_ref(&out)).is_err());
+
+        // loose files get a torrent named after the root
+        let cli = Cli::parse_from([
+            "mktorrent-rs",
+            "--announce=http://example.com",
+            "--piece-length=14",
+            "--per-subdir",
+            "--force",
+            "-o",
+            out.to_str().unwrap(),
+            root.to_str().unwrap(),
+        ]);
+        build_per_subdir(&cli, &opts, "root", std::slice::from_ref(&out)).unwrap();
+        let loose =
+            String::from_utf8_lossy(&fs::read(out.join("root.torrent")).unwrap()).into_owned();
+        assert!(
+            loose.contains("9:file treed5:loosed0:d6:lengthi5e"),
+            "{}",
+            loose
+        );
+
+        // a subdirectory named like the root would replace its torrent
+        fs::create_dir(root.join("root")).unwrap();
+        fs::write(root.join("root/a"), "a").unwrap();
+        fs::remove_file(out.join("show1.torrent")).unwrap();
+        let err = build_per_subdir(&cli, &opts, "root", std::slice::from_ref(&out)).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "more than one torrent would be written to `root.torrent`"
+        );
+        // nothing was written
+        assert!(!out.join("show1.torrent").exists());
+
+        // options for a single torrent do not apply
+        for flag in ["--start-offset=1", "--single-file", "--print-infohash"] {
+            assert!(Cli::try_parse_from([
+                "mktorrent-rs",
+                "--announce=http://example.com",
+                "--piece-length=14",
+                "--per-subdir",
+                flag,
+                "-o",
+                out.to_str().unwrap(),
+                root.to_str().unwrap(),
+            ])
+            .is_err());
+        }
+    }
+
+    #[test]
+    fn per_subdir_filters() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("root");
+        for p in [
+            "show1/season1/ep1.mkv",
+            "show1/season1/ep1.nfo",
+            "show2/ep1.mkv",
+            "show2/ep2.mkv",
+            "extras/notes.txt",
+            "cover.jpg",
+        ] {
+            let p = root.join(p);
+            fs::create_dir_all(p.parent().unwrap()).unwrap();
+            fs::write(&p, p.file_name().unwrap().as_encoded_bytes()).unwrap();
+        }
+
+        // Returns the files of each torrent written by --per-subdir with args.
+        let build = |args: &[&str]| {
+            let out = tempfile::tempdir().unwrap();
+            let mut argv = vec![
+                "mktorrent-rs",
+                "--announce=http://example.com",
+                "--piece-length=14",
+                "--per-subdir",
+                "-o",
+                out.path().to_str().unwrap(),
+            ];
+            argv.extend_from_slice(args);
+            argv.push(root.to_str().unwrap());
+            let cli = Cli::parse_from(argv);
+            let opts = HashOptions::new(PieceLength { layers: 0 });
+            build_per_subdir(&cli, &opts, "root", &[out.path().to_path_buf()]).unwrap();
+
+            let mut torrents = BTreeMap::new();
+            for entry in fs::read_dir(out.path()).unwrap() {
+                let data = fs::read(entry.unwrap().path()).unwrap();
+                check_canonical(&data).unwrap();
+                let t = Torrent::from_bencode(&data).unwrap();
+                let files: Vec<_> = t.files().into_iter().map(|(p, _)| p).collect();
+                torrents.insert(t.info.name, files);
+            }
+            torrents
+        };
+
+        assert_eq!(
+            build(&["--trim-common-prefix"]),
+            BTreeMap::from([
+                ("extras".to_owned(), vec!["notes.txt".to_owned()]),
+                ("root".to_owned(), vec!["cover.jpg".to_owned()]),
+                (
+                    "show1".to_owned(),
+                    vec!["ep1.mkv".to_owned(), "ep1.nfo".to_owned()]
+                ),
+                (
+                    "show2".to_owned(),
+                    vec!["ep1.mkv".to_owned(), "ep2.mkv".to_owned()]
+                ),
+            ])
+        );
+
+        // a subdirectory left with no files gets no torrent
+        let mkv = build(&["--include-ext=mkv"]);
+        assert_eq!(mkv.keys().collect::<Vec<_>>(), vec!["show1", "show2"]);
+        assert_eq!(mkv["show1"], vec!["season1/ep1.mkv"]);
+        let no_mkv = build(&["--exclude-ext=mkv"]);
+        assert_eq!(
+            no_mkv.keys().collect::<Vec<_>>(),
+            vec!["extras", "root", "show1"]
+        );
+        assert_eq!(no_mkv["show1"], vec!["season1/ep1.nfo"]);
+
+        // patterns match paths relative to the root, not the subdirectory
+        let ignore = dir.path().join("ignore");
+        fs::write(&ignore, "/show2/ep2.mkv\n*.txt\n").unwrap();
+        let ignored = build(&["--gitignore", ignore.to_str().unwrap()]);
+        assert_eq!(
+            ignored.keys().collect::<Vec<_>>(),
+            vec!["root", "show1", "show2"]
+        );
+        assert_eq!(ignored["show2"], vec!["ep1.mkv"]);
+
+        let old = fs::File::options()
+            .write(true)
+            .open(root.join("show2/ep2.mkv"))
+            .unwrap();
+        old.set_modified(UNIX_EPOCH + Duration::from_secs(1_000))
+            .unwrap();
+        let newer = build(&["--newer-than=2000"]);
+        assert_eq!(newer["show2"], vec!["ep1.mkv"]);
+        assert_eq!(newer["show1"].len(), 2);
+        let older = build(&["--older-than=2000"]);
+        assert_eq!(older.keys().collect::<Vec<_>>(), vec!["show2"]);
+        assert_eq!(older["show2"], vec!["ep2.mkv"]);
+    }
+
+    #[test]
+    fn directory_name_is_only_folder() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("raw_dump");
+        fs::create_dir_all(root.join("sub")).unwrap();
+        fs::write(root.join("a"), "a").unwrap();
+        fs::write(root.join("sub/b"), "b").unwrap();
+        let out = dir.path().join("out.torrent");
+
+        let cli = Cli::parse_from([
+            "mktorrent-rs",
+            "--announce=http://example.com",
+            "--piece-length=14",
+            "--name=My Collection",
+            &format!("--output={}", out.to_str().unwrap()),
+            root.to_str().unwrap(),
+        ]);
+        let opts = HashOptions::new(PieceLength { layers: 0 });
+        let name = display_name(&cli, &torrent_name_from_path(&root).unwrap()).unwrap();
+        let files = get_file_list(&root).unwrap();
+        let (t, mtimes, _) = build_torrent(&cli, &opts, name, &root, files, false).unwrap();
+        write_build(&cli, std::slice::from_ref(&out), &t, &mtimes, &[], false).unwrap();
+        check_canonical(&fs::read(&out).unwrap()).unwrap();
+
+        // clients place files at <name>/<file tree path>
+        let decoded = read_torrent(&out).unwrap();
+        assert_eq!(decoded.info.name, "My Collection");
+        let tree = &decoded.info.file_tree.entries;
+        assert_eq!(tree.keys().collect::<Vec<_>>(), ["a", "sub"]);
+        match &tree["sub"] {
+            PathElement::Directory(d) => {
+                assert_eq!(d.entries.keys().collect::<Vec<_>>(), ["b"])
+            }
+            x => panic!("expected directory, got: {:?}", x),
+        }
+
+        let encoded = fs::read(&out).unwrap();
+        assert!(!String::from_utf8_lossy(&encoded).contains("raw_dump"));
+    }
+
+    #[test]
+    fn meta_version_v1() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("root");
+        fs::create_dir_all(root.join("b")).unwrap();
+        fs::write(root.join("a"), vec![1u8; 10000]).unwrap();
+        fs::write(root.join("b/c"), vec![2u8; 30000]).unwrap();
+        fs::write(dir.path().join("a.txt"), vec![b'a'; 20000]).unwrap();
+
+        let cli = Cli::parse_from([
+            "mktorrent-rs",
+            "--announce=http://example.com",
+            "--piece-length=14",
+            "--meta-version=v1",
+            root.to_str().unwrap(),
+        ]);
+        let piece_length = PieceLength { layers: 0 };
+
+        // expected infohashes were computed independently of this crate
+        let files = get_file_list(&root).unwrap();
+        let (t, _) =
+            build_torrent_v1(&cli, piece_length, "root".to_owned(), &root, files, false).unwrap();
+        // the second piece spans both files
+        assert_eq!(t.info.pieces.len(), 3);
+        assert_eq!(
+            t.info.infohash().unwrap().to_string(),
+            "69eb9ab6113b1bdeaabd61e6cc198de8007cb845"
+        );
+
+        let files = vec![("a.txt".to_owned(), 20000)];
+        let (t, _) = build_torrent_v1(
+            &cli,
+            piece_length,
+            "a.txt".to_owned(),
+            dir.path(),
+            files,
+            true,
+        )
+        .unwrap();
+        assert_eq!(
+            t.info.infohash().unwrap().to_string(),
+            "a8a8e4abd7ea0ce39b5e5b2ded34b631de2df51c"
+        );
+
+        let encoded = String::from_utf8_lossy(&t.to_bencode().unwrap()).into_owned();
+        assert!(!encoded.contains("file tree"), "{}", encoded);
+        assert!(!encoded.contains("meta version"), "{}", encoded);
+    }
+
+    #[test]
+    fn group_by_extension() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("music/album")).unwrap();
+        fs::write(
+            dir.path().join("music/album/track.mp3"),
+            vec![1u8; 40 << 10],
+        )
+        .unwrap();
+        fs::write(dir.path().join("music/Cover.JPG"), "jpg").unwrap();
+        fs::write(dir.path().join("photo.jpg"), "photo").unwrap();
+        fs::write(dir.path().join("README"), "readme").unwrap();
+
+        let parse = |args: &[&str]| {
+            let mut argv = vec!["mktorrent-rs", "--announce=", "--piece-length=14"];
+            argv.extend_from_slice(args);
+            argv.push(dir.path().to_str().unwrap());
+            Cli::try_parse_from(argv)
+        };
+        let opts = HashOptions::new(PieceLength { layers: 0 });
+        let build = |cli: &Cli| {
+            let files = get_file_list(dir.path()).unwrap();
+            build_torrent(cli, &opts, "x".to_owned(), dir.path(), files, false).map(|(t, _, _)| t)
+        };
+
+        let plain = build(&parse(&[]).unwrap()).unwrap();
+        let grouped = build(&parse(&["--group-by-extension"]).unwrap()).unwrap();
+        let paths: Vec<_> = grouped.files().into_iter().map(|(p, _)| p).collect();
+        assert_eq!(
+            paths,
+            vec![
+                "jpg/Cover.JPG",
+                "jpg/photo.jpg",
+                "mp3/track.mp3",
+                "other/README"
+            ]
+        );
+
+        // the content is hashed from its path on disk
+        let root = |t: &Torrent, path: &str| {
+            t.files()
+                .into_iter()
+                .find(|(p, _)| p == path)
+                .unwrap()
+                .1
+                .pieces_root
+        };
+        assert_eq!(
+            root(&grouped, "mp3/track.mp3"),
+            root(&plain, "music/album/track.mp3")
+        );
+        assert_eq!(grouped.piece_layers, plain.piece_layers);
+
+        // v1 torrents are grouped the same way
+        let build_v1 = |cli: &Cli| {
+            let files = get_file_list(dir.path()).unwrap();
+            let piece_length = PieceLength { layers: 0 };
+            build_torrent_v1(cli, piece_length, "x".to_owned(), dir.path(), files, false)
+                .map(|(t, _)| t)
+        };
+        let cli = parse(&["--group-by-extension", "--meta-version=v1"]).unwrap();
+        check_v1_options(&cli).unwrap();
+        let t = build_v1(&cli).unwrap();
+        let paths: Vec<_> = match &t.info.files {
+            v1::Files::Multi(files) => files.iter().map(|f| f.path.join("/")).collect(),
+            v1::Files::Single { .. } => panic!("single file torrent"),
+        };
+        assert_eq!(
+            paths,
+            vec![
+                "jpg/Cover.JPG",
+                "jpg/photo.jpg",
+                "mp3/track.mp3",
+                "other/README"
+            ]
+        );
+
+        // files with the same name from different directories conflict
+        fs::write(dir.path().join("music/README"), "other readme").unwrap();
+        assert!(build(&parse(&["--group-by-extension"]).unwrap()).is_err());
+        let err = build_v1(&cli).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "conflicting file: other/README: added more than once"
+        );
+
+        assert!(parse(&["--group-by-extension", "--from-zip=a.zip"]).is_err());
+    }
+
+    #[test]
+    fn group_by_extension_manifest() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("root");
+        fs::create_dir_all(root.join("music")).unwrap();
+        fs::write(root.join("music/x.mp3"), vec![1u8; 40 << 10]).unwrap();
+        fs::write(root.join("README"), "readme").unwrap();
+        let manifest = dir.path().join("m.json");
+        let out = dir.path().join("out.torrent");
+
+        let parse = |args: &[&str]| {
+            let mut argv = vec![
+                "mktorrent-rs",
+                "--announce=",
+                "--piece-length=14",
+                "--group-by-extension",
+                "--force",
+                "-o",
+                out.to_str().unwrap(),
+            ];
+            argv.extend_from_slice(args);
+            argv.push(root.to_str().unwrap());
+            Cli::parse_from(argv)
+        };
+        let opts = HashOptions::new(PieceLength { layers: 0 });
+        let build = |cli: &Cli| {
+            let files = get_file_list(&root).unwrap();
+            let (t, mtimes, _) =
+                build_torrent(cli, &opts, "root".to_owned(), &root, files.clone(), false).unwrap();
+            (t, mtimes, files)
+        };
+
+        let cli = parse(&[
+            "--verify-after-build",
+            "--manifest",
+            manifest.to_str().unwrap(),
+        ]);
+        let (t, mtimes, files) = build(&cli);
+        assert!(self_check(&cli, &opts, &root, &files, &t, false)
+            .unwrap()
+            .is_empty());
+        write_build(&cli, std::slice::from_ref(&out), &t, &mtimes, &files, false).unwrap();
+
+        let m = Manifest::load(&manifest).unwrap();
+        let recorded: Vec<_> = m
+            .files
+            .iter()
+            .map(|e| (e.path.as_str(), e.mtime_ns.is_some()))
+            .collect();
+        assert_eq!(recorded, vec![("mp3/x.mp3", true), ("other/README", true)]);
+
+        // every file is read again, under its path on disk
+        let f = root.join("music/x.mp3");
+        let mtime = fs::metadata(&f).unwrap().modified().unwrap();
+        fs::write(&f, vec![2u8; 40 << 10]).unwrap();
+        assert_eq!(
+            self_check(&cli, &opts, &root, &files, &t, false).unwrap(),
+            vec!["music/x.mp3"]
+        );
+
+        // the manifest's hashes are reused while the size and mtime match
+        fs::File::options()
+            .write(true)
+            .open(&f)
+            .unwrap()
+            .set_modified(mtime)
+            .unwrap();
+        let (cached, _, _) = build(&parse(&["--incremental", manifest.to_str().unwrap()]));
+        assert_eq!(cached.info.infohash().unwrap(), t.info.infohash().unwrap());
+    }
+
+    #[test]
+    fn check_only() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("root");
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("a"), vec![1u8; 40 << 10]).unwrap();
+
+        let argv = [
+            "mktorrent-rs",
+            "--announce=http://example.com",
+            "--piece-length=14",
+            "--check-only",
+            root.to_str().unwrap(),
+        ];
+        let cli = Cli::parse_from(argv);
+        let opts = HashOptions::new(PieceLength { layers: 0 });
+        let (t, mtimes, _) = build_torrent(
+            &cli,
+            &opts,
+            "root".to_owned(),
+            &root,
+            get_file_list(&root).unwrap(),
+            false,
+        )
+        .unwrap();
+        assert_eq!(t.piece_layers.values().next().unwrap().len(), 3);
+
+        let mut out = Vec::new();
+        print_infohash(&mut out, &t, cli.infohash_encoding).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            format!("{}\n", t.info.infohash().unwrap())
+        );
+
+        // nothing is written even if there is somewhere to write it
+        let outputs = [dir.path().join("root.torrent")];
+        write_build(&cli, &outputs, &t, &mtimes, &[], false).unwrap();
+        assert!(!outputs[0].exists());
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+
+        let without: Vec<_> = argv.into_iter().filter(|a| *a != "--check-only").collect();
+        write_build(&Cli::parse_from(without), &outputs, &t, &mtimes, &[], false).unwrap();
+        check_canonical(&fs::read(&outputs[0]).unwrap()).unwrap();
+
+        // hashing errors are still reported
+        assert!(build_torrent(
+            &cli,
+            &opts,
+            "root".to_owned(),
+            &root,
+            vec![("missing".to_owned(), 1)],
+            false
+        )
+        .is_err());
+
+        for flag in [
+            "--output=out.torrent",
+            "--delta-against=prev.json --delta-output=delta.json",
+        ] {
+            let mut argv = argv.to_vec();
+            argv.extend(flag.split(' '));
+            assert!(Cli::try_parse_from(argv).is_err(), "{}", flag);
+        }
+    }
+
+    #[test]
+    fn digest_bytes() {
+        use bendy::value::Value;
+
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("root");
+        fs::create_dir(&root).unwrap();
+        fs::write(root.join("a"), vec![1u8; 40 << 10]).unwrap();
+        fs::write(root.join("b"), vec![2u8; 10 << 10]).unwrap();
+
+        let out = dir.path().join("out.torrent");
+        let build = |extra: &[&str]| {
+            let mut argv = vec!["mktorrent-rs", "--announce=", "--no-date", "--force"];
+            argv.extend(extra);
+            argv.extend(["-o", out.to_str().unwrap(), root.to_str().unwrap()]);
+            let cli = Cli::parse_from(argv);
+            let opts = hash_options(&cli, PieceLength { layers: 0 }).unwrap();
+            let files = get_file_list(&root).unwrap();
+            let (t, _, _) =
+                build_torrent(&cli, &opts, "root".to_owned(), &root, files, false).unwrap();
+            emit_encoded(&cli, std::slice::from_ref(&out), &t.to_bencode().unwrap()).unwrap();
+            let data = fs::read(&out).unwrap();
+            check_canonical(&data).unwrap();
+            (t, data)
+        };
+
+        let (standard, standard_data) = build(&[]);
+        assert_eq!(build(&["--digest-bytes=32"]).1, standard_data);
+
+        let (t, data) = build(&["--digest-bytes=8"]);
+        let root_a = t.files().into_iter().find(|(p, _)| p == "a").unwrap().1;
+        let standard_a = standard
+            .files()
+            .into_iter()
+            .find(|(p, _)| p == "a")
+            .unwrap()
+            .1;
+        assert_ne!(root_a.pieces_root, standard_a.pieces_root);
+
+        let Value::Dict(d) = Value::from_bencode(&data).unwrap() else {
+            panic!("torrent is not a dictionary");
+        };
+        let Some(Value::Dict(info)) = d.get(b"info".as_slice()) else {
+            panic!("missing info");
+        };
+        let Some(Value::Dict(tree)) = info.get(b"file tree".as_slice()) else {
+            panic!("missing file tree");
+        };
+        for name in [b"a".as_slice(), b"b"] {
+            let Some(Value::Dict(f)) = tree.get(name) else {
+                panic!("missing file");
+            };
+            let Some(Value::Dict(f)) = f.get(b"".as_slice()) else {
+                panic!("missing file");
+            };
+            let Some(Value::Bytes(root)) = f.get(b"pieces root".as_slice()) else {
+                panic!("missing pieces root");
+            };
+            assert_eq!(root.len(), 8);
+        }
+        let Some(Value::Dict(layers)) = d.get(b"piece layers".as_slice()) else {
+            panic!("missing piece layers");
+        };
+        let (root, Value::Bytes(layer)) = layers.iter().next().unwrap() else {
+            panic!("piece layer is not a string");
+        };
+        assert_eq!(root.as_ref(), &root_a.pieces_root.as_ref()[..8]);
+        assert_eq!(layer.len(), 3 * 8);
+
+        assert!(Cli::try_parse_from(["mktorrent-rs", "--digest-bytes=33", "root"]).is_err());
+        assert!(Cli::try_parse_from(["mktorrent-rs", "--digest-bytes=0", "root"]).is_err());
+    }
+
+    #[test]
+    fn from_zip() {
+        use zip::write::SimpleFileOptions;
+        use zip::CompressionMethod;
+
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("root");
+        let contents: [(&str, Vec<u8>); 3] = [
+            ("a", b"hello".to_vec()),
+            ("sub/big", (0..100 << 10).map(|i| (i % 251) as u8).collect()),
+            ("sub/empty", Vec::new()),
+        ];
+
+        let zip_path = dir.path().join("content.zip");
+        let mut w = zip::ZipWriter::new(fs::File::create(&zip_path).unwrap());
+        w.add_directory("sub/", SimpleFileOptions::default())
+            .unwrap();
+        for (i, (p, data)) in contents.iter().enumerate() {
+            let method = if i % 2 == 0 {
+                CompressionMethod::Deflated
+            } else {
+                CompressionMethod::Stored
+            };
+            w.start_file(*p, SimpleFileOptions::default().compression_method(method))
+                .unwrap();
+            w.write_all(data).unwrap();
+
+            fs::create_dir_all(root.join(p).parent().unwrap()).unwrap();
+            fs::write(root.join(p), data).unwrap();
+        }
+        w.finish().unwrap();
+
+        let cli = Cli::parse_from([
+            "mktorrent-rs",
+            "--announce=http://example.com",
+            "--piece-length=14",
+            "--name=content",
+            "--from-zip",
+            zip_path.to_str().unwrap(),
+        ]);
+        let piece_length = PieceLength { layers: 0 };
+        let t = build_from_zip(&cli, Some(piece_length), &zip_path).unwrap();
+        // 100KiB of content is split into 32KiB pieces when none is given
+        assert_eq!(
+            build_from_zip(&cli, None, &zip_path)
+                .unwrap()
+                .info
+                .piece_length,
+            PieceLength { layers: 1 }
+        );
+
+        let (expected, _, _) = build_torrent(
+            &cli,
+            &HashOptions::new(piece_length),
+            "content".to_owned(),
+            &root,
+            get_file_list(&root).unwrap(),
+            false,
+        )
+        .unwrap();
+        assert_eq!(t.to_bencode().unwrap(), expected.to_bencode().unwrap());
+
+        // entries that collide in the file tree are reported as conflicts
+        let dup = dir.path().join("dup.zip");
+        let mut w = zip::ZipWriter::new(fs::File::create(&dup).unwrap());
+        for p in ["x", "x/y"] {
+            w.start_file(p, SimpleFileOptions::default()).unwrap();
+        }
+        w.finish().unwrap();
+        let err = build_from_zip(&cli, Some(piece_length), &dup).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "conflicting file: x/y: `x` is a file and cannot also be a directory"
+        );
+    }
+
+    #[test]
+    fn from_manifest() {
+        let dir = tempfile::tempdir().unwrap();
+        let piece_length = PieceLength { layers: 0 };
+        fs::write(dir.path().join("big"), vec![3u8; 40 << 10]).unwrap();
+        let (big, layer) =
+            hash_file(dir.path(), &HashOptions::new(piece_length), "big", 40 << 10).unwrap();
+
+        let layer_hex: Vec<String> = layer.iter().map(|d| d.to_string()).collect();
+        let list = dir.path().join("list");
+        fs::write(
+            &list,
+            format!(
+                "a\t5\t{}\n\nsub/big\t{}\t{}\t{}\n",
+                "aa".repeat(32),
+                big.length,
+                big.pieces_root,
+                layer_hex.join("\t")
+            ),
+        )
+        .unwrap();
+
+        let cli = Cli::parse_from([
+            "mktorrent-rs",
+            "--announce=http://example.com",
+            "--piece-length=14",
+            "--name=catalog",
+            "--from-manifest",
+            list.to_str().unwrap(),
+        ]);
+        let t = build_from_manifest(&cli, piece_length, read_precomputed(&list).unwrap()).unwrap();
+        let files: Vec<_> = t
+            .files()
+            .into_iter()
+            .map(|(p, f)| (p, f.length, f.pieces_root))
+            .collect();
+        assert_eq!(
+            files,
+            vec![
+                ("a".to_owned(), 5, [0xaa; 32].into()),
+                ("sub/big".to_owned(), 40 << 10, big.pieces_root),
+            ]
+        );
+        assert_eq!(t.piece_layers[&big.pieces_root], layer);
+        assert!(t.validate().is_empty());
+
+        // a layer that does not match the root is rejected
+        fs::write(
+            &list,
+            format!(
+                "big\t{}\t{}\t{}\n",
+                big.length,
+                "bb".repeat(32),
+                layer_hex.join("\t")
+            ),
+        )
+        .unwrap();
+        let err =
+            build_from_manifest(&cli, piece_length, read_precomputed(&list).unwrap()).unwrap_err();
+        assert!(
+            err.to_string().starts_with("big: piece layer hashes to"),
+            "{}",
+            err
+        );
+
+        // so is a file longer than a piece without a layer
+        fs::write(&list, format!("big\t{}\t{}\n", big.length, big.pieces_root)).unwrap();
+        let err =
+            build_from_manifest(&cli, piece_length, read_precomputed(&list).unwrap()).unwrap_err();
+        assert_eq!(err.to_string(), "big: missing piece layer");
+
+        fs::write(&list, "big\tlong\n").unwrap();
+        let err = read_precomputed(&list).unwrap_err();
+        assert_eq!(err.to_string(), "line 1: invalid length");
+
+        for path in ["/big", "a/../big", "a//big", "."] {
+            fs::write(&list, format!("{}\t5\t{}\n", path, "aa".repeat(32))).unwrap();
+            let err = read_precomputed(&list).unwrap_err();
+            assert!(
+                err.to_string().starts_with("line 1: invalid path"),
+                "{}",
+                err
+            );
+        }
+    }
+
+    #[test]
+    fn warn_zero_pieces() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut data = vec![1u8; 16 << 10];
+        data.extend(vec![0u8; 16 << 10]);
+        data.extend(vec![2u8; 16 << 10]);
+        data.extend(vec![0u8; 100]);
+        fs::write(dir.path().join("a"), &data).unwrap();
+        fs::write(dir.path().join("b"), vec![3u8; 40 << 10]).unwrap();
+
+        let cli = Cli::parse_from([
+            "mktorrent-rs",
+            "--announce=",
+            "--piece-length=14",
+            "--warn-zero-pieces",
+            "root",
+        ]);
+        let (t, _, _) = build_torrent(
+            &cli,
+            &HashOptions::new(PieceLength { layers: 0 }),
+            "root".to_owned(),
+            dir.path(),
+            get_file_list(dir.path()).unwrap(),
+            false,
+        )
+        .unwrap();
+
+        assert_eq!(zero_pieces(&t), vec![("a".to_owned(), vec![1, 3])]);
+    }
+
+    #[test]
+    fn build_warnings() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(dir.path().join(".config")).unwrap();
+        fs::write(dir.path().join(".config/a"), "a").unwrap();
+        fs::write(dir.path().join("empty"), "").unwrap();
+        fs::write(dir.path().join("README"), "b").unwrap();
+        fs::write(dir.path().join("readme"), "c").unwrap();
+        fs::write(dir.path().join("x.torrent"), "d").unwrap();
+
+        let cli = Cli::parse_from([
+            "mktorrent-rs",
+            "--announce=",
+            "--piece-length=14",
+            "--warn-nested-torrents",
+            "root",
+        ]);
+        let mut files = get_file_list(dir.path()).unwrap();
+        files.sort();
+        let (_, _, warnings) = build_torrent(
+            &cli,
+            &HashOptions::new(PieceLength { layers: 0 }),
+            "root".to_owned(),
+            dir.path(),
+            files,
+            false,
+        )
+        .unwrap();
+
+        assert_eq!(
+            warnings,
+            vec![
+                BuildWarning::HiddenFile(".config/a".to_owned()),
+                BuildWarning::EmptyFile("empty".to_owned()),
+                BuildWarning::NestedTorrent("x.torrent".to_owned()),
+                BuildWarning::CaseCollision("README".to_owned(), "readme".to_owned()),
+            ]
+        );
+        assert!(warnings[1].is_minor());
+        assert_eq!(
+            warnings[3].to_string(),
+            "paths differ only by case: README and readme"
+        );
+
+        // v1 builds find the same warnings
+        let mut files = get_file_list(dir.path()).unwrap();
+        files.sort();
+        let (_, v1_warnings) = build_torrent_v1(
+            &cli,
+            PieceLength { layers: 0 },
+            "root".to_owned(),
+            dir.path(),
+            files,
+            false,
+        )
+        .unwrap();
+        assert_eq!(v1_warnings, warnings);
+    }
+
+    #[test]
+    fn fail_on_warning() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("a"), "a").unwrap();
+        fs::write(dir.path().join("empty"), "").unwrap();
+
+        let build = |args: &[&str]| {
+            let mut argv = vec!["mktorrent-rs", "--announce=", "--piece-length=14"];
+            argv.extend_from_slice(args);
+            argv.push("root");
+            let cli = Cli::parse_from(argv);
+            let (_, _, warnings) = build_torrent(
+                &cli,
+                &HashOptions::new(PieceLength { layers: 0 }),
+                "root".to_owned(),
+                dir.path(),
+                get_file_list(dir.path()).unwrap(),
+                false,
+            )
+            .unwrap();
+            report_warnings(&cli, &warnings)
+        };
+
+        // the empty file is a minor warning but still fails the build
+        build(&[]).unwrap();
+        assert_eq!(
+            build(&["--fail-on-warning"]).unwrap_err().to_string(),
+            "found 1 warnings with --fail-on-warning"
+        );
+
+        // and the same for a v1 build
+        let cli = Cli::parse_from([
+            "mktorrent-rs",
+            "--announce=",
+            "--piece-length=14",
+            "--meta-version=v1",
+            "--fail-on-warning",
+            "root",
+        ]);
+        let (_, warnings) = build_torrent_v1(
+            &cli,
+            PieceLength { layers: 0 },
+            "root".to_owned(),
+            dir.path(),
+            get_file_list(dir.path()).unwrap(),
+            false,
+        )
+        .unwrap();
+        assert!(report_warnings(&cli, &warnings).is_err());
+
+        fs::remove_file(dir.path().join("empty")).unwrap();
+        build(&["--fail-on-warning"]).unwrap();
+    }
+
+    #[test]
+    fn warn_wide_dirs() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("a/wide")).unwrap();
+        for i in 0..20 {
+            fs::write(dir.path().join(format!("a/wide/{}", i)), "w").unwrap();
+        }
+        fs::write(dir.path().join("a/b"), "b").unwrap();
+
+        let build = |args: &[&str]| {
+            let mut argv = vec!["mktorrent-rs", "--announce=", "--piece-length=14"];
+            argv.extend_from_slice(args);
+            argv.push("root");
+            build_torrent(
+                &Cli::parse_from(argv),
+                &HashOptions::new(PieceLength { layers: 0 }),
+                "root".to_owned(),
+                dir.path(),
+                get_file_list(dir.path()).unwrap(),
+                false,
+            )
+            .map(|(_, _, warnings)| warnings)
+        };
+
+        assert!(build(&[]).unwrap().is_empty());
+        assert!(build(&["--warn-wide-dirs=20"]).unwrap().is_empty());
+        assert_eq!(
+            build(&["--warn-wide-dirs=10"]).unwrap(),
+            vec![BuildWarning::WideDirectory("a/wide".to_owned(), 20)]
+        );
+        assert_eq!(
+            build(&["--warn-wide-dirs=10"]).unwrap()[0].to_string(),
+            "directory has 20 entries: a/wide"
+        );
+        assert_eq!(
+            build(&["--warn-wide-dirs=10", "--strict"])
+                .unwrap_err()
+                .to_string(),
+            "1 directories have more than 10 entries"
+        );
+
+        // found from the file list before anything is read
+        let mut files = get_file_list(dir.path()).unwrap();
+        files.push(("a/wide/missing".to_owned(), 1));
+        let cli = Cli::parse_from([
+            "mktorrent-rs",
+            "--announce=",
+            "--piece-length=14",
+            "--warn-wide-dirs=10",
+            "--strict",
+            "root",
+        ]);
+        let opts = HashOptions::new(PieceLength { layers: 0 });
+        let err =
+            build_torrent(&cli, &opts, "root".to_owned(), dir.path(), files, false).unwrap_err();
+        assert_eq!(err.to_string(), "1 directories have more than 10 entries");
+    }
+
+    #[test]
+    fn unsupported_options() {
+        let parse = |args: &[&str]| {
+            let mut argv = vec!["mktorrent-rs", "--announce=", "-o", "out"];
+            argv.extend_from_slice(args);
+            argv.push("root");
+            Cli::parse_from(argv)
+        };
+        let err = |r: Result<()>| r.unwrap_err().to_string();
+
+        let cli = parse(&[]);
+        check_v1_options(&cli).unwrap();
+        check_multiple_piece_lengths_options(&cli).unwrap();
+
+        let cli = parse(&["--single-threaded"]);
+        check_v1_options(&cli).unwrap();
+        assert_eq!(
+            err(check_multiple_piece_lengths_options(&cli)),
+            "--single-threaded requires a single --piece-length"
+        );
+
+        let cli = parse(&["--always-emit-layer"]);
+        check_multiple_piece_lengths_options(&cli).unwrap();
+        assert_eq!(
+            err(check_v1_options(&cli)),
+            "--always-emit-layer requires --meta-version=v2"
+        );
+
+        let cli = parse(&["--store-crc32"]);
+        assert!(err(check_v1_options(&cli)).starts_with("--store-crc32"));
+        assert!(err(check_multiple_piece_lengths_options(&cli)).starts_with("--store-crc32"));
+    }
+
+    #[test]
+    fn multiple_piece_lengths() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("root");
+        fs::create_dir_all(root.join("sub")).unwrap();
+        let data: Vec<u8> = (0..(300 << 10) + 5u32).map(|i| (i % 251) as u8).collect();
+        fs::write(root.join("big"), &data).unwrap();
+        fs::write(root.join("sub/small"), &data[..100]).unwrap();
+        fs::write(root.join("piece"), &data[..32 << 10]).unwrap();
+
+        let argv = [
+            "mktorrent-rs",
+            "--announce=http://example.com",
+            "--piece-length=14",
+            "--piece-length=15",
+            "--piece-length=17",
+            "--output=out.torrent",
+            root.to_str().unwrap(),
+        ];
+        let cli = Cli::parse_from(argv);
+        check_multiple_piece_lengths_options(&cli).unwrap();
+
+        let mut max_buffer_memory = argv.to_vec();
+        max_buffer_memory.insert(1, "--max-buffer-memory=1048576");
+        let err =
+            check_multiple_piece_lengths_options(&Cli::parse_from(max_buffer_memory)).unwrap_err();
+        assert!(err.to_string().contains("--max-buffer-memory"), "{}", err);
+
+        let piece_lengths: Vec<_> = cli
+            .piece_length
+            .iter()
+            .map(|e| PieceLength { layers: e - 14 })
+            .collect();
+        let files = get_file_list(&root).unwrap();
+        let opts = HashOptions::new(piece_lengths[0]);
+        let torrents = build_torrents(
+            &cli,
+            &opts,
+            &piece_lengths,
+            "root".to_owned(),
+            &root,
+            files.clone(),
+            false,
+        )
+        .unwrap();
+        assert_eq!(torrents.len(), 3);
+
+        for (t, &piece_length) in torrents.iter().zip(&piece_lengths) {
+            let (single, _, _) = build_torrent(
+                &cli,
+                &HashOptions::new(piece_length),
+                "root".to_owned(),
+                &root,
+                files.clone(),
+                false,
+            )
+            .unwrap();
+            assert_eq!(
+                t.to_bencode().unwrap(),
+                single.to_bencode().unwrap(),
+                "piece length {}",
+                piece_length.bytes()
+            );
+        }
+
+        assert_eq!(
+            with_piece_length(Path::new("dir/out.torrent"), 18),
+            Path::new("dir/out.18.torrent")
+        );
+        assert_eq!(with_piece_length(Path::new("out"), 18), Path::new("out.18"));
+
+        let no_output: Vec<_> = argv
+            .into_iter()
+            .filter(|a| !a.starts_with("--output"))
+            .collect();
+        assert!(check_multiple_piece_lengths_options(&Cli::parse_from(no_output)).is_err());
+    }
+
+    #[test]
+    fn auto_piece_length_log() {
+        let log = |args: &[&str]| {
+            let mut argv = vec!["mktorrent-rs", "--announce=", "-o", "out"];
+            argv.extend_from_slice(args);
+            argv.push("root");
+            let mut log = Vec::new();
+            let piece_length = auto_piece_length(&Cli::parse_from(argv), 50_000, &mut log).unwrap();
+            assert_eq!(piece_length, PieceLength::for_content(50_000));
+            String::from_utf8(log).unwrap()
+        };
+
+        assert_eq!(
+            log(&[]),
+            "using --piece-length=15 for 50000 bytes of content\n"
+        );
+        assert_eq!(log(&["--json-summary"]), "");
+    }
+
+    #[test]
+    fn encoding_is_canonical() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("b")).unwrap();
+        fs::write(dir.path().join("a"), vec![1u8; 40 << 10]).unwrap();
+        fs::write(dir.path().join("b/c"), "c").unwrap();
+        fs::write(dir.path().join("b/empty"), "").unwrap();
+
+        let cli = Cli::parse_from([
+            "mktorrent-rs",
+            "--announce=http://a",
+            "--announce=http://b",
+            "--announce-tier=http://c",
+            "--comment=comment",
+            "--http-seed=http://seed",
+            "--piece-length=14",
+            "root",
+        ]);
+        let files = get_file_list(dir.path()).unwrap();
+        let mut opts = HashOptions::new(PieceLength { layers: 0 });
+        opts.store_crc32 = true;
+        let (t, _, _) = build_torrent(
+            &cli,
+            &opts,
+            "root".to_owned(),
+            dir.path(),
+            files.clone(),
+            false,
+        )
+        .unwrap();
+        check_canonical(&t.to_bencode().unwrap()).unwrap();
+
+        let (t, _) = build_torrent_v1(
+            &cli,
+            PieceLength { layers: 0 },
+            "root".to_owned(),
+            dir.path(),
+            files,
+            false,
+        )
+        .unwrap();
+        check_canonical(&t.to_bencode().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn creation_date() {
+        let parse = |args: &[&str]| {
+            let mut argv = vec!["mktorrent-rs", "--announce=", "--piece-length=14"];
+            argv.extend_from_slice(args);
+            argv.push("root");
+            Cli::try_parse_from(argv)
+        };
+        let cli = parse(&["--creation-date=1700000000"]).unwrap();
+        let mut t = Torrent::new("".to_owned(), "".to_owned(), PieceLength { layers: 0 });
+        set_torrent_options(&cli, &mut t);
+        assert_eq!(t.creation_date, Some(1700000000));
+
+        assert!(parse(&["--creation-date=1", "--no-date"]).is_err());
+    }
+
+    #[test]
+    fn http_seed() {
+        let parse = |args: &[&str]| {
+            let mut argv = vec!["mktorrent-rs", "--announce=", "--piece-length=14"];
+            argv.extend_from_slice(args);
+            argv.push("root");
+            Cli::try_parse_from(argv)
+        };
+        let cli = parse(&["--http-seed=http://a/seed", "--http-seed=https://b/"]).unwrap();
+        let mut t = Torrent::new("".to_owned(), "".to_owned(), PieceLength { layers: 0 });
+        set_torrent_options(&cli, &mut t);
+        assert_eq!(t.httpseeds, vec!["http://a/seed", "https://b/"]);
+
+        assert!(parse(&["--http-seed=ftp://a/"]).is_err());
+        assert!(parse(&["--http-seed=not a url"]).is_err());
+    }
+
+    #[test]
+    fn web_seed() {
+        let parse = |args: &[&str]| {
+            let mut argv = vec!["mktorrent-rs", "--announce=", "--piece-length=14"];
+            argv.extend_from_slice(args);
+            argv.push("root");
+            Cli::try_parse_from(argv)
+        };
+        let cli = parse(&["--web-seed=https://a/files/", "--web-seed=ftp://b/"]).unwrap();
+        let mut t = Torrent::new("".to_owned(), "".to_owned(), PieceLength { layers: 0 });
+        set_torrent_options(&cli, &mut t);
+        assert_eq!(t.url_list, vec!["https://a/files/", "ftp://b/"]);
+
+        assert!(parse(&["--web-seed=file:///srv/files"]).is_err());
+        assert!(parse(&["--web-seed=not a url"]).is_err());
+        assert!(parse(&["--web-seed=http://"]).is_err());
+    }
+
+    #[test]
+    fn dht_nodes() {
+        assert_eq!(
+            parse_node("router.example.com:6881"),
+            Ok(("router.example.com".to_owned(), 6881))
+        );
+        assert_eq!(parse_node("10.0.0.1:1"), Ok(("10.0.0.1".to_owned(), 1)));
+        assert_eq!(parse_node("[::1]:6881"), Ok(("::1".to_owned(), 6881)));
+        assert_eq!(
+            parse_node("[2001:DB8::1]:51413"),
+            Ok(("2001:db8::1".to_owned(), 51413))
+        );
+        for bad in [
+            "",
+            "host",
+            ":6881",
+            "host:",
+            "host:0",
+            "host:65536",
+            "::1:6881",
+            "[::1:6881",
+            "[nope]:6881",
+            "bad host:6881",
+        ] {
+            assert!(parse_node(bad).is_err(), "{}", bad);
+        }
+
+        // --node makes --announce optional
+        let parse = |args: &[&str]| {
+            let mut argv = vec!["mktorrent-rs", "--piece-length=14"];
+            argv.extend_from_slice(args);
+            argv.push("root");
+            Cli::try_parse_from(argv).unwrap()
+        };
+        assert!(check_peer_sources(&parse(&[])).is_err());
+        assert!(check_peer_sources(&parse(&["--announce=http://a"])).is_ok());
+        assert!(check_peer_sources(&parse(&["--announce-tier=http://a"])).is_ok());
+        let cli = parse(&["--node=[::1]:6881", "--node=dht.example.com:6881"]);
+        assert!(check_peer_sources(&cli).is_ok());
+        let mut t = Torrent::new(announce(&cli), "".to_owned(), PieceLength { layers: 0 });
+        set_torrent_options(&cli, &mut t);
+        assert_eq!(t.announce, "");
+        assert_eq!(
+            t.nodes,
+            vec![
+                ("::1".to_owned(), 6881),
+                ("dht.example.com".to_owned(), 6881)
+            ]
+        );
+    }
+
+    #[test]
+    fn announce_tiers() {
+        let parse = |args: &[&str]| {
+            let mut argv = vec!["mktorrent-rs", "--piece-length=14"];
+            argv.extend_from_slice(args);
+            argv.push("root");
+            Cli::try_parse_from(argv)
+        };
+        let build = |cli: &Cli| {
+            let mut t = Torrent::new(announce(cli), "".to_owned(), PieceLength { layers: 0 });
+            set_torrent_options(cli, &mut t);
+            t
+        };
+
+        // a single announce url has no announce-list
+        let t = build(&parse(&["--announce=http://a"]).unwrap());
+        assert_eq!(t.announce, "http://a");
+        assert!(t.announce_list.is_empty());
+
+        let cli = parse(&[
+            "--announce=http://b",
+            "--announce=http://a",
+            "--announce-tier=http://d,http://c",
+            "--announce-tier=http://e",
+        ])
+        .unwrap();
+        let t = build(&cli);
+        assert_eq!(t.announce, "http://b");
+        assert_eq!(
+            t.announce_list,
+            vec![
+                vec!["http://b", "http://a"],
+                vec!["http://d", "http://c"],
+                vec!["http://e"],
+            ]
+        );
+
+        // without --announce the first tier provides the announce url
+        let t = build(&parse(&["--announce-tier=http://a,http://b"]).unwrap());
+        assert_eq!(t.announce, "http://a");
+        assert_eq!(t.announce_list, vec![vec!["http://a", "http://b"]]);
+        let t = build(&parse(&["--announce-tier=http://a"]).unwrap());
+        assert_eq!(t.announce, "http://a");
+        assert!(t.announce_list.is_empty());
+
+        assert!(parse(&["--announce=http://a", "--announce-tier=,"]).is_err());
+
+        // equivalent urls are removed within and across tiers
+        let mut cli = parse(&[
+            "--announce=http://TRACKER:80/",
+            "--announce=http://tracker/",
+            "--announce-tier=http://tracker:80,http://other",
+            "--announce-tier=HTTP://Tracker",
+            "--normalize-announce",
+        ])
+        .unwrap();
+        normalize_announce(&mut cli).unwrap();
+        let t = build(&cli);
+        assert_eq!(t.announce, "http://tracker/");
+        assert_eq!(
+            t.announce_list,
+            vec![vec!["http://tracker/"], vec!["http://other/"]]
+        );
+
+        let mut cli = parse(&[
+            "--announce=http://TRACKER:80/",
+            "--announce=http://tracker/",
+            "--normalize-announce",
+        ])
+        .unwrap();
+        normalize_announce(&mut cli).unwrap();
+        let t = build(&cli);
+        assert_eq!(t.announce, "http://tracker/");
+        assert!(t.announce_list.is_empty());
+
+        let mut cli = parse(&["--announce=not a url", "--normalize-announce"]).unwrap();
+        assert!(normalize_announce(&mut cli).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn refuse_suspicious() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let p = dir.path().join("a");
+        fs::write(&p, "a").unwrap();
+
+        fs::set_permissions(&p, fs::Permissions::from_mode(0o644)).unwrap();
+        check_suspicious(&p, &fs::metadata(&p).unwrap()).unwrap();
+
+        fs::set_permissions(&p, fs::Permissions::from_mode(0o4755)).unwrap();
+        let err = check_suspicious(&p, &fs::metadata(&p).unwrap()).unwrap_err();
+        assert!(err.to_string().contains("setuid"), "{}", err);
+        assert!(err.to_string().contains(&*p.to_string_lossy()), "{}", err);
+
+        // v1 builds are checked too
+        fs::set_permissions(&p, fs::Permissions::from_mode(0o666)).unwrap();
+        let cli = Cli::parse_from([
+            "mktorrent-rs",
+            "--announce=",
+            "--piece-length=14",
+            "--meta-version=v1",
+            "--refuse-suspicious",
+            "root",
+        ]);
+        let files = vec![("a".to_owned(), 1)];
+        let err = build_torrent_v1(
+            &cli,
+            PieceLength { layers: 0 },
+            "a".to_owned(),
+            dir.path(),
+            files,
+            true,
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("world-writable"), "{}", err);
+
+        // and so are builds with several piece lengths
+        let cli = Cli::parse_from([
+            "mktorrent-rs",
+            "--announce=",
+            "--piece-length=14",
+            "--piece-length=15",
+            "--refuse-suspicious",
+            "root",
+        ]);
+        let piece_lengths = [PieceLength { layers: 0 }, PieceLength { layers: 1 }];
+        let err = build_torrents(
+            &cli,
+            &HashOptions::new(piece_lengths[0]),
+            &piece_lengths,
+            "a".to_owned(),
+            dir.path(),
+            vec![("a".to_owned(), 1)],
+            false,
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("world-writable"), "{}", err);
+    }
+}
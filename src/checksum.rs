@@ -1,7 +1,16 @@
 extern crate ring;
 
+pub mod adaptive;
+pub mod crc32;
 pub mod merkle;
+pub mod sha1;
 pub mod sha256;
+pub mod torrent1;
 pub mod torrent2;
 
-pub use torrent2::{checksum_file, checksum_file_multithreaded};
+pub use torrent1::checksum_files_v1;
+pub use torrent2::{
+    checksum_file_adaptive, checksum_file_crc32, checksum_file_limited,
+    checksum_file_multithreaded, checksum_file_progress, checksum_file_root,
+    checksum_file_sequential, single_piece_layer,
+};
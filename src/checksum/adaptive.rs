@@ -0,0 +1,148 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use rayon::{ThreadPool, ThreadPoolBuilder};
+
+// Picks a thread count by measuring hashing throughput. It starts with a
+// single thread and doubles the count while throughput keeps improving. Once
+// doubling stops helping, it settles on the best count seen.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ThreadController {
+    threads: usize,
+    max_threads: usize,
+    // The thread count and throughput (bytes/s) of the best batch so far.
+    best: Option<(usize, f64)>,
+    settled: bool,
+}
+
+impl ThreadController {
+    // Throughput must improve by this factor to keep adding threads.
+    const MIN_IMPROVEMENT: f64 = 1.1;
+
+    pub fn new(max_threads: usize) -> Self {
+        let max_threads = max_threads.max(1);
+        Self {
+            threads: 1,
+            max_threads,
+            best: None,
+            settled: max_threads == 1,
+        }
+    }
+
+    // The number of threads to use for the next batch.
+    pub fn threads(&self) -> usize {
+        self.threads
+    }
+
+    // Records that the last batch hashed bytes in elapsed time using
+    // threads() threads and adjusts the thread count.
+    pub fn record(&mut self, bytes: u64, elapsed: Duration) {
+        if self.settled {
+            return;
+        }
+
+        let throughput = bytes as f64 / elapsed.as_secs_f64().max(1e-9);
+        match self.best {
+            Some((best_threads, best)) if throughput < best * Self::MIN_IMPROVEMENT => {
+                self.threads = best_threads;
+                self.settled = true;
+            }
+            _ => {
+                self.best = Some((self.threads, throughput));
+                if self.threads == self.max_threads {
+                    self.settled = true;
+                } else {
+                    self.threads = (self.threads * 2).min(self.max_threads);
+                }
+            }
+        }
+    }
+}
+
+// A ThreadController along with a rayon pool for each thread count it tries.
+pub struct AdaptiveThreads {
+    controller: Mutex<ThreadController>,
+    pools: Mutex<HashMap<usize, Arc<ThreadPool>>>,
+}
+
+impl AdaptiveThreads {
+    pub fn new(max_threads: usize) -> Self {
+        Self {
+            controller: Mutex::new(ThreadController::new(max_threads)),
+            pools: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Returns the pool to use for the next batch.
+    pub fn pool(&self) -> Arc<ThreadPool> {
+        let threads = self.controller.lock().unwrap().threads();
+        self.pools
+            .lock()
+            .unwrap()
+            .entry(threads)
+            .or_insert_with(|| {
+                Arc::new(
+                    ThreadPoolBuilder::new()
+                        .num_threads(threads)
+                        .build()
+                        .expect("failed to build thread pool"),
+                )
+            })
+            .clone()
+    }
+
+    pub fn record(&self, bytes: u64, elapsed: Duration) {
+        self.controller.lock().unwrap().record(bytes, elapsed);
+    }
+
+    pub fn threads(&self) -> usize {
+        self.controller.lock().unwrap().threads()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Simulates a disk where throughput scales with threads up to a point and
+    // then suffers from contention.
+    fn simulated_duration(threads: usize, bytes: u64) -> Duration {
+        const PER_THREAD: f64 = 100e6;
+        let throughput = if threads <= 4 {
+            threads as f64 * PER_THREAD
+        } else {
+            4.0 * PER_THREAD / (1.0 + 0.2 * (threads - 4) as f64)
+        };
+        Duration::from_secs_f64(bytes as f64 / throughput)
+    }
+
+    #[test]
+    fn converges() {
+        let mut c = ThreadController::new(32);
+        for _ in 0..10 {
+            let threads = c.threads();
+            c.record(1 << 30, simulated_duration(threads, 1 << 30));
+        }
+        assert!(c.settled);
+        assert_eq!(c.threads(), 4);
+    }
+
+    #[test]
+    fn converges_at_max() {
+        let mut c = ThreadController::new(3);
+        for _ in 0..10 {
+            let threads = c.threads();
+            c.record(1 << 30, simulated_duration(threads, 1 << 30));
+        }
+        assert!(c.settled);
+        assert_eq!(c.threads(), 3);
+    }
+
+    #[test]
+    fn single_thread() {
+        let c = ThreadController::new(1);
+        assert!(c.settled);
+        assert_eq!(c.threads(), 1);
+    }
+}
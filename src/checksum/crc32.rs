@@ -0,0 +1,50 @@
+use std::io::{self, Read};
+
+// Wraps a reader and computes the CRC32 of everything read through it.
+pub struct Crc32Reader<R> {
+    inner: R,
+    hasher: crc32fast::Hasher,
+}
+
+impl<R: Read> Crc32Reader<R> {
+    pub fn new(inner: R) -> Self {
+        Crc32Reader {
+            inner,
+            hasher: crc32fast::Hasher::new(),
+        }
+    }
+
+    // Returns the CRC32 of the data read so far.
+    pub fn finish(self) -> u32 {
+        self.hasher.finalize()
+    }
+
+    // Returns the hasher so the CRC32 can be combined with that of the data
+    // which follows.
+    pub fn into_hasher(self) -> crc32fast::Hasher {
+        self.hasher
+    }
+}
+
+impl<R: Read> Read for Crc32Reader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crc32_reader() {
+        let mut r = Crc32Reader::new(b"123456789".as_slice());
+        let mut out = Vec::new();
+        r.read_to_end(&mut out).unwrap();
+        assert_eq!(out, b"123456789");
+        // the standard check value for CRC-32/ISO-HDLC
+        assert_eq!(r.finish(), 0xcbf43926);
+    }
+}
@@ -1,39 +1,149 @@
+use std::fmt;
+use std::sync::OnceLock;
+
 use crate::checksum::sha256;
 
+// The hash function a merkle tree is built with. BEP 52 only uses SHA-256 but
+// the tree logic does not depend on it.
+pub trait TreeHash {
+    type Digest: Clone + Default + fmt::Debug + PartialEq + Eq;
+    // Incrementally hashes the contents of a block.
+    type Context: Clone + Default;
+
+    fn update(ctx: &mut Self::Context, data: &[u8]);
+    // Returns the digest and resets the context.
+    fn finish(ctx: &mut Self::Context) -> Self::Digest;
+    // Returns the parent of two sibling nodes.
+    fn combine(a: &Self::Digest, b: &Self::Digest) -> Self::Digest;
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Sha256;
+
+impl TreeHash for Sha256 {
+    type Digest = sha256::Digest;
+    type Context = sha256::Hasher;
+
+    fn update(ctx: &mut Self::Context, data: &[u8]) {
+        ctx.update(data);
+    }
+
+    fn finish(ctx: &mut Self::Context) -> Self::Digest {
+        ctx.finish()
+    }
+
+    // Computes SHA256(a + b).
+    fn combine(a: &sha256::Digest, b: &sha256::Digest) -> sha256::Digest {
+        let mut h = sha256::Hasher::default();
+        h.update(a.as_ref());
+        h.update(b.as_ref());
+        h.into_digest()
+    }
+}
+
+// Experimental and nonstandard: SHA-256 with every digest truncated. Digests
+// keep the length of a full SHA-256 digest with the truncated bytes zeroed,
+// so only their first len bytes are meaningful. Truncated to 32 bytes this is
+// the same as Sha256.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Truncated;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TruncatedDigest {
+    pub digest: sha256::Digest,
+    // The number of leading bytes of digest kept.
+    pub len: usize,
+}
+
+impl TruncatedDigest {
+    // Keeps the first len bytes of d. len must be at most 32.
+    pub fn new(d: sha256::Digest, len: usize) -> Self {
+        let mut digest: [u8; sha256::Digest::LENGTH] = d.into();
+        digest[len..].fill(0);
+        TruncatedDigest {
+            digest: digest.into(),
+            len,
+        }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.digest.as_ref()[..self.len]
+    }
+}
+
+impl TreeHash for Truncated {
+    type Digest = TruncatedDigest;
+    type Context = sha256::Hasher;
+
+    fn update(ctx: &mut Self::Context, data: &[u8]) {
+        ctx.update(data);
+    }
+
+    // The length to truncate to is not known here so the full digest is kept.
+    // Callers truncate block hashes themselves.
+    fn finish(ctx: &mut Self::Context) -> Self::Digest {
+        TruncatedDigest::new(ctx.finish(), sha256::Digest::LENGTH)
+    }
+
+    // Computes SHA256(a + b) truncated to the length of a.
+    fn combine(a: &TruncatedDigest, b: &TruncatedDigest) -> TruncatedDigest {
+        let mut h = sha256::Hasher::default();
+        h.update(a.as_bytes());
+        h.update(b.as_bytes());
+        TruncatedDigest::new(h.into_digest(), a.len)
+    }
+}
+
 // Calculate the root hash of a merkle tree given a layer of the merkle tree.
 // Missing hashes are assumed to be zeros at layer zero.
 pub fn root_hash<'a>(
     layer: u8,
     digests: impl IntoIterator<Item = &'a sha256::Digest>,
+) -> sha256::Digest {
+    root_hash_owned(layer, digests.into_iter().copied())
+}
+
+// Same as root_hash but consumes owned digests.
+pub fn root_hash_owned(
+    layer: u8,
+    digests: impl IntoIterator<Item = sha256::Digest>,
 ) -> sha256::Digest {
     let mut hasher = Hasher::new();
     for d in digests {
-        hasher.add_block(d);
+        hasher.add_block(&d);
     }
 
     hasher.finish_tree(&zero_root(layer))
 }
 
-#[derive(Clone, Debug, Default, PartialEq, Eq)]
-pub struct Hasher {
-    stack: Vec<Entry>,
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Hasher<H: TreeHash = Sha256> {
+    stack: Vec<Entry<H::Digest>>,
 }
 
 impl Hasher {
     pub fn new() -> Self {
         Self::default()
     }
+}
+
+impl<H: TreeHash> Default for Hasher<H> {
+    fn default() -> Self {
+        Self { stack: Vec::new() }
+    }
+}
 
+impl<H: TreeHash> Hasher<H> {
     // Adds an entry to the bottom layer of the merkle tree.
-    pub fn add_block(&mut self, hash: &sha256::Digest) {
-        self.stack.push(Entry::new(*hash));
+    pub fn add_block(&mut self, hash: &H::Digest) {
+        self.stack.push(Entry::new(hash.clone()));
         while self.stack.len() >= 2
             && self.stack[self.stack.len() - 1].layer == self.stack[self.stack.len() - 2].layer
         {
             let b = self.stack.pop().unwrap();
             let a = self.stack.pop().unwrap();
 
-            let d = Hasher::combine_digests(&a.digest, &b.digest);
+            let d = H::combine(&a.digest, &b.digest);
             self.stack.push(Entry {
                 layer: a.layer + 1,
                 digest: d,
@@ -41,14 +151,6 @@ impl Hasher {
         }
     }
 
-    // Computes SHA256(a + b).
-    fn combine_digests(a: &sha256::Digest, b: &sha256::Digest) -> sha256::Digest {
-        let mut h = sha256::Hasher::default();
-        h.update(a.as_ref());
-        h.update(b.as_ref());
-        h.into_digest()
-    }
-
     // Returns the current layer if that layer is complete, otherwise None.
     pub fn current_layer(&self) -> Option<u8> {
         if self.stack.len() == 1 {
@@ -60,12 +162,12 @@ impl Hasher {
 
     // Adds the pad to the merkle tree until there is a single root. This
     // resets the hasher.
-    pub fn finish_tree(&mut self, pad: &sha256::Digest) -> sha256::Digest {
+    pub fn finish_tree(&mut self, pad: &H::Digest) -> H::Digest {
         while self.stack.len() != 1 {
             self.add_block(pad);
         }
 
-        let ret = self.stack[0].digest;
+        let ret = self.stack.pop().unwrap().digest;
         self.reset();
         ret
     }
@@ -73,7 +175,7 @@ impl Hasher {
     // Adds the pad to the merkle tree until the root is at the given layer. If
     // the next root is greater than the given layer, None is returned. In
     // either case the hasher is reset.s
-    pub fn finish_layer(&mut self, pad: &sha256::Digest, layer: u8) -> Option<sha256::Digest> {
+    pub fn finish_layer(&mut self, pad: &H::Digest, layer: u8) -> Option<H::Digest> {
         if let Some(e) = self.stack.first() {
             // If we have too many blocks, we can't pad to reach tht layer.
             if e.layer > layer || (e.layer == layer && self.stack.len() > 1) {
@@ -86,7 +188,7 @@ impl Hasher {
             self.add_block(pad);
         }
 
-        let ret = self.stack[0].digest;
+        let ret = self.stack.pop().unwrap().digest;
         self.reset();
         Some(ret)
     }
@@ -97,28 +199,67 @@ impl Hasher {
     }
 }
 
-#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
-struct Entry {
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+struct Entry<D> {
     layer: u8,
-    digest: sha256::Digest,
+    digest: D,
 }
 
-impl Entry {
-    fn new(digest: sha256::Digest) -> Self {
+impl<D> Entry<D> {
+    fn new(digest: D) -> Self {
         Self { layer: 0, digest }
     }
 }
 
-// Calculates the merkle root of a tree with the given layer assuming all input
-// blocks are zeroed digests.
-pub fn zero_root(layer: u8) -> sha256::Digest {
-    let mut d = sha256::Digest::default();
+// Takes the root of a tree at layer from and returns the root of the tree at
+// layer to where the original tree is the leftmost subtree and the rest of the
+// leaves are zeroed digests.
+pub fn extend_root(mut root: sha256::Digest, from: u8, to: u8) -> sha256::Digest {
+    for layer in from..to {
+        root = Sha256::combine(&root, &zero_root(layer));
+    }
+    root
+}
+
+// Number of layers zero_root caches. Roots of larger layers are derived from
+// the largest cached root.
+const ZERO_ROOT_CACHE_LAYERS: usize = 64;
+
+static ZERO_ROOTS: OnceLock<[sha256::Digest; ZERO_ROOT_CACHE_LAYERS]> = OnceLock::new();
+
+// Returns the merkle root of a tree with the given layer whose blocks are all
+// pad. zero_root is the same for Sha256 and a zeroed pad.
+pub fn pad_root<H: TreeHash>(pad: &H::Digest, layer: u8) -> H::Digest {
+    let mut d = pad.clone();
     for _ in 0..layer {
-        d = Hasher::combine_digests(&d, &d);
+        d = H::combine(&d, &d);
     }
     d
 }
 
+// Calculates the merkle root of a tree with the given layer assuming all input
+// blocks are zeroed digests. Roots are cached after the first call.
+pub fn zero_root(layer: u8) -> sha256::Digest {
+    let roots = ZERO_ROOTS.get_or_init(|| {
+        let mut roots = [sha256::Digest::default(); ZERO_ROOT_CACHE_LAYERS];
+        for i in 1..roots.len() {
+            roots[i] = Sha256::combine(&roots[i - 1], &roots[i - 1]);
+        }
+        roots
+    });
+
+    match roots.get(layer as usize) {
+        Some(d) => *d,
+        None => {
+            let mut d = roots[ZERO_ROOT_CACHE_LAYERS - 1];
+            for _ in ZERO_ROOT_CACHE_LAYERS - 1..layer as usize {
+                d = Sha256::combine(&d, &d);
+            }
+            d
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -126,8 +267,8 @@ mod tests {
     #[test]
     fn test_finish_reset() {
         // test that finish resets the hasher
-        let a = ['a' as u8; sha256::Digest::LENGTH].try_into().unwrap();
-        let b = ['b' as u8; sha256::Digest::LENGTH].try_into().unwrap();
+        let a = [b'a'; sha256::Digest::LENGTH].into();
+        let b = [b'b'; sha256::Digest::LENGTH].into();
         let mut h = Hasher::new();
         h.add_block(&a);
         assert_eq!(h.finish_tree(&sha256::Digest::default()), a);
@@ -144,15 +285,15 @@ mod tests {
     #[test]
     fn test_single_block() {
         // acts as an identity function
-        let d = ['a' as u8; sha256::Digest::LENGTH].try_into().unwrap();
+        let d = [b'a'; sha256::Digest::LENGTH].into();
         assert_eq!(root_hash(0, [&d]), d);
     }
 
     #[test]
     fn test_two_blocks() {
         // returns the hash of a + b
-        let a = ['a' as u8; sha256::Digest::LENGTH].try_into().unwrap();
-        let b = ['b' as u8; sha256::Digest::LENGTH].try_into().unwrap();
+        let a = [b'a'; sha256::Digest::LENGTH].into();
+        let b = [b'b'; sha256::Digest::LENGTH].into();
         assert_eq!(
             root_hash(0, [&a, &b]),
             [
@@ -163,14 +304,23 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_owned() {
+        // matches the borrowed version
+        let digests: Vec<sha256::Digest> = (b'a'..=b'e')
+            .map(|c| [c; sha256::Digest::LENGTH].into())
+            .collect();
+        assert_eq!(root_hash_owned(2, digests.clone()), root_hash(2, &digests));
+    }
+
     #[test]
     fn test_five_blocks() {
         // forms a merkle tree adding 3 zeroed Digests to complete the tree
-        let a = ['a' as u8; sha256::Digest::LENGTH].try_into().unwrap();
-        let b = ['b' as u8; sha256::Digest::LENGTH].try_into().unwrap();
-        let c = ['c' as u8; sha256::Digest::LENGTH].try_into().unwrap();
-        let d = ['d' as u8; sha256::Digest::LENGTH].try_into().unwrap();
-        let e = ['e' as u8; sha256::Digest::LENGTH].try_into().unwrap();
+        let a = [b'a'; sha256::Digest::LENGTH].into();
+        let b = [b'b'; sha256::Digest::LENGTH].into();
+        let c = [b'c'; sha256::Digest::LENGTH].into();
+        let d = [b'd'; sha256::Digest::LENGTH].into();
+        let e = [b'e'; sha256::Digest::LENGTH].into();
         assert_eq!(
             root_hash(0, [&a, &b, &c, &d, &e]),
             [
@@ -180,4 +330,64 @@ mod tests {
             .into()
         );
     }
+
+    // Computes zero_root without the cache.
+    fn zero_root_uncached(layer: u8) -> sha256::Digest {
+        let mut d = sha256::Digest::default();
+        for _ in 0..layer {
+            d = Sha256::combine(&d, &d);
+        }
+        d
+    }
+
+    // A hash that spells out the shape of the tree instead of hashing.
+    #[derive(Clone, Debug, PartialEq, Eq)]
+    struct Shape;
+
+    impl TreeHash for Shape {
+        type Digest = String;
+        type Context = String;
+
+        fn update(ctx: &mut String, data: &[u8]) {
+            ctx.push_str(std::str::from_utf8(data).unwrap());
+        }
+
+        fn finish(ctx: &mut String) -> String {
+            std::mem::take(ctx)
+        }
+
+        fn combine(a: &String, b: &String) -> String {
+            format!("({},{})", a, b)
+        }
+    }
+
+    #[test]
+    fn test_tree_shape() {
+        let pad = "0".to_owned();
+        let mut h = Hasher::<Shape>::default();
+        for c in ["a", "b", "c", "d", "e"] {
+            h.add_block(&c.to_owned());
+        }
+        assert_eq!(h.finish_tree(&pad), "(((a,b),(c,d)),((e,0),(0,0)))");
+
+        h.add_block(&"a".to_owned());
+        assert_eq!(h.finish_layer(&pad, 2).unwrap(), "((a,0),(0,0))");
+
+        for c in ["a", "b", "c"] {
+            h.add_block(&c.to_owned());
+        }
+        assert_eq!(h.finish_layer(&pad, 1), None);
+    }
+
+    #[test]
+    fn test_zero_root_cached() {
+        for layer in 0..=80 {
+            assert_eq!(
+                zero_root(layer),
+                zero_root_uncached(layer),
+                "layer {}",
+                layer
+            );
+        }
+    }
 }
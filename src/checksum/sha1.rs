@@ -0,0 +1,75 @@
+use std::fmt;
+use std::io::Write;
+
+use ring::digest;
+
+// A SHA-1 digest. Only used for v1 torrents, which BEP 3 defines in terms of
+// SHA-1.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Digest([u8; Digest::LENGTH]);
+
+impl Digest {
+    pub const LENGTH: usize = 20;
+}
+
+// Formats the digest as lowercase hex.
+impl fmt::Display for Digest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.iter().try_for_each(|b| write!(f, "{:02x}", b))
+    }
+}
+
+impl std::convert::AsRef<[u8]> for Digest {
+    fn as_ref(&self) -> &[u8] {
+        self.0.as_slice()
+    }
+}
+
+impl std::convert::From<[u8; Digest::LENGTH]> for Digest {
+    fn from(a: [u8; Digest::LENGTH]) -> Self {
+        Self(a)
+    }
+}
+
+#[derive(Clone)]
+pub struct Hasher {
+    ctx: digest::Context,
+}
+
+impl Hasher {
+    pub fn update(&mut self, data: &[u8]) {
+        self.ctx.update(data);
+    }
+
+    // Returns the digest and resets the hash.
+    pub fn finish(&mut self) -> Digest {
+        std::mem::take(self).into_digest()
+    }
+
+    // Finishes and destroys the hasher instead of resetting.
+    pub fn into_digest(self) -> Digest {
+        let mut ret = [0; Digest::LENGTH];
+        ret.copy_from_slice(self.ctx.finish().as_ref());
+        Digest(ret)
+    }
+}
+
+impl Default for Hasher {
+    fn default() -> Self {
+        Self {
+            ctx: digest::Context::new(&digest::SHA1_FOR_LEGACY_USE_ONLY),
+        }
+    }
+}
+
+impl Write for Hasher {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        // no-op
+        Ok(())
+    }
+}
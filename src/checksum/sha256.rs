@@ -1,3 +1,4 @@
+use std::fmt;
 use std::io::Write;
 
 use ring::digest::Digest as RingDigest;
@@ -10,6 +11,30 @@ impl Digest {
     pub const LENGTH: usize = SHA256_OUTPUT_LEN;
 }
 
+// Formats the digest as lowercase hex.
+impl fmt::Display for Digest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.iter().try_for_each(|b| write!(f, "{:02x}", b))
+    }
+}
+
+// Parses a digest from hex.
+impl std::str::FromStr for Digest {
+    type Err = &'static str;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.len() != Self::LENGTH * 2 || !s.is_ascii() {
+            return Err("digest must be 64 hex characters");
+        }
+
+        let mut ret = [0; Self::LENGTH];
+        for (i, b) in ret.iter_mut().enumerate() {
+            *b = u8::from_str_radix(&s[i * 2..i * 2 + 2], 16)
+                .map_err(|_| "digest must be 64 hex characters")?;
+        }
+        Ok(Self(ret))
+    }
+}
+
 impl std::convert::AsRef<[u8]> for Digest {
     fn as_ref(&self) -> &[u8] {
         self.0.as_slice()
@@ -82,3 +107,18 @@ impl Write for Hasher {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hex_roundtrip() {
+        let d: Digest = [0xab; Digest::LENGTH].into();
+        assert_eq!(d.to_string(), "ab".repeat(Digest::LENGTH));
+        assert_eq!(d.to_string().parse::<Digest>(), Ok(d));
+
+        assert!("ab".parse::<Digest>().is_err());
+        assert!("zz".repeat(Digest::LENGTH).parse::<Digest>().is_err());
+    }
+}
@@ -0,0 +1,93 @@
+use std::cmp;
+use std::io::{self, Read};
+
+use crate::checksum::sha1;
+
+// Hashes the concatenation of the readers in pieces of piece_length bytes as
+// defined in BEP 3. Unlike v2, pieces span file boundaries and the last piece
+// may be short. Each reader is paired with its expected length and an error is
+// returned if it yields a different number of bytes.
+pub fn checksum_files_v1<R: Read>(
+    piece_length: u64,
+    readers: impl IntoIterator<Item = io::Result<(R, u64)>>,
+) -> io::Result<Vec<sha1::Digest>> {
+    let mut pieces = Vec::new();
+    let mut hasher = sha1::Hasher::default();
+    // Bytes still needed to complete the current piece.
+    let mut remaining = piece_length;
+    let mut buf = vec![0; 1 << 20];
+
+    for r in readers {
+        let (r, expected_length) = r?;
+        let mut r = r.take(expected_length);
+        let mut read = 0;
+
+        loop {
+            let n = match r.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            read += n as u64;
+
+            let mut data = &buf[..n];
+            while !data.is_empty() {
+                let m = cmp::min(remaining, data.len() as u64) as usize;
+                hasher.update(&data[..m]);
+                data = &data[m..];
+                remaining -= m as u64;
+
+                if remaining == 0 {
+                    pieces.push(hasher.finish());
+                    remaining = piece_length;
+                }
+            }
+        }
+
+        if read != expected_length {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "unexpected EOF",
+            ));
+        }
+    }
+
+    if remaining != piece_length {
+        pieces.push(hasher.finish());
+    }
+
+    Ok(pieces)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn readers(files: &[&'static [u8]]) -> Vec<io::Result<(&'static [u8], u64)>> {
+        files.iter().map(|f| Ok((*f, f.len() as u64))).collect()
+    }
+
+    #[test]
+    fn pieces_span_files() {
+        // the same bytes split differently across files hash the same
+        let a = checksum_files_v1(4, readers(&[b"abcdefghij"])).unwrap();
+        let b = checksum_files_v1(4, readers(&[b"abc", b"", b"defg", b"hij"])).unwrap();
+        assert_eq!(a, b);
+        assert_eq!(a.len(), 3);
+    }
+
+    #[test]
+    fn empty() {
+        assert_eq!(checksum_files_v1(4, readers(&[b""])).unwrap(), Vec::new());
+    }
+
+    #[test]
+    fn short_file() {
+        let r: Vec<io::Result<(&[u8], u64)>> = vec![Ok((b"abc", 4))];
+        assert_eq!(
+            checksum_files_v1(4, r).unwrap_err().kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+    }
+}
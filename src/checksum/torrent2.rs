@@ -1,27 +1,45 @@
 use std::cmp;
 use std::io::{self, Read, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
 
 use positioned_io::{Cursor, ReadAt, Slice};
 use rayon::prelude::*;
 
-use crate::checksum::{merkle, sha256};
+use crate::checksum::adaptive::AdaptiveThreads;
+use crate::checksum::crc32::Crc32Reader;
+use crate::checksum::merkle::{self, TreeHash, TruncatedDigest};
+use crate::checksum::sha256;
 use crate::metainfo::{self, PieceLength};
 
 const BLOCK_SIZE: usize = 16 << 10; // 16MiB
 
 // Produces the metainfo and piece_layer for a file.
 pub fn checksum_file(
+    piece_length: PieceLength,
+    r: impl Read,
+) -> io::Result<(metainfo::File, Vec<sha256::Digest>)> {
+    checksum_file_with_blocks(piece_length, r, |_, _| {})
+}
+
+// Like checksum_file but also calls on_block with the index and hash of each
+// 16KiB block of the file, in order, as soon as the block is hashed.
+//
+// This is a low-level hook for tools that verify content at block
+// granularity or build their own trees over the leaf hashes. The final block
+// of the file may be shorter than 16KiB; no padding blocks are reported.
+pub fn checksum_file_with_blocks(
     piece_length: PieceLength,
     mut r: impl Read,
+    on_block: impl FnMut(u64, &sha256::Digest),
 ) -> io::Result<(metainfo::File, Vec<sha256::Digest>)> {
     let l = piece_length.bytes();
     let mut pieces_layer = Vec::new();
-    let mut hasher = PieceV2Hasher::new(piece_length);
+    let mut hasher = PieceV2Hasher::new(piece_length).with_block_callback(on_block);
     let mut read = 0;
 
     // If the first piece is incomplete, we need to handle it specially.
-    let mut piece = (&mut r).take(l);
-    let n = io::copy(&mut piece, &mut hasher)?;
+    let n = copy_piece(&mut r, &mut hasher, l)?;
     read += n;
     if n == 0 {
         return Ok((metainfo::File::default(), Vec::new()));
@@ -31,13 +49,13 @@ pub fn checksum_file(
         let f = metainfo::File {
             pieces_root: hasher.finish_first_piece(),
             length: read,
+            crc32: None,
         };
         return Ok((f, Vec::new()));
     }
 
     loop {
-        let mut piece = (&mut r).take(l);
-        let n = io::copy(&mut piece, &mut hasher)?;
+        let n = copy_piece(&mut r, &mut hasher, l)?;
         read += n;
         if n == 0 {
             // end of file
@@ -47,8 +65,7 @@ pub fn checksum_file(
         pieces_layer.push(hasher.finish());
 
         if n < l {
-            // Partial piece read means end of file. Attempting to read may
-            // return more but would be unaligned.
+            // copy_piece only returns a partial piece at end of file.
             break;
         }
     }
@@ -56,90 +73,574 @@ pub fn checksum_file(
     let f = metainfo::File {
         pieces_root: merkle::root_hash(piece_length.layers, &pieces_layer),
         length: read,
+        crc32: None,
     };
 
     Ok((f, pieces_layer))
 }
 
+// Copies up to l bytes from r into w. A short copy is retried until a read
+// returns no data so pipes and sockets that stall mid-piece are not mistaken
+// for end of file. Returns the number of bytes copied.
+fn copy_piece(r: &mut impl Read, w: &mut impl Write, l: u64) -> io::Result<u64> {
+    let mut copied = 0;
+    while copied < l {
+        let n = io::copy(&mut r.take(l - copied), w)?;
+        if n == 0 {
+            break;
+        }
+        copied += n;
+    }
+    Ok(copied)
+}
+
 // Produces the metainfo and piece_layer for a file.
 pub fn checksum_file_multithreaded<T: ReadAt + Sync>(
     piece_length: PieceLength,
     file_length: u64,
     r: &T,
 ) -> io::Result<(metainfo::File, Vec<sha256::Digest>)> {
-    let piece_bytes = piece_length.bytes();
-    let num_pieces = {
-        file_length / piece_length.bytes()
-            + if file_length % piece_length.bytes() > 0 {
-                1
-            } else {
-                0
-            }
-    };
+    checksum_file_progress(piece_length, file_length, r, |_, _| {})
+}
+
+// Same as checksum_file_multithreaded but calls on_piece with the index of
+// each piece as it is hashed and the number of bytes of the file hashed so
+// far. Pieces complete out of order.
+pub fn checksum_file_progress<T: ReadAt + Sync>(
+    piece_length: PieceLength,
+    file_length: u64,
+    r: &T,
+    on_piece: impl Fn(u64, u64) + Sync,
+) -> io::Result<(metainfo::File, Vec<sha256::Digest>)> {
+    let num_pieces = num_pieces(piece_length, file_length);
 
     // Files with less than 2 pieces have edge cases and would not benefit from
     // multithreading.
     if num_pieces <= 1 {
-        return checksum_file(piece_length, piece_reader(r, 0, piece_bytes));
+        let ret = checksum_file(piece_length, piece_reader(r, 0, piece_length.bytes()))?;
+        if num_pieces == 1 {
+            on_piece(0, file_length);
+        }
+        return Ok(ret);
     }
 
     // Number of pieces to process at a time.
     let batch_size = cmp::max((128 << 20) / piece_length.bytes(), 1);
 
-    let pieces_layer = (0..num_pieces as usize)
+    let done = AtomicU64::new(0);
+    let pieces_layer = par_pieces(r, piece_length, file_length)
+        .enumerate()
+        .with_min_len(batch_size as usize)
+        .map(|(idx, piece)| {
+            let piece = piece?;
+            let offset = idx as u64 * piece_length.bytes();
+            let n = cmp::min(piece_length.bytes(), file_length - offset);
+            on_piece(idx as u64, done.fetch_add(n, Ordering::Relaxed) + n);
+            Ok(piece)
+        })
+        .collect::<Result<Vec<_>, io::Error>>()?;
+
+    let f = metainfo::File {
+        pieces_root: merkle::root_hash(piece_length.layers, &pieces_layer),
+        length: file_length,
+        crc32: None,
+    };
+
+    Ok((f, pieces_layer))
+}
+
+// Same as checksum_file_multithreaded but also computes the CRC32 of the
+// file. Each piece's CRC32 is computed as it is hashed and they are combined
+// in order afterwards.
+pub fn checksum_file_crc32<T: ReadAt + Sync>(
+    piece_length: PieceLength,
+    file_length: u64,
+    r: &T,
+) -> io::Result<(metainfo::File, Vec<sha256::Digest>)> {
+    if num_pieces(piece_length, file_length) <= 1 {
+        let mut r = Crc32Reader::new(piece_reader(r, 0, piece_length.bytes()));
+        let (mut f, pieces_layer) = checksum_file(piece_length, &mut r)?;
+        f.crc32 = Some(r.finish());
+        return Ok((f, pieces_layer));
+    }
+
+    let batch_size = cmp::max((128 << 20) / piece_length.bytes(), 1);
+    let (pieces_layer, crcs): (Vec<_>, Vec<_>) = (0..num_pieces(piece_length, file_length)
+        as usize)
         .into_par_iter()
         .with_min_len(batch_size as usize)
-        .map_with(r, |r, idx| {
-            let mut piece =
-                io::BufReader::with_capacity(1 << 20, piece_reader(r, idx as u64, piece_bytes));
-            let mut hasher = PieceV2Hasher::new(piece_length);
+        .map(|idx| {
+            let idx = idx as u64;
+            let mut piece = Crc32Reader::new(piece_reader(r, idx, piece_length.bytes()));
+            let digest = read_piece(&mut piece, piece_length, file_length, idx)?;
+            Ok((digest, piece.into_hasher()))
+        })
+        .collect::<Result<Vec<_>, io::Error>>()?
+        .into_iter()
+        .unzip();
 
-            let expected_length = {
-                if idx as u64 != num_pieces - 1 || file_length % piece_length.bytes() == 0 {
-                    piece_length.bytes()
-                } else {
-                    file_length % piece_length.bytes()
-                }
-            };
-
-            let n = io::copy(&mut piece, &mut hasher)?;
-            if n != expected_length {
-                return Err(io::Error::new(
-                    io::ErrorKind::UnexpectedEof,
-                    "unexpected EOF",
-                ));
-            }
+    let mut crc = crc32fast::Hasher::new();
+    crcs.iter().for_each(|c| crc.combine(c));
+    let f = metainfo::File {
+        pieces_root: merkle::root_hash(piece_length.layers, &pieces_layer),
+        length: file_length,
+        crc32: Some(crc.finalize()),
+    };
+
+    Ok((f, pieces_layer))
+}
+
+// Same as checksum_file_multithreaded but reads and hashes at most
+// max_inflight pieces at a time, which bounds the memory used for piece
+// buffers to max_inflight times piece_memory.
+pub fn checksum_file_limited<T: ReadAt + Sync>(
+    piece_length: PieceLength,
+    file_length: u64,
+    r: &T,
+    max_inflight: usize,
+) -> io::Result<(metainfo::File, Vec<sha256::Digest>)> {
+    if num_pieces(piece_length, file_length) <= 1 {
+        return checksum_file(piece_length, piece_reader(r, 0, piece_length.bytes()));
+    }
+
+    let n = layer_pieces(piece_length, file_length) as usize;
+    let mut pieces_layer = Vec::with_capacity(n);
+    for start in (0..n).step_by(max_inflight) {
+        let end = cmp::min(start + max_inflight, n);
+        let window = (start..end)
+            .into_par_iter()
+            .map(|idx| hash_piece(r, piece_length, file_length, idx as u64))
+            .collect::<Result<Vec<_>, _>>()?;
+        pieces_layer.extend(window);
+    }
+
+    let f = metainfo::File {
+        pieces_root: merkle::root_hash(piece_length.layers, &pieces_layer),
+        length: file_length,
+        crc32: None,
+    };
+
+    Ok((f, pieces_layer))
+}
+
+// Returns the hash of every 16KiB block of a file. The same block hashes can
+// build the pieces root and piece layer for any piece length with
+// from_block_hashes, so a file only needs to be read once for several piece
+// lengths.
+pub fn block_hashes<T: ReadAt + Sync>(file_length: u64, r: &T) -> io::Result<Vec<sha256::Digest>> {
+    let num_blocks = file_length.div_ceil(BLOCK_SIZE as u64);
+
+    (0..num_blocks as usize)
+        .into_par_iter()
+        .with_min_len(cmp::max((128 << 20) / BLOCK_SIZE, 1))
+        .map_init(
+            || vec![0; BLOCK_SIZE],
+            |buf, idx| {
+                let offset = idx as u64 * BLOCK_SIZE as u64;
+                let n = cmp::min(BLOCK_SIZE as u64, file_length - offset) as usize;
+                r.read_exact_at(offset, &mut buf[..n])?;
+
+                let mut h = sha256::Hasher::default();
+                h.update(&buf[..n]);
+                Ok(h.into_digest())
+            },
+        )
+        .collect()
+}
+
+// Produces the metainfo and piece_layer for a file from the hashes of its
+// blocks. The result is the same as checksum_file's.
+pub fn from_block_hashes(
+    piece_length: PieceLength,
+    file_length: u64,
+    blocks: &[sha256::Digest],
+) -> (metainfo::File, Vec<sha256::Digest>) {
+    let (pieces_root, pieces_layer) = tree_from_blocks::<merkle::Sha256>(
+        piece_length,
+        file_length,
+        blocks,
+        &sha256::Digest::default(),
+    );
+    let f = metainfo::File {
+        pieces_root,
+        length: file_length,
+        crc32: None,
+    };
+    (f, pieces_layer)
+}
 
-            Ok(hasher.finish())
+// Experimental and nonstandard: same as from_block_hashes but every hash in the
+// tree, including the block hashes, is truncated to digest_bytes bytes. The
+// truncated hashes are zero padded back to 32 bytes until the torrent is
+// encoded. With 32 bytes the result is the same as from_block_hashes'.
+pub fn from_block_hashes_truncated(
+    piece_length: PieceLength,
+    file_length: u64,
+    blocks: &[sha256::Digest],
+    digest_bytes: usize,
+) -> (metainfo::File, Vec<sha256::Digest>) {
+    let blocks: Vec<_> = blocks
+        .iter()
+        .map(|&b| TruncatedDigest::new(b, digest_bytes))
+        .collect();
+    let pad = TruncatedDigest::new(sha256::Digest::default(), digest_bytes);
+    let (pieces_root, pieces_layer) =
+        tree_from_blocks::<merkle::Truncated>(piece_length, file_length, &blocks, &pad);
+
+    let f = metainfo::File {
+        pieces_root: pieces_root.digest,
+        length: file_length,
+        crc32: None,
+    };
+    (f, pieces_layer.into_iter().map(|d| d.digest).collect())
+}
+
+// Returns the pieces root and piece layer of a file from the hashes of its
+// blocks, padding the tree with pad. The pieces root of an empty file is the
+// default digest.
+fn tree_from_blocks<H: TreeHash>(
+    piece_length: PieceLength,
+    file_length: u64,
+    blocks: &[H::Digest],
+    pad: &H::Digest,
+) -> (H::Digest, Vec<H::Digest>) {
+    let mut merkle = merkle::Hasher::<H>::default();
+
+    if file_length < piece_length.bytes() {
+        if blocks.is_empty() {
+            return (H::Digest::default(), Vec::new());
+        }
+
+        blocks.iter().for_each(|b| merkle.add_block(b));
+        return (merkle.finish_tree(pad), Vec::new());
+    }
+
+    let blocks_per_piece = 1 << piece_length.layers;
+    let pieces_layer: Vec<_> = blocks
+        .chunks(blocks_per_piece)
+        .map(|piece| {
+            piece.iter().for_each(|b| merkle.add_block(b));
+            merkle.finish_layer(pad, piece_length.layers).unwrap()
         })
-        .collect::<Result<Vec<_>, _>>()?;
+        .collect();
+
+    pieces_layer.iter().for_each(|p| merkle.add_block(p));
+    let pieces_root = merkle.finish_tree(&merkle::pad_root::<H>(pad, piece_length.layers));
+    (pieces_root, pieces_layer)
+}
+
+// Same as checksum_file_multithreaded but only returns the pieces root. Piece
+// hashes are added to the merkle tree a batch at a time instead of being kept,
+// so memory use does not grow with the file.
+pub fn checksum_file_root<T: ReadAt + Sync>(
+    piece_length: PieceLength,
+    file_length: u64,
+    r: &T,
+) -> io::Result<metainfo::File> {
+    let num_pieces = num_pieces(piece_length, file_length);
+
+    if num_pieces <= 1 {
+        let (f, _) = checksum_file(piece_length, piece_reader(r, 0, piece_length.bytes()))?;
+        return Ok(f);
+    }
+
+    let batch_size = cmp::max((128 << 20) / piece_length.bytes(), 1);
+
+    let mut merkle = merkle::Hasher::new();
+    let mut start = 0;
+    while start < num_pieces {
+        let end = cmp::min(start + batch_size, num_pieces);
+        let batch = (start as usize..end as usize)
+            .into_par_iter()
+            .map(|idx| hash_piece(r, piece_length, file_length, idx as u64))
+            .collect::<Result<Vec<_>, _>>()?;
+        batch.iter().for_each(|d| merkle.add_block(d));
+        start = end;
+    }
+
+    Ok(metainfo::File {
+        pieces_root: merkle.finish_tree(&merkle::zero_root(piece_length.layers)),
+        length: file_length,
+        crc32: None,
+    })
+}
+
+// Same as checksum_file_multithreaded but hashes the file in batches, letting
+// adaptive pick the number of threads for each batch based on the throughput
+// of the previous ones.
+pub fn checksum_file_adaptive<T: ReadAt + Sync>(
+    piece_length: PieceLength,
+    file_length: u64,
+    r: &T,
+    adaptive: &AdaptiveThreads,
+) -> io::Result<(metainfo::File, Vec<sha256::Digest>)> {
+    let num_pieces = num_pieces(piece_length, file_length);
+
+    if num_pieces <= 1 {
+        return checksum_file(piece_length, piece_reader(r, 0, piece_length.bytes()));
+    }
+
+    let batch_size = cmp::max((128 << 20) / piece_length.bytes(), 1);
+
+    let mut pieces_layer = Vec::with_capacity(num_pieces as usize);
+    let mut start = 0;
+    while start < num_pieces {
+        let end = cmp::min(start + batch_size, num_pieces);
+        let pool = adaptive.pool();
+
+        let now = Instant::now();
+        let batch = pool.install(|| {
+            (start..end)
+                .into_par_iter()
+                .map(|idx| hash_piece(r, piece_length, file_length, idx))
+                .collect::<Result<Vec<_>, _>>()
+        })?;
+        let bytes =
+            cmp::min(end * piece_length.bytes(), file_length) - start * piece_length.bytes();
+        adaptive.record(bytes, now.elapsed());
+
+        pieces_layer.extend(batch);
+        start = end;
+    }
 
     let f = metainfo::File {
         pieces_root: merkle::root_hash(piece_length.layers, &pieces_layer),
         length: file_length,
+        crc32: None,
     };
 
     Ok((f, pieces_layer))
 }
 
-#[derive(Clone)]
-struct PieceV2Hasher {
+// Same as checksum_file_progress but hashes the pieces in order on the
+// calling thread, one at a time, so at most one piece is buffered.
+pub fn checksum_file_sequential<T: ReadAt>(
     piece_length: PieceLength,
-    block_hasher: sha256::Hasher,
+    file_length: u64,
+    r: &T,
+    mut on_piece: impl FnMut(u64, u64),
+) -> io::Result<(metainfo::File, Vec<sha256::Digest>)> {
+    let num_pieces = num_pieces(piece_length, file_length);
+
+    if num_pieces <= 1 {
+        let ret = checksum_file(piece_length, piece_reader(r, 0, piece_length.bytes()))?;
+        if num_pieces == 1 {
+            on_piece(0, file_length);
+        }
+        return Ok(ret);
+    }
+
+    let mut pieces_layer = Vec::with_capacity(num_pieces as usize);
+    for (idx, piece) in PieceIterator::new(r, piece_length, file_length).enumerate() {
+        pieces_layer.push(piece?);
+        let done = cmp::min((idx as u64 + 1) * piece_length.bytes(), file_length);
+        on_piece(idx as u64, done);
+    }
+
+    let f = metainfo::File {
+        pieces_root: merkle::root_hash(piece_length.layers, &pieces_layer),
+        length: file_length,
+        crc32: None,
+    };
+
+    Ok((f, pieces_layer))
+}
+
+// Lazily hashes the pieces of a file, yielding the same digests as the
+// pieces_layer returned by checksum_file. Pieces are hashed one at a time on
+// the calling thread; see par_pieces for a parallel version.
+pub struct PieceIterator<T: ReadAt> {
+    r: T,
+    piece_length: PieceLength,
+    file_length: u64,
+    next: u64,
+    end: u64,
+}
+
+impl<T: ReadAt> PieceIterator<T> {
+    pub fn new(r: T, piece_length: PieceLength, file_length: u64) -> Self {
+        Self {
+            r,
+            piece_length,
+            file_length,
+            next: 0,
+            end: layer_pieces(piece_length, file_length),
+        }
+    }
+}
+
+impl<T: ReadAt> Iterator for PieceIterator<T> {
+    type Item = io::Result<sha256::Digest>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.next == self.end {
+            return None;
+        }
+
+        let ret = hash_piece(&self.r, self.piece_length, self.file_length, self.next);
+        // Stop after an error since the following pieces are unaligned.
+        self.next = if ret.is_ok() { self.next + 1 } else { self.end };
+        Some(ret)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let n = (self.end - self.next) as usize;
+        (n, Some(n))
+    }
+}
+
+impl<T: ReadAt> ExactSizeIterator for PieceIterator<T> {}
+
+// Same as PieceIterator but hashes pieces on the rayon thread pool.
+pub fn par_pieces<T: ReadAt + Sync>(
+    r: &T,
+    piece_length: PieceLength,
+    file_length: u64,
+) -> impl IndexedParallelIterator<Item = io::Result<sha256::Digest>> + '_ {
+    (0..layer_pieces(piece_length, file_length) as usize)
+        .into_par_iter()
+        .map(move |idx| hash_piece(r, piece_length, file_length, idx as u64))
+}
+
+// Number of entries in the pieces layer of a file. Files no larger than a
+// piece have no piece layer unless they are exactly one piece long.
+fn layer_pieces(piece_length: PieceLength, file_length: u64) -> u64 {
+    if file_length < piece_length.bytes() {
+        0
+    } else {
+        num_pieces(piece_length, file_length)
+    }
+}
+
+fn num_pieces(piece_length: PieceLength, file_length: u64) -> u64 {
+    file_length.div_ceil(piece_length.bytes())
+}
+
+// Size of the buffer used to read each piece hashed in parallel.
+pub fn piece_memory(piece_length: PieceLength) -> u64 {
+    cmp::min(piece_length.bytes(), 1 << 20)
+}
+
+// Hashes piece idx of a file with more than one piece.
+fn hash_piece<T: ReadAt>(
+    r: &T,
+    piece_length: PieceLength,
+    file_length: u64,
+    idx: u64,
+) -> io::Result<sha256::Digest> {
+    read_piece(
+        piece_reader(r, idx, piece_length.bytes()),
+        piece_length,
+        file_length,
+        idx,
+    )
+}
+
+// Hashes piece idx of a file with more than one piece from piece, which reads
+// only that piece.
+fn read_piece(
+    piece: impl Read,
+    piece_length: PieceLength,
+    file_length: u64,
+    idx: u64,
+) -> io::Result<sha256::Digest> {
+    let mut piece = io::BufReader::with_capacity(piece_memory(piece_length) as usize, piece);
+    let mut hasher = PieceV2Hasher::new(piece_length);
+
+    let expected_length = {
+        if idx != num_pieces(piece_length, file_length) - 1
+            || file_length.is_multiple_of(piece_length.bytes())
+        {
+            piece_length.bytes()
+        } else {
+            file_length % piece_length.bytes()
+        }
+    };
+
+    let n = io::copy(&mut piece, &mut hasher)?;
+    if n != expected_length {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "unexpected EOF",
+        ));
+    }
+
+    Ok(hasher.finish())
+}
+
+// Returns the piece layer for a file with a single partial piece. Such files
+// normally have no piece layer since the single piece hash can be derived from
+// pieces_root, which only covers the blocks the file actually has.
+pub fn single_piece_layer(piece_length: PieceLength, f: &metainfo::File) -> sha256::Digest {
+    let blocks = f.length.div_ceil(BLOCK_SIZE as u64);
+    let layer = blocks.next_power_of_two().trailing_zeros() as u8;
+    merkle::extend_root(f.pieces_root, layer, piece_length.layers)
+}
+
+// Returns the hash of a piece made of length bytes that are all zero. length
+// is the piece length for every piece but the last piece of a file, which may
+// be shorter.
+pub fn zero_piece(piece_length: PieceLength, length: u64) -> sha256::Digest {
+    let zeros = [0u8; BLOCK_SIZE];
+    let block = |n: usize| {
+        let mut h = sha256::Hasher::default();
+        h.update(&zeros[..n]);
+        h.into_digest()
+    };
+
+    let mut merkle = merkle::Hasher::new();
+    let full = block(BLOCK_SIZE);
+    for _ in 0..length / BLOCK_SIZE as u64 {
+        merkle.add_block(&full);
+    }
+    let rem = (length % BLOCK_SIZE as u64) as usize;
+    if rem != 0 {
+        merkle.add_block(&block(rem));
+    }
+
+    merkle
+        .finish_layer(&sha256::Digest::default(), piece_length.layers)
+        .unwrap()
+}
+
+// Called with the index of a block within the file and its hash.
+type BlockCallback<'a, D> = Box<dyn FnMut(u64, &D) + 'a>;
+
+struct PieceV2Hasher<'a, H: TreeHash = merkle::Sha256> {
+    piece_length: PieceLength,
+    block_hasher: H::Context,
     block_pos: usize,
-    merkle: merkle::Hasher,
+    merkle: merkle::Hasher<H>,
+    // Blocks finished since the hasher was created. Unlike the rest of the
+    // state this is not reset between pieces.
+    blocks: u64,
+    on_block: Option<BlockCallback<'a, H::Digest>>,
 }
 
-impl PieceV2Hasher {
+impl PieceV2Hasher<'_> {
     fn new(piece_length: PieceLength) -> Self {
+        Self::with_hash(piece_length)
+    }
+}
+
+impl<'a, H: TreeHash> PieceV2Hasher<'a, H> {
+    fn with_hash(piece_length: PieceLength) -> Self {
         Self {
             piece_length,
-            block_hasher: sha256::Hasher::default(),
+            block_hasher: H::Context::default(),
             block_pos: 0,
             merkle: merkle::Hasher::default(),
+            blocks: 0,
+            on_block: None,
         }
     }
 
+    // Calls f with each block's index and hash once the block is complete.
+    fn with_block_callback(mut self, f: impl FnMut(u64, &H::Digest) + 'a) -> Self {
+        self.on_block = Some(Box::new(f));
+        self
+    }
+
     fn update(&mut self, mut data: &[u8]) {
         while !data.is_empty() {
             let n = self.update_block(data);
@@ -149,32 +650,32 @@ impl PieceV2Hasher {
 
     // Returns the hash of the piece. This resets the hasher making it reusable
     // for the next piece. Panics if too much data was provided.
-    fn finish(&mut self) -> sha256::Digest {
+    fn finish(&mut self) -> H::Digest {
         self.finish_block();
         let ret = self
             .merkle
-            .finish_layer(&sha256::Digest::default(), self.piece_length.layers)
+            .finish_layer(&H::Digest::default(), self.piece_length.layers)
             .unwrap();
         self.reset();
         ret
     }
 
-    fn finish_first_piece(&mut self) -> sha256::Digest {
+    fn finish_first_piece(&mut self) -> H::Digest {
         self.finish_block();
-        self.merkle.finish_tree(&sha256::Digest::default())
+        self.merkle.finish_tree(&H::Digest::default())
     }
 
     fn reset(&mut self) {
-        self.block_hasher = sha256::Hasher::default();
+        self.block_hasher = H::Context::default();
         self.block_pos = 0;
         self.merkle.reset();
     }
 
     fn update_block(&mut self, data: &[u8]) -> usize {
         let needed = BLOCK_SIZE - self.block_pos;
-        let n = cmp::min(needed as usize, data.len());
+        let n = cmp::min(needed, data.len());
 
-        self.block_hasher.update(&data[..n]);
+        H::update(&mut self.block_hasher, &data[..n]);
         self.block_pos += n;
 
         if self.block_pos == BLOCK_SIZE {
@@ -190,12 +691,16 @@ impl PieceV2Hasher {
         }
 
         self.block_pos = 0;
-        let digest = self.block_hasher.finish();
+        let digest = H::finish(&mut self.block_hasher);
+        if let Some(f) = &mut self.on_block {
+            f(self.blocks, &digest);
+        }
+        self.blocks += 1;
         self.merkle.add_block(&digest);
     }
 }
 
-impl Write for PieceV2Hasher {
+impl<H: TreeHash> Write for PieceV2Hasher<'_, H> {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
         self.update(buf);
         Ok(buf.len())
@@ -224,7 +729,8 @@ mod tests {
             f,
             metainfo::File {
                 length: 0,
-                pieces_root: [0; 32].into()
+                pieces_root: [0; 32].into(),
+                crc32: None,
             }
         );
         assert_eq!(pieces_layer, Vec::new());
@@ -244,7 +750,8 @@ mod tests {
                     230, 159, 27, 131, 197, 211, 213, 133, 84, 248, 147, 160, 97, 88, 105, 146, 81,
                     144, 15, 69, 203, 145, 187, 180, 46, 23, 211, 74, 172, 184, 160, 31
                 ]
-                .into()
+                .into(),
+                crc32: None,
             }
         );
 
@@ -270,6 +777,340 @@ mod tests {
         );
     }
 
+    #[test]
+    fn single_piece_layer_matches_piece_hash() {
+        let piece_length = metainfo::PieceLength::from_bytes(128 << 10).unwrap();
+        for l in [1, 16 << 10, (16 << 10) + 1, 50 << 10, 128 << 10] {
+            let data = vec![7u8; l];
+            let (f, pieces_layer) = checksum_file(piece_length, data.as_slice()).unwrap();
+
+            let mut hasher = PieceV2Hasher::new(piece_length);
+            hasher.update(&data);
+            let expected = hasher.finish();
+
+            assert_eq!(
+                single_piece_layer(piece_length, &f),
+                expected,
+                "length {}",
+                l
+            );
+            if !pieces_layer.is_empty() {
+                assert_eq!(pieces_layer, vec![expected]);
+            }
+        }
+    }
+
+    #[test]
+    fn checksum_file_root_matches() {
+        let piece_length = metainfo::PieceLength::from_bytes(16 << 10).unwrap();
+        for l in [0u64, 1, 16 << 10, 50 << 10, 300 << 10] {
+            let data: Vec<u8> = (0..l).map(|i| (i % 251) as u8).collect();
+            let (f, _) = checksum_file(piece_length, data.as_slice()).unwrap();
+            assert_eq!(
+                checksum_file_root(piece_length, l, &data.as_slice()).unwrap(),
+                f,
+                "length {}",
+                l
+            );
+        }
+    }
+
+    #[test]
+    fn from_block_hashes_truncated() {
+        let l = 300 << 10;
+        let data: Vec<u8> = (0..l).map(|i| (i % 251) as u8).collect();
+        let blocks = block_hashes(l, &data.as_slice()).unwrap();
+        let piece_length = metainfo::PieceLength::from_bytes(64 << 10).unwrap();
+        let standard = checksum_file(piece_length, data.as_slice()).unwrap();
+
+        assert_eq!(
+            super::from_block_hashes_truncated(piece_length, l, &blocks, 32),
+            standard
+        );
+
+        let (f, pieces_layer) = super::from_block_hashes_truncated(piece_length, l, &blocks, 8);
+        assert_ne!(f.pieces_root, standard.0.pieces_root);
+        assert_eq!(f.length, l);
+        assert_eq!(pieces_layer.len(), standard.1.len());
+        for d in pieces_layer.iter().chain([&f.pieces_root]) {
+            assert!(d.as_ref()[8..].iter().all(|&b| b == 0), "{}", d);
+        }
+        // inner nodes hash their truncated children so even the kept bytes
+        // differ from the standard hashes
+        assert_ne!(pieces_layer[0].as_ref()[..8], standard.1[0].as_ref()[..8]);
+    }
+
+    #[test]
+    fn from_block_hashes_matches() {
+        for l in [
+            0u64,
+            1,
+            16 << 10,
+            20 << 10,
+            32 << 10,
+            50 << 10,
+            64 << 10,
+            300 << 10,
+        ] {
+            let data: Vec<u8> = (0..l).map(|i| (i % 251) as u8).collect();
+            let blocks = block_hashes(l, &data.as_slice()).unwrap();
+            for piece_length in [16 << 10, 32 << 10, 64 << 10] {
+                let piece_length = metainfo::PieceLength::from_bytes(piece_length).unwrap();
+                assert_eq!(
+                    from_block_hashes(piece_length, l, &blocks),
+                    checksum_file(piece_length, data.as_slice()).unwrap(),
+                    "length {} piece length {}",
+                    l,
+                    piece_length.bytes()
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn piece_iterator_matches() {
+        let piece_length = metainfo::PieceLength::from_bytes(16 << 10).unwrap();
+        for l in [0, 1, 16 << 10, 50 << 10, 64 << 10] {
+            let data: Vec<u8> = (0..l).map(|i| (i % 251) as u8).collect();
+            let (_, pieces_layer) = checksum_file(piece_length, data.as_slice()).unwrap();
+
+            let it = PieceIterator::new(data.as_slice(), piece_length, l);
+            assert_eq!(it.len(), pieces_layer.len(), "length {}", l);
+            assert_eq!(
+                it.collect::<io::Result<Vec<_>>>().unwrap(),
+                pieces_layer,
+                "length {}",
+                l
+            );
+
+            assert_eq!(
+                par_pieces(&data.as_slice(), piece_length, l)
+                    .collect::<io::Result<Vec<_>>>()
+                    .unwrap(),
+                pieces_layer,
+                "length {}",
+                l
+            );
+        }
+    }
+
+    // Returns a zero-byte read after every chunk bytes of data, like a
+    // terminal or a file that is still being written. chunk must not divide
+    // the piece length, as a stall at the start of a piece is end of file.
+    struct Trickle<'a> {
+        data: &'a [u8],
+        chunk: usize,
+        read: usize,
+        stalled: bool,
+    }
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.read > 0 && self.read.is_multiple_of(self.chunk) && !self.stalled {
+                self.stalled = true;
+                return Ok(0);
+            }
+            self.stalled = false;
+            let left = self.chunk - self.read % self.chunk;
+            let n = cmp::min(cmp::min(left, buf.len()), self.data.len());
+            buf[..n].copy_from_slice(&self.data[..n]);
+            self.data = &self.data[n..];
+            self.read += n;
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn checksum_file_short_reads() {
+        const L: usize = 70 << 10;
+        let data: Vec<u8> = (0..L).map(|i| (i % 251) as u8).collect();
+        let piece_length = metainfo::PieceLength::from_bytes(16 << 10).unwrap();
+        let expected = checksum_file(piece_length, data.as_slice()).unwrap();
+
+        let r = Trickle {
+            data: &data,
+            chunk: 1000,
+            read: 0,
+            stalled: false,
+        };
+        assert_eq!(checksum_file(piece_length, r).unwrap(), expected);
+    }
+
+    #[test]
+    fn checksum_file_block_callback() {
+        // two full pieces of four blocks and a partial last block
+        const L: usize = (128 << 10) + 100;
+        let data: Vec<u8> = (0..L).map(|i| (i % 251) as u8).collect();
+        let piece_length = metainfo::PieceLength::from_bytes(64 << 10).unwrap();
+
+        let mut blocks = Vec::new();
+        let got =
+            checksum_file_with_blocks(piece_length, data.as_slice(), |i, d| blocks.push((i, *d)))
+                .unwrap();
+        assert_eq!(got, checksum_file(piece_length, data.as_slice()).unwrap());
+
+        assert_eq!(blocks.len(), 9);
+        for (n, (i, d)) in blocks.iter().enumerate() {
+            assert_eq!(*i, n as u64);
+            let start = n * BLOCK_SIZE;
+            let end = cmp::min(start + BLOCK_SIZE, L);
+            let mut h = sha256::Hasher::default();
+            h.update(&data[start..end]);
+            assert_eq!(*d, h.into_digest(), "block {}", n);
+        }
+    }
+
+    #[test]
+    fn checksum_file_limited_matches() {
+        const L: usize = (300 << 10) + 5;
+        let data: Vec<u8> = (0..L).map(|i| (i % 251) as u8).collect();
+        let piece_length = metainfo::PieceLength::from_bytes(32 << 10).unwrap();
+        let expected = checksum_file(piece_length, data.as_slice()).unwrap();
+
+        for max_inflight in [1, 3, 10, 100] {
+            assert_eq!(
+                checksum_file_limited(piece_length, L as u64, &data, max_inflight).unwrap(),
+                expected,
+                "max_inflight {}",
+                max_inflight
+            );
+        }
+    }
+
+    #[test]
+    fn checksum_file_sequential_matches() {
+        let piece_length = metainfo::PieceLength::from_bytes(32 << 10).unwrap();
+        for l in [0, 1, 32 << 10, (300 << 10) + 5] {
+            let data: Vec<u8> = (0..l).map(|i| (i % 251) as u8).collect();
+
+            let mut events = Vec::new();
+            let ret = checksum_file_sequential(piece_length, l as u64, &data, |piece, done| {
+                events.push((piece, done))
+            })
+            .unwrap();
+            assert_eq!(ret, checksum_file(piece_length, data.as_slice()).unwrap());
+
+            let pieces = num_pieces(piece_length, l as u64);
+            let expected: Vec<_> = (0..pieces)
+                .map(|p| (p, cmp::min((p + 1) * piece_length.bytes(), l as u64)))
+                .collect();
+            assert_eq!(events, expected, "length {}", l);
+        }
+    }
+
+    #[test]
+    fn checksum_file_progress_events() {
+        use std::sync::Mutex;
+
+        const L: usize = (300 << 10) + 5;
+        let data: Vec<u8> = (0..L).map(|i| (i % 251) as u8).collect();
+        let piece_length = metainfo::PieceLength::from_bytes(32 << 10).unwrap();
+
+        let events = Mutex::new(Vec::new());
+        let ret = checksum_file_progress(piece_length, L as u64, &data, |piece, done| {
+            events.lock().unwrap().push((piece, done))
+        })
+        .unwrap();
+        assert_eq!(ret, checksum_file(piece_length, data.as_slice()).unwrap());
+
+        let mut events = events.into_inner().unwrap();
+        events.sort_unstable();
+        let pieces: Vec<_> = events.iter().map(|&(p, _)| p).collect();
+        assert_eq!(pieces, (0..10).collect::<Vec<_>>());
+        assert_eq!(events.iter().map(|&(_, d)| d).max(), Some(L as u64));
+    }
+
+    #[test]
+    fn zero_piece_matches() {
+        let piece_length = metainfo::PieceLength::from_bytes(64 << 10).unwrap();
+        for l in [1, 16 << 10, 20 << 10, 64 << 10] {
+            let mut hasher = PieceV2Hasher::new(piece_length);
+            hasher.update(&vec![0; l]);
+            assert_eq!(
+                zero_piece(piece_length, l as u64),
+                hasher.finish(),
+                "length {}",
+                l
+            );
+        }
+    }
+
+    #[test]
+    fn checksum_file_adaptive_matches() {
+        const L: u64 = 300 << 10;
+        let input_file: Vec<u8> = (0..L).map(|i| (i % 251) as u8).collect();
+        let piece_length = metainfo::PieceLength::from_bytes(16 << 10).unwrap();
+        let adaptive = AdaptiveThreads::new(4);
+
+        assert_eq!(
+            checksum_file_adaptive(piece_length, L, &input_file.as_slice(), &adaptive).unwrap(),
+            checksum_file_multithreaded(piece_length, L, &input_file.as_slice()).unwrap()
+        );
+    }
+
+    #[test]
+    fn checksum_file_crc32_matches() {
+        let piece_length = metainfo::PieceLength::from_bytes(16 << 10).unwrap();
+        // a partial piece, exactly one piece and several pieces
+        for l in [100, 16 << 10, (300 << 10) + 7] {
+            let input_file: Vec<u8> = (0..l).map(|i| (i % 251) as u8).collect();
+            let (f, pieces_layer) =
+                checksum_file_crc32(piece_length, l, &input_file.as_slice()).unwrap();
+
+            let (mut expected, expected_layer) =
+                checksum_file_multithreaded(piece_length, l, &input_file.as_slice()).unwrap();
+            expected.crc32 = Some(crc32fast::hash(&input_file));
+            assert_eq!((f, pieces_layer), (expected, expected_layer), "{}", l);
+        }
+    }
+
+    #[test]
+    fn checksum_file_block_boundaries() {
+        // files of 1 byte, exactly one block and one block + 1 byte
+        let cases: [(usize, sha256::Digest); 3] = [
+            (
+                1,
+                [
+                    45, 113, 22, 66, 183, 38, 176, 68, 1, 98, 124, 169, 251, 172, 50, 245, 200, 83,
+                    15, 177, 144, 60, 196, 219, 2, 37, 135, 23, 146, 26, 72, 129,
+                ]
+                .into(),
+            ),
+            (
+                BLOCK_SIZE,
+                [
+                    21, 54, 196, 34, 195, 28, 201, 136, 52, 117, 157, 112, 133, 205, 163, 148, 163,
+                    81, 10, 3, 215, 129, 136, 36, 137, 134, 166, 177, 167, 32, 125, 3,
+                ]
+                .into(),
+            ),
+            (
+                BLOCK_SIZE + 1,
+                [
+                    93, 126, 59, 74, 150, 113, 51, 90, 147, 239, 229, 102, 117, 239, 131, 201, 195,
+                    255, 5, 62, 224, 191, 87, 94, 221, 167, 183, 175, 143, 125, 57, 177,
+                ]
+                .into(),
+            ),
+        ];
+
+        // with 16KiB pieces a block + 1 byte is two pieces, with 32KiB it is
+        // a single partial piece
+        for piece_length in [16 << 10, 32 << 10] {
+            let piece_length = metainfo::PieceLength::from_bytes(piece_length).unwrap();
+            for (l, pieces_root) in cases {
+                let data = vec![b'x'; l];
+                let (f, pieces_layer) = checksum_file(piece_length, data.as_slice()).unwrap();
+                assert_eq!(f.pieces_root, pieces_root, "length {}", l);
+                assert_eq!(f.length, l as u64);
+
+                let multithreaded =
+                    checksum_file_multithreaded(piece_length, l as u64, &data.as_slice()).unwrap();
+                assert_eq!(multithreaded, (f, pieces_layer), "length {}", l);
+            }
+        }
+    }
+
     #[test]
     fn checksum_file_lessthan_block() {
         let input_file = "test".as_bytes();
@@ -285,7 +1126,8 @@ mod tests {
                     159, 134, 208, 129, 136, 76, 125, 101, 154, 47, 234, 160, 197, 90, 208, 21,
                     163, 191, 79, 27, 43, 11, 130, 44, 209, 93, 108, 21, 176, 240, 10, 8
                 ]
-                .into()
+                .into(),
+                crc32: None,
             }
         );
 
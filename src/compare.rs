@@ -0,0 +1,124 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::metainfo::Torrent;
+
+// A structural difference between two torrents. first and second refer to
+// the order the torrents were passed to compare.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Difference {
+    Name(String, String),
+    PieceLength(u64, u64),
+    OnlyInFirst(String),
+    OnlyInSecond(String),
+    Length(String, u64, u64),
+    PiecesRoot(String),
+    PieceLayer(String),
+}
+
+impl fmt::Display for Difference {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Difference::Name(a, b) => write!(f, "name: {:?} != {:?}", a, b),
+            Difference::PieceLength(a, b) => write!(f, "piece length: {} != {}", a, b),
+            Difference::OnlyInFirst(p) => write!(f, "{}: only in first torrent", p),
+            Difference::OnlyInSecond(p) => write!(f, "{}: only in second torrent", p),
+            Difference::Length(p, a, b) => write!(f, "{}: length {} != {}", p, a, b),
+            Difference::PiecesRoot(p) => write!(f, "{}: pieces root differs", p),
+            Difference::PieceLayer(p) => write!(f, "{}: piece layer differs", p),
+        }
+    }
+}
+
+// Returns the differences between the info dictionaries and piece layers of
+// two torrents. Files are matched by path.
+pub fn compare(a: &Torrent, b: &Torrent) -> Vec<Difference> {
+    let mut ret = Vec::new();
+
+    if a.info.name != b.info.name {
+        ret.push(Difference::Name(a.info.name.clone(), b.info.name.clone()));
+    }
+    if a.info.piece_length != b.info.piece_length {
+        ret.push(Difference::PieceLength(
+            a.info.piece_length.bytes(),
+            b.info.piece_length.bytes(),
+        ));
+    }
+
+    let a_files: BTreeMap<_, _> = a.files().into_iter().collect();
+    let mut b_files: BTreeMap<_, _> = b.files().into_iter().collect();
+
+    for (path, fa) in a_files {
+        let fb = match b_files.remove(&path) {
+            Some(f) => f,
+            None => {
+                ret.push(Difference::OnlyInFirst(path));
+                continue;
+            }
+        };
+
+        if fa.length != fb.length {
+            ret.push(Difference::Length(path, fa.length, fb.length));
+        } else if fa.pieces_root != fb.pieces_root {
+            ret.push(Difference::PiecesRoot(path));
+        } else if a.piece_layers.get(&fa.pieces_root) != b.piece_layers.get(&fb.pieces_root) {
+            ret.push(Difference::PieceLayer(path));
+        }
+    }
+
+    ret.extend(b_files.into_keys().map(Difference::OnlyInSecond));
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metainfo::{File, PieceLength};
+
+    fn file(length: u64, root: u8) -> File {
+        File {
+            length,
+            pieces_root: [root; 32].into(),
+            crc32: None,
+        }
+    }
+
+    #[test]
+    fn compare_torrents() {
+        let mut a = Torrent::new(
+            "".to_string(),
+            "name".to_string(),
+            PieceLength { layers: 0 },
+        );
+        assert!(a.add_file("same", file(1, b'a'), Vec::new()));
+        assert!(a.add_file("length", file(1, b'b'), Vec::new()));
+        assert!(a.add_file("root", file(1, b'c'), Vec::new()));
+        assert!(a.add_file("layer", file(1 << 15, b'd'), vec![[b'x'; 32].into(); 2]));
+        assert!(a.add_file("first", file(1, b'e'), Vec::new()));
+        assert_eq!(compare(&a, &a), Vec::new());
+
+        let mut b = Torrent::new(
+            "".to_string(),
+            "other".to_string(),
+            PieceLength { layers: 1 },
+        );
+        assert!(b.add_file("same", file(1, b'a'), Vec::new()));
+        assert!(b.add_file("length", file(2, b'b'), Vec::new()));
+        assert!(b.add_file("root", file(1, b'f'), Vec::new()));
+        assert!(b.add_file("layer", file(1 << 15, b'd'), vec![[b'y'; 32].into(); 2]));
+        assert!(b.add_file("dir/second", file(1, b'g'), Vec::new()));
+
+        assert_eq!(
+            compare(&a, &b),
+            vec![
+                Difference::Name("name".to_owned(), "other".to_owned()),
+                Difference::PieceLength(16 << 10, 32 << 10),
+                Difference::OnlyInFirst("first".to_owned()),
+                Difference::PieceLayer("layer".to_owned()),
+                Difference::Length("length".to_owned(), 1, 2),
+                Difference::PiecesRoot("root".to_owned()),
+                Difference::OnlyInSecond("dir/second".to_owned()),
+            ]
+        );
+    }
+}
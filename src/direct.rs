@@ -0,0 +1,99 @@
+use std::cell::RefCell;
+use std::cmp;
+use std::fs;
+use std::io;
+use std::os::unix::fs::{FileExt, OpenOptionsExt};
+use std::path::Path;
+
+use positioned_io::ReadAt;
+
+const ALIGN: usize = 4096;
+
+#[repr(C, align(4096))]
+#[derive(Clone, Copy)]
+struct Block([u8; ALIGN]);
+
+thread_local! {
+    // The aligned buffer for reads on this thread. It grows to the largest
+    // read so far and is reused rather than allocated for every read.
+    static BLOCKS: RefCell<Vec<Block>> = const { RefCell::new(Vec::new()) };
+}
+
+// A file opened with O_DIRECT so reads bypass the page cache. O_DIRECT requires
+// the buffer, offset and length of every read to be aligned, so reads go
+// through an aligned buffer kept for each thread and are copied out.
+pub struct DirectFile {
+    file: fs::File,
+}
+
+impl DirectFile {
+    pub fn open(p: impl AsRef<Path>) -> io::Result<Self> {
+        let file = fs::OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_DIRECT)
+            .open(p)?;
+        Ok(DirectFile { file })
+    }
+}
+
+impl ReadAt for DirectFile {
+    fn read_at(&self, pos: u64, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let start = pos - pos % ALIGN as u64;
+        let skip = (pos - start) as usize;
+        let len = (skip + buf.len()).div_ceil(ALIGN) * ALIGN;
+
+        BLOCKS.with_borrow_mut(|blocks| {
+            if blocks.len() < len / ALIGN {
+                blocks.resize(len / ALIGN, Block([0; ALIGN]));
+            }
+            // SAFETY: Block is plain bytes with no padding, so the blocks are
+            // at least len contiguous initialized bytes.
+            let aligned =
+                unsafe { std::slice::from_raw_parts_mut(blocks.as_mut_ptr() as *mut u8, len) };
+
+            // The read may be short at the end of the file.
+            let n = FileExt::read_at(&self.file, aligned, start)?;
+            if n <= skip {
+                return Ok(0);
+            }
+
+            let n = cmp::min(n - skip, buf.len());
+            buf[..n].copy_from_slice(&aligned[skip..skip + n]);
+            Ok(n)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unaligned_reads() {
+        let dir = tempfile::tempdir().unwrap();
+        let p = dir.path().join("a");
+        let data: Vec<u8> = (0..10_000u32).map(|i| (i % 251) as u8).collect();
+        fs::write(&p, &data).unwrap();
+
+        let f = DirectFile::open(&p).unwrap();
+        for (pos, len) in [
+            (0, 10_000),
+            (1, 100),
+            (4095, 2),
+            (4096, 4096),
+            (9_000, 5_000),
+        ] {
+            let mut buf = vec![0; len];
+            let n = f.read_at(pos as u64, &mut buf).unwrap();
+            let end = cmp::min(pos + len, data.len());
+            assert_eq!(&buf[..n], &data[pos..end], "pos {}, len {}", pos, len);
+        }
+
+        let mut buf = [0; 10];
+        assert_eq!(f.read_at(20_000, &mut buf).unwrap(), 0);
+    }
+}
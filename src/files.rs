@@ -0,0 +1,643 @@
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Error, Result};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use indicatif::ProgressBar;
+use serde::Serialize;
+use walkdir::WalkDir;
+
+use crate::metainfo::MAX_FILE_PATH_DEPTH;
+use crate::source::{ContentSource, Filesystem};
+use crate::{hide_progress, scan_spinner, Cli};
+
+// Lists the files under the directory root after every filter and returns the
+// directory they are relative to with the files.
+pub fn select_files(cli: &Cli, root: &Path) -> Result<(PathBuf, Vec<(String, u64)>)> {
+    let files = filtered_files(cli, root)?;
+    if cli.trim_common_prefix {
+        let (prefix, files) = trim_common_prefix(files);
+        Ok((root.join(prefix), files))
+    } else {
+        Ok((root.to_path_buf(), files))
+    }
+}
+
+// Lists the files under the directory root, relative to it, after every
+// filter but --trim-common-prefix.
+pub fn filtered_files(cli: &Cli, root: &Path) -> Result<Vec<(String, u64)>> {
+    let files = match &cli.files_from {
+        Some(list) => read_file_list(list, root, cli.windows_paths)?,
+        None => {
+            let spinner = scan_spinner(hide_progress(cli), cli.progress_template.as_deref());
+            let files = Filesystem::new(root)
+                .dereference(cli.dereference)
+                .progress(spinner.clone())
+                .file_list()?;
+            spinner.finish_and_clear();
+            files
+        }
+    };
+
+    let files = match gitignore(cli, root)? {
+        Some(g) => filter_ignored(&g, files),
+        None => files,
+    };
+
+    let files = filter_extensions(&cli.include_ext, &cli.exclude_ext, files);
+
+    if cli.newer_than.is_some() || cli.older_than.is_some() {
+        Ok(filter_mtime(cli, root, files))
+    } else {
+        Ok(files)
+    }
+}
+
+// A file to be added to the torrent, as printed by --list-files-json.
+#[derive(Serialize)]
+struct ListedFile<'a> {
+    path: &'a str,
+    size: u64,
+}
+
+// Writes files as a JSON array of their paths and sizes in path order.
+pub fn write_file_list_json(w: impl Write, files: &[(String, u64)]) -> Result<()> {
+    let mut listed: Vec<_> = files
+        .iter()
+        .map(|(path, size)| ListedFile { path, size: *size })
+        .collect();
+    listed.sort_unstable_by_key(|f| f.path);
+    serde_json::to_writer(w, &listed)?;
+    Ok(())
+}
+
+// Returns the relative path from the root for each file in the root,
+// incrementing progress for each file found. If dereference is set, symlinks
+// to files are included with the length of their target.
+pub fn get_file_list_progress(
+    root: &Path,
+    dereference: bool,
+    progress: &ProgressBar,
+) -> Result<Vec<(String, u64)>> {
+    let mut ret = Vec::new();
+
+    for entry in WalkDir::new(root) {
+        let entry = entry?;
+        let l = if entry.file_type().is_file() {
+            entry.metadata()?.len()
+        } else if dereference && entry.path_is_symlink() {
+            let metadata = fs::metadata(entry.path()).context(format!(
+                "broken symlink: {}",
+                entry.path().to_string_lossy()
+            ))?;
+            if !metadata.is_file() {
+                continue;
+            }
+            metadata.len()
+        } else {
+            continue;
+        };
+
+        if entry.depth() >= MAX_FILE_PATH_DEPTH {
+            return Err(Error::msg(format!(
+                "hit max file depth ({}) at file: {}",
+                MAX_FILE_PATH_DEPTH,
+                entry.path().to_string_lossy(),
+            )));
+        }
+
+        let rel_path = entry.path().strip_prefix(root).unwrap();
+
+        let rel_path_str = rel_path
+            .to_str()
+            .ok_or_else(|| {
+                Error::msg(format!(
+                    "cannot convert path to UTF-8: {}",
+                    rel_path.to_string_lossy(),
+                ))
+            })?
+            .to_owned();
+
+        ret.push((rel_path_str, l));
+        progress.inc(1);
+    }
+
+    Ok(ret)
+}
+
+// Reads a list of paths relative to root, one per line, and returns each path
+// with the length of the file. Absolute paths are made relative to root.
+fn read_file_list(list: &Path, root: &Path, windows_paths: bool) -> Result<Vec<(String, u64)>> {
+    let f = fs::File::open(list).context(format!("failed to open `{}`", list.to_string_lossy()))?;
+    let canonical_root =
+        fs::canonicalize(root).context(format!("failed to read `{}`", root.to_string_lossy()))?;
+    let mut ret = Vec::new();
+
+    for line in io::BufReader::new(f).lines() {
+        let line = line?;
+        if line.is_empty() {
+            continue;
+        }
+
+        let path = normalize_separators(&line, windows_paths);
+        let path = relative_to_root(&path, root, &canonical_root)?;
+        check_relative_path(&path)?;
+
+        let metadata =
+            fs::metadata(root.join(&path)).context(format!("failed to stat `{}`", path))?;
+        if !metadata.is_file() {
+            return Err(Error::msg(format!("not a regular file: {}", path)));
+        }
+
+        ret.push((path, metadata.len()));
+    }
+
+    Ok(ret)
+}
+
+// Returns path relative to root. An absolute path must be under root as given
+// or as canonical_root, its canonical form.
+fn relative_to_root(path: &str, root: &Path, canonical_root: &Path) -> Result<String> {
+    let p = Path::new(path);
+    if !p.is_absolute() {
+        return Ok(path.to_owned());
+    }
+    let rel = p
+        .strip_prefix(root)
+        .or_else(|_| p.strip_prefix(canonical_root))
+        .map_err(|_| Error::msg(format!("path is outside the root: {}", path)))?;
+    // rel is a suffix of a str.
+    Ok(rel.to_str().unwrap().to_owned())
+}
+
+// Returns an error if path, relative with '/' separators, is absolute or has
+// an empty, `.` or `..` component. Any of these would put a name in the file
+// tree that clients cannot create.
+pub fn check_relative_path(path: &str) -> Result<()> {
+    let reason = if path.starts_with('/') {
+        "must be relative"
+    } else if path.split('/').any(str::is_empty) {
+        "has an empty component"
+    } else if path.split('/').any(|c| c == "." || c == "..") {
+        "has a `.` or `..` component"
+    } else {
+        return Ok(());
+    };
+    Err(Error::msg(format!("invalid path `{}`: {}", path, reason)))
+}
+
+// Builds the matcher for --gitignore and --respect-gitignore. Returns None if
+// neither applies.
+pub fn gitignore(cli: &Cli, root: &Path) -> Result<Option<Gitignore>> {
+    let mut paths = Vec::new();
+    if let Some(p) = &cli.gitignore {
+        paths.push(p.clone());
+    }
+    if cli.respect_gitignore {
+        let p = root.join(".gitignore");
+        if p.is_file() {
+            paths.push(p);
+        }
+    }
+    if paths.is_empty() {
+        return Ok(None);
+    }
+
+    let mut builder = GitignoreBuilder::new(root);
+    for p in &paths {
+        if let Some(e) = builder.add(p) {
+            return Err(Error::msg(format!(
+                "failed to read `{}`: {}",
+                p.to_string_lossy(),
+                e
+            )));
+        }
+    }
+    Ok(Some(builder.build()?))
+}
+
+// Removes files ignored by g, or by a pattern matching one of their parent
+// directories, unless a negated pattern includes the file itself.
+fn filter_ignored(g: &Gitignore, files: Vec<(String, u64)>) -> Vec<(String, u64)> {
+    files
+        .into_iter()
+        .filter(|(p, _)| !g.matched_path_or_any_parents(p, false).is_ignore())
+        .collect()
+}
+
+// Keeps files whose extension is in include, or every file if include is
+// empty, and whose extension is not in exclude. Extensions may be given with
+// a leading dot.
+fn filter_extensions(
+    include: &[String],
+    exclude: &[String],
+    files: Vec<(String, u64)>,
+) -> Vec<(String, u64)> {
+    let matches = |exts: &[String], p: &str| {
+        Path::new(p).extension().is_some_and(|e| {
+            exts.iter()
+                .any(|x| e.eq_ignore_ascii_case(x.trim_start_matches('.')))
+        })
+    };
+
+    files
+        .into_iter()
+        .filter(|(p, _)| include.is_empty() || matches(include, p))
+        .filter(|(p, _)| !matches(exclude, p))
+        .collect()
+}
+
+// Removes files, relative to root, modified outside the --newer-than and
+// --older-than window. Files whose modification time cannot be read are kept
+// with a warning.
+fn filter_mtime(cli: &Cli, root: &Path, files: Vec<(String, u64)>) -> Vec<(String, u64)> {
+    files
+        .into_iter()
+        .filter(|(p, _)| {
+            let mtime = match fs::metadata(root.join(p)).and_then(|m| m.modified()) {
+                Ok(m) => m,
+                Err(e) => {
+                    eprintln!("warning: cannot read modification time of {}: {}", p, e);
+                    return true;
+                }
+            };
+            let keep = cli.newer_than.is_none_or(|t| mtime > t)
+                && cli.older_than.is_none_or(|t| mtime < t);
+            if !keep && cli.verbose > 0 {
+                eprintln!("skipping {}: modified outside the time window", p);
+            }
+            keep
+        })
+        .collect()
+}
+
+// Strips the longest common directory prefix from every path and returns it.
+// A single file is trimmed to its file name.
+pub fn trim_common_prefix(files: Vec<(String, u64)>) -> (PathBuf, Vec<(String, u64)>) {
+    let mut prefix: Option<PathBuf> = None;
+    for (p, _) in &files {
+        let parent = Path::new(p).parent().unwrap_or_else(|| Path::new(""));
+        prefix = Some(match prefix {
+            None => parent.to_path_buf(),
+            Some(prefix) => prefix
+                .components()
+                .zip(parent.components())
+                .take_while(|(a, b)| a == b)
+                .map(|(a, _)| a)
+                .collect(),
+        });
+    }
+    let prefix = prefix.unwrap_or_default();
+
+    let files = files
+        .into_iter()
+        .map(|(p, l)| {
+            // p came from a str and prefix is a prefix of it.
+            let trimmed = Path::new(&p).strip_prefix(&prefix).unwrap();
+            (trimmed.to_str().unwrap().to_owned(), l)
+        })
+        .collect();
+
+    (prefix, files)
+}
+
+// Converts `\` separators to `/` when windows_paths is set. Otherwise the path
+// is returned as-is since `\` is a valid filename character on Unix.
+fn normalize_separators(path: &str, windows_paths: bool) -> String {
+    if windows_paths {
+        path.replace('\\', "/")
+    } else {
+        path.to_owned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::build::build_torrent;
+    use crate::hash::{hash_file, HashOptions};
+    use crate::metainfo::{File, PathElement, PieceLength, Torrent};
+    use crate::test_util::get_file_list;
+    use crate::{parse_time, scan_spinner};
+    use clap::Parser;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn windows_paths() {
+        let mut t = Torrent::new("".to_string(), "".to_string(), PieceLength { layers: 0 });
+        assert!(t.add_file(
+            &normalize_separators("dir\\file.txt", false),
+            File::default(),
+            Vec::new()
+        ));
+        // without the flag the backslash is part of a single file name
+        assert!(matches!(
+            t.info.file_tree.entries.get("dir\\file.txt"),
+            Some(PathElement::File(_))
+        ));
+
+        let mut t = Torrent::new("".to_string(), "".to_string(), PieceLength { layers: 0 });
+        assert!(t.add_file(
+            &normalize_separators("dir\\file.txt", true),
+            File::default(),
+            Vec::new()
+        ));
+        match t.info.file_tree.entries.get("dir") {
+            Some(PathElement::Directory(d)) => {
+                assert!(matches!(
+                    d.entries.get("file.txt"),
+                    Some(PathElement::File(_))
+                ))
+            }
+            x => panic!("expected directory, got: {:?}", x),
+        }
+    }
+
+    #[test]
+    fn trim_common_prefix() {
+        let files = |paths: &[&str]| -> Vec<(String, u64)> {
+            paths.iter().map(|p| (p.to_string(), 1)).collect()
+        };
+
+        let (prefix, trimmed) =
+            super::trim_common_prefix(files(&["/data/x/a.txt", "/data/x/b.txt", "/data/x/y/c"]));
+        assert_eq!(prefix, Path::new("/data/x"));
+        assert_eq!(trimmed, files(&["a.txt", "b.txt", "y/c"]));
+
+        // the prefix is made of whole directories
+        let (prefix, trimmed) = super::trim_common_prefix(files(&["/data/xa/a", "/data/xb/b"]));
+        assert_eq!(prefix, Path::new("/data"));
+        assert_eq!(trimmed, files(&["xa/a", "xb/b"]));
+
+        let (prefix, trimmed) = super::trim_common_prefix(files(&["/data/x/a.txt"]));
+        assert_eq!(prefix, Path::new("/data/x"));
+        assert_eq!(trimmed, files(&["a.txt"]));
+
+        let (prefix, trimmed) = super::trim_common_prefix(files(&["a", "b/c"]));
+        assert_eq!(prefix, Path::new(""));
+        assert_eq!(trimmed, files(&["a", "b/c"]));
+
+        let dir = tempfile::tempdir().unwrap();
+        let data = dir.path().join("data/x");
+        fs::create_dir_all(&data).unwrap();
+        fs::write(data.join("a.txt"), "a").unwrap();
+        fs::write(data.join("b.txt"), "b").unwrap();
+        let list = dir.path().join("list");
+        fs::write(
+            &list,
+            format!(
+                "{}\n{}\n",
+                data.join("a.txt").to_str().unwrap(),
+                data.join("b.txt").to_str().unwrap()
+            ),
+        )
+        .unwrap();
+
+        let files = read_file_list(&list, dir.path(), false).unwrap();
+        let (prefix, files) = super::trim_common_prefix(files);
+        let cli = Cli::parse_from([
+            "mktorrent-rs",
+            "--announce=",
+            "--piece-length=14",
+            dir.path().to_str().unwrap(),
+        ]);
+        let opts = HashOptions::new(PieceLength { layers: 0 });
+        let root = dir.path().join(prefix);
+        let (t, _, _) = build_torrent(&cli, &opts, "x".to_owned(), &root, files, false).unwrap();
+        let paths: Vec<_> = t.files().into_iter().map(|(p, _)| p).collect();
+        assert_eq!(paths, vec!["a.txt", "b.txt"]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn dereference_symlinks() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("target");
+        let root = dir.path().join("root");
+        fs::create_dir_all(&root).unwrap();
+        fs::write(&target, vec![7u8; 20 << 10]).unwrap();
+        std::os::unix::fs::symlink(&target, root.join("link")).unwrap();
+
+        // symlinks are skipped by default
+        assert!(get_file_list(&root).unwrap().is_empty());
+
+        let files = get_file_list_progress(&root, true, &ProgressBar::hidden()).unwrap();
+        assert_eq!(files, vec![("link".to_owned(), 20 << 10)]);
+
+        let opts = HashOptions::new(PieceLength { layers: 0 });
+        assert_eq!(
+            hash_file(&root, &opts, "link", 20 << 10).unwrap(),
+            hash_file(dir.path(), &opts, "target", 20 << 10).unwrap()
+        );
+
+        fs::remove_file(&target).unwrap();
+        let err = get_file_list_progress(&root, true, &ProgressBar::hidden()).unwrap_err();
+        assert!(err.to_string().contains("broken symlink"), "{}", err);
+    }
+
+    #[test]
+    fn filter_extensions() {
+        let files: Vec<(String, u64)> = [
+            "movie.MKV",
+            "movie.nfo",
+            "extras/clip.mp4",
+            "extras/clip.srt",
+            "README",
+            "sample.mkv",
+        ]
+        .iter()
+        .map(|p| (p.to_string(), 1))
+        .collect();
+        let parse = |args: &[&str]| {
+            let mut argv = vec!["mktorrent-rs", "--announce=", "--piece-length=14"];
+            argv.extend_from_slice(args);
+            argv.push("root");
+            let cli = Cli::parse_from(argv);
+            super::filter_extensions(&cli.include_ext, &cli.exclude_ext, files.clone())
+                .into_iter()
+                .map(|(p, _)| p)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            parse(&["--include-ext=mkv,.mp4"]),
+            vec!["movie.MKV", "extras/clip.mp4", "sample.mkv"]
+        );
+        assert_eq!(
+            parse(&["--exclude-ext=nfo,srt"]),
+            vec!["movie.MKV", "extras/clip.mp4", "README", "sample.mkv"]
+        );
+        assert_eq!(
+            parse(&["--include-ext=mkv,srt", "--exclude-ext=srt"]),
+            vec!["movie.MKV", "sample.mkv"]
+        );
+        assert_eq!(parse(&[]).len(), files.len());
+    }
+
+    #[test]
+    fn newer_older_than() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        let at = |secs: u64| UNIX_EPOCH + Duration::from_secs(secs);
+        for (p, secs) in [("old", 1_000), ("mid", 2_000), ("new", 3_000)] {
+            let f = fs::File::create(root.join(p)).unwrap();
+            f.set_modified(at(secs)).unwrap();
+        }
+        let files = get_file_list(root).unwrap();
+        let filtered = |args: &[&str]| {
+            let mut argv = vec!["mktorrent-rs", "--announce=", "--piece-length=14"];
+            argv.extend_from_slice(args);
+            argv.push("root");
+            let mut got: Vec<_> = filter_mtime(&Cli::parse_from(argv), root, files.clone())
+                .into_iter()
+                .map(|(p, _)| p)
+                .collect();
+            got.sort();
+            got
+        };
+
+        assert_eq!(filtered(&["--newer-than=1500"]), vec!["mid", "new"]);
+        assert_eq!(
+            filtered(&["--older-than=1970-01-01T00:40:00Z"]),
+            vec!["mid", "old"]
+        );
+        assert_eq!(
+            filtered(&["--newer-than=1000", "--older-than=1970-01-01T00:50:00"]),
+            vec!["mid"]
+        );
+
+        assert_eq!(parse_time("1970-01-02").unwrap(), at(86400));
+        assert_eq!(parse_time("1970-01-01T01:00:00+01:00").unwrap(), at(0));
+        assert!(parse_time("-1").is_err());
+        assert!(parse_time("yesterday").is_err());
+    }
+
+    #[test]
+    fn files_from_invalid_paths() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("root");
+        fs::create_dir_all(root.join("a")).unwrap();
+        fs::write(root.join("a/b"), "b").unwrap();
+        fs::write(dir.path().join("outside"), "x").unwrap();
+        let list = dir.path().join("list");
+
+        let read = |line: &str| {
+            fs::write(&list, format!("{}\n", line)).unwrap();
+            read_file_list(&list, &root, true)
+        };
+        assert_eq!(read("a/b").unwrap(), vec![("a/b".to_owned(), 1)]);
+        assert_eq!(read("a\\b").unwrap(), vec![("a/b".to_owned(), 1)]);
+        for bad in ["../outside", "a/../a/b", "a//b", "./a/b", "a/./b", "a\\"] {
+            let err = read(bad).unwrap_err().to_string();
+            assert!(err.starts_with("invalid path"), "{}: {}", bad, err);
+        }
+    }
+
+    #[test]
+    fn gitignore() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        for p in [
+            "build/out",
+            "src/main.rs",
+            "a.log",
+            "keep.log",
+            "logs/b.log",
+        ] {
+            let p = root.join(p);
+            fs::create_dir_all(p.parent().unwrap()).unwrap();
+            fs::write(p, "x").unwrap();
+        }
+        fs::write(root.join(".gitignore"), "*.log\n!keep.log\nbuild/\n").unwrap();
+
+        let parse = |args: &[&str]| {
+            let mut argv = vec!["mktorrent-rs", "--announce=", "--piece-length=14"];
+            argv.extend_from_slice(args);
+            argv.push("root");
+            Cli::parse_from(argv)
+        };
+        assert!(super::gitignore(&parse(&[]), root).unwrap().is_none());
+
+        let g = super::gitignore(&parse(&["--respect-gitignore"]), root)
+            .unwrap()
+            .unwrap();
+        let mut files: Vec<String> = filter_ignored(&g, get_file_list(root).unwrap())
+            .into_iter()
+            .map(|(p, _)| p)
+            .collect();
+        files.sort();
+        assert_eq!(files, vec![".gitignore", "keep.log", "src/main.rs"]);
+
+        let other = dir.path().join("src/ignore");
+        fs::write(&other, "src/\n").unwrap();
+        let g = super::gitignore(&parse(&["--gitignore", other.to_str().unwrap()]), root)
+            .unwrap()
+            .unwrap();
+        assert!(g
+            .matched_path_or_any_parents("src/main.rs", false)
+            .is_ignore());
+        assert!(!g.matched_path_or_any_parents("a.log", false).is_ignore());
+
+        // absolute --files-from paths are matched relative to the root
+        let list = dir.path().join("list");
+        fs::write(
+            &list,
+            format!("{}\nkeep.log\n", root.join("src/main.rs").to_str().unwrap()),
+        )
+        .unwrap();
+        let files = filter_ignored(&g, read_file_list(&list, root, false).unwrap());
+        assert_eq!(files, vec![("keep.log".to_owned(), 1)]);
+
+        let outside = tempfile::NamedTempFile::new().unwrap();
+        fs::write(&list, format!("{}\n", outside.path().to_str().unwrap())).unwrap();
+        let err = read_file_list(&list, root, false).unwrap_err().to_string();
+        assert!(err.starts_with("path is outside the root"), "{}", err);
+    }
+
+    #[test]
+    fn list_files_json() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("sub")).unwrap();
+        for (p, data) in [("a.txt", "aa"), ("b.log", "b"), ("sub/c.txt", "ccc")] {
+            fs::write(dir.path().join(p), data).unwrap();
+        }
+
+        let cli = Cli::parse_from([
+            "mktorrent-rs",
+            "--announce=",
+            "--list-files-json",
+            "--exclude-ext=log",
+            "--no-progress",
+            "root",
+        ]);
+        let (_, files) = select_files(&cli, dir.path()).unwrap();
+        let mut out = Vec::new();
+        write_file_list_json(&mut out, &files).unwrap();
+
+        let listed: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(
+            listed,
+            serde_json::json!([
+                {"path": "a.txt", "size": 2},
+                {"path": "sub/c.txt", "size": 3},
+            ])
+        );
+    }
+
+    #[test]
+    fn scan_progress() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("a/b")).unwrap();
+        for name in ["1", "a/2", "a/b/3", "a/b/4"] {
+            fs::write(dir.path().join(name), name).unwrap();
+        }
+
+        let spinner = scan_spinner(false, None);
+        spinner.set_draw_target(indicatif::ProgressDrawTarget::hidden());
+        let files = get_file_list_progress(dir.path(), false, &spinner).unwrap();
+        assert_eq!(files, get_file_list(dir.path()).unwrap());
+        assert_eq!(files.len(), 4);
+        assert_eq!(spinner.position(), 4);
+    }
+}
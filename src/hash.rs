@@ -0,0 +1,1257 @@
+use std::cmp;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Read};
+use std::iter;
+use std::path::Path;
+use std::sync::mpsc;
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+use anyhow::{Context, Error, Result};
+use indicatif::ProgressBar;
+use positioned_io::{Cursor, ReadAt, Slice};
+use rayon::prelude::*;
+
+use crate::build::{check_suspicious_files, entry_path, BuildWarning, Mtimes};
+use crate::checksum::adaptive::AdaptiveThreads;
+use crate::checksum::crc32::Crc32Reader;
+use crate::checksum::{self, sha256};
+#[cfg(target_os = "linux")]
+use crate::direct::DirectFile;
+use crate::manifest::{self, Manifest};
+use crate::metainfo::{self, FileEntry, PieceLength, Torrent};
+use crate::newlines::CrlfToLf;
+use crate::pipe::PieceEvent;
+use crate::source::{ContentSource, Filesystem};
+use crate::{hide_progress, Cli};
+
+// Hashes files, which are relative to dir. The entries, the modification
+// times and the --incremental cache are keyed by each file's path in the file
+// tree, which differs from its path on disk with --group-by-extension or when
+// name is set for a single file (see entry_path). Also returns the
+// modification time of each file that has one.
+pub fn hash_files(
+    cli: &Cli,
+    hash_options: &HashOptions,
+    dir: &Path,
+    files: Vec<(String, u64)>,
+    name: Option<&str>,
+) -> Result<(Vec<FileEntry>, Mtimes, Vec<BuildWarning>)> {
+    let piece_length = hash_options.piece_length;
+
+    check_suspicious_files(cli, dir, &files)?;
+
+    let prev = match cli
+        .incremental
+        .as_ref()
+        .or(cli.resume_from_checkpoint.as_ref())
+    {
+        Some(p) => {
+            let m = Manifest::load(p)?;
+            m.check_piece_length(piece_length)?;
+            if cli.resume_from_checkpoint.is_some() && !cli.resume_allow_changes {
+                let tree_files: Vec<_> = files
+                    .iter()
+                    .map(|(file, l)| (entry_path(cli, name, file), *l))
+                    .collect();
+                check_resume(&m, p, &tree_files)?;
+            }
+            Some(m)
+        }
+        None => None,
+    };
+    let prev = prev.as_ref().map(Manifest::index);
+
+    let hash = |(file, l): (String, u64)| -> Result<FileOutcome<FileEntry>> {
+        let path = entry_path(cli, name, &file);
+        hash_checked(cli, dir, &file, |mtime| {
+            let (f, mut pieces_layer, hashed) =
+                hash_file_cached(dir, hash_options, &file, &path, l, mtime, prev.as_ref())?;
+            if !hashed {
+                hash_options.hashed(l);
+            }
+
+            if cli.always_emit_layer && pieces_layer.is_empty() && f.length > 0 {
+                pieces_layer = vec![checksum::single_piece_layer(piece_length, &f)];
+            }
+
+            let entry = FileEntry {
+                path: path.clone(),
+                file: f,
+                pieces_layer,
+            };
+            Ok((entry, hashed))
+        })
+    };
+
+    // Results are put back in the original order so the tree is assembled the
+    // same way either way.
+    let hashed: Vec<_> = if hash_options.parallel_files {
+        let mut hashed = schedule(files, hash_options.largest_first)
+            .into_par_iter()
+            .map(|(i, f)| hash(f).map(|r| (i, r)))
+            .collect::<Result<Vec<_>>>()?;
+        hashed.sort_unstable_by_key(|&(i, _)| i);
+        hashed.into_iter().map(|(_, r)| r).collect()
+    } else {
+        files.into_iter().map(hash).collect::<Result<_>>()?
+    };
+
+    let mut entries = Vec::with_capacity(hashed.len());
+    let mut mtimes = HashMap::new();
+    let mut warnings = Vec::new();
+    for outcome in hashed {
+        let (entry, mtime, changed) = match outcome {
+            FileOutcome::Hashed {
+                result,
+                mtime,
+                changed,
+            } => (result, mtime, changed),
+            FileOutcome::Vanished(path) => {
+                warnings.push(BuildWarning::VanishedFile(path));
+                continue;
+            }
+        };
+
+        if changed {
+            warnings.push(BuildWarning::ChangedFile(entry.path.clone()));
+        }
+        if let Some(m) = mtime {
+            let m = match cli.mtime_clamp {
+                Some(epoch) => cmp::min(m, epoch.saturating_mul(1_000_000_000)),
+                None => m,
+            };
+            mtimes.insert(entry.path.clone(), m);
+        }
+        entries.push(entry);
+    }
+
+    Ok((entries, mtimes, warnings))
+}
+
+// Returns an error if files were added or removed since the checkpoint at p,
+// which resuming would silently include or drop.
+fn check_resume(m: &Manifest, p: &Path, files: &[(String, u64)]) -> Result<()> {
+    let (added, removed) = m.file_set_changes(files);
+    if added.is_empty() && removed.is_empty() {
+        return Ok(());
+    }
+
+    for path in &added {
+        eprintln!("added since checkpoint: {}", path);
+    }
+    for path in &removed {
+        eprintln!("removed since checkpoint: {}", path);
+    }
+    Err(Error::msg(format!(
+        "{} files added and {} removed since `{}`, use --resume-allow-changes to resume anyway",
+        added.len(),
+        removed.len(),
+        p.to_string_lossy()
+    )))
+}
+
+// The result of hashing a single file in hash_checked.
+pub enum FileOutcome<T> {
+    Hashed {
+        result: T,
+        mtime: Option<u64>,
+        changed: bool,
+    },
+    // The file was removed after it was listed and --skip-errors is set.
+    Vanished(String),
+}
+
+// Hashes file, which is relative to dir, with hash and checks it did not
+// vanish or change meanwhile. hash is given the file's mtime and returns its
+// result and whether it read the file rather than reusing earlier hashes. A
+// file that vanished is an error unless --skip-errors is set.
+pub fn hash_checked<T>(
+    cli: &Cli,
+    dir: &Path,
+    file: &str,
+    hash: impl FnOnce(Option<u64>) -> Result<(T, bool)>,
+) -> Result<FileOutcome<T>> {
+    let p = dir.join(file);
+    // Files that cannot change need no second stat after hashing.
+    let recheck = !cli.assume_immutable;
+    let length = || fs::metadata(&p).map(|m| m.len()).ok();
+    let mtime = file_mtime(&p);
+    let before = if recheck { length() } else { None };
+    let (result, hashed) = match hash(mtime) {
+        Ok(r) => r,
+        Err(e) if is_vanished(&e, &p) => {
+            if cli.skip_errors {
+                return Ok(FileOutcome::Vanished(file.to_owned()));
+            }
+            return Err(Error::msg(format!(
+                "file disappeared during processing: {}",
+                file
+            )));
+        }
+        Err(e) => return Err(e.context(format!("failed to hash `{}`", file))),
+    };
+    let changed = recheck && hashed && (file_mtime(&p) != mtime || length() != before);
+
+    Ok(FileOutcome::Hashed {
+        result,
+        mtime,
+        changed,
+    })
+}
+
+// Returns true if e was caused by the file at p no longer existing.
+fn is_vanished(e: &Error, p: &Path) -> bool {
+    let not_found = e.chain().any(|c| {
+        c.downcast_ref::<io::Error>()
+            .is_some_and(|e| e.kind() == io::ErrorKind::NotFound)
+    });
+    not_found || fs::symlink_metadata(p).is_err_and(|e| e.kind() == io::ErrorKind::NotFound)
+}
+
+// Pairs each file with its index and orders them for hashing. Starting the
+// largest files first avoids a long tail where one large file is hashed
+// after everything else has finished.
+fn schedule(files: Vec<(String, u64)>, largest_first: bool) -> Vec<(usize, (String, u64))> {
+    let mut files: Vec<_> = files.into_iter().enumerate().collect();
+    if largest_first {
+        files.sort_by_key(|(_, (_, l))| cmp::Reverse(*l));
+    }
+    files
+}
+
+// Returns the number of threads, up to threads, whose piece buffers fit in
+// max_buffer_memory. At least one thread is always used.
+fn memory_limited_threads(
+    threads: usize,
+    max_buffer_memory: u64,
+    piece_length: PieceLength,
+) -> usize {
+    let fit = max_buffer_memory / checksum::torrent2::piece_memory(piece_length);
+    cmp::max(cmp::min(threads as u64, fit), 1) as usize
+}
+
+// How much of the largest file calibrate hashes.
+const ESTIMATE_SAMPLE_BYTES: u64 = 64 << 20;
+
+// Returns whether to estimate the hashing time before hashing total bytes of
+// content. Calibrating reads part of the content, so it is skipped unless the
+// estimate is asked for or is printed alongside progress on a terminal.
+pub fn show_estimate(cli: &Cli, total: u64, terminal: bool) -> bool {
+    cli.estimate_time
+        || (!cli.no_estimate && !hide_progress(cli) && total > ESTIMATE_SAMPLE_BYTES && terminal)
+}
+
+// Times hashing the start of the largest of files, relative to dir, and
+// returns the throughput in bytes per second, or None if every file is empty.
+// v1 content is hashed with SHA-1 in a single stream, as build_torrent_v1
+// does, and v2 content with SHA-256 a piece per thread.
+pub fn calibrate(
+    opts: &HashOptions,
+    v1: bool,
+    dir: &Path,
+    files: &[(String, u64)],
+) -> Result<Option<f64>> {
+    let Some((path, l)) = files.iter().max_by_key(|(_, l)| *l).filter(|(_, l)| *l > 0) else {
+        return Ok(None);
+    };
+    let n = cmp::min(*l, ESTIMATE_SAMPLE_BYTES);
+
+    let f = Filesystem::new(dir).open(path)?;
+    let sample = Slice::new(&f, opts.start_offset, Some(n));
+    let start = Instant::now();
+    if v1 {
+        let readers = iter::once(Ok((Cursor::new(sample), n)));
+        checksum::checksum_files_v1(opts.piece_length.bytes(), readers).map(drop)
+    } else {
+        checksum::checksum_file_multithreaded(opts.piece_length, n, &sample).map(drop)
+    }
+    .context(format!("failed to read `{}`", path))?;
+    let elapsed = cmp::max(start.elapsed(), Duration::from_millis(1));
+
+    Ok(Some(n as f64 / elapsed.as_secs_f64()))
+}
+
+// Returns how long hashing total bytes takes at throughput bytes per second.
+pub fn estimate_time(total: u64, throughput: f64) -> Duration {
+    Duration::from_secs_f64(total as f64 / throughput)
+}
+
+// Formats an estimate as "estimated ~Xm at ~Y MiB/s", in seconds for
+// estimates under a minute.
+pub fn format_estimate(estimate: Duration, throughput: f64) -> String {
+    let secs = estimate.as_secs_f64().round() as u64;
+    let time = if secs < 60 {
+        format!("{}s", secs)
+    } else {
+        format!("{}m", (secs + 30) / 60)
+    };
+    format!(
+        "estimated ~{} at ~{:.0} MiB/s",
+        time,
+        throughput / (1 << 20) as f64
+    )
+}
+
+// Configures the global thread pool and returns the HashOptions set by cli.
+pub fn hash_options(cli: &Cli, piece_length: PieceLength) -> Result<HashOptions> {
+    let threads = match cli.max_buffer_memory {
+        Some(m) => {
+            let requested = cli.threads.unwrap_or_else(rayon::current_num_threads);
+            let n = memory_limited_threads(requested, m, piece_length);
+            if n < requested && cli.verbose > 0 {
+                eprintln!("--max-buffer-memory: hashing with {} threads", n);
+            }
+            Some(n)
+        }
+        None => cli.threads,
+    };
+
+    if let Some(n) = threads {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(n)
+            .build_global()
+            .context("failed to configure thread pool")?;
+    }
+
+    let mut hash_options = HashOptions::new(piece_length);
+    hash_options.normalize_newlines = cli.normalize_newlines.clone();
+    hash_options.store_crc32 = cli.store_crc32;
+    hash_options.pieces_root_only = cli.pieces_root_only;
+    hash_options.digest_bytes = cli.digest_bytes.map(usize::from);
+    if cli.direct_io {
+        if cfg!(target_os = "linux") {
+            hash_options.direct_io = true;
+        } else {
+            eprintln!("warning: --direct-io is only supported on Linux, ignoring");
+        }
+    }
+    if cli.no_parallel_files {
+        hash_options.parallel_files = false;
+    }
+    if cli.single_threaded {
+        hash_options.single_threaded = true;
+        hash_options.parallel_files = false;
+    }
+    if let Some(n) = cli.max_inflight_pieces {
+        hash_options.max_inflight_pieces = Some(n.get());
+        hash_options.parallel_files = false;
+    }
+    if cli.adaptive_threads {
+        hash_options.adaptive = Some(AdaptiveThreads::new(rayon::current_num_threads()));
+        // Concurrent files would skew the throughput of each timed batch.
+        hash_options.parallel_files = false;
+    }
+    if cli.progress_pipe.is_some() {
+        // Events do not say which file they belong to, so the events of one
+        // file must all be written before those of the next.
+        hash_options.parallel_files = false;
+    }
+
+    Ok(hash_options)
+}
+
+// Settings controlling how file contents are hashed.
+pub struct HashOptions {
+    pub piece_length: PieceLength,
+    // Picks the number of threads from measured throughput when set.
+    pub adaptive: Option<AdaptiveThreads>,
+    // Where the content starts in each file. Only non-zero for a subset of a
+    // single file.
+    pub start_offset: u64,
+    // Extensions of files whose CRLF line endings are hashed as LF.
+    pub normalize_newlines: Vec<String>,
+    // Read files with O_DIRECT. Only supported on Linux.
+    pub direct_io: bool,
+    // Hash files concurrently in addition to the pieces within each file.
+    // This avoids per-file overhead dominating many small files.
+    pub parallel_files: bool,
+    // Start hashing the largest files first when hashing files concurrently.
+    pub largest_first: bool,
+    // Read every file from start to end on the calling thread.
+    pub single_threaded: bool,
+    // Compute each file's CRC32 while hashing it.
+    pub store_crc32: bool,
+    // Hash at most this many pieces of a file at once.
+    pub max_inflight_pieces: Option<usize>,
+    // Only compute each file's pieces root, leaving its piece layer empty.
+    pub pieces_root_only: bool,
+    // Experimental: truncate every digest in the merkle trees to this many
+    // bytes.
+    pub digest_bytes: Option<usize>,
+    // Receives an event for each piece hashed on the thread pool.
+    pub piece_events: Option<mpsc::SyncSender<PieceEvent>>,
+    // Counts the bytes of content hashed, including files taken from a cache.
+    pub progress: Option<ProgressBar>,
+}
+
+impl HashOptions {
+    pub fn new(piece_length: PieceLength) -> Self {
+        HashOptions {
+            piece_length,
+            adaptive: None,
+            start_offset: 0,
+            normalize_newlines: Vec::new(),
+            direct_io: false,
+            parallel_files: true,
+            largest_first: true,
+            single_threaded: false,
+            store_crc32: false,
+            max_inflight_pieces: None,
+            pieces_root_only: false,
+            digest_bytes: None,
+            piece_events: None,
+            progress: None,
+        }
+    }
+
+    // Advances the progress bar, if any, by n bytes hashed.
+    pub fn hashed(&self, n: u64) {
+        if let Some(bar) = &self.progress {
+            bar.inc(n);
+        }
+    }
+
+    // Returns true if the file at path has its newlines normalized.
+    pub fn normalizes_newlines(&self, path: &str) -> bool {
+        Path::new(path).extension().is_some_and(|e| {
+            self.normalize_newlines
+                .iter()
+                .any(|n| e.eq_ignore_ascii_case(n.as_str()))
+        })
+    }
+}
+
+// Produces the metainfo and piece layer for the file at path relative to root.
+pub fn hash_file(
+    root: &Path,
+    opts: &HashOptions,
+    path: &str,
+    file_length: u64,
+) -> Result<(metainfo::File, Vec<sha256::Digest>)> {
+    #[cfg(target_os = "linux")]
+    if opts.direct_io {
+        let f = DirectFile::open(root.join(path))
+            .context(format!("failed to open `{}` with O_DIRECT", path))?;
+        return hash_read_at(&f, opts, path, file_length);
+    }
+
+    hash_source(&Filesystem::new(root), opts, path, file_length)
+}
+
+// Hashes the content of the file at path in source.
+pub fn hash_source<S: ContentSource>(
+    source: &S,
+    opts: &HashOptions,
+    path: &str,
+    file_length: u64,
+) -> Result<(metainfo::File, Vec<sha256::Digest>)> {
+    hash_read_at(&source.open(path)?, opts, path, file_length)
+}
+
+// Hashes the content of the file at path, which has been opened as f.
+fn hash_read_at<T: ReadAt + Sync>(
+    f: &T,
+    opts: &HashOptions,
+    path: &str,
+    file_length: u64,
+) -> Result<(metainfo::File, Vec<sha256::Digest>)> {
+    let r = Slice::new(f, opts.start_offset, Some(file_length));
+
+    // Truncated trees are only built from block hashes.
+    if let Some(n) = opts.digest_bytes {
+        let blocks =
+            checksum::torrent2::block_hashes(file_length, &r).context("failed to checksum file")?;
+        opts.hashed(file_length);
+        return Ok(checksum::torrent2::from_block_hashes_truncated(
+            opts.piece_length,
+            file_length,
+            &blocks,
+            n,
+        ));
+    }
+
+    // A CRC32 covers the content as described by the torrent, after any
+    // normalizing. Pieces hashed in parallel each have their own CRC32 which
+    // are combined, otherwise the file is streamed through one.
+    let streamed = opts.single_threaded
+        || opts.normalizes_newlines(path)
+        || opts.adaptive.is_some()
+        || opts.max_inflight_pieces.is_some();
+    if opts.store_crc32 && !streamed {
+        return checksum::checksum_file_crc32(opts.piece_length, file_length, &r)
+            .inspect(|_| opts.hashed(file_length))
+            .context("failed to checksum file");
+    }
+    if opts.store_crc32 {
+        let r: Box<dyn Read + '_> = if opts.normalizes_newlines(path) {
+            Box::new(CrlfToLf::new(Cursor::new(r)))
+        } else {
+            Box::new(Cursor::new(r))
+        };
+        let mut r = Crc32Reader::new(r);
+        let (mut f, pieces_layer) = checksum::torrent2::checksum_file(opts.piece_length, &mut r)
+            .context("failed to checksum file")?;
+        f.crc32 = Some(r.finish());
+        opts.hashed(file_length);
+        return Ok((f, pieces_layer));
+    }
+
+    if opts.single_threaded && !opts.normalizes_newlines(path) {
+        let piece_bytes = opts.piece_length.bytes();
+        return checksum::checksum_file_sequential(
+            opts.piece_length,
+            file_length,
+            &r,
+            |piece, done| {
+                opts.hashed(cmp::min(piece_bytes, file_length - piece * piece_bytes));
+                if let Some(tx) = &opts.piece_events {
+                    let _ = tx.try_send(PieceEvent { piece, done });
+                }
+            },
+        )
+        .context("failed to checksum file");
+    }
+
+    // Normalizing changes the length so the file must be streamed.
+    if opts.normalizes_newlines(path) {
+        let r = CrlfToLf::new(Cursor::new(r));
+        return checksum::torrent2::checksum_file(opts.piece_length, r)
+            .inspect(|_| opts.hashed(file_length))
+            .context("failed to checksum file");
+    }
+
+    // Only the default and single threaded paths report progress a piece at a
+    // time.
+    match (&opts.adaptive, opts.max_inflight_pieces) {
+        (Some(a), _) => checksum::checksum_file_adaptive(opts.piece_length, file_length, &r, a)
+            .inspect(|_| opts.hashed(file_length)),
+        (None, Some(n)) => checksum::checksum_file_limited(opts.piece_length, file_length, &r, n)
+            .inspect(|_| opts.hashed(file_length)),
+        (None, None) if opts.pieces_root_only => {
+            checksum::checksum_file_root(opts.piece_length, file_length, &r)
+                .map(|f| (f, Vec::new()))
+                .inspect(|_| opts.hashed(file_length))
+        }
+        (None, None) if opts.progress.is_none() && opts.piece_events.is_none() => {
+            checksum::checksum_file_multithreaded(opts.piece_length, file_length, &r)
+        }
+        (None, None) => {
+            let piece_bytes = opts.piece_length.bytes();
+            checksum::checksum_file_progress(opts.piece_length, file_length, &r, |piece, done| {
+                opts.hashed(cmp::min(piece_bytes, file_length - piece * piece_bytes));
+                if let Some(tx) = &opts.piece_events {
+                    let _ = tx.try_send(PieceEvent { piece, done });
+                }
+            })
+        }
+    }
+    .context("failed to checksum file")
+}
+
+// Hashes every file of torrent again, ignoring any cache, and returns the
+// paths whose pieces root differs from the torrent. files are the listed
+// files, relative to dir, the torrent was built from. single_file is the same
+// as for build_torrent.
+pub fn self_check(
+    cli: &Cli,
+    opts: &HashOptions,
+    dir: &Path,
+    files: &[(String, u64)],
+    torrent: &Torrent,
+    single_file: bool,
+) -> Result<Vec<String>> {
+    let name = single_file.then_some(torrent.info.name.as_str());
+    let roots: HashMap<String, sha256::Digest> = torrent
+        .files()
+        .into_iter()
+        .map(|(path, f)| (path, f.pieces_root))
+        .collect();
+    let mut mismatched = files
+        .par_iter()
+        .filter_map(|(path, l)| {
+            // Files skipped while building, such as vanished ones, are not in
+            // the torrent.
+            let root = roots.get(&entry_path(cli, name, path))?;
+            match hash_file(dir, opts, path, *l) {
+                Ok((again, _)) if again.pieces_root == *root => None,
+                Ok(_) => Some(Ok(path.clone())),
+                Err(e) => Some(Err(e.context(format!("failed to hash `{}`", path)))),
+            }
+        })
+        .collect::<Result<Vec<_>>>()?;
+    mismatched.sort();
+    Ok(mismatched)
+}
+
+// Same as hash_file but reuses the hashes from prev, where the file is listed
+// at tree_path, if the file is unchanged. The returned bool is true if the
+// file had to be hashed.
+fn hash_file_cached(
+    root: &Path,
+    opts: &HashOptions,
+    path: &str,
+    tree_path: &str,
+    file_length: u64,
+    mtime_ns: Option<u64>,
+    prev: Option<&manifest::Index>,
+) -> Result<(metainfo::File, Vec<sha256::Digest>, bool)> {
+    let cached = match prev {
+        Some(m) => m.cached(tree_path, file_length, mtime_ns)?,
+        None => None,
+    };
+
+    match cached {
+        Some((f, pieces_layer)) => Ok((f, pieces_layer, false)),
+        None => {
+            let (f, pieces_layer) = hash_file(root, opts, path, file_length)?;
+            Ok((f, pieces_layer, true))
+        }
+    }
+}
+
+// Returns the modification time of the file in nanoseconds since the unix
+// epoch, or None if it is not available.
+fn file_mtime(path: &Path) -> Option<u64> {
+    let d = fs::metadata(path)
+        .ok()?
+        .modified()
+        .ok()?
+        .duration_since(UNIX_EPOCH)
+        .ok()?;
+    d.as_nanos().try_into().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::build::build_torrent;
+    use crate::hash_bar;
+    use crate::test_util::get_file_list;
+    use bendy::encoding::ToBencode;
+    use clap::Parser;
+
+    #[test]
+    fn incremental() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        fs::write(root.join("a"), vec![1u8; 40 << 10]).unwrap();
+        fs::write(root.join("b"), vec![2u8; 40 << 10]).unwrap();
+
+        let piece_length = PieceLength { layers: 0 };
+        let opts = HashOptions::new(piece_length);
+        let build = |prev: Option<&manifest::Index>| {
+            let mut t = Torrent::new("".to_string(), "".to_string(), piece_length);
+            let mut mtimes = HashMap::new();
+            let mut hashed = Vec::new();
+            for (file, l) in get_file_list(root).unwrap() {
+                let mtime = file_mtime(&root.join(&file));
+                let (f, pieces_layer, was_hashed) =
+                    hash_file_cached(root, &opts, &file, &file, l, mtime, prev).unwrap();
+                if was_hashed {
+                    hashed.push(file.clone());
+                }
+                assert!(t.add_file(&file, f, pieces_layer));
+                mtimes.insert(file, mtime.unwrap());
+            }
+            let m = Manifest::new(&t, &mtimes);
+            (t, m, hashed)
+        };
+
+        let (_, m, hashed) = build(None);
+        assert_eq!(hashed.len(), 2);
+
+        fs::write(root.join("b"), vec![3u8; 50 << 10]).unwrap();
+        let (t, _, hashed) = build(Some(&m.index()));
+        assert_eq!(hashed, vec!["b"]);
+
+        // the result is the same as a build from scratch
+        let (fresh, _, _) = build(None);
+        assert_eq!(t.to_bencode().unwrap(), fresh.to_bencode().unwrap());
+    }
+
+    #[test]
+    fn resume_from_checkpoint() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("root");
+        fs::create_dir(&root).unwrap();
+        fs::write(root.join("a"), vec![1u8; 40 << 10]).unwrap();
+        fs::write(root.join("b"), vec![2u8; 40 << 10]).unwrap();
+        let checkpoint = dir.path().join("checkpoint.json");
+
+        let opts = HashOptions::new(PieceLength { layers: 0 });
+        let build = |args: &[&str]| {
+            let mut argv = vec!["mktorrent-rs", "--announce=", "--piece-length=14"];
+            argv.extend_from_slice(args);
+            argv.push("root");
+            let cli = Cli::parse_from(argv);
+            build_torrent(
+                &cli,
+                &opts,
+                "root".to_owned(),
+                &root,
+                get_file_list(&root).unwrap(),
+                false,
+            )
+            .map(|(t, mtimes, _)| (t, mtimes))
+        };
+
+        let (t, mtimes) = build(&[]).unwrap();
+        Manifest::new(&t, &mtimes).write(&checkpoint).unwrap();
+
+        fs::write(root.join("c"), vec![3u8; 50 << 10]).unwrap();
+        fs::remove_file(root.join("b")).unwrap();
+
+        let resume = format!("--resume-from-checkpoint={}", checkpoint.to_str().unwrap());
+        let err = build(&[&resume]).unwrap_err().to_string();
+        assert!(err.starts_with("1 files added and 1 removed"), "{}", err);
+
+        // the result is the same as a build from scratch
+        let (t, _) = build(&[&resume, "--resume-allow-changes"]).unwrap();
+        let (fresh, _) = build(&[]).unwrap();
+        assert_eq!(t.to_bencode().unwrap(), fresh.to_bencode().unwrap());
+        let paths: Vec<_> = t.files().into_iter().map(|(p, _)| p).collect();
+        assert_eq!(paths.len(), 2);
+        assert!(!paths.contains(&"b".to_owned()));
+    }
+
+    // Creates n small files of different sizes under dir.
+    fn small_files(dir: &Path, n: usize) {
+        for i in 0..n {
+            let sub = dir.join(format!("{}", i % 10));
+            fs::create_dir_all(&sub).unwrap();
+            fs::write(sub.join(format!("{}", i)), vec![i as u8; i % 300 + 1]).unwrap();
+        }
+    }
+
+    #[test]
+    fn parallel_files() {
+        let dir = tempfile::tempdir().unwrap();
+        small_files(dir.path(), 500);
+        fs::write(dir.path().join("big"), vec![9u8; 100 << 10]).unwrap();
+
+        let cli = Cli::parse_from(["mktorrent-rs", "--announce=", "--piece-length=14", "root"]);
+        let mut opts = HashOptions::new(PieceLength { layers: 0 });
+        let files = get_file_list(dir.path()).unwrap();
+
+        let (parallel, _, _) = hash_files(&cli, &opts, dir.path(), files.clone(), None).unwrap();
+        opts.parallel_files = false;
+        let (serial, _, _) = hash_files(&cli, &opts, dir.path(), files, None).unwrap();
+        assert_eq!(parallel.len(), 501);
+        assert_eq!(parallel, serial);
+
+        assert!(
+            hash_options(&cli, opts.piece_length)
+                .unwrap()
+                .parallel_files
+        );
+        let cli = Cli::parse_from([
+            "mktorrent-rs",
+            "--announce=",
+            "--piece-length=14",
+            "--no-parallel-files",
+            "root",
+        ]);
+        assert!(
+            !hash_options(&cli, opts.piece_length)
+                .unwrap()
+                .parallel_files
+        );
+    }
+
+    #[test]
+    fn largest_first() {
+        let dir = tempfile::tempdir().unwrap();
+        small_files(dir.path(), 50);
+        fs::write(dir.path().join("big"), vec![9u8; 100 << 10]).unwrap();
+
+        let cli = Cli::parse_from(["mktorrent-rs", "--announce=", "--piece-length=14", "root"]);
+        let mut opts = HashOptions::new(PieceLength { layers: 0 });
+        let files = get_file_list(dir.path()).unwrap();
+
+        let (sorted, _, _) = hash_files(&cli, &opts, dir.path(), files.clone(), None).unwrap();
+        opts.largest_first = false;
+        let (unsorted, _, _) = hash_files(&cli, &opts, dir.path(), files, None).unwrap();
+        assert_eq!(sorted, unsorted);
+
+        let files = vec![
+            ("a".to_owned(), 1),
+            ("b".to_owned(), 3),
+            ("c".to_owned(), 2),
+            ("d".to_owned(), 3),
+        ];
+        let order = |largest_first| -> Vec<usize> {
+            schedule(files.clone(), largest_first)
+                .into_iter()
+                .map(|(i, _)| i)
+                .collect()
+        };
+        assert_eq!(order(false), vec![0, 1, 2, 3]);
+        assert_eq!(order(true), vec![1, 3, 2, 0]);
+
+        // Simulates two workers that each take the next file in the schedule
+        // when idle, with a file taking its length to hash, and returns when
+        // the last finishes.
+        let makespan = |largest_first| {
+            let files = [1, 1, 1, 1, 1, 1, 6].map(|l| ("f".to_owned(), l));
+            let mut busy = [0; 2];
+            for (_, (_, l)) in schedule(files.to_vec(), largest_first) {
+                *busy.iter_mut().min().unwrap() += l;
+            }
+            busy.into_iter().max().unwrap()
+        };
+        assert_eq!(makespan(false), 9);
+        assert_eq!(makespan(true), 6);
+    }
+
+    #[test]
+    fn mtime_clamp() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("new"), "new").unwrap();
+        fs::write(dir.path().join("old"), "old").unwrap();
+        let old = UNIX_EPOCH + Duration::from_secs(500);
+        fs::File::options()
+            .write(true)
+            .open(dir.path().join("old"))
+            .unwrap()
+            .set_modified(old)
+            .unwrap();
+
+        let cli = Cli::parse_from([
+            "mktorrent-rs",
+            "--announce=",
+            "--piece-length=14",
+            "--manifest=m.json",
+            "--mtime-clamp=1000",
+            "root",
+        ]);
+        let (_, mtimes, _) = hash_files(
+            &cli,
+            &HashOptions::new(PieceLength { layers: 0 }),
+            dir.path(),
+            get_file_list(dir.path()).unwrap(),
+            None,
+        )
+        .unwrap();
+
+        assert_eq!(mtimes["new"], 1000 * 1_000_000_000);
+        assert_eq!(mtimes["old"], 500 * 1_000_000_000);
+    }
+
+    #[test]
+    fn always_emit_layer() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("a"), "single piece").unwrap();
+
+        let piece_length = PieceLength { layers: 2 };
+        let (f, pieces_layer) =
+            hash_file(dir.path(), &HashOptions::new(piece_length), "a", 12).unwrap();
+        assert!(pieces_layer.is_empty());
+
+        let mut t = Torrent::new("".to_string(), "".to_string(), piece_length);
+        assert!(t.add_file("a", f, pieces_layer));
+        assert!(t.piece_layers.is_empty());
+
+        let mut t = Torrent::new("".to_string(), "".to_string(), piece_length);
+        let pieces_layer = vec![checksum::single_piece_layer(piece_length, &f)];
+        assert!(t.add_file("a", f, pieces_layer.clone()));
+        assert_eq!(t.piece_layers.get(&f.pieces_root), Some(&pieces_layer));
+    }
+
+    #[test]
+    fn normalize_newlines() {
+        let dir = tempfile::tempdir().unwrap();
+        let crlf = "line\r\n".repeat(10 << 10);
+        let lf = "line\n".repeat(10 << 10);
+        fs::write(dir.path().join("crlf.txt"), &crlf).unwrap();
+        fs::write(dir.path().join("lf.txt"), &lf).unwrap();
+        fs::write(dir.path().join("crlf.bin"), &crlf).unwrap();
+
+        let mut opts = HashOptions::new(PieceLength { layers: 0 });
+        opts.normalize_newlines = vec!["TXT".to_owned()];
+
+        let expected = hash_file(dir.path(), &opts, "lf.txt", lf.len() as u64).unwrap();
+        let normalized = hash_file(dir.path(), &opts, "crlf.txt", crlf.len() as u64).unwrap();
+        assert_eq!(normalized, expected);
+        assert_eq!(normalized.0.length, lf.len() as u64);
+
+        // other extensions are hashed as-is
+        let raw = hash_file(dir.path(), &opts, "crlf.bin", crlf.len() as u64).unwrap();
+        assert_eq!(raw.0.length, crlf.len() as u64);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn direct_io() {
+        let dir = tempfile::tempdir().unwrap();
+        let data: Vec<u8> = (0..(100 << 10) + 123u32).map(|i| (i % 251) as u8).collect();
+        fs::write(dir.path().join("a"), &data).unwrap();
+
+        let mut opts = HashOptions::new(PieceLength { layers: 0 });
+        let expected = hash_file(dir.path(), &opts, "a", data.len() as u64).unwrap();
+        opts.direct_io = true;
+        let direct = hash_file(dir.path(), &opts, "a", data.len() as u64).unwrap();
+        assert_eq!(direct, expected);
+    }
+
+    #[test]
+    fn single_threaded() {
+        let dir = tempfile::tempdir().unwrap();
+        let data: Vec<u8> = (0..(200 << 10) + 7u32).map(|i| (i % 251) as u8).collect();
+        fs::write(dir.path().join("a"), &data).unwrap();
+
+        let mut opts = HashOptions::new(PieceLength { layers: 0 });
+        let multi = hash_file(dir.path(), &opts, "a", data.len() as u64).unwrap();
+        opts.single_threaded = true;
+        let single = hash_file(dir.path(), &opts, "a", data.len() as u64).unwrap();
+        assert_eq!(single.1.len(), 13);
+        assert_eq!(single, multi);
+    }
+
+    #[test]
+    fn max_buffer_memory_threads() {
+        let piece_length = PieceLength { layers: 0 };
+        assert_eq!(memory_limited_threads(8, 1 << 30, piece_length), 8);
+        assert_eq!(memory_limited_threads(8, 48 << 10, piece_length), 3);
+        assert_eq!(memory_limited_threads(8, 1, piece_length), 1);
+        // buffers are capped at 1MiB for large pieces
+        assert_eq!(
+            memory_limited_threads(8, 2 << 20, PieceLength { layers: 10 }),
+            2
+        );
+
+        let dir = tempfile::tempdir().unwrap();
+        let data: Vec<u8> = (0..200 << 10).map(|i| (i % 251) as u8).collect();
+        fs::write(dir.path().join("a"), &data).unwrap();
+        let opts = HashOptions::new(piece_length);
+        let hash = |threads| {
+            rayon::ThreadPoolBuilder::new()
+                .num_threads(threads)
+                .build()
+                .unwrap()
+                .install(|| hash_file(dir.path(), &opts, "a", data.len() as u64).unwrap())
+        };
+        assert_eq!(
+            hash(memory_limited_threads(8, 16 << 10, piece_length)),
+            hash(8)
+        );
+    }
+
+    #[test]
+    fn pieces_root_only() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("a"), vec![1u8; 40 << 10]).unwrap();
+        fs::write(dir.path().join("b"), vec![2u8; 10 << 10]).unwrap();
+
+        let parse = |extra: &[&str]| {
+            let mut argv = vec![
+                "mktorrent-rs",
+                "--announce=",
+                "--piece-length=14",
+                "--check-only",
+            ];
+            argv.extend(extra);
+            argv.push(dir.path().to_str().unwrap());
+            Cli::try_parse_from(argv)
+        };
+        let build = |cli: &Cli| {
+            let opts = hash_options(cli, PieceLength { layers: 0 }).unwrap();
+            let files = get_file_list(dir.path()).unwrap();
+            build_torrent(cli, &opts, "root".to_owned(), dir.path(), files, false)
+                .unwrap()
+                .0
+        };
+
+        let full = build(&parse(&[]).unwrap());
+        let root_only = build(&parse(&["--pieces-root-only"]).unwrap());
+        assert_eq!(full.piece_layers.len(), 1);
+        assert!(root_only.piece_layers.is_empty());
+        assert_eq!(
+            root_only.info.infohash().unwrap(),
+            full.info.infohash().unwrap()
+        );
+
+        assert!(Cli::try_parse_from(["mktorrent-rs", "--pieces-root-only", "root"]).is_err());
+        assert!(parse(&["--pieces-root-only", "--single-threaded"]).is_err());
+    }
+
+    // Fails every read as if the file had been removed.
+    struct Vanished;
+
+    impl ReadAt for Vanished {
+        fn read_at(&self, _pos: u64, _buf: &mut [u8]) -> io::Result<usize> {
+            Err(io::Error::new(io::ErrorKind::NotFound, "gone"))
+        }
+    }
+
+    #[test]
+    fn verify_after_build() {
+        let dir = tempfile::tempdir().unwrap();
+        small_files(dir.path(), 20);
+        fs::write(dir.path().join("big"), vec![7u8; 100 << 10]).unwrap();
+
+        let cli = Cli::parse_from([
+            "mktorrent-rs",
+            "--announce=",
+            "--piece-length=14",
+            "--verify-after-build",
+            "root",
+        ]);
+        let opts = HashOptions::new(PieceLength { layers: 0 });
+        let files = get_file_list(dir.path()).unwrap();
+        let (t, _, _) = build_torrent(
+            &cli,
+            &opts,
+            "x".to_owned(),
+            dir.path(),
+            files.clone(),
+            false,
+        )
+        .unwrap();
+        assert!(self_check(&cli, &opts, dir.path(), &files, &t, false)
+            .unwrap()
+            .is_empty());
+
+        // content that changes after the build is reported
+        fs::write(dir.path().join("big"), vec![8u8; 100 << 10]).unwrap();
+        assert_eq!(
+            self_check(&cli, &opts, dir.path(), &files, &t, false).unwrap(),
+            vec!["big"]
+        );
+    }
+
+    #[test]
+    fn store_crc32() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("a"), "123456789").unwrap();
+        let mut opts = HashOptions::new(PieceLength { layers: 0 });
+        let (plain, _) = hash_file(dir.path(), &opts, "a", 9).unwrap();
+        assert_eq!(plain.crc32, None);
+
+        opts.store_crc32 = true;
+        let (f, _) = hash_file(dir.path(), &opts, "a", 9).unwrap();
+        assert_eq!(f.crc32, Some(0xcbf43926));
+        assert_eq!(f.pieces_root, plain.pieces_root);
+
+        // the same whether pieces are hashed in parallel or streamed
+        let data: Vec<u8> = (0..100 << 10).map(|i| (i % 251) as u8).collect();
+        fs::write(dir.path().join("b"), &data).unwrap();
+        let (parallel, _) = hash_file(dir.path(), &opts, "b", data.len() as u64).unwrap();
+        opts.single_threaded = true;
+        let (streamed, _) = hash_file(dir.path(), &opts, "b", data.len() as u64).unwrap();
+        assert_eq!(parallel, streamed);
+        assert_eq!(parallel.crc32, Some(crc32fast::hash(&data)));
+    }
+
+    #[test]
+    fn assume_immutable() {
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use std::time::SystemTime;
+
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("a"), vec![1u8; 4 << 20]).unwrap();
+        let files = get_file_list(dir.path()).unwrap();
+        let opts = HashOptions::new(PieceLength { layers: 0 });
+        let parse = |args: &[&str]| {
+            let mut argv = vec!["mktorrent-rs", "--announce=", "--piece-length=14"];
+            argv.extend_from_slice(args);
+            argv.push("root");
+            Cli::parse_from(argv)
+        };
+        let (expected, _, _) =
+            hash_files(&parse(&[]), &opts, dir.path(), files.clone(), None).unwrap();
+
+        // touch the file for the whole hash; only the mtime changes
+        let done = AtomicBool::new(false);
+        let (entries, _, warnings) = std::thread::scope(|s| {
+            s.spawn(|| {
+                let f = fs::File::options()
+                    .write(true)
+                    .open(dir.path().join("a"))
+                    .unwrap();
+                let mut t = SystemTime::now();
+                while !done.load(Ordering::Relaxed) {
+                    t += Duration::from_secs(1);
+                    f.set_modified(t).unwrap();
+                }
+            });
+            let r = hash_files(
+                &parse(&["--assume-immutable"]),
+                &opts,
+                dir.path(),
+                files,
+                None,
+            );
+            done.store(true, Ordering::Relaxed);
+            r.unwrap()
+        });
+        assert!(warnings.is_empty(), "{:?}", warnings);
+        assert_eq!(entries, expected);
+    }
+
+    #[test]
+    fn vanished_files() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("a"), "a").unwrap();
+        let opts = HashOptions::new(PieceLength { layers: 0 });
+
+        let err = hash_read_at(&Vanished, &opts, "a", 100 << 10).unwrap_err();
+        assert!(is_vanished(&err, &dir.path().join("a")));
+        let other = Error::new(io::Error::new(io::ErrorKind::PermissionDenied, "denied"));
+        assert!(!is_vanished(&other, &dir.path().join("a")));
+        // only a missing file counts, not one that cannot be looked up
+        assert!(is_vanished(&other, &dir.path().join("b")));
+        assert!(!is_vanished(&other, &dir.path().join("a/b")));
+
+        let files = vec![("a".to_owned(), 1), ("gone".to_owned(), 1)];
+        let parse = |args: &[&str]| {
+            let mut argv = vec!["mktorrent-rs", "--announce=", "--piece-length=14"];
+            argv.extend_from_slice(args);
+            argv.push("root");
+            Cli::parse_from(argv)
+        };
+
+        let err = hash_files(&parse(&[]), &opts, dir.path(), files.clone(), None).unwrap_err();
+        assert_eq!(err.to_string(), "file disappeared during processing: gone");
+
+        let (entries, _, warnings) =
+            hash_files(&parse(&["--skip-errors"]), &opts, dir.path(), files, None).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].path, "a");
+        assert_eq!(
+            warnings,
+            vec![BuildWarning::VanishedFile("gone".to_owned())]
+        );
+    }
+
+    #[test]
+    fn hash_progress() {
+        let dir = tempfile::tempdir().unwrap();
+        let lengths = [0, 5, 16 << 10, (100 << 10) + 3];
+        for (i, l) in lengths.iter().enumerate() {
+            fs::write(dir.path().join(i.to_string()), vec![1u8; *l]).unwrap();
+        }
+        let total: u64 = lengths.iter().map(|&l| l as u64).sum();
+
+        for args in [
+            &[][..],
+            &["--single-threaded"],
+            &["--max-inflight-pieces=2"],
+        ] {
+            let mut argv = vec!["mktorrent-rs", "--announce=", "--piece-length=14"];
+            argv.extend_from_slice(args);
+            argv.push("root");
+            let cli = Cli::parse_from(argv);
+
+            let mut opts = HashOptions::new(PieceLength { layers: 0 });
+            opts.single_threaded = cli.single_threaded;
+            opts.max_inflight_pieces = cli.max_inflight_pieces.map(|n| n.get());
+            let bar = ProgressBar::hidden();
+            bar.set_length(total);
+            opts.progress = Some(bar.clone());
+
+            build_torrent(
+                &cli,
+                &opts,
+                "root".to_owned(),
+                dir.path(),
+                get_file_list(dir.path()).unwrap(),
+                false,
+            )
+            .unwrap();
+            assert_eq!(bar.position(), total, "{:?}", args);
+        }
+
+        // --no-progress always hides the bar
+        assert!(hash_bar(true, None, total).is_hidden());
+    }
+
+    #[test]
+    fn progress_pipe_multiple_files() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("a"), vec![1u8; (80 << 10) + 3]).unwrap();
+        fs::write(dir.path().join("b"), vec![1u8; 48 << 10]).unwrap();
+
+        let cli = Cli::parse_from([
+            "mktorrent-rs",
+            "--announce=",
+            "--piece-length=14",
+            "--progress-pipe=progress",
+            "root",
+        ]);
+        let mut opts = hash_options(&cli, PieceLength { layers: 0 }).unwrap();
+        assert!(!opts.parallel_files);
+        let (tx, rx) = mpsc::sync_channel(64);
+        opts.piece_events = Some(tx);
+
+        let files = get_file_list(dir.path()).unwrap();
+        build_torrent(
+            &cli,
+            &opts,
+            "root".to_owned(),
+            dir.path(),
+            files.clone(),
+            false,
+        )
+        .unwrap();
+        drop(opts);
+        let mut events: Vec<_> = rx.into_iter().collect();
+
+        // every event of a file comes before those of the next
+        for (_, length) in files {
+            let n = length.div_ceil(16 << 10) as usize;
+            let rest = events.split_off(n);
+            let mut pieces: Vec<_> = events.iter().map(|e| e.piece).collect();
+            pieces.sort_unstable();
+            assert_eq!(pieces, (0..n as u64).collect::<Vec<_>>());
+            assert_eq!(events.iter().map(|e| e.done).max(), Some(length));
+            events = rest;
+        }
+        assert!(events.is_empty());
+    }
+
+    #[test]
+    fn estimate() {
+        let throughput = (100 << 20) as f64;
+        let d = estimate_time(10 << 30, throughput);
+        assert_eq!(d.as_secs(), 102);
+        assert_eq!(
+            format_estimate(d, throughput),
+            "estimated ~2m at ~100 MiB/s"
+        );
+        assert_eq!(
+            format_estimate(estimate_time(1 << 30, throughput), throughput),
+            "estimated ~10s at ~100 MiB/s"
+        );
+        assert_eq!(
+            format_estimate(estimate_time(3 << 40, 1.5 * throughput), 1.5 * throughput),
+            "estimated ~350m at ~150 MiB/s"
+        );
+
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("a"), vec![1u8; 1 << 20]).unwrap();
+        fs::write(dir.path().join("empty"), "").unwrap();
+        let opts = HashOptions::new(PieceLength { layers: 0 });
+        let files = get_file_list(dir.path()).unwrap();
+        for v1 in [false, true] {
+            assert!(calibrate(&opts, v1, dir.path(), &files).unwrap().unwrap() > 0.0);
+        }
+        let empty = [("empty".to_owned(), 0)];
+        assert_eq!(calibrate(&opts, false, dir.path(), &empty).unwrap(), None);
+
+        let show = |args: &[&str], total, terminal| {
+            let mut argv = vec!["mktorrent-rs", "--announce=", "--piece-length=14"];
+            argv.extend_from_slice(args);
+            argv.push("root");
+            show_estimate(&Cli::parse_from(argv), total, terminal)
+        };
+        let large = ESTIMATE_SAMPLE_BYTES + 1;
+        assert!(show(&[], large, true));
+        assert!(!show(&[], large, false));
+        assert!(!show(&[], ESTIMATE_SAMPLE_BYTES, true));
+        assert!(!show(&["--no-estimate"], large, true));
+        assert!(!show(&["--no-progress"], large, true));
+        assert!(!show(&["--json-summary", "-o", "out"], large, true));
+        assert!(show(&["--estimate-time"], 1, false));
+    }
+}
@@ -0,0 +1,4 @@
+// Hashing and metainfo encoding, shared by the mktorrent-rs binary and the
+// benchmarks.
+pub mod checksum;
+pub mod metainfo;
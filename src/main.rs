@@ -1,132 +1,2144 @@
-mod checksum;
-mod metainfo;
+mod base32;
+mod build;
+mod compare;
+#[cfg(target_os = "linux")]
+mod direct;
+mod files;
+mod hash;
+mod manifest;
+mod newlines;
+mod output;
+mod pipe;
+mod source;
+mod summary;
+#[cfg(test)]
+mod test_util;
 
+use std::env::{self, VarError};
 use std::fs;
-use std::io::{self, Write};
+use std::io::{self, BufRead, IsTerminal, Write};
+use std::iter;
+use std::mem;
+use std::num::NonZeroUsize;
 use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use anyhow::{Context, Error, Result};
+use bendy::decoding::FromBencode;
 use bendy::encoding::ToBencode;
-use clap::Parser;
-use metainfo::{PieceLength, Torrent, MAX_FILE_PATH_DEPTH};
-use positioned_io::RandomAccessFile;
-use walkdir::WalkDir;
+use build::{
+    auto_piece_length, build_from_manifest, build_from_zip, build_per_subdir, build_torrent,
+    build_torrent_v1, build_torrents, check_multiple_piece_lengths_options, check_v1_options,
+    read_precomputed, report_warnings, with_piece_length, write_build, zero_pieces, BuildWarning,
+    CREATED_BY,
+};
+use chrono::{DateTime, NaiveDate, NaiveDateTime, NaiveTime};
+use clap::{Parser, Subcommand};
+use compare::compare;
+use files::{select_files, write_file_list_json};
+use hash::{
+    calibrate, estimate_time, format_estimate, hash_file, hash_options, self_check, show_estimate,
+    HashOptions,
+};
+use indicatif::{HumanBytes, HumanDuration, ProgressBar, ProgressStyle};
+use metainfo::raw::{normalize_tiers, normalize_url, RawTorrent};
+use metainfo::{PieceLength, Torrent};
+use mktorrent_rs::{checksum, metainfo};
+use output::{
+    check_outputs, emit_torrent, output_path, print_infohash, print_infohash_v1, unique_path,
+    write_output,
+};
+use pipe::ProgressPipe;
 
 #[derive(Parser)]
 #[clap(name = "mktorrent-rs")]
 #[clap(author = "Stephen Weinberg <stephenmweinberg@gmail.com>")]
 #[clap(version = "0.1-SNAPSHOT")]
 #[clap(about = "Create torrent v2 files", long_about = None)]
+#[clap(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Cli {
+    #[clap(subcommand)]
+    command: Option<Command>,
+
+    // root is required unless a subcommand is used. main checks there is
+    // a tracker or a DHT node.
+    /// The announce url. May be given more than once, in which case the urls
+    /// form the first tier of the announce-list in the order given. May be
+    /// left out for a trackerless torrent when --node is given.
+    #[clap(long, value_name = "URL")]
+    announce: Vec<String>,
+
+    /// Add a comma separated list of announce urls as a further tier of the
+    /// announce-list. May be given more than once.
+    #[clap(long, value_name = "URL,...", value_parser = parse_tier)]
+    announce_tier: Vec<Vec<String>>,
+
+    /// Mark the torrent private so clients only get peers from its trackers.
+    /// This changes the infohash.
+    #[clap(long)]
+    private: bool,
+
+    /// Store STRING under `source` in the info dictionary. Private trackers
+    /// use it to give the same content a different infohash on each tracker.
+    #[clap(long, value_name = "STRING")]
+    source: Option<String>,
+
+    /// Add a comment to the torrent.
+    #[clap(long)]
+    comment: Option<String>,
+
+    /// The program named in the torrent's `created by` field. An empty
+    /// value leaves the field out.
+    #[clap(long, value_name = "NAME", default_value = CREATED_BY)]
+    created_by: String,
+
+    /// Set the torrent's creation date to the unix time SECONDS instead of
+    /// the current time.
+    #[clap(long, value_name = "SECONDS")]
+    creation_date: Option<i64>,
+
+    /// Leave out the creation date so the same content always produces the
+    /// same torrent file.
+    #[clap(long, conflicts_with = "creation_date")]
+    no_date: bool,
+
+    /// Add a BEP 17 HTTP seed URL under the top level `httpseeds` key. May be
+    /// given more than once.
+    #[clap(long, value_name = "URL", value_parser = parse_http_url)]
+    http_seed: Vec<String>,
+
+    /// Add a BEP 19 web seed URL under the top level `url-list` key. May be
+    /// given more than once.
+    #[clap(long, value_name = "URL", value_parser = parse_web_seed_url)]
+    web_seed: Vec<String>,
+
+    /// Add a BEP 5 DHT bootstrap node under the top level `nodes` key. IPv6
+    /// addresses go in brackets, as in [::1]:6881. May be given more than
+    /// once.
+    #[clap(long, value_name = "HOST:PORT", value_parser = parse_node)]
+    node: Vec<(String, u16)>,
+
+    /// Store the announce url in canonical form: lowercase scheme and host,
+    /// no default port and a normalized path.
+    #[clap(long)]
+    normalize_announce: bool,
+
+    /// The exponent of the piece_length, between 14 and 40, or the piece
+    /// length in bytes with a binary unit, such as 256KiB, 1M or 4MiB. Defaults
+    /// to the smallest exponent between 15 and 24 that splits the content
+    /// into at most 2000 pieces, or to MKTORRENT_PIECE_LENGTH if it is set.
+    ///
+    /// May be given more than once to write a torrent for each piece length
+    /// while reading the content once. Each is written to --output with the
+    /// exponent added before the extension, e.g. name.18.torrent. This keeps
+    /// a 32 byte hash of every 16KiB block of the file being hashed, 1/512 of
+    /// its size, in memory, so it cannot be used with --max-buffer-memory.
+    #[clap(
+        long,
+        value_name = "EXPONENT",
+        value_parser = parse_piece_length,
+    )]
+    piece_length: Vec<u8>,
+
+    /// The torrent's display name. Defaults to the root's file name.
+    ///
+    /// For a directory root, this is the folder clients save the files into.
+    /// Paths in the file tree are relative to the root, so the root's own
+    /// name is not recorded anywhere else in the torrent.
     #[clap(long)]
-    announce: String,
+    name: Option<String>,
+
+    /// Read the torrent's display name from the first line of FILE.
+    #[clap(long, value_name = "FILE", conflicts_with = "name")]
+    name_from: Option<PathBuf>,
+
+    /// Read the files to include from FILE, one path per line relative to
+    /// the root, instead of walking the root directory.
+    #[clap(long, value_name = "FILE")]
+    files_from: Option<PathBuf>,
+
+    /// Exclude files matching the gitignore style patterns in FILE. Patterns
+    /// are relative to the root.
+    #[clap(long, value_name = "FILE")]
+    gitignore: Option<PathBuf>,
+
+    /// Exclude files matching the patterns in the root's .gitignore, if any.
+    #[clap(long)]
+    respect_gitignore: bool,
+
+    /// Only include files with these comma separated extensions, compared
+    /// without case. Other files are left out of the torrent.
+    #[clap(long, value_name = "EXTS", value_delimiter = ',')]
+    include_ext: Vec<String>,
+
+    /// Leave out files with these comma separated extensions, compared
+    /// without case. Applied after --include-ext.
+    #[clap(long, value_name = "EXTS", value_delimiter = ',')]
+    exclude_ext: Vec<String>,
+
+    /// Only include files modified after TIME, given as seconds since the
+    /// unix epoch or an ISO 8601 date or time. Times without a zone are UTC.
+    #[clap(long, value_name = "TIME", value_parser = parse_time)]
+    newer_than: Option<SystemTime>,
+
+    /// Only include files modified before TIME. Accepts the same formats as
+    /// --newer-than.
+    #[clap(long, value_name = "TIME", value_parser = parse_time)]
+    older_than: Option<SystemTime>,
 
-    /// The exponent of the piece_length. Must be between 14 and 40.
-    #[clap(long, value_name = "EXPONENT")]
-    piece_length: u8,
+    /// Build the torrent from precomputed hashes without reading any
+    /// content. Each line of FILE is a path, length and hex pieces root,
+    /// followed by the hex piece layer hashes for files longer than a piece,
+    /// separated by tabs. Requires --name and --piece-length.
+    #[clap(
+        long,
+        value_name = "FILE",
+        requires = "name",
+        conflicts_with_all = [
+            "root",
+            "files_from",
+            "per_subdir",
+            "incremental",
+            "resume_from_checkpoint",
+            "manifest",
+            "store_crc32",
+        ]
+    )]
+    from_manifest: Option<PathBuf>,
+
+    /// Build the torrent from the files in a zip archive instead of a root.
+    /// Paths are the entry names and directory entries are skipped. Each
+    /// entry is decompressed and hashed in turn. Requires --name.
+    #[clap(
+        long,
+        value_name = "FILE",
+        requires = "name",
+        conflicts_with_all = [
+            "root",
+            "from_manifest",
+            "files_from",
+            "per_subdir",
+            "incremental",
+            "resume_from_checkpoint",
+            "manifest",
+            "store_crc32",
+        ]
+    )]
+    from_zip: Option<PathBuf>,
+
+    /// Treat `\` in --files-from paths as a path separator.
+    #[clap(long)]
+    windows_paths: bool,
+
+    /// List files in --files-from order instead of sorted order.
+    ///
+    /// The v2 file tree is always encoded with sorted keys, so this does not
+    /// change a v2 infohash. It changes the order of the file list in
+    /// --meta-version=v1 torrents, and so their infohash, and the order files
+    /// are listed in by outputs such as --pieces-sidecar.
+    #[clap(long, requires = "files_from")]
+    preserve_order: bool,
+
+    /// Place each file in the torrent under a directory named by its
+    /// lowercased extension, as in mp3/track.mp3, or under other/ if it has
+    /// none. Files are still read from their paths on disk.
+    ///
+    /// This changes the layout clients reconstruct when downloading: the
+    /// original directories are dropped, so two files with the same name
+    /// and extension conflict.
+    #[clap(long, conflicts_with_all = ["from_manifest", "from_zip"])]
+    group_by_extension: bool,
+
+    /// Write the torrent to FILE instead of stdout. If FILE is a directory
+    /// the torrent is written inside it as <NAME>.torrent.
+    ///
+    /// May be given more than once to write the same torrent to several
+    /// places. Every file is written before any is renamed into place, and
+    /// if one fails the others are removed again.
+    #[clap(short, long, value_name = "FILE")]
+    output: Vec<PathBuf>,
+
+    /// Replace characters that are unsafe in file names, such as `:` and
+    /// control characters, with `_` when naming a torrent written to an
+    /// --output directory. The name inside the torrent is unchanged.
+    #[clap(
+        long,
+        value_name = "BOOL",
+        default_value_t = true,
+        action = clap::ArgAction::Set
+    )]
+    sanitize_output_name: bool,
+
+    /// If the output file exists, add " (2)", " (3)", etc. to its name until
+    /// it is unused instead of replacing it.
+    #[clap(long)]
+    output_unique: bool,
+
+    /// Replace the output file if it already exists, and write the torrent
+    /// to stdout even when stdout is a terminal.
+    #[clap(short, long, conflicts_with = "output_unique")]
+    force: bool,
+
+    /// Print a JSON summary of the built torrent to stdout instead of any
+    /// human readable output. Progress and other informational messages are
+    /// not printed to stderr, but warnings and errors still are. Requires
+    /// --output.
+    #[clap(
+        long,
+        requires = "output",
+        conflicts_with_all = ["print_content_fingerprint", "list_files_json", "estimate_time"]
+    )]
+    json_summary: bool,
+
+    /// Print the files that would be hashed, after every filter, to stdout
+    /// as a JSON array of objects with their path and size in path order,
+    /// then exit without hashing.
+    #[clap(
+        long,
+        conflicts_with_all = ["from_manifest", "from_zip", "per_subdir", "check_only"]
+    )]
+    list_files_json: bool,
+
+    /// Estimate how long hashing will take from a timed hash of the start of
+    /// the largest file, print it and exit without hashing. The estimate is
+    /// also printed before hashing large content when progress is shown.
+    #[clap(
+        long,
+        conflicts_with_all = ["from_manifest", "from_zip", "per_subdir", "list_files_json"]
+    )]
+    estimate_time: bool,
+
+    /// Do not print an estimate of the hashing time before hashing.
+    #[clap(long, conflicts_with = "estimate_time")]
+    no_estimate: bool,
+
+    /// Print the infohash to stdout, formatted by --infohash-encoding. With
+    /// base32 a v2 infohash is truncated to 20 bytes. Requires --output.
+    #[clap(long, requires = "output", conflicts_with = "json_summary")]
+    print_infohash: bool,
+
+    /// Build a v1 torrent, as --meta-version=v1 does, so --print-infohash
+    /// prints its 20 byte SHA-1 infohash.
+    ///
+    /// Hybrid torrents, which carry both a v1 and a v2 infohash, are not
+    /// supported, so there is no way to get a v1 infohash for a v2 torrent.
+    #[clap(long, requires = "print_infohash", conflicts_with = "meta_version")]
+    v1: bool,
+
+    /// How --print-infohash and --check-only print the infohash. hex prints
+    /// the whole infohash. base32 prints a v2 infohash truncated to its first
+    /// 20 bytes, the form BEP 52 uses where a 20 byte infohash is expected,
+    /// such as in magnet links read by v1 clients. A v1 infohash is already
+    /// 20 bytes.
+    #[clap(long, value_enum, value_name = "ENCODING", default_value = "hex")]
+    infohash_encoding: InfohashEncoding,
+
+    /// Hash the content and print the infohash without writing a torrent.
+    #[clap(
+        long,
+        conflicts_with_all = [
+            "output",
+            "manifest",
+            "delta_against",
+            "pieces_sidecar",
+            "json_summary",
+            "print_infohash",
+            "print_content_fingerprint",
+        ]
+    )]
+    check_only: bool,
+
+    /// With --check-only, only compute each file's pieces root rather than
+    /// its piece layer. Piece hashes are added to the merkle tree as they are
+    /// hashed instead of being kept, so memory use does not grow with the
+    /// size of a file. The infohash does not cover piece layers so it is
+    /// unchanged.
+    #[clap(
+        long,
+        requires = "check_only",
+        conflicts_with_all = [
+            "single_threaded",
+            "adaptive_threads",
+            "max_inflight_pieces",
+            "store_crc32",
+            "progress_pipe",
+        ]
+    )]
+    pieces_root_only: bool,
+
+    /// Print a fingerprint of the content to stdout. It is the infohash the
+    /// torrent would have with an empty name, so it matches other torrents
+    /// of the same files under any name. Requires --output.
+    #[clap(long, requires = "output")]
+    print_content_fingerprint: bool,
+
+    /// Print a content ID to stdout: the merkle root of every file's pieces
+    /// root in path order. It is not a BitTorrent infohash. Paths are not
+    /// hashed, so it only changes when the content does or files are renamed
+    /// into a different order. Requires --output.
+    #[clap(long, requires = "output", conflicts_with = "json_summary")]
+    content_id: bool,
+
+    /// Print statistics about the piece layers to stderr: how many there
+    /// are, how large they are when encoded and how many files share one.
+    #[clap(long)]
+    dump_piece_layers_stats: bool,
+
+    /// Print the shape of the file tree to stderr: the deepest path, the
+    /// number of directories and the largest and average number of entries
+    /// per directory.
+    #[clap(long)]
+    print_tree_stats: bool,
+
+    /// Warn about pieces that only contain zeros, which may mean the content
+    /// is sparse or was not fully written. Files with a single piece are not
+    /// checked.
+    #[clap(long)]
+    warn_zero_pieces: bool,
+
+    /// Warn about any .torrent files found in the content.
+    #[clap(long)]
+    warn_nested_torrents: bool,
+
+    /// Refuse to include setuid, setgid or world-writable files.
+    #[cfg(unix)]
+    #[clap(long)]
+    refuse_suspicious: bool,
+
+    /// Write a JSON manifest of every file's size, mtime and hashes to FILE.
+    #[clap(long, value_name = "FILE")]
+    manifest: Option<PathBuf>,
+
+    /// Record mtimes newer than EPOCH, in seconds since the unix epoch, as
+    /// EPOCH in the --manifest so it is reproducible as files are touched.
+    /// Files with clamped mtimes are always rehashed by --incremental.
+    #[clap(long, value_name = "EPOCH", requires = "manifest")]
+    mtime_clamp: Option<u64>,
+
+    /// Write every file's piece hashes to FILE as JSON. This can be large.
+    #[clap(long, value_name = "FILE", conflicts_with = "per_subdir")]
+    pieces_sidecar: Option<PathBuf>,
+
+    /// Reuse hashes from a previous --manifest for files whose size and mtime
+    /// are unchanged.
+    #[clap(long, value_name = "FILE")]
+    incremental: Option<PathBuf>,
+
+    /// Resume from a --manifest written by an earlier build of the same
+    /// files, reusing the hashes of files whose size and mtime are unchanged.
+    /// Fails if files were added or removed since.
+    #[clap(long, value_name = "FILE", conflicts_with = "incremental")]
+    resume_from_checkpoint: Option<PathBuf>,
+
+    /// Let --resume-from-checkpoint continue when files were added or
+    /// removed. Added files are hashed and removed files are left out.
+    #[clap(long, requires = "resume_from_checkpoint")]
+    resume_allow_changes: bool,
+
+    /// Compare the files against a previous --manifest and write the files
+    /// added, changed or removed since to --delta-output as JSON.
+    #[clap(long, value_name = "FILE", requires = "delta_output")]
+    delta_against: Option<PathBuf>,
+
+    /// Where to write the delta for --delta-against.
+    #[clap(long, value_name = "FILE", requires = "delta_against")]
+    delta_output: Option<PathBuf>,
+
+    /// After building, hash every file again and fail if any pieces root
+    /// differs. Doubles the reads but catches flaky hardware.
+    #[clap(long)]
+    verify_after_build: bool,
+
+    /// Emit a piece layer even for files with a single piece.
+    #[clap(long, hide = true)]
+    always_emit_layer: bool,
+
+    /// Experimental and nonstandard: truncate every SHA-256 digest in the
+    /// merkle trees to N bytes and write the pieces roots and piece layers
+    /// with N byte hashes. Only clients built for the truncation can use the
+    /// torrent unless N is 32, which gives exactly the standard torrent. The
+    /// block hashes of each file are kept while it is hashed, 1/512 of its
+    /// size.
+    #[clap(
+        long,
+        hide = true,
+        value_name = "N",
+        value_parser = clap::value_parser!(u8).range(1..=32),
+        conflicts_with_all = [
+            "from_manifest",
+            "from_zip",
+            "incremental",
+            "resume_from_checkpoint",
+            "store_crc32",
+            "normalize_newlines",
+            "check_only",
+            "manifest",
+            "delta_against",
+            "pieces_sidecar",
+            "verify_after_build",
+            "always_emit_layer",
+            "warn_zero_pieces",
+            "json_summary",
+            "print_infohash",
+            "print_content_fingerprint",
+            "content_id",
+        ]
+    )]
+    digest_bytes: Option<u8>,
+
+    /// Increase verbosity. -vv lists every file with its pieces root after
+    /// the build.
+    #[clap(short, long, action = clap::ArgAction::Count)]
+    verbose: u8,
+
+    /// Number of threads to hash with. Defaults to the number of CPUs.
+    #[clap(long, value_name = "N")]
+    threads: Option<usize>,
+
+    /// Cap the number of hashing threads so that their piece buffers fit in
+    /// BYTES. This does not bound the memory of the whole build: the piece
+    /// layer of every file, 32 bytes per piece, is still kept in memory until
+    /// the torrent is written.
+    #[clap(long, value_name = "BYTES")]
+    max_buffer_memory: Option<u64>,
+
+    /// Read and hash at most N pieces at once, capping the memory used for
+    /// piece buffers regardless of the number of threads. Files are hashed
+    /// one at a time so the limit applies to the whole build.
+    #[clap(long, value_name = "N", conflicts_with_all = ["adaptive_threads", "single_threaded"])]
+    max_inflight_pieces: Option<NonZeroUsize>,
+
+    /// Fail instead of warning when the piece length is outside the range
+    /// most clients support (15 to 24) or a directory is wider than
+    /// --warn-wide-dirs.
+    #[clap(long)]
+    strict: bool,
+
+    /// Warn about directories in the torrent with more than N immediate
+    /// entries, which some clients handle poorly.
+    #[clap(long, value_name = "N")]
+    warn_wide_dirs: Option<usize>,
+
+    /// Check that the encoded torrent is canonical bencode, with sorted
+    /// dictionary keys and no leading zeros, before writing it.
+    #[clap(long)]
+    validate_canonical: bool,
+
+    /// Exit with an error after printing warnings if the build produced any,
+    /// including those only printed with --verbose. No torrent is written.
+    #[clap(long)]
+    fail_on_warning: bool,
+
+    /// Hash one file at a time, reading each from start to end on a single
+    /// thread. Useful for debugging.
+    #[clap(long, conflicts_with_all = ["threads", "adaptive_threads"])]
+    single_threaded: bool,
+
+    /// Hash one file at a time, still hashing the pieces of each file on
+    /// every thread. By default files are hashed concurrently, which is
+    /// faster for many small files but reads several files at once.
+    #[clap(long)]
+    no_parallel_files: bool,
+
+    /// Experimental: pick the number of threads by measuring throughput while
+    /// hashing, up to --threads.
+    #[clap(long)]
+    adaptive_threads: bool,
+
+    /// Create a torrent for each subdirectory of the root, plus one for any
+    /// files directly in the root. Torrents are written to the --output
+    /// directory. Requires --piece-length.
+    ///
+    /// Files are filtered as for a single torrent of the root, so patterns
+    /// match paths relative to the root. --trim-common-prefix applies to each
+    /// torrent separately.
+    #[clap(
+        long,
+        requires = "output",
+        conflicts_with_all = [
+            "files_from",
+            "manifest",
+            "delta_against",
+            "verify_after_build",
+            "json_summary",
+            "name",
+            "name_from",
+            "single_file",
+            "start_offset",
+            "length",
+            "max_files",
+            "check_only",
+            "pieces_sidecar",
+            "print_infohash",
+            "print_content_fingerprint",
+            "content_id",
+            "print_tree_stats",
+            "dump_piece_layers_stats",
+            "warn_zero_pieces",
+        ],
+    )]
+    per_subdir: bool,
+
+    /// The metainfo version to write. v1 writes a BEP 3 torrent with SHA-1
+    /// pieces for clients without v2 support.
+    #[clap(long, value_enum, value_name = "VERSION", default_value = "v2")]
+    meta_version: MetaVersion,
+
+    /// Require the root to be a single file.
+    #[clap(long)]
+    single_file: bool,
+
+    /// Require the root to be a directory.
+    #[clap(long, conflicts_with = "single_file")]
+    multi_file: bool,
+
+    /// Strip the longest directory prefix shared by every file, such as the
+    /// common parent of absolute --files-from paths.
+    #[clap(long)]
+    trim_common_prefix: bool,
+
+    /// Refuse to build a torrent with more than N files.
+    #[clap(long, value_name = "N")]
+    max_files: Option<usize>,
+
+    /// Only include the content of a single file root starting at BYTES.
+    /// Must be a multiple of the piece length, so requires --piece-length.
+    #[clap(
+        long,
+        value_name = "BYTES",
+        conflicts_with_all = ["incremental", "resume_from_checkpoint"]
+    )]
+    start_offset: Option<u64>,
+
+    /// Only include BYTES of a single file root, starting at --start-offset.
+    /// Defaults to the rest of the file. Requires --piece-length.
+    #[clap(
+        long,
+        value_name = "BYTES",
+        conflicts_with_all = ["incremental", "resume_from_checkpoint"]
+    )]
+    length: Option<u64>,
+
+    /// Store a CRC32 of each file under a non-standard `crc32` key for quick
+    /// spot-checks. This changes the infohash. With --single-threaded,
+    /// --adaptive-threads, --max-inflight-pieces or --normalize-newlines each
+    /// file is read in order on a single thread, which is slower for large
+    /// files.
+    #[clap(long, conflicts_with_all = ["incremental", "resume_from_checkpoint"])]
+    store_crc32: bool,
+
+    /// Read files with O_DIRECT to bypass the page cache. Only supported on
+    /// Linux.
+    #[clap(long)]
+    direct_io: bool,
+
+    /// Hash files with these comma separated extensions as if their CRLF line
+    /// endings were LF.
+    ///
+    /// The torrent describes the transformed content, including its length,
+    /// so it will not match the files on disk unless they are converted too.
+    #[clap(long, value_name = "EXTS", value_delimiter = ',')]
+    normalize_newlines: Vec<String>,
+
+    /// Treat the content as read-only, such as a filesystem snapshot, and
+    /// skip checking whether each file changed while it was hashed.
+    #[clap(long)]
+    assume_immutable: bool,
 
-    root: PathBuf,
+    /// Skip files that disappear after they are listed, with a warning,
+    /// instead of failing.
+    #[clap(long)]
+    skip_errors: bool,
+
+    /// Hash the targets of symlinks to files and add them under the name of
+    /// the link. Symlinks to directories are still skipped.
+    #[clap(long)]
+    dereference: bool,
+
+    /// Do not show progress on stderr. Progress is never shown when stderr
+    /// is not a terminal.
+    #[clap(long)]
+    no_progress: bool,
+
+    /// Write a JSON line such as {"piece":3,"done":65536} to the named pipe
+    /// PATH as each piece is hashed, where done is the number of bytes of the
+    /// file hashed so far. Files are hashed one at a time and piece numbers
+    /// start again for each file. Hashing does not wait for a reader: events
+    /// are dropped while a slow reader falls behind, and writing stops if the
+    /// reader goes away.
+    #[clap(
+        long,
+        value_name = "PATH",
+        conflicts_with_all = [
+            "per_subdir",
+            "adaptive_threads",
+            "max_inflight_pieces",
+            "single_threaded",
+            "store_crc32",
+        ]
+    )]
+    progress_pipe: Option<PathBuf>,
+
+    /// Template for the progress shown on stderr, in indicatif's format.
+    /// Falls back to the default if the template is invalid.
+    #[clap(long, value_name = "TEMPLATE")]
+    progress_template: Option<String>,
+
+    /// Write a nonstandard container instead of a torrent: a dictionary with
+    /// the whole torrent under KEY and any --wrap-field values beside it.
+    /// Clients cannot open the result. The infohash is unchanged since it
+    /// only covers the inner info dictionary.
+    #[clap(long, value_name = "KEY")]
+    wrap_key: Option<String>,
+
+    /// Add a string field beside the torrent in the --wrap-key container.
+    /// May be given more than once.
+    #[clap(
+        long,
+        value_name = "KEY=VALUE",
+        requires = "wrap_key",
+        value_parser = parse_field
+    )]
+    wrap_field: Vec<(String, String)>,
+
+    /// Sync the output file and the directory it is renamed into to disk
+    /// before exiting. Has no effect when writing to stdout.
+    #[clap(long)]
+    fsync: bool,
+
+    /// Remove temporary files next to the output file left behind by runs
+    /// that are no longer running. Does nothing on platforms where that
+    /// can't be checked.
+    #[clap(long)]
+    clean_temps: bool,
+
+    #[clap(required_unless_present_any = ["from_manifest", "from_zip"])]
+    root: Option<PathBuf>,
 }
 
-fn main() -> Result<()> {
-    let cli = Cli::parse();
+#[derive(Subcommand)]
+enum Command {
+    /// Check that a torrent's piece layers match its file tree.
+    Validate {
+        /// Print the problems found as a JSON array.
+        #[clap(long)]
+        json: bool,
+
+        file: PathBuf,
+    },
+
+    /// Check that content matches a single file torrent. DATA is hashed as
+    /// it is read, so `-` can verify a stream from stdin without storing it.
+    Verify {
+        file: PathBuf,
+
+        /// The content to check, or `-` for stdin.
+        data: PathBuf,
+    },
+
+    /// Rewrite a torrent's top level keys without changing its info
+    /// dictionary or infohash.
+    Repair {
+        /// Set the comment.
+        #[clap(long, value_name = "TEXT")]
+        set_comment: Option<String>,
+
+        /// Add an announce url as a new tier. May be given more than once.
+        #[clap(long, value_name = "URL")]
+        add_announce: Vec<String>,
+
+        /// Write the repaired torrent to FILE instead of stdout.
+        #[clap(short, long, value_name = "FILE")]
+        output: Option<PathBuf>,
+
+        file: PathBuf,
+    },
+
+    /// Edit the trackers of a torrent without changing its infohash, or add
+    /// files to it.
+    Edit {
+        /// Replace the announce url OLD with NEW wherever it appears. May be
+        /// given more than once. Split at the first `=`.
+        #[clap(long, value_name = "OLD=NEW", value_parser = parse_replacement)]
+        replace_announce: Vec<(String, String)>,
+
+        /// Canonicalize every announce url after replacing and remove urls
+        /// that are equivalent to one in an earlier tier.
+        #[clap(long)]
+        normalize_announce: bool,
+
+        /// Hash PATH and add it to the top level of the file tree under its
+        /// file name. May be given more than once. Existing files are not
+        /// read again. This changes the infohash.
+        #[clap(long, value_name = "PATH")]
+        add_file: Vec<PathBuf>,
 
-    let root = cli.root;
+        /// Write the edited torrent to FILE instead of stdout.
+        #[clap(short, long, value_name = "FILE")]
+        output: Option<PathBuf>,
 
-    let piece_length = {
-        if cli.piece_length < 14 || cli.piece_length > 40 {
-            return Err(Error::msg("--piece-length must be between 14 and 40"));
+        file: PathBuf,
+    },
+
+    /// Print the structural differences between two torrents.
+    #[clap(hide = true)]
+    Compare { first: PathBuf, second: PathBuf },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+enum InfohashEncoding {
+    // The full SHA-256 digest in hex.
+    Hex,
+    // The digest truncated to 20 bytes, as used for v1 compatible lookups, in
+    // base32.
+    Base32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+enum MetaVersion {
+    V1,
+    V2,
+}
+
+// Sets the default --piece-length.
+const PIECE_LENGTH_ENV: &str = "MKTORRENT_PIECE_LENGTH";
+
+// Piece length exponents most clients accept. Others are allowed but warned
+// about.
+const COMMON_PIECE_LENGTHS: std::ops::RangeInclusive<u8> = 15..=24;
+
+// Returns a warning if clients commonly reject the piece length exponent.
+fn piece_length_warning(exponent: u8) -> Option<String> {
+    if COMMON_PIECE_LENGTHS.contains(&exponent) {
+        return None;
+    }
+
+    let suggested = exponent.clamp(*COMMON_PIECE_LENGTHS.start(), *COMMON_PIECE_LENGTHS.end());
+    Some(format!(
+        "piece length exponent {} is outside the range most clients support ({}..={}), \
+         consider --piece-length={}",
+        exponent,
+        COMMON_PIECE_LENGTHS.start(),
+        COMMON_PIECE_LENGTHS.end(),
+        suggested
+    ))
+}
+
+// Parses a piece length exponent, or a size such as 256KiB which is converted
+// to its exponent.
+fn parse_piece_length(s: &str) -> Result<u8, String> {
+    if s.bytes().all(|b| b.is_ascii_digit()) {
+        s.parse()
+            .ok()
+            .filter(|n| (14..=40).contains(n))
+            .ok_or_else(|| "must be an exponent between 14 and 40".to_owned())
+    } else {
+        parse_piece_size(s)
+    }
+}
+
+// Uses env, the value of MKTORRENT_PIECE_LENGTH, when --piece-length is not
+// given, then checks the options that need a piece length have one. This is
+// done here rather than by clap so that an invalid value can be reported as
+// coming from the environment.
+fn resolve_piece_length(cli: &mut Cli, env: Result<String, VarError>) -> Result<()> {
+    if !cli.piece_length.is_empty() {
+        return Ok(());
+    }
+    let v = match env {
+        Ok(v) => Some(v),
+        Err(VarError::NotPresent) => None,
+        Err(VarError::NotUnicode(v)) => {
+            return Err(Error::msg(format!(
+                "invalid value `{}` for {}: not valid unicode",
+                v.to_string_lossy(),
+                PIECE_LENGTH_ENV
+            )))
         }
+    };
+    if let Some(v) = v {
+        let exponent = parse_piece_length(&v).map_err(|e| {
+            Error::msg(format!(
+                "invalid value `{}` for {}: {}",
+                v, PIECE_LENGTH_ENV, e
+            ))
+        })?;
+        cli.piece_length.push(exponent);
+    }
+
+    if cli.piece_length.is_empty() {
+        let needs = [
+            ("--from-manifest", cli.from_manifest.is_some()),
+            ("--per-subdir", cli.per_subdir),
+            ("--start-offset", cli.start_offset.is_some()),
+            ("--length", cli.length.is_some()),
+        ];
+        if let Some((flag, _)) = needs.iter().find(|(_, set)| *set) {
+            return Err(Error::msg(format!("{} requires --piece-length", flag)));
+        }
+    }
+    Ok(())
+}
 
-        PieceLength {
-            layers: cli.piece_length - 14,
+// Parses a number followed by a binary unit, such as 512K or 1MiB, and
+// returns its exponent. The size must be a power of two from 16KiB to 1TiB.
+fn parse_piece_size(s: &str) -> Result<u8, String> {
+    let (n, unit) = s.split_at(s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len()));
+    let shift = match unit.to_ascii_lowercase().as_str() {
+        "k" | "kib" => 10,
+        "m" | "mib" => 20,
+        "g" | "gib" => 30,
+        "t" | "tib" => 40,
+        _ => {
+            return Err("must be an exponent between 14 and 40 or a size such as 256KiB".to_owned())
         }
     };
+    let bytes = n
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(1 << shift))
+        .filter(|b| (1 << 14..=1 << 40).contains(b))
+        .ok_or_else(|| "must be a size between 16KiB and 1TiB".to_owned())?;
+
+    match PieceLength::from_bytes(bytes) {
+        Some(pl) => Ok(pl.layers + 14),
+        None => {
+            let lower = 1 << (63 - bytes.leading_zeros());
+            Err(format!(
+                "{} is not a power of two, the nearest piece lengths are {} and {}",
+                s,
+                format_size(lower),
+                format_size(lower << 1)
+            ))
+        }
+    }
+}
+
+// Formats bytes with the largest binary unit that divides it exactly.
+fn format_size(bytes: u64) -> String {
+    [(40, "TiB"), (30, "GiB"), (20, "MiB"), (10, "KiB")]
+        .into_iter()
+        .find(|&(shift, _)| bytes != 0 && bytes.is_multiple_of(1 << shift))
+        .map(|(shift, unit)| format!("{}{}", bytes >> shift, unit))
+        .unwrap_or_else(|| format!("{}B", bytes))
+}
+
+fn main() -> Result<()> {
+    let mut cli = Cli::parse();
+    if cli.v1 {
+        cli.meta_version = MetaVersion::V1;
+    }
+
+    match &cli.command {
+        Some(Command::Validate { json, file }) => return validate(file, *json),
+        Some(Command::Verify { file, data }) => {
+            let torrent = read_torrent(file)?;
+            if data == Path::new("-") {
+                return verify_stream(&torrent, io::stdin().lock());
+            }
+            let f = fs::File::open(data)
+                .context(format!("failed to open `{}`", data.to_string_lossy()))?;
+            return verify_stream(&torrent, io::BufReader::new(f));
+        }
+        Some(Command::Repair {
+            set_comment,
+            add_announce,
+            output,
+            file,
+        }) => {
+            let data = repair(file, set_comment.clone(), add_announce.clone())?;
+            return write_output(output.as_deref(), &data, false);
+        }
+        Some(Command::Edit {
+            replace_announce,
+            normalize_announce,
+            add_file,
+            output,
+            file,
+        }) => {
+            let data = edit(file, replace_announce, *normalize_announce, add_file)?;
+            return write_output(output.as_deref(), &data, false);
+        }
+        Some(Command::Compare { first, second }) => {
+            let differences = compare(&read_torrent(first)?, &read_torrent(second)?);
+            for d in &differences {
+                println!("{}", d);
+            }
+            if !differences.is_empty() {
+                return Err(Error::msg(format!(
+                    "found {} differences",
+                    differences.len()
+                )));
+            }
+            return Ok(());
+        }
+        None => {}
+    }
+
+    resolve_piece_length(&mut cli, env::var(PIECE_LENGTH_ENV))?;
+    check_peer_sources(&cli)?;
+
+    // Set once so every torrent written by this run has the same date.
+    if !cli.no_date && cli.creation_date.is_none() {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?;
+        cli.creation_date = Some(now.as_secs() as i64);
+    }
+
+    if cli.normalize_announce {
+        normalize_announce(&mut cli)?;
+    }
+
+    // parse_piece_length checks the exponents are in range. Options that
+    // only support a single piece length use the first. If none are given
+    // one is chosen once the size of the content is known.
+    let mut piece_lengths: Vec<PieceLength> = cli
+        .piece_length
+        .iter()
+        .map(|e| PieceLength { layers: e - 14 })
+        .collect();
+
+    for &exponent in &cli.piece_length {
+        if let Some(w) = piece_length_warning(exponent) {
+            if cli.strict {
+                return Err(Error::msg(w));
+            }
+            report_warnings(&cli, &[BuildWarning::PieceLength(w)])?;
+        }
+    }
+
+    if piece_lengths.len() > 1 {
+        check_multiple_piece_lengths_options(&cli)?;
+    }
+
+    if let Some(p) = &cli.from_manifest {
+        if cli.meta_version == MetaVersion::V1 {
+            return Err(Error::msg("--from-manifest requires --meta-version=v2"));
+        }
+        // --from-manifest requires --piece-length.
+        let torrent = build_from_manifest(&cli, piece_lengths[0], read_precomputed(p)?)?;
+        emit_torrent(&cli, &cli.output, &torrent)?;
+        if cli.print_infohash {
+            print_infohash(io::stdout().lock(), &torrent, cli.infohash_encoding)?;
+        }
+        return Ok(());
+    }
+
+    if let Some(p) = &cli.from_zip {
+        if cli.meta_version == MetaVersion::V1 {
+            return Err(Error::msg("--from-zip requires --meta-version=v2"));
+        }
+        let torrent = build_from_zip(&cli, piece_lengths.first().copied(), p)?;
+        emit_torrent(&cli, &cli.output, &torrent)?;
+        if cli.print_infohash {
+            print_infohash(io::stdout().lock(), &torrent, cli.infohash_encoding)?;
+        }
+        return Ok(());
+    }
+
+    let root = cli.root.as_ref().unwrap();
 
     let torrent_name =
-        torrent_name_from_path(&root).context("could not convert root filename to UTF-8")?;
+        torrent_name_from_path(root).context("could not convert root filename to UTF-8")?;
 
-    let mut torrent = Torrent::new(cli.announce, torrent_name.clone(), piece_length);
+    if cli.per_subdir {
+        if cli.meta_version == MetaVersion::V1 {
+            return Err(Error::msg("--per-subdir requires --meta-version=v2"));
+        }
+        // per_subdir requires output and --piece-length.
+        let hash_options = hash_options(&cli, piece_lengths[0])?;
+        return build_per_subdir(&cli, &hash_options, &torrent_name, &cli.output);
+    }
+
+    let display_name = display_name(&cli, &torrent_name)?;
+
+    let outputs: Vec<PathBuf> = cli
+        .output
+        .iter()
+        .map(|o| {
+            let p = output_path(o, &display_name, cli.sanitize_output_name);
+            if cli.output_unique {
+                unique_path(&p)
+            } else {
+                p
+            }
+        })
+        .collect();
+
+    // Fail before hashing. Multiple piece lengths write to other paths which
+    // are checked as they are written. --list-files-json and --estimate-time
+    // write no torrent.
+    if piece_lengths.len() <= 1 && !cli.list_files_json && !cli.estimate_time {
+        check_outputs(&cli, &outputs)?;
+    }
 
     let metadata =
-        fs::metadata(&root).context(format!("failed to stat `{}`", root.to_string_lossy()))?;
+        fs::metadata(root).context(format!("failed to stat `{}`", root.to_string_lossy()))?;
+
+    check_layout(&cli, metadata.is_file())?;
+
+    // Where the content starts in a single file root.
+    let mut start_offset = 0;
+
+    // The directory files are relative to and the files to add.
+    let (dir, files) = if metadata.is_file() {
+        if cli.files_from.is_some() {
+            return Err(Error::msg("--files-from requires a directory root"));
+        }
 
-    if metadata.is_file() {
-        let filename = &torrent_name;
         let dir = root.parent().unwrap_or_else(|| Path::new(""));
+        let (offset, length) = match piece_lengths.first() {
+            Some(&piece_length) => subset(&cli, piece_length, metadata.len())?,
+            // --start-offset and --length require --piece-length.
+            None => (0, metadata.len()),
+        };
+        start_offset = offset;
+        (dir.to_path_buf(), vec![(torrent_name.clone(), length)])
+    } else {
+        if cli.start_offset.is_some() || cli.length.is_some() {
+            return Err(Error::msg(
+                "--start-offset and --length require a single file root",
+            ));
+        }
+
+        select_files(&cli, root)?
+    };
 
-        add_file(&mut torrent, dir, piece_length, filename, metadata.len())?;
+    if cli.list_files_json {
+        let mut stdout = io::stdout().lock();
+        write_file_list_json(&mut stdout, &files)?;
+        writeln!(stdout)?;
+        return Ok(());
+    }
+
+    check_max_files(files.len(), cli.max_files)?;
+
+    if piece_lengths.is_empty() {
+        let total = files.iter().map(|(_, l)| l).sum();
+        piece_lengths.push(auto_piece_length(&cli, total, io::stderr())?);
+    }
+    let piece_length = piece_lengths[0];
+
+    let mut hash_options = hash_options(&cli, piece_length)?;
+    hash_options.start_offset = start_offset;
+
+    let total: u64 = files.iter().map(|(_, l)| l).sum();
+    if show_estimate(&cli, total, io::stderr().is_terminal()) {
+        let v1 = cli.meta_version == MetaVersion::V1;
+        match calibrate(&hash_options, v1, &dir, &files)? {
+            Some(throughput) => eprintln!(
+                "{}",
+                format_estimate(estimate_time(total, throughput), throughput)
+            ),
+            None => eprintln!("nothing to hash"),
+        }
+        if cli.estimate_time {
+            return Ok(());
+        }
+    }
+
+    if piece_lengths.len() > 1 {
+        // check_multiple_piece_lengths_options requires --output.
+        let torrents = build_torrents(
+            &cli,
+            &hash_options,
+            &piece_lengths,
+            display_name,
+            &dir,
+            files,
+            metadata.is_file(),
+        )?;
+        for (t, exponent) in torrents.iter().zip(&cli.piece_length) {
+            let outputs: Vec<_> = outputs
+                .iter()
+                .map(|o| with_piece_length(o, *exponent))
+                .collect();
+            emit_torrent(&cli, &outputs, t)?;
+        }
+        return Ok(());
+    }
+
+    if cli.meta_version == MetaVersion::V1 {
+        check_v1_options(&cli)?;
+        let (torrent, warnings) = build_torrent_v1(
+            &cli,
+            piece_length,
+            display_name,
+            &dir,
+            files,
+            metadata.is_file(),
+        )?;
+        report_warnings(&cli, &warnings)?;
+        emit_torrent(&cli, &outputs, &torrent)?;
+        if cli.print_infohash {
+            print_infohash_v1(io::stdout().lock(), &torrent, cli.infohash_encoding)?;
+        }
+        return Ok(());
+    }
+
+    // The listed files are hashed again by --verify-after-build, and the
+    // manifest records their lengths on disk where they differ from the
+    // torrent's.
+    let manifest = cli.manifest.is_some() || cli.delta_against.is_some();
+    let listed = if cli.verify_after_build || manifest {
+        files.clone()
     } else {
-        for (file, l) in get_file_list(&root)? {
-            add_file(&mut torrent, &root, piece_length, &file, l)?;
+        Vec::new()
+    };
+    let pipe = cli.progress_pipe.clone().map(ProgressPipe::spawn);
+    hash_options.piece_events = pipe.as_ref().map(ProgressPipe::sender);
+    let bar = hash_bar(
+        hide_progress(&cli),
+        cli.progress_template.as_deref(),
+        files.iter().map(|(_, l)| l).sum(),
+    );
+    hash_options.progress = Some(bar.clone());
+    let (torrent, mtimes, warnings) = build_torrent(
+        &cli,
+        &hash_options,
+        display_name,
+        &dir,
+        files,
+        metadata.is_file(),
+    )?;
+    hash_options.piece_events = None;
+    hash_options.progress = None;
+    finish_hash_bar(&bar);
+    if let Some(p) = pipe {
+        p.finish();
+    }
+    report_warnings(&cli, &warnings)?;
+
+    if cli.verify_after_build {
+        let mismatched = self_check(
+            &cli,
+            &hash_options,
+            &dir,
+            &listed,
+            &torrent,
+            metadata.is_file(),
+        )?;
+        for path in &mismatched {
+            eprintln!("error: {} hashed differently when read again", path);
         }
+        if !mismatched.is_empty() {
+            return Err(Error::msg(format!(
+                "self-check failed for {} files",
+                mismatched.len()
+            )));
+        }
+    }
+
+    if let Some(a) = hash_options.adaptive.as_ref().filter(|_| !cli.json_summary) {
+        eprintln!("adaptive threads: finished with {} threads", a.threads());
     }
 
-    let encoded = torrent.to_bencode().unwrap();
-    io::stdout().write_all(&encoded).unwrap();
+    if cli.warn_zero_pieces {
+        for (path, pieces) in zero_pieces(&torrent) {
+            eprintln!(
+                "warning: {} has pieces of only zeros: {}",
+                path,
+                pieces
+                    .iter()
+                    .map(|i| i.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+        }
+    }
+
+    write_build(
+        &cli,
+        &outputs,
+        &torrent,
+        &mtimes,
+        &listed,
+        metadata.is_file(),
+    )
+}
+
+// Reads and decodes the v2 torrent at p.
+fn read_torrent(p: &Path) -> Result<Torrent> {
+    let data = fs::read(p).context(format!("failed to read `{}`", p.to_string_lossy()))?;
+    Torrent::from_bencode(&data)
+        .map_err(|e| Error::msg(format!("failed to decode `{}`: {}", p.to_string_lossy(), e)))
+}
+
+// Decodes the torrent at p and prints any problems found by
+// Torrent::validate. Returns an error if there are any problems.
+fn validate(p: &Path, json: bool) -> Result<()> {
+    let data = fs::read(p).context(format!("failed to read `{}`", p.to_string_lossy()))?;
+    let torrent = Torrent::from_bencode(&data)
+        .map_err(|e| Error::msg(format!("failed to decode `{}`: {}", p.to_string_lossy(), e)))?;
+
+    let problems = torrent.validate();
+    if json {
+        serde_json::to_writer(io::stdout(), &problems)?;
+        println!();
+    } else {
+        for problem in &problems {
+            println!("{}", problem);
+        }
+    }
+
+    if !problems.is_empty() {
+        return Err(Error::msg(format!("found {} problems", problems.len())));
+    }
 
     Ok(())
 }
 
-fn add_file(
-    torrent: &mut Torrent,
-    root: &Path,
-    piece_length: PieceLength,
-    path: &str,
-    file_length: u64,
-) -> Result<()> {
-    let (f, pieces_layer) = {
-        let r = RandomAccessFile::open(root.join(path))?;
-        checksum::checksum_file_multithreaded(piece_length, file_length, &r)
-            .context("failed to checksum file")?
+// Hashes r and checks it against the only file of torrent.
+fn verify_stream(torrent: &Torrent, r: impl io::Read) -> Result<()> {
+    let files = torrent.files();
+    let expected = match files.as_slice() {
+        [(_, f)] => **f,
+        _ => {
+            return Err(Error::msg(format!(
+                "verify requires a single file torrent, found {} files",
+                files.len()
+            )))
+        }
     };
 
-    if !torrent.add_file(path, f, pieces_layer) {
-        return Err(Error::msg("conflicting file"));
+    let (got, _) = checksum::torrent2::checksum_file(torrent.info.piece_length, r)
+        .context("failed to read data")?;
+    if got.length != expected.length {
+        return Err(Error::msg(format!(
+            "length mismatch: read {} bytes, expected {}",
+            got.length, expected.length
+        )));
+    }
+    if got.pieces_root != expected.pieces_root {
+        return Err(Error::msg(format!(
+            "pieces root mismatch: computed {}, expected {}",
+            got.pieces_root, expected.pieces_root
+        )));
     }
 
     Ok(())
 }
 
-// Returns the relative path from the root for each file in the root.
-fn get_file_list(root: &Path) -> Result<Vec<(String, u64)>> {
-    let mut ret = Vec::new();
+// Decodes the torrent at p, applies the changes to its top level keys and
+// returns the encoded result. The info dictionary is copied as-is.
+fn repair(p: &Path, comment: Option<String>, announces: Vec<String>) -> Result<Vec<u8>> {
+    let data = fs::read(p).context(format!("failed to read `{}`", p.to_string_lossy()))?;
+    let decode_err = |e: bendy::decoding::Error| {
+        Error::msg(format!("failed to decode `{}`: {}", p.to_string_lossy(), e))
+    };
+
+    let mut torrent = RawTorrent::decode(&data).map_err(decode_err)?;
+    if let Some(c) = comment {
+        torrent
+            .set(b"comment", c)
+            .map_err(|e| Error::msg(e.to_string()))?;
+    }
+    for url in announces {
+        torrent.add_announce(url).map_err(decode_err)?;
+    }
+
+    Ok(torrent.encode())
+}
+
+// Parses an http or https URL.
+fn parse_http_url(s: &str) -> Result<String, String> {
+    let url = url::Url::parse(s).map_err(|e| e.to_string())?;
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err("must be an http or https URL".to_owned());
+    }
+    Ok(s.to_owned())
+}
 
-    for entry in WalkDir::new(root) {
-        let entry = entry?;
-        if !entry.file_type().is_file() {
-            continue;
+// Parses an http, https or ftp URL.
+fn parse_web_seed_url(s: &str) -> Result<String, String> {
+    let url = url::Url::parse(s).map_err(|e| e.to_string())?;
+    if !matches!(url.scheme(), "http" | "https" | "ftp") {
+        return Err("must be an http, https or ftp URL".to_owned());
+    }
+    Ok(s.to_owned())
+}
+
+// Parses a DHT node as HOST:PORT. An IPv6 host is written in brackets and
+// stored without them.
+fn parse_node(s: &str) -> Result<(String, u16), String> {
+    let (host, port) = s
+        .rsplit_once(':')
+        .ok_or_else(|| "must be HOST:PORT".to_owned())?;
+    if host.is_empty() {
+        return Err("missing host".to_owned());
+    }
+    if host.contains(':') && !host.starts_with('[') {
+        return Err("an IPv6 address must be in brackets, as in [::1]:6881".to_owned());
+    }
+    let host = match url::Host::parse(host).map_err(|e| e.to_string())? {
+        url::Host::Ipv6(ip) => ip.to_string(),
+        _ => host.to_owned(),
+    };
+    let port = match port.parse::<u16>() {
+        Ok(p) if p != 0 => p,
+        _ => return Err(format!("invalid port `{}`", port)),
+    };
+    Ok((host, port))
+}
+
+// Parses a comma separated tier of announce urls.
+fn parse_tier(s: &str) -> Result<Vec<String>, String> {
+    let urls: Vec<String> = s
+        .split(',')
+        .filter(|u| !u.is_empty())
+        .map(str::to_owned)
+        .collect();
+    if urls.is_empty() {
+        return Err("expected at least one url".to_owned());
+    }
+    Ok(urls)
+}
+
+// Parses a KEY=VALUE field.
+fn parse_field(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((k, v)) => Ok((k.to_owned(), v.to_owned())),
+        None => Err("expected KEY=VALUE".to_owned()),
+    }
+}
+
+// Parses an OLD=NEW replacement.
+fn parse_replacement(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((old, new)) => Ok((old.to_owned(), new.to_owned())),
+        None => Err("expected OLD=NEW".to_owned()),
+    }
+}
+
+// Decodes the torrent at p, replaces announce urls, normalizes them if
+// requested, adds files and returns the encoded result. Unless files are
+// added, the info dictionary is copied as-is.
+fn edit(
+    p: &Path,
+    replacements: &[(String, String)],
+    normalize: bool,
+    add_files: &[PathBuf],
+) -> Result<Vec<u8>> {
+    let data = fs::read(p).context(format!("failed to read `{}`", p.to_string_lossy()))?;
+    let decode_err = |e: bendy::decoding::Error| {
+        Error::msg(format!("failed to decode `{}`: {}", p.to_string_lossy(), e))
+    };
+
+    let mut torrent = RawTorrent::decode(&data).map_err(decode_err)?;
+    for (old, new) in replacements {
+        if torrent.replace_announce(old, new).map_err(decode_err)? == 0 {
+            eprintln!("warning: announce url not found: {}", old);
         }
+    }
+    if normalize {
+        torrent.normalize_announce().map_err(decode_err)?;
+    }
+    if !add_files.is_empty() {
+        eprintln!("warning: adding files changes the infohash");
+        let decoded = Torrent::from_bencode(&data).map_err(decode_err)?;
+        let added = add_files_to(decoded, add_files)?
+            .to_bencode()
+            .map_err(|e| Error::msg(e.to_string()))?;
+        // Only the file tree and piece layers change. Keys this crate does
+        // not know about are kept.
+        let added = RawTorrent::decode(&added).map_err(decode_err)?;
+        torrent
+            .set_info_raw(
+                b"file tree",
+                added.get_info(b"file tree").map_err(decode_err)?.unwrap(),
+            )
+            .map_err(decode_err)?;
+        torrent.set_raw(
+            b"piece layers",
+            added.get(b"piece layers").unwrap().to_vec(),
+        );
+    }
+
+    Ok(torrent.encode())
+}
+
+// Hashes each file with the torrent's piece length and adds it to the top
+// level of the file tree under its file name.
+fn add_files_to(mut torrent: Torrent, files: &[PathBuf]) -> Result<Torrent> {
+    let opts = HashOptions::new(torrent.info.piece_length);
+    for p in files {
+        let name = p
+            .file_name()
+            .and_then(|n| n.to_str())
+            .ok_or_else(|| Error::msg(format!("invalid file name: {}", p.to_string_lossy())))?;
+        let dir = p.parent().unwrap_or_else(|| Path::new(""));
+        let length = fs::metadata(p)
+            .context(format!("failed to stat `{}`", p.to_string_lossy()))?
+            .len();
 
-        if entry.depth() >= MAX_FILE_PATH_DEPTH {
+        let (f, pieces_layer) = hash_file(dir, &opts, name, length)
+            .context(format!("failed to hash `{}`", p.to_string_lossy()))?;
+        if !torrent.add_file(name, f, pieces_layer) {
             return Err(Error::msg(format!(
-                "hit max file depth ({}) at file: {}",
-                MAX_FILE_PATH_DEPTH,
-                entry.path().to_string_lossy(),
+                "conflicting file: {}: {}",
+                name,
+                torrent.conflict(name)
             )));
         }
+    }
+    Ok(torrent)
+}
 
-        let rel_path = entry.path().strip_prefix(root).unwrap();
+// Replaces the announce urls with their normalize_url form, removing urls
+// already given in an earlier or the same tier the same way edit
+// --normalize-announce does. Tiers left empty are dropped.
+fn normalize_announce(cli: &mut Cli) -> Result<()> {
+    for url in cli
+        .announce
+        .iter()
+        .chain(cli.announce_tier.iter().flatten())
+    {
+        normalize_url(url)
+            .map_err(|e| Error::msg(format!("invalid announce url `{}`: {}", url, e)))?;
+    }
 
-        let rel_path_str = rel_path
-            .to_str()
-            .ok_or_else(|| {
-                Error::msg(format!(
-                    "cannot convert path to UTF-8: {}",
-                    rel_path.to_string_lossy(),
-                ))
-            })?
-            .to_owned();
+    let tiers = iter::once(mem::take(&mut cli.announce))
+        .chain(mem::take(&mut cli.announce_tier))
+        .collect();
+    let mut tiers = normalize_tiers(tiers).into_iter();
+    cli.announce = tiers.next().unwrap_or_default();
+    cli.announce_tier = tiers.filter(|t| !t.is_empty()).collect();
+    Ok(())
+}
 
-        let l = entry.metadata()?.len();
+// A torrent without a tracker or a DHT node to bootstrap from gives clients
+// no way to find peers.
+fn check_peer_sources(cli: &Cli) -> Result<()> {
+    if cli.announce.is_empty() && cli.announce_tier.is_empty() && cli.node.is_empty() {
+        return Err(Error::msg(
+            "no --announce or --node given, clients would have no way to find peers for the torrent",
+        ));
+    }
+    Ok(())
+}
 
-        ret.push((rel_path_str, l));
+// Returns an error if --single-file, --multi-file or --group-by-extension
+// contradicts the root.
+fn check_layout(cli: &Cli, is_file: bool) -> Result<()> {
+    if cli.single_file && !is_file {
+        return Err(Error::msg("--single-file requires a file root"));
     }
+    if cli.multi_file && is_file {
+        return Err(Error::msg("--multi-file requires a directory root"));
+    }
+    // Grouping would move a single file into a directory.
+    if cli.group_by_extension && is_file {
+        return Err(Error::msg("--group-by-extension requires a directory root"));
+    }
+    Ok(())
+}
 
-    Ok(ret)
+// Returns an error if there are more than max files.
+fn check_max_files(count: usize, max: Option<usize>) -> Result<()> {
+    match max {
+        Some(max) if count > max => Err(Error::msg(format!(
+            "found {} files, more than --max-files={}",
+            count, max
+        ))),
+        _ => Ok(()),
+    }
+}
+
+// Returns the offset and length of the window of a single file root selected
+// by --start-offset and --length. The offset must be aligned to a piece.
+fn subset(cli: &Cli, piece_length: PieceLength, file_length: u64) -> Result<(u64, u64)> {
+    let offset = cli.start_offset.unwrap_or(0);
+    if !offset.is_multiple_of(piece_length.bytes()) {
+        return Err(Error::msg(format!(
+            "--start-offset must be a multiple of the piece length ({})",
+            piece_length.bytes()
+        )));
+    }
+    if offset > file_length {
+        return Err(Error::msg(format!(
+            "--start-offset is past the end of the file ({} bytes)",
+            file_length
+        )));
+    }
+
+    let length = cli.length.unwrap_or(file_length - offset);
+    if length > file_length - offset {
+        return Err(Error::msg(format!(
+            "--length extends past the end of the file ({} bytes)",
+            file_length
+        )));
+    }
+
+    Ok((offset, length))
+}
+
+const SCAN_TEMPLATE: &str = "{spinner} scanning... {pos} files found";
+
+// Returns a spinner counting the files found while walking the root. It is
+// hidden if hidden is set or stderr is not a terminal.
+fn scan_spinner(hidden: bool, template: Option<&str>) -> ProgressBar {
+    if hidden {
+        return ProgressBar::hidden();
+    }
+
+    let spinner = ProgressBar::new_spinner().with_style(progress_style(template, SCAN_TEMPLATE));
+    spinner.enable_steady_tick(Duration::from_millis(100));
+    spinner
+}
+
+const HASH_TEMPLATE: &str = "{bar:40} {bytes}/{total_bytes} {binary_bytes_per_sec} eta {eta}";
+
+// Returns a bar for hashing total bytes of content. It is hidden if hidden is
+// set or stderr is not a terminal.
+fn hash_bar(hidden: bool, template: Option<&str>, total: u64) -> ProgressBar {
+    if hidden || !io::stderr().is_terminal() {
+        return ProgressBar::hidden();
+    }
+
+    ProgressBar::new(total).with_style(progress_style(template, HASH_TEMPLATE))
+}
+
+// Returns true if progress should not be shown on stderr. --json-summary
+// leaves stderr to warnings and errors.
+fn hide_progress(cli: &Cli) -> bool {
+    cli.no_progress || cli.json_summary
+}
+
+// Clears bar and prints how much was hashed and how long it took, unless bar
+// is hidden.
+fn finish_hash_bar(bar: &ProgressBar) {
+    if bar.is_hidden() {
+        return;
+    }
+
+    bar.finish_and_clear();
+    eprintln!(
+        "hashed {} in {}",
+        HumanBytes(bar.position()),
+        HumanDuration(bar.elapsed())
+    );
+}
+
+// Returns the style for template, or for default if template is None or
+// invalid.
+fn progress_style(template: Option<&str>, default: &str) -> ProgressStyle {
+    if let Some(t) = template {
+        match ProgressStyle::with_template(t) {
+            Ok(style) => return style,
+            Err(e) => eprintln!("warning: invalid --progress-template: {}", e),
+        }
+    }
+    ProgressStyle::with_template(default).unwrap()
+}
+
+// Parses a time given as seconds since the unix epoch, an RFC 3339 time, or
+// an ISO 8601 date or date and time, which are taken to be in UTC.
+fn parse_time(s: &str) -> Result<SystemTime, String> {
+    let secs = if let Ok(secs) = s.parse::<i64>() {
+        secs
+    } else if let Ok(t) = DateTime::parse_from_rfc3339(s) {
+        t.timestamp()
+    } else if let Ok(t) = NaiveDateTime::parse_from_str(s, "%Y-%m-%dT%H:%M:%S") {
+        t.and_utc().timestamp()
+    } else if let Ok(d) = NaiveDate::parse_from_str(s, "%Y-%m-%d") {
+        d.and_time(NaiveTime::MIN).and_utc().timestamp()
+    } else {
+        return Err("expected seconds since the unix epoch or an ISO 8601 time".to_owned());
+    };
+
+    u64::try_from(secs)
+        .map(|secs| UNIX_EPOCH + Duration::from_secs(secs))
+        .map_err(|_| "must not be before the unix epoch".to_owned())
+}
+
+// Reads a torrent name from the first line of the file at p.
+fn read_name_from(p: &Path) -> Result<String> {
+    let f = fs::File::open(p).context(format!("failed to open `{}`", p.to_string_lossy()))?;
+    let mut line = String::new();
+    io::BufReader::new(f).read_line(&mut line)?;
+    validate_name(line.trim().to_owned())
+}
+
+// Returns the name if it is usable as a torrent name.
+fn validate_name(name: String) -> Result<String> {
+    if name.is_empty() {
+        return Err(Error::msg("torrent name must not be empty"));
+    }
+    if name.contains('/') {
+        return Err(Error::msg(format!(
+            "torrent name must not contain '/': {}",
+            name
+        )));
+    }
+    Ok(name)
+}
+
+// Returns the torrent's name from --name or --name-from, or torrent_name, the
+// root's file name, if neither is given.
+fn display_name(cli: &Cli, torrent_name: &str) -> Result<String> {
+    match (&cli.name, &cli.name_from) {
+        (Some(n), _) => validate_name(n.clone()),
+        (None, Some(p)) => read_name_from(p),
+        (None, None) => Ok(torrent_name.to_owned()),
+    }
 }
 
 // Build the torrent name from the root directory or file.
 fn torrent_name_from_path(p: &Path) -> Option<String> {
     Some(p.file_name()?.to_str()?.to_owned())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use metainfo::raw::check_canonical;
+
+    use crate::build::build_torrent;
+    use crate::hash::{hash_file, hash_files, HashOptions};
+    use crate::test_util::{get_file_list, write_torrent};
+
+    #[test]
+    fn name_from() {
+        let dir = tempfile::tempdir().unwrap();
+        let p = dir.path().join("name");
+
+        fs::write(&p, "  My Torrent \nsecond line\n").unwrap();
+        assert_eq!(read_name_from(&p).unwrap(), "My Torrent");
+
+        fs::write(&p, "\n").unwrap();
+        assert!(read_name_from(&p).is_err());
+
+        fs::write(&p, "a/b\n").unwrap();
+        assert!(read_name_from(&p).is_err());
+    }
+
+    #[test]
+    fn subset() {
+        let dir = tempfile::tempdir().unwrap();
+        let p = dir.path().join("video");
+        let data: Vec<u8> = (0..100u32 << 10).map(|i| (i % 251) as u8).collect();
+        fs::write(&p, &data).unwrap();
+        fs::write(dir.path().join("window"), &data[32 << 10..72 << 10]).unwrap();
+
+        let piece_length = PieceLength { layers: 0 };
+        let parse = |args: &[&str]| {
+            let mut argv = vec!["mktorrent-rs", "--announce=", "--piece-length=14"];
+            argv.extend_from_slice(args);
+            argv.push(p.to_str().unwrap());
+            Cli::parse_from(argv)
+        };
+
+        let cli = parse(&["--start-offset=32768", "--length=40960"]);
+        let (offset, length) = super::subset(&cli, piece_length, data.len() as u64).unwrap();
+        assert_eq!((offset, length), (32 << 10, 40 << 10));
+
+        // hashing the window matches hashing a file with just its content
+        let mut opts = HashOptions::new(piece_length);
+        opts.start_offset = offset;
+        let windowed = hash_file(dir.path(), &opts, "video", length).unwrap();
+        let expected = hash_file(
+            dir.path(),
+            &HashOptions::new(piece_length),
+            "window",
+            length,
+        )
+        .unwrap();
+        assert_eq!(windowed, expected);
+        assert_eq!(windowed.0.length, 40 << 10);
+
+        let cli = parse(&["--start-offset=1000"]);
+        let err = super::subset(&cli, piece_length, data.len() as u64).unwrap_err();
+        assert!(
+            err.to_string().contains("multiple of the piece length"),
+            "{}",
+            err
+        );
+
+        let cli = parse(&["--start-offset=98304", "--length=16384"]);
+        assert!(super::subset(&cli, piece_length, data.len() as u64).is_err());
+    }
+
+    #[test]
+    fn layout_override() {
+        let parse = |flag: &str| {
+            Cli::parse_from([
+                "mktorrent-rs",
+                "--announce=",
+                "--piece-length=14",
+                flag,
+                "root",
+            ])
+        };
+
+        check_layout(&parse("--single-file"), true).unwrap();
+        check_layout(&parse("--multi-file"), false).unwrap();
+
+        let err = check_layout(&parse("--single-file"), false).unwrap_err();
+        assert!(err.to_string().contains("file root"), "{}", err);
+        let err = check_layout(&parse("--multi-file"), true).unwrap_err();
+        assert!(err.to_string().contains("directory root"), "{}", err);
+        check_layout(&parse("--group-by-extension"), false).unwrap();
+        let err = check_layout(&parse("--group-by-extension"), true).unwrap_err();
+        assert!(err.to_string().contains("directory root"), "{}", err);
+
+        assert!(Cli::try_parse_from([
+            "mktorrent-rs",
+            "--announce=",
+            "--piece-length=14",
+            "--single-file",
+            "--multi-file",
+            "root",
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn max_files() {
+        let dir = tempfile::tempdir().unwrap();
+        for name in ["a", "b", "c"] {
+            fs::write(dir.path().join(name), name).unwrap();
+        }
+
+        let files = get_file_list(dir.path()).unwrap();
+        check_max_files(files.len(), None).unwrap();
+        check_max_files(files.len(), Some(3)).unwrap();
+        let err = check_max_files(files.len(), Some(2)).unwrap_err();
+        assert!(err.to_string().contains("found 3 files"), "{}", err);
+    }
+
+    #[test]
+    fn repair() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("a"), vec![1u8; 40 << 10]).unwrap();
+        let p = dir.path().join("a.torrent");
+
+        let cli = Cli::parse_from([
+            "mktorrent-rs",
+            "--announce=http://old.example.com",
+            "--piece-length=14",
+            dir.path().to_str().unwrap(),
+        ]);
+        let opts = HashOptions::new(PieceLength { layers: 0 });
+        let files = get_file_list(dir.path()).unwrap();
+        let (t, _, _) =
+            build_torrent(&cli, &opts, "a".to_owned(), dir.path(), files, false).unwrap();
+        write_torrent(Some(&p), &t, false).unwrap();
+
+        let repaired = super::repair(
+            &p,
+            Some("fixed".to_owned()),
+            vec!["http://new.example.com".to_owned()],
+        )
+        .unwrap();
+        check_canonical(&repaired).unwrap();
+        let decoded = Torrent::from_bencode(&repaired).unwrap();
+        assert_eq!(decoded.info.infohash().unwrap(), t.info.infohash().unwrap());
+
+        let raw = RawTorrent::decode(&repaired).unwrap();
+        let original = RawTorrent::decode(&fs::read(&p).unwrap()).unwrap();
+        assert_eq!(raw.get(b"info"), original.get(b"info"));
+        assert_eq!(raw.get(b"comment"), Some(b"5:fixed".as_slice()));
+    }
+
+    #[test]
+    fn verify_stream() {
+        let data: Vec<u8> = (0..50 << 10).map(|i| (i % 251) as u8).collect();
+        let piece_length = PieceLength { layers: 0 };
+        let mut t = Torrent::new("".to_owned(), "a".to_owned(), piece_length);
+        let (f, layer) = checksum::torrent2::checksum_file(piece_length, data.as_slice()).unwrap();
+        assert!(t.add_file("a", f, layer));
+
+        super::verify_stream(&t, data.as_slice()).unwrap();
+
+        let mut corrupted = data.clone();
+        corrupted[20 << 10] ^= 1;
+        let err = super::verify_stream(&t, corrupted.as_slice()).unwrap_err();
+        assert!(
+            err.to_string()
+                .starts_with("pieces root mismatch: computed "),
+            "{}",
+            err
+        );
+
+        let err = super::verify_stream(&t, &data[1..]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "length mismatch: read {} bytes, expected {}",
+                data.len() - 1,
+                data.len()
+            )
+        );
+
+        assert!(t.add_file("b", metainfo::File::default(), Vec::new()));
+        assert!(super::verify_stream(&t, data.as_slice()).is_err());
+    }
+
+    #[test]
+    fn json_summary_options() {
+        let parse = |args: &[&str]| {
+            let mut argv = vec![
+                "mktorrent-rs",
+                "--announce=",
+                "--piece-length=14",
+                "--json-summary",
+                "-o",
+                "out",
+            ];
+            argv.extend_from_slice(args);
+            argv.push("root");
+            Cli::try_parse_from(argv)
+        };
+        assert!(hide_progress(&parse(&[]).unwrap()));
+
+        // anything else printed to stdout would break the JSON
+        for flag in [
+            "--print-infohash",
+            "--content-id",
+            "--print-content-fingerprint",
+            "--list-files-json",
+            "--estimate-time",
+        ] {
+            assert!(parse(&[flag]).is_err(), "{}", flag);
+        }
+    }
+
+    #[test]
+    fn progress_template() {
+        progress_style(Some("{prefix} {pos} files {elapsed}"), SCAN_TEMPLATE);
+
+        // a bad width fails to parse and falls back to the default
+        assert!(ProgressStyle::with_template("{pos:abc}").is_err());
+        progress_style(Some("{pos:abc}"), SCAN_TEMPLATE);
+
+        let spinner = scan_spinner(false, Some("{pos:abc}"));
+        spinner.set_draw_target(indicatif::ProgressDrawTarget::hidden());
+        spinner.inc(1);
+        assert_eq!(spinner.position(), 1);
+    }
+
+    #[test]
+    fn uncommon_piece_length() {
+        assert_eq!(piece_length_warning(20), None);
+        assert_eq!(piece_length_warning(15), None);
+        assert_eq!(piece_length_warning(24), None);
+        assert_eq!(
+            piece_length_warning(30).unwrap(),
+            "piece length exponent 30 is outside the range most clients support (15..=24), \
+             consider --piece-length=24"
+        );
+        assert!(piece_length_warning(14)
+            .unwrap()
+            .ends_with("--piece-length=15"));
+        // automatically chosen piece lengths are never warned about
+        for total in [0, 1 << 20, 1 << 50] {
+            assert!(piece_length_warning(PieceLength::for_content(total).layers + 14).is_none());
+        }
+    }
+
+    #[test]
+    fn piece_length_sizes() {
+        let tests = [
+            ("14", 14),
+            ("40", 40),
+            ("16KiB", 14),
+            ("256K", 18),
+            ("256kib", 18),
+            ("1M", 20),
+            ("4MiB", 22),
+            ("2G", 31),
+            ("1TiB", 40),
+        ];
+        for (s, exponent) in tests {
+            assert_eq!(parse_piece_length(s), Ok(exponent), "{}", s);
+        }
+
+        assert_eq!(
+            parse_piece_length("3M").unwrap_err(),
+            "3M is not a power of two, the nearest piece lengths are 2MiB and 4MiB"
+        );
+        assert_eq!(
+            parse_piece_length("1536K").unwrap_err(),
+            "1536K is not a power of two, the nearest piece lengths are 1MiB and 2MiB"
+        );
+        for s in ["8K", "2TiB", "99999999999999999999K"] {
+            assert_eq!(
+                parse_piece_length(s).unwrap_err(),
+                "must be a size between 16KiB and 1TiB",
+                "{}",
+                s
+            );
+        }
+        for s in ["13", "256", "1MB", "K", "1.5M", ""] {
+            assert!(parse_piece_length(s).is_err(), "{}", s);
+        }
+    }
+
+    #[test]
+    fn piece_length_env() {
+        let resolve = |args: &[&str], env: Option<&str>| {
+            let mut argv = vec!["mktorrent-rs", "--announce="];
+            argv.extend_from_slice(args);
+            argv.push("root");
+            let mut cli = Cli::try_parse_from(argv).map_err(Error::from)?;
+            let env = env.map(str::to_owned).ok_or(VarError::NotPresent);
+            resolve_piece_length(&mut cli, env).map(|()| cli.piece_length)
+        };
+
+        assert_eq!(resolve(&[], Some("23")).unwrap(), vec![23]);
+        assert_eq!(resolve(&[], Some("8MiB")).unwrap(), vec![23]);
+        // the flag takes precedence, even over an invalid value
+        assert_eq!(
+            resolve(&["--piece-length=14"], Some("23")).unwrap(),
+            vec![14]
+        );
+        assert_eq!(
+            resolve(&["--piece-length=15"], Some("3MiB")).unwrap(),
+            vec![15]
+        );
+
+        let err = resolve(&[], Some("3MiB")).unwrap_err().to_string();
+        assert!(err.contains(PIECE_LENGTH_ENV), "{}", err);
+        let mut cli = Cli::parse_from(["mktorrent-rs", "--announce=", "root"]);
+        let err = resolve_piece_length(&mut cli, Err(VarError::NotUnicode("x".into())))
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains(PIECE_LENGTH_ENV), "{}", err);
+        // an invalid flag is not blamed on the environment
+        let err = resolve(&["--piece-length=41"], Some("41"))
+            .unwrap_err()
+            .to_string();
+        assert!(!err.contains(PIECE_LENGTH_ENV), "{}", err);
+
+        assert!(resolve(&[], None).unwrap().is_empty());
+        let err = resolve(&["--output=out", "--per-subdir"], None)
+            .unwrap_err()
+            .to_string();
+        assert_eq!(err, "--per-subdir requires --piece-length");
+        assert_eq!(
+            resolve(&["--output=out", "--per-subdir"], Some("16")).unwrap(),
+            vec![16]
+        );
+    }
+
+    #[test]
+    fn edit_add_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("root");
+        fs::create_dir(&root).unwrap();
+        fs::write(root.join("a"), vec![1u8; 40 << 10]).unwrap();
+        let extra = dir.path().join("extra");
+        fs::write(&extra, vec![2u8; 50 << 10]).unwrap();
+
+        let piece_length = PieceLength { layers: 0 };
+        let opts = HashOptions::new(piece_length);
+        let cli = Cli::parse_from(["mktorrent-rs", "--announce=", "--piece-length=14", "root"]);
+        let (t, _, _) = build_torrent(
+            &cli,
+            &opts,
+            "root".to_owned(),
+            &root,
+            get_file_list(&root).unwrap(),
+            false,
+        )
+        .unwrap();
+
+        // a key unknown to Info must survive
+        let mut raw = RawTorrent::decode(&t.to_bencode().unwrap()).unwrap();
+        raw.set_info_raw(b"private", b"i1e".to_vec()).unwrap();
+        let p = dir.path().join("root.torrent");
+        fs::write(&p, raw.encode()).unwrap();
+
+        let edited = edit(&p, &[], false, &[extra]).unwrap();
+        let decoded = Torrent::from_bencode(&edited).unwrap();
+        assert!(decoded.validate().is_empty());
+        assert_ne!(decoded.info.infohash().unwrap(), t.info.infohash().unwrap());
+
+        let (expected, layer) = hash_file(dir.path(), &opts, "extra", 50 << 10).unwrap();
+        let files: Vec<_> = decoded.files().into_iter().map(|(p, f)| (p, *f)).collect();
+        assert_eq!(files.len(), 2);
+        assert_eq!(files[1], ("extra".to_owned(), expected));
+        assert_eq!(decoded.piece_layers[&expected.pieces_root], layer);
+        assert_eq!(
+            RawTorrent::decode(&edited)
+                .unwrap()
+                .get_info(b"private")
+                .unwrap()
+                .unwrap(),
+            b"i1e"
+        );
+
+        // adding a file that is already there fails
+        let err = edit(&p, &[], false, &[root.join("a")]).unwrap_err();
+        assert_eq!(err.to_string(), "conflicting file: a: added more than once");
+    }
+
+    #[test]
+    fn edit_replace_announce() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("a"), "a").unwrap();
+        let p = dir.path().join("a.torrent");
+
+        let t = Torrent::from_entries(
+            "http://old.example.com".to_string(),
+            "a".to_string(),
+            PieceLength { layers: 0 },
+            hash_files(
+                &Cli::parse_from(["mktorrent-rs", "--announce=", "--piece-length=14", "a"]),
+                &HashOptions::new(PieceLength { layers: 0 }),
+                dir.path(),
+                get_file_list(dir.path()).unwrap(),
+                None,
+            )
+            .unwrap()
+            .0,
+        )
+        .unwrap();
+        write_torrent(Some(&p), &t, false).unwrap();
+
+        // put the old url in several tiers
+        let data = super::repair(
+            &p,
+            None,
+            vec![
+                "http://other.example.com".to_owned(),
+                "http://old.example.com".to_owned(),
+            ],
+        )
+        .unwrap();
+        fs::write(&p, data).unwrap();
+
+        let edited = edit(
+            &p,
+            &[parse_replacement("http://old.example.com=http://new.example.com").unwrap()],
+            false,
+            &[],
+        )
+        .unwrap();
+        let encoded = String::from_utf8_lossy(&edited).into_owned();
+        assert!(!encoded.contains("old.example.com"), "{}", encoded);
+        assert_eq!(encoded.matches("new.example.com").count(), 3, "{}", encoded);
+
+        let decoded = Torrent::from_bencode(&edited).unwrap();
+        assert_eq!(decoded.info.infohash().unwrap(), t.info.infohash().unwrap());
+
+        // equivalent urls collapse into the first tier they appear in
+        fs::write(&p, edited).unwrap();
+        let normalized = edit(
+            &p,
+            &[parse_replacement("http://other.example.com=HTTP://NEW.example.com:80/").unwrap()],
+            true,
+            &[],
+        )
+        .unwrap();
+        let raw = RawTorrent::decode(&normalized).unwrap();
+        assert_eq!(
+            Vec::<Vec<String>>::from_bencode(raw.get(b"announce-list").unwrap()).unwrap(),
+            vec![vec!["http://new.example.com/".to_owned()]]
+        );
+        let decoded = Torrent::from_bencode(&normalized).unwrap();
+        assert_eq!(decoded.announce, "http://new.example.com/");
+        assert_eq!(decoded.info.infohash().unwrap(), t.info.infohash().unwrap());
+
+        assert!(parse_replacement("no-equals").is_err());
+    }
+}
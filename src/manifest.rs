@@ -0,0 +1,270 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+
+use anyhow::{Context, Error, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::checksum::sha256;
+use crate::metainfo::{self, PieceLength, Torrent};
+
+// A JSON sidecar recording the hashes of every file in a torrent along with
+// the file metadata needed to tell if the file changed since.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Manifest {
+    pub piece_length: u64,
+    pub files: Vec<Entry>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Entry {
+    pub path: String,
+    // The length of the file in the torrent.
+    pub length: u64,
+    // The length of the file on disk if it differs from length, as for files
+    // hashed with --normalize-newlines.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub disk_length: Option<u64>,
+    // Modification time in nanoseconds since the unix epoch, if known.
+    pub mtime_ns: Option<u64>,
+    pub pieces_root: String,
+    #[serde(default)]
+    pub pieces_layer: Vec<String>,
+}
+
+// The files whose hashes differ between two manifests.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Delta {
+    pub added: Vec<Entry>,
+    pub changed: Vec<Entry>,
+    pub removed: Vec<String>,
+}
+
+impl Delta {
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let data = serde_json::to_vec_pretty(self)?;
+        fs::write(path, data).context(format!("failed to write `{}`", path.to_string_lossy()))
+    }
+}
+
+impl Manifest {
+    // Builds a manifest from a torrent. mtimes maps file paths to their
+    // modification times.
+    pub fn new(torrent: &Torrent, mtimes: &HashMap<String, u64>) -> Self {
+        let files = torrent
+            .files()
+            .into_iter()
+            .map(|(path, f)| Entry {
+                mtime_ns: mtimes.get(&path).copied(),
+                length: f.length,
+                disk_length: None,
+                pieces_root: f.pieces_root.to_string(),
+                pieces_layer: torrent
+                    .piece_layers
+                    .get(&f.pieces_root)
+                    .map(|l| l.iter().map(|d| d.to_string()).collect())
+                    .unwrap_or_default(),
+                path,
+            })
+            .collect();
+
+        Manifest {
+            piece_length: torrent.info.piece_length.bytes(),
+            files,
+        }
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let data =
+            fs::read(path).context(format!("failed to read `{}`", path.to_string_lossy()))?;
+        serde_json::from_slice(&data)
+            .context(format!("failed to parse `{}`", path.to_string_lossy()))
+    }
+
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let data = serde_json::to_vec_pretty(self)?;
+        fs::write(path, data).context(format!("failed to write `{}`", path.to_string_lossy()))
+    }
+
+    // Returns an error if the manifest was built with a different piece
+    // length. Cached hashes are only valid for the same piece length.
+    pub fn check_piece_length(&self, piece_length: PieceLength) -> Result<()> {
+        if self.piece_length != piece_length.bytes() {
+            return Err(Error::msg(format!(
+                "manifest piece length ({}) does not match --piece-length ({})",
+                self.piece_length,
+                piece_length.bytes()
+            )));
+        }
+
+        Ok(())
+    }
+
+    // Records the on-disk lengths in files, by path, of the entries whose
+    // length in the torrent differs.
+    pub fn with_disk_lengths(mut self, files: &[(String, u64)]) -> Self {
+        let lengths: HashMap<&str, u64> = files.iter().map(|(p, l)| (p.as_str(), *l)).collect();
+        for e in &mut self.files {
+            e.disk_length = lengths
+                .get(e.path.as_str())
+                .copied()
+                .filter(|&l| l != e.length);
+        }
+        self
+    }
+
+    // Returns an index of the entries by path to look up cached hashes in.
+    pub fn index(&self) -> Index<'_> {
+        Index(self.files.iter().map(|e| (e.path.as_str(), e)).collect())
+    }
+
+    // Returns the paths in files that are not in the manifest and the paths in
+    // the manifest that are not in files.
+    pub fn file_set_changes<'a>(
+        &'a self,
+        files: &'a [(String, u64)],
+    ) -> (Vec<&'a str>, Vec<&'a str>) {
+        let recorded: HashSet<&str> = self.files.iter().map(|e| e.path.as_str()).collect();
+        let current: HashSet<&str> = files.iter().map(|(p, _)| p.as_str()).collect();
+
+        let added = files
+            .iter()
+            .map(|(p, _)| p.as_str())
+            .filter(|p| !recorded.contains(p))
+            .collect();
+        let removed = self
+            .files
+            .iter()
+            .map(|e| e.path.as_str())
+            .filter(|p| !current.contains(p))
+            .collect();
+        (added, removed)
+    }
+
+    // Returns the files added, changed or removed since prev. A file changed
+    // if its pieces_root differs, which does not depend on the piece length,
+    // so prev may have been built with a different one.
+    pub fn delta(&self, prev: &Manifest) -> Delta {
+        let before: HashMap<&str, &Entry> =
+            prev.files.iter().map(|e| (e.path.as_str(), e)).collect();
+        let after: HashSet<&str> = self.files.iter().map(|e| e.path.as_str()).collect();
+
+        let mut delta = Delta::default();
+        for e in &self.files {
+            match before.get(e.path.as_str()) {
+                None => delta.added.push(e.clone()),
+                Some(p) if p.pieces_root != e.pieces_root => delta.changed.push(e.clone()),
+                Some(_) => {}
+            }
+        }
+        delta.removed = prev
+            .files
+            .iter()
+            .filter(|e| !after.contains(e.path.as_str()))
+            .map(|e| e.path.clone())
+            .collect();
+
+        delta
+    }
+}
+
+// A manifest's entries by path.
+pub struct Index<'a>(HashMap<&'a str, &'a Entry>);
+
+impl Index<'_> {
+    // Returns the cached hashes for the file at path if its length on disk and
+    // mtime match the manifest.
+    pub fn cached(
+        &self,
+        path: &str,
+        length: u64,
+        mtime_ns: Option<u64>,
+    ) -> Result<Option<(metainfo::File, Vec<sha256::Digest>)>> {
+        let e = match self.0.get(path) {
+            Some(e) => e,
+            None => return Ok(None),
+        };
+
+        let disk_length = e.disk_length.unwrap_or(e.length);
+        if disk_length != length || e.mtime_ns.is_none() || e.mtime_ns != mtime_ns {
+            return Ok(None);
+        }
+
+        let parse = |s: &str| {
+            s.parse::<sha256::Digest>()
+                .map_err(|e| Error::msg(format!("bad digest in manifest for {}: {}", path, e)))
+        };
+
+        let f = metainfo::File {
+            length: e.length,
+            pieces_root: parse(&e.pieces_root)?,
+            crc32: None,
+        };
+        let layer = e
+            .pieces_layer
+            .iter()
+            .map(|s| parse(s))
+            .collect::<Result<_>>()?;
+
+        Ok(Some((f, layer)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(path: &str, pieces_root: &str) -> Entry {
+        Entry {
+            path: path.to_owned(),
+            length: 1,
+            disk_length: None,
+            mtime_ns: None,
+            pieces_root: pieces_root.to_owned(),
+            pieces_layer: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn delta() {
+        let prev = Manifest {
+            piece_length: 16384,
+            files: vec![entry("a", "1"), entry("b", "2"), entry("c", "3")],
+        };
+        let next = Manifest {
+            piece_length: 32768,
+            files: vec![entry("a", "1"), entry("b", "4"), entry("d", "5")],
+        };
+
+        assert_eq!(
+            next.delta(&prev),
+            Delta {
+                added: vec![entry("d", "5")],
+                changed: vec![entry("b", "4")],
+                removed: vec!["c".to_owned()],
+            }
+        );
+        assert_eq!(next.delta(&next), Delta::default());
+    }
+
+    #[test]
+    fn cached_disk_length() {
+        let mut e = entry("a", &"aa".repeat(32));
+        e.length = 3;
+        e.mtime_ns = Some(1);
+        let m = Manifest {
+            piece_length: 16384,
+            files: vec![e, entry("b", "1")],
+        }
+        .with_disk_lengths(&[("a".to_owned(), 4), ("b".to_owned(), 1)]);
+        assert_eq!(m.files[0].disk_length, Some(4));
+        assert_eq!(m.files[1].disk_length, None);
+
+        // the file is looked up by its length on disk and keeps its length in
+        // the torrent
+        let index = m.index();
+        let (f, _) = index.cached("a", 4, Some(1)).unwrap().unwrap();
+        assert_eq!(f.length, 3);
+        assert!(index.cached("a", 3, Some(1)).unwrap().is_none());
+    }
+}
@@ -1,10 +1,18 @@
 extern crate ring;
 
-use std::collections::{hash_map::Entry, HashMap};
+pub mod raw;
+pub mod v1;
 
-use crate::checksum::sha256;
+use std::collections::HashMap;
+use std::fmt;
+use std::io::Write;
 
-use bendy::encoding::{AsString, Error, SingleItemEncoder, ToBencode};
+use crate::checksum::{merkle, sha256};
+
+use bendy::decoding::{self, FromBencode, Object};
+use bendy::encoding::{AsString, Error, SingleItemEncoder, SortedDictEncoder, ToBencode};
+use indexmap::{map::Entry, IndexMap};
+use serde::Serialize;
 
 const META_VERSION: u8 = 2;
 // Arbitrary maximum depth for a path to protect against bad torrent files.
@@ -13,22 +21,76 @@ pub const MAX_FILE_PATH_DEPTH: usize = 20;
 // A Torrent metainfo file defined in bep_0052.
 #[derive(Clone, Debug)]
 pub struct Torrent {
+    // Omitted when empty, for a trackerless torrent.
     pub announce: String,
+    // Tiers of announce urls as defined in bep_0012. Omitted when empty.
+    pub announce_list: Vec<Vec<String>>,
+    pub comment: Option<String>,
+    pub created_by: Option<String>,
+    // Unix time in seconds.
+    pub creation_date: Option<i64>,
+    // HTTP seed URLs as defined in bep_0017. Omitted when empty.
+    pub httpseeds: Vec<String>,
     pub info: Info,
+    // DHT bootstrap nodes as host and port, defined in bep_0005. Omitted when
+    // empty.
+    pub nodes: Vec<(String, u16)>,
     pub piece_layers: HashMap<sha256::Digest, Vec<sha256::Digest>>,
+    // Web seed URLs as defined in bep_0019. Omitted when empty and encoded as
+    // a single string when there is only one.
+    pub url_list: Vec<String>,
+    // List files in the order they were added instead of sorted by path. The
+    // v2 file tree is always encoded sorted since bencode dictionaries must
+    // be, so this only affects order sensitive consumers of files().
+    pub preserve_order: bool,
+}
+
+// A hashed file that has not been added to a torrent yet.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct FileEntry {
+    pub path: String,
+    pub file: File,
+    pub pieces_layer: Vec<sha256::Digest>,
 }
 
 impl Torrent {
     pub fn new(announce: String, name: String, piece_length: PieceLength) -> Self {
         Torrent {
             announce,
+            announce_list: Vec::new(),
+            comment: None,
+            created_by: None,
+            creation_date: None,
+            httpseeds: Vec::new(),
             info: Info {
                 name,
                 piece_length,
                 file_tree: Directory::default(),
+                private: false,
+                source: None,
             },
+            nodes: Vec::new(),
             piece_layers: HashMap::new(),
+            url_list: Vec::new(),
+            preserve_order: false,
+        }
+    }
+
+    // Builds a torrent from hashed files. If two entries conflict, the path of
+    // the conflicting entry and the reason are returned as the error.
+    pub fn from_entries(
+        announce: String,
+        name: String,
+        piece_length: PieceLength,
+        entries: impl IntoIterator<Item = FileEntry>,
+    ) -> Result<Self, String> {
+        let mut t = Torrent::new(announce, name, piece_length);
+        for e in entries {
+            if !t.add_file(&e.path, e.file, e.pieces_layer) {
+                return Err(format!("{}: {}", e.path, t.conflict(&e.path)));
+            }
         }
+        Ok(t)
     }
 
     // Adds a file to the torrent. If the file already exists or the path is
@@ -70,6 +132,259 @@ impl Torrent {
 
         true
     }
+
+    // Describes why add_file rejected path: a leading component is already a
+    // file, such as a directory prefix named like an existing file, or the
+    // path itself is already in the tree.
+    pub fn conflict(&self, path: &str) -> String {
+        let mut dir = &self.info.file_tree;
+        let mut components = path.split('/').peekable();
+        let mut prefix = String::new();
+
+        while let Some(c) = components.next() {
+            if !prefix.is_empty() {
+                prefix.push('/');
+            }
+            prefix.push_str(c);
+
+            let last = components.peek().is_none();
+            match dir.entries.get(c) {
+                Some(PathElement::File(_)) if last => return "added more than once".to_owned(),
+                Some(PathElement::File(_)) => {
+                    return format!("`{}` is a file and cannot also be a directory", prefix)
+                }
+                Some(PathElement::Directory(_)) if last => {
+                    return "a directory with the same path already exists".to_owned()
+                }
+                Some(PathElement::Directory(d)) => dir = d,
+                None => break,
+            }
+        }
+
+        "conflicting file".to_owned()
+    }
+
+    // Returns every file in the torrent with its full path. Files are sorted
+    // by path unless preserve_order is set, in which case directories are
+    // visited in the order their first file was added.
+    pub fn files(&self) -> Vec<(String, &File)> {
+        let mut ret = Vec::new();
+        self.info
+            .file_tree
+            .collect_files("", self.preserve_order, &mut ret);
+        ret
+    }
+
+    // Adds a file whose hashes were computed elsewhere. pieces_layer must
+    // match the file's length and pieces root, and may only be empty if the
+    // file fits in a single piece.
+    pub fn add_precomputed(
+        &mut self,
+        path: &str,
+        f: File,
+        pieces_layer: Vec<sha256::Digest>,
+    ) -> Result<(), String> {
+        if pieces_layer.is_empty() && f.length > self.info.piece_length.bytes() {
+            return Err("missing piece layer".to_owned());
+        }
+        if !pieces_layer.is_empty() {
+            if let Some((_, detail)) = layer_problem(self.info.piece_length, &f, &pieces_layer) {
+                return Err(detail);
+            }
+        }
+
+        if !self.add_file(path, f, pieces_layer) {
+            return Err(self.conflict(path));
+        }
+        Ok(())
+    }
+
+    // Returns the merkle root of every file's pieces root, in path order. It
+    // is not an infohash. Paths are not hashed, so it only changes when file
+    // content changes or files are renamed into a different order.
+    pub fn content_id(&self) -> sha256::Digest {
+        let mut files = self.files();
+        files.sort_unstable_by(|(a, _), (b, _)| a.cmp(b));
+        merkle::root_hash_owned(0, files.into_iter().map(|(_, f)| f.pieces_root))
+    }
+
+    // Encodes the torrent and returns it along with its infohash. The info
+    // dictionary is encoded once and hashed before being spliced between the
+    // keys that sort around it, rather than being encoded a second time as
+    // Info::infohash does.
+    pub fn encode_with_infohash(&self) -> Result<(Vec<u8>, sha256::Digest), Error> {
+        let info = self.info.to_bencode()?;
+        let mut h = sha256::Hasher::default();
+        h.write_all(&info).unwrap();
+
+        // Both parts are encoded as dictionaries of their own, so the closing
+        // e of the head and the opening d of the tail are dropped.
+        let head = TorrentPart(self, Part::Head).to_bencode()?;
+        let tail = TorrentPart(self, Part::Tail).to_bencode()?;
+        let mut data = Vec::with_capacity(head.len() + b"4:info".len() + info.len() + tail.len());
+        data.extend_from_slice(&head[..head.len() - 1]);
+        data.extend_from_slice(b"4:info");
+        data.extend_from_slice(&info);
+        data.extend_from_slice(&tail[1..]);
+        Ok((data, h.into_digest()))
+    }
+
+    // Checks that every file with more than one piece has a piece layer with
+    // one hash per piece whose merkle root is the file's pieces root.
+    pub fn validate(&self) -> Vec<Problem> {
+        let piece_length = self.info.piece_length;
+        let mut ret = Vec::new();
+
+        for (path, f) in self.files() {
+            if f.length <= piece_length.bytes() {
+                continue;
+            }
+
+            let problem = match self.piece_layers.get(&f.pieces_root) {
+                Some(layer) => layer_problem(piece_length, f, layer),
+                None => Some((
+                    ProblemKind::MissingLayer,
+                    format!("no piece layer for pieces root {}", f.pieces_root),
+                )),
+            };
+
+            if let Some((kind, detail)) = problem {
+                ret.push(Problem {
+                    file: path,
+                    kind,
+                    detail,
+                });
+            }
+        }
+
+        ret
+    }
+
+    // Emits the keys that sort before info.
+    fn emit_head(&self, e: &mut SortedDictEncoder) -> Result<(), Error> {
+        if !self.announce.is_empty() {
+            e.emit_pair(b"announce", &self.announce)?;
+        }
+        if !self.announce_list.is_empty() {
+            e.emit_pair(b"announce-list", &self.announce_list)?;
+        }
+        if let Some(comment) = &self.comment {
+            e.emit_pair(b"comment", comment)?;
+        }
+        if let Some(created_by) = &self.created_by {
+            e.emit_pair(b"created by", created_by)?;
+        }
+        if let Some(date) = self.creation_date {
+            e.emit_pair(b"creation date", date)?;
+        }
+        if !self.httpseeds.is_empty() {
+            e.emit_pair(b"httpseeds", &self.httpseeds)?;
+        }
+        Ok(())
+    }
+
+    // Emits the keys that sort after info.
+    fn emit_tail(&self, e: &mut SortedDictEncoder) -> Result<(), Error> {
+        if !self.nodes.is_empty() {
+            e.emit_pair_with(b"nodes", |e| {
+                e.emit_list(|e| {
+                    for (host, port) in &self.nodes {
+                        e.emit_with(|e| {
+                            e.emit_list(|e| {
+                                e.emit_str(host)?;
+                                e.emit_int(*port)
+                            })
+                        })?;
+                    }
+                    Ok(())
+                })
+            })?;
+        }
+        e.emit_pair_with(b"piece layers", |e| {
+            e.emit_dict(|mut e| {
+                // Sort layers to emit them in order.
+                let mut layers: Vec<_> = self.piece_layers.iter().collect();
+                layers.sort_unstable_by_key(|&(k, _)| k);
+
+                let max_len = layers.iter().map(|&(_, v)| v.len()).max().unwrap_or(0);
+                let mut buf = Vec::with_capacity(max_len * sha256::Digest::LENGTH);
+
+                for (k, v) in layers {
+                    if v.is_empty() {
+                        continue;
+                    }
+
+                    buf.truncate(0);
+                    v.iter().for_each(|s| buf.extend_from_slice(s.as_ref()));
+                    e.emit_pair(k.as_ref(), AsString(&buf))?;
+                }
+                Ok(())
+            })
+        })?;
+        match self.url_list.as_slice() {
+            [] => {}
+            [url] => e.emit_pair(b"url-list", url)?,
+            _ => e.emit_pair(b"url-list", &self.url_list)?,
+        }
+        Ok(())
+    }
+}
+
+// Checks that layer has one hash per piece of f and hashes to its pieces root.
+fn layer_problem(
+    piece_length: PieceLength,
+    f: &File,
+    layer: &[sha256::Digest],
+) -> Option<(ProblemKind, String)> {
+    let expected = f.length.div_ceil(piece_length.bytes());
+    if layer.len() as u64 != expected {
+        return Some((
+            ProblemKind::SizeMismatch,
+            format!("expected {} piece hashes, found {}", expected, layer.len()),
+        ));
+    }
+
+    let root = merkle::root_hash(piece_length.layers, layer);
+    if root != f.pieces_root {
+        return Some((
+            ProblemKind::RootMismatch,
+            format!("piece layer hashes to {}, expected {}", root, f.pieces_root),
+        ));
+    }
+
+    None
+}
+
+// An inconsistency between a torrent's file tree and piece layers.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Problem {
+    pub file: String,
+    pub kind: ProblemKind,
+    pub detail: String,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}: {}", self.file, self.kind, self.detail)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProblemKind {
+    MissingLayer,
+    SizeMismatch,
+    RootMismatch,
+}
+
+impl fmt::Display for ProblemKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ProblemKind::MissingLayer => "missing piece layer",
+            ProblemKind::SizeMismatch => "piece layer size mismatch",
+            ProblemKind::RootMismatch => "pieces root mismatch",
+        })
+    }
 }
 
 impl ToBencode for Torrent {
@@ -77,38 +392,210 @@ impl ToBencode for Torrent {
 
     fn encode(&self, encoder: SingleItemEncoder) -> Result<(), Error> {
         encoder.emit_dict(|mut e| {
-            e.emit_pair(b"announce", &self.announce)?;
+            self.emit_head(&mut e)?;
             e.emit_pair(b"info", &self.info)?;
-            e.emit_pair_with(b"piece layers", |e| {
-                e.emit_dict(|mut e| {
-                    // Sort layers to emit them in order.
-                    let mut layers: Vec<_> = self.piece_layers.iter().collect();
-                    layers.sort_unstable_by_key(|&(k, _)| k);
+            self.emit_tail(&mut e)
+        })
+    }
+}
 
-                    let max_len = layers.iter().map(|&(_, v)| v.len()).max().unwrap_or(0);
-                    let mut buf = Vec::with_capacity(max_len * sha256::Digest::LENGTH);
+#[derive(Clone, Copy)]
+enum Part {
+    Head,
+    Tail,
+}
 
-                    for (k, v) in layers {
-                        if v.is_empty() {
-                            continue;
-                        }
+// Encodes the keys of a torrent on one side of info as a dictionary, for
+// Torrent::encode_with_infohash.
+struct TorrentPart<'a>(&'a Torrent, Part);
 
-                        buf.truncate(0);
-                        v.iter().for_each(|s| buf.extend_from_slice(s.as_ref()));
-                        e.emit_pair(k.as_ref(), AsString(&buf))?;
-                    }
-                    Ok(())
-                })
-            })
+impl ToBencode for TorrentPart<'_> {
+    const MAX_DEPTH: usize = Torrent::MAX_DEPTH;
+
+    fn encode(&self, encoder: SingleItemEncoder) -> Result<(), Error> {
+        encoder.emit_dict(|mut e| match self.1 {
+            Part::Head => self.0.emit_head(&mut e),
+            Part::Tail => self.0.emit_tail(&mut e),
         })
     }
 }
 
+impl FromBencode for Torrent {
+    const EXPECTED_RECURSION_DEPTH: usize = <Self as ToBencode>::MAX_DEPTH;
+
+    fn decode_bencode_object(object: Object) -> Result<Self, decoding::Error> {
+        let mut announce = None;
+        let mut announce_list = Vec::new();
+        let mut comment = None;
+        let mut created_by = None;
+        let mut creation_date = None;
+        let mut httpseeds = Vec::new();
+        let mut info = None;
+        let mut nodes = Vec::new();
+        let mut piece_layers = HashMap::new();
+        let mut url_list = Vec::new();
+
+        let mut dict = object.try_into_dictionary()?;
+        while let Some((k, v)) = dict.next_pair()? {
+            match k {
+                b"announce" => {
+                    announce =
+                        Some(String::decode_bencode_object(v).map_err(|e| e.context("announce"))?)
+                }
+                b"announce-list" => {
+                    announce_list = Vec::<Vec<String>>::decode_bencode_object(v)
+                        .map_err(|e| e.context("announce-list"))?
+                }
+                b"comment" => {
+                    comment =
+                        Some(String::decode_bencode_object(v).map_err(|e| e.context("comment"))?)
+                }
+                b"created by" => {
+                    created_by = Some(
+                        String::decode_bencode_object(v).map_err(|e| e.context("created by"))?,
+                    )
+                }
+                b"creation date" => {
+                    creation_date = Some(
+                        i64::decode_bencode_object(v).map_err(|e| e.context("creation date"))?,
+                    )
+                }
+                b"httpseeds" => {
+                    httpseeds = Vec::<String>::decode_bencode_object(v)
+                        .map_err(|e| e.context("httpseeds"))?
+                }
+                b"info" => {
+                    info = Some(Info::decode_bencode_object(v).map_err(|e| e.context("info"))?)
+                }
+                b"nodes" => nodes = decode_nodes(v).map_err(|e| e.context("nodes"))?,
+                b"piece layers" => {
+                    piece_layers = decode_piece_layers(v).map_err(|e| e.context("piece layers"))?
+                }
+                b"url-list" => url_list = decode_url_list(v).map_err(|e| e.context("url-list"))?,
+                _ => {}
+            }
+        }
+
+        Ok(Torrent {
+            announce: announce.unwrap_or_default(),
+            announce_list,
+            comment,
+            created_by,
+            creation_date,
+            httpseeds,
+            info: info.ok_or_else(|| decoding::Error::missing_field("info"))?,
+            nodes,
+            piece_layers,
+            url_list,
+            preserve_order: false,
+        })
+    }
+}
+
+// Decodes url-list, which is either a single url or a list of them.
+fn decode_url_list(object: Object) -> Result<Vec<String>, decoding::Error> {
+    match object {
+        o @ Object::Bytes(_) => Ok(vec![String::decode_bencode_object(o)?]),
+        o => Vec::<String>::decode_bencode_object(o),
+    }
+}
+
+// Decodes nodes, a list of [host, port] lists.
+fn decode_nodes(object: Object) -> Result<Vec<(String, u16)>, decoding::Error> {
+    let mut ret = Vec::new();
+
+    let mut list = object.try_into_list()?;
+    while let Some(node) = list.next_object()? {
+        let mut node = node.try_into_list()?;
+        let host = match node.next_object()? {
+            Some(o) => String::decode_bencode_object(o)?,
+            None => return Err(decoding::Error::missing_field("host")),
+        };
+        let port = match node.next_object()? {
+            Some(o) => u16::decode_bencode_object(o)?,
+            None => return Err(decoding::Error::missing_field("port")),
+        };
+        ret.push((host, port));
+    }
+    Ok(ret)
+}
+
+// Decodes the piece layers dictionary which maps a pieces root to the
+// concatenated hashes of its pieces.
+fn decode_piece_layers(
+    object: Object,
+) -> Result<HashMap<sha256::Digest, Vec<sha256::Digest>>, decoding::Error> {
+    let mut ret = HashMap::new();
+
+    let mut dict = object.try_into_dictionary()?;
+    while let Some((k, v)) = dict.next_pair()? {
+        let root = decode_digest(k)?;
+        let hashes = v.try_into_bytes()?;
+        if hashes.len() % sha256::Digest::LENGTH != 0 {
+            return Err(decoding::Error::malformed_content(InvalidLength(
+                hashes.len(),
+            )));
+        }
+
+        let layer = hashes
+            .chunks_exact(sha256::Digest::LENGTH)
+            .map(|c| decode_digest(c).unwrap())
+            .collect();
+        ret.insert(root, layer);
+    }
+
+    Ok(ret)
+}
+
+// Converts bytes to a digest, failing if it has the wrong length.
+fn decode_digest(b: &[u8]) -> Result<sha256::Digest, decoding::Error> {
+    let a: [u8; sha256::Digest::LENGTH] = b
+        .try_into()
+        .map_err(|_| decoding::Error::malformed_content(InvalidLength(b.len())))?;
+    Ok(a.into())
+}
+
+// A byte string that should contain digests had the wrong length.
+#[derive(Debug)]
+struct InvalidLength(usize);
+
+impl fmt::Display for InvalidLength {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid digest length: {}", self.0)
+    }
+}
+
+impl std::error::Error for InvalidLength {}
+
 #[derive(Clone, Debug)]
 pub struct Info {
     pub name: String,
     pub piece_length: PieceLength,
     pub file_tree: Directory,
+    // Set for torrents of private trackers as defined in bep_0027.
+    pub private: bool,
+    // Changes the infohash so the same content gets a distinct torrent for
+    // each tracker.
+    pub source: Option<String>,
+}
+
+impl Info {
+    // Returns the v2 infohash: the SHA-256 of the bencoded info dictionary.
+    pub fn infohash(&self) -> Result<sha256::Digest, Error> {
+        let mut h = sha256::Hasher::default();
+        h.update(&self.to_bencode()?);
+        Ok(h.into_digest())
+    }
+
+    // Returns the infohash the torrent would have with an empty name. Torrents
+    // of the same content have the same fingerprint whatever they are named.
+    pub fn content_fingerprint(&self) -> Result<sha256::Digest, Error> {
+        Info {
+            name: String::new(),
+            ..self.clone()
+        }
+        .infohash()
+    }
 }
 
 impl ToBencode for Info {
@@ -119,11 +606,92 @@ impl ToBencode for Info {
             e.emit_pair(b"file tree", &self.file_tree)?;
             e.emit_pair(b"meta version", META_VERSION)?;
             e.emit_pair(b"name", &self.name)?;
-            e.emit_pair(b"piece length", self.piece_length.bytes())
+            e.emit_pair(b"piece length", self.piece_length.bytes())?;
+            // Omitted when unset so public torrents keep their layout.
+            if self.private {
+                e.emit_pair(b"private", 1)?;
+            }
+            if let Some(source) = &self.source {
+                e.emit_pair(b"source", source)?;
+            }
+            Ok(())
+        })
+    }
+}
+
+impl FromBencode for Info {
+    const EXPECTED_RECURSION_DEPTH: usize = <Self as ToBencode>::MAX_DEPTH;
+
+    fn decode_bencode_object(object: Object) -> Result<Self, decoding::Error> {
+        let mut file_tree = None;
+        let mut meta_version = None;
+        let mut name = None;
+        let mut piece_length = None;
+        let mut private = false;
+        let mut source = None;
+
+        let mut dict = object.try_into_dictionary()?;
+        while let Some((k, v)) = dict.next_pair()? {
+            match k {
+                b"file tree" => {
+                    file_tree = Some(
+                        Directory::decode_bencode_object(v).map_err(|e| e.context("file tree"))?,
+                    )
+                }
+                b"meta version" => meta_version = Some(u8::decode_bencode_object(v)?),
+                b"name" => name = Some(String::decode_bencode_object(v)?),
+                b"piece length" => piece_length = Some(u64::decode_bencode_object(v)?),
+                b"private" => private = u8::decode_bencode_object(v)? == 1,
+                b"source" => source = Some(String::decode_bencode_object(v)?),
+                _ => {}
+            }
+        }
+
+        match meta_version {
+            Some(META_VERSION) => {}
+            Some(v) => return Err(decoding::Error::malformed_content(UnsupportedVersion(v))),
+            None => return Err(decoding::Error::missing_field("meta version")),
+        }
+
+        let piece_length =
+            piece_length.ok_or_else(|| decoding::Error::missing_field("piece length"))?;
+
+        Ok(Info {
+            name: name.ok_or_else(|| decoding::Error::missing_field("name"))?,
+            piece_length: PieceLength::from_bytes(piece_length).ok_or_else(|| {
+                decoding::Error::malformed_content(InvalidPieceLength(piece_length))
+            })?,
+            file_tree: file_tree.ok_or_else(|| decoding::Error::missing_field("file tree"))?,
+            private,
+            source,
         })
     }
 }
 
+// The torrent has a meta version other than 2.
+#[derive(Debug)]
+struct UnsupportedVersion(u8);
+
+impl fmt::Display for UnsupportedVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "unsupported meta version: {}", self.0)
+    }
+}
+
+impl std::error::Error for UnsupportedVersion {}
+
+// The piece length is not a power of two of at least 16KiB.
+#[derive(Debug)]
+struct InvalidPieceLength(u64);
+
+impl fmt::Display for InvalidPieceLength {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid piece length: {}", self.0)
+    }
+}
+
+impl std::error::Error for InvalidPieceLength {}
+
 #[derive(Clone, Debug)]
 pub enum PathElement {
     Directory(Directory),
@@ -165,13 +733,44 @@ impl ToBencode for PathElement {
 
 #[derive(Clone, Debug, Default)]
 pub struct Directory {
-    pub entries: HashMap<String, PathElement>,
+    // Entries are kept in insertion order but always sorted when encoded, as
+    // bencode dictionaries must be, so their order never changes the v2
+    // infohash. Torrent::preserve_order only changes the order of
+    // Torrent::files().
+    pub entries: IndexMap<String, PathElement>,
 }
 
 impl Directory {
     fn get_entry(&mut self, name: String) -> Entry<'_, String, PathElement> {
         self.entries.entry(name)
     }
+
+    // Appends all files under this directory to out, prefixing their paths
+    // with prefix. Entries are visited in sorted order unless in_order is set.
+    fn collect_files<'a>(
+        &'a self,
+        prefix: &str,
+        in_order: bool,
+        out: &mut Vec<(String, &'a File)>,
+    ) {
+        let mut entries: Vec<_> = self.entries.iter().collect();
+        if !in_order {
+            entries.sort_unstable_by_key(|&(k, _)| k);
+        }
+
+        for (k, v) in entries {
+            let path = if prefix.is_empty() {
+                k.clone()
+            } else {
+                format!("{}/{}", prefix, k)
+            };
+
+            match v {
+                PathElement::Directory(d) => d.collect_files(&path, in_order, out),
+                PathElement::File(f) => out.push((path, f)),
+            }
+        }
+    }
 }
 
 impl ToBencode for Directory {
@@ -191,10 +790,59 @@ impl ToBencode for Directory {
     }
 }
 
+impl FromBencode for Directory {
+    const EXPECTED_RECURSION_DEPTH: usize = <Self as ToBencode>::MAX_DEPTH;
+
+    fn decode_bencode_object(object: Object) -> Result<Self, decoding::Error> {
+        let mut entries = IndexMap::new();
+
+        let mut dict = object.try_into_dictionary()?;
+        while let Some((k, v)) = dict.next_pair()? {
+            let name = String::from_utf8(k.to_vec())?;
+            let e = decode_path_element(v).map_err(|e| e.context(&name))?;
+            entries.insert(name, e);
+        }
+
+        Ok(Directory { entries })
+    }
+}
+
+// Decodes either a file, which is a dictionary with the single key "", or a
+// directory.
+fn decode_path_element(object: Object) -> Result<PathElement, decoding::Error> {
+    let mut file = None;
+    let mut entries = IndexMap::new();
+
+    let mut dict = object.try_into_dictionary()?;
+    while let Some((k, v)) = dict.next_pair()? {
+        if file.is_some() {
+            return Err(decoding::Error::unexpected_field("entry next to file"));
+        }
+
+        if k.is_empty() {
+            // "" sorts first so this is the first key of a file.
+            file = Some(File::decode_bencode_object(v)?);
+            continue;
+        }
+
+        let name = String::from_utf8(k.to_vec())?;
+        let e = decode_path_element(v).map_err(|e| e.context(&name))?;
+        entries.insert(name, e);
+    }
+
+    Ok(match file {
+        Some(f) => PathElement::File(f),
+        None => PathElement::Directory(Directory { entries }),
+    })
+}
+
 #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
 pub struct File {
     pub length: u64,
     pub pieces_root: sha256::Digest,
+    // A CRC32 of the content stored under the non-standard "crc32" key. It is
+    // part of info so it changes the infohash.
+    pub crc32: Option<u32>,
 }
 
 impl ToBencode for File {
@@ -206,6 +854,9 @@ impl ToBencode for File {
         encoder.emit_dict(|mut e| {
             e.emit_pair_with(b"", |e| {
                 e.emit_dict(|mut e| {
+                    if let Some(crc) = self.crc32 {
+                        e.emit_pair(b"crc32", crc)?;
+                    }
                     e.emit_pair(b"length", self.length)?;
                     if self.length != 0 {
                         e.emit_pair(b"pieces root", AsString(self.pieces_root.as_ref()))?;
@@ -217,6 +868,38 @@ impl ToBencode for File {
     }
 }
 
+// Decodes the file information stored under a file's "" key.
+impl FromBencode for File {
+    const EXPECTED_RECURSION_DEPTH: usize = 1;
+
+    fn decode_bencode_object(object: Object) -> Result<Self, decoding::Error> {
+        let mut crc32 = None;
+        let mut length = None;
+        let mut pieces_root = None;
+
+        let mut dict = object.try_into_dictionary()?;
+        while let Some((k, v)) = dict.next_pair()? {
+            match k {
+                b"crc32" => crc32 = Some(u32::decode_bencode_object(v)?),
+                b"length" => length = Some(u64::decode_bencode_object(v)?),
+                b"pieces root" => pieces_root = Some(decode_digest(v.try_into_bytes()?)?),
+                _ => {}
+            }
+        }
+
+        let length = length.ok_or_else(|| decoding::Error::missing_field("length"))?;
+        if length != 0 && pieces_root.is_none() {
+            return Err(decoding::Error::missing_field("pieces root"));
+        }
+
+        Ok(File {
+            length,
+            pieces_root: pieces_root.unwrap_or_default(),
+            crc32,
+        })
+    }
+}
+
 // The piece length of a v2 torrent. It is measured in number of layers in the
 // merkle tree.
 #[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
@@ -227,7 +910,6 @@ pub struct PieceLength {
 impl PieceLength {
     // Takes a number of bytes and returns a piece length. Return None if an
     // invalid n is used. n must be a power of two greater than 16KiB (2^14).
-    #[allow(dead_code)]
     pub fn from_bytes(n: u64) -> Option<Self> {
         let layers = log2(n).filter(|&n| n >= 14).map(|n| n - 14)?;
         Some(PieceLength { layers })
@@ -236,6 +918,17 @@ impl PieceLength {
     pub fn bytes(&self) -> u64 {
         1 << (self.layers as u64 + 14)
     }
+
+    // Returns the smallest piece length that splits total bytes of content
+    // into at most 2000 pieces, which is at least 1000 pieces unless clamped
+    // to 2^15 to 2^24 bytes. 2^14 is never picked since many clients reject
+    // it.
+    pub fn for_content(total: u64) -> Self {
+        let layers = (1..10)
+            .find(|&layers| total.div_ceil(PieceLength { layers }.bytes()) <= 2000)
+            .unwrap_or(10);
+        PieceLength { layers }
+    }
 }
 
 // Returns log2 of the number if an only if it is a perfect power of 2.
@@ -276,7 +969,8 @@ mod tests {
     fn file_encode() {
         let f = File {
             length: 1024,
-            pieces_root: ['a' as u8; 32].into(),
+            pieces_root: [b'a'; 32].into(),
+            crc32: None,
         };
 
         assert_eq!(
@@ -289,35 +983,73 @@ mod tests {
     fn file_encode_zerolen() {
         let f = File {
             length: 0,
-            pieces_root: ['a' as u8; 32].into(),
+            pieces_root: [b'a'; 32].into(),
+            crc32: None,
         };
 
         assert_eq!(to_bencode_str(f), "d0:d6:lengthi0eee",);
     }
 
+    #[test]
+    fn file_encode_crc32() {
+        let f = File {
+            length: 9,
+            pieces_root: [b'a'; 32].into(),
+            crc32: Some(0xcbf43926),
+        };
+
+        // crc32 sorts before length
+        let encoded = to_bencode_str(f);
+        assert_eq!(
+            encoded,
+            "d0:d5:crc32i3421780262e6:lengthi9e11:pieces root32:aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaee",
+        );
+
+        let mut decoder = bendy::decoding::Decoder::new(encoded.as_bytes());
+        let mut dict = decoder
+            .next_object()
+            .unwrap()
+            .unwrap()
+            .try_into_dictionary()
+            .unwrap();
+        let (_, v) = dict.next_pair().unwrap().unwrap();
+        assert_eq!(File::decode_bencode_object(v).unwrap(), f);
+    }
+
     #[test]
     fn torrent_encode_maxdepth() {
         let mut t = Torrent {
             announce: "http://announce.example.com:8080".to_string(),
+            announce_list: Vec::new(),
+            comment: None,
+            created_by: None,
+            creation_date: None,
+            httpseeds: Vec::new(),
             info: Info {
                 name: "my display name".to_string(),
                 piece_length: PieceLength { layers: 0 },
                 file_tree: Directory {
-                    entries: HashMap::new(),
+                    entries: IndexMap::new(),
                 },
+                private: false,
+                source: None,
             },
+            nodes: Vec::new(),
             piece_layers: HashMap::new(),
+            url_list: Vec::new(),
+            preserve_order: false,
         };
 
         let f = File {
             length: 0,
-            pieces_root: ['a' as u8; 32].into(),
+            pieces_root: [b'a'; 32].into(),
+            crc32: None,
         };
 
         let mut p = PathElement::File(f);
         for _ in 0..MAX_FILE_PATH_DEPTH {
             p = PathElement::Directory(Directory {
-                entries: HashMap::from([("a_dir".to_owned(), p)]),
+                entries: IndexMap::from([("a_dir".to_owned(), p)]),
             });
         }
 
@@ -329,7 +1061,7 @@ mod tests {
         t.to_bencode().unwrap();
 
         p = PathElement::Directory(Directory {
-            entries: HashMap::from([("a_dir".to_owned(), p)]),
+            entries: IndexMap::from([("a_dir".to_owned(), p)]),
         });
 
         // Test that 1 past max depth is NestingTooDeep. Due to StructureError
@@ -347,29 +1079,32 @@ mod tests {
     #[test]
     fn directory_encode() {
         let d = Directory {
-            entries: HashMap::from([
+            entries: IndexMap::from([
                 (
                     "file1".to_owned(),
                     PathElement::File(File {
                         length: 1024,
-                        pieces_root: ['a' as u8; 32].into(),
+                        pieces_root: [b'a'; 32].into(),
+                        crc32: None,
                     }),
                 ),
                 (
                     "file2".to_owned(),
                     PathElement::File(File {
                         length: 0,
-                        pieces_root: ['b' as u8; 32].into(),
+                        pieces_root: [b'b'; 32].into(),
+                        crc32: None,
                     }),
                 ),
                 (
                     "dir1".to_owned(),
                     PathElement::Directory(Directory {
-                        entries: HashMap::from([(
+                        entries: IndexMap::from([(
                             "file3".to_owned(),
                             PathElement::File(File {
                                 length: 0,
-                                pieces_root: ['b' as u8; 32].into(),
+                                pieces_root: [b'b'; 32].into(),
+                                crc32: None,
                             }),
                         )]),
                     }),
@@ -387,23 +1122,34 @@ mod tests {
     fn torrent_encode() {
         let t = Torrent {
             announce: "http://announce.example.com:8080".to_string(),
+            announce_list: Vec::new(),
+            comment: None,
+            created_by: None,
+            creation_date: None,
+            httpseeds: Vec::new(),
             info: Info {
                 name: "my display name".to_string(),
                 piece_length: PieceLength { layers: 5 },
                 file_tree: Directory {
-                    entries: HashMap::from([(
+                    entries: IndexMap::from([(
                         "file1".to_owned(),
                         PathElement::File(File {
                             length: 1024,
-                            pieces_root: ['a' as u8; 32].into(),
+                            pieces_root: [b'a'; 32].into(),
+                            crc32: None,
                         }),
                     )]),
                 },
+                private: false,
+                source: None,
             },
+            nodes: Vec::new(),
             piece_layers: HashMap::from([(
-                ['a' as u8; 32].into(),
-                vec![['b' as u8; 32].into(), ['c' as u8; 32].into()],
+                [b'a'; 32].into(),
+                vec![[b'b'; 32].into(), [b'c'; 32].into()],
             )]),
+            url_list: Vec::new(),
+            preserve_order: false,
         };
 
         assert_eq!(
@@ -412,6 +1158,366 @@ mod tests {
         );
     }
 
+    #[test]
+    fn torrent_encode_announce_list() {
+        let mut t = Torrent::new(
+            "http://b".to_string(),
+            "n".to_string(),
+            PieceLength { layers: 0 },
+        );
+        t.announce_list = vec![
+            vec!["http://b".to_owned(), "http://a".to_owned()],
+            vec!["http://c".to_owned()],
+        ];
+
+        // urls keep their order within each tier
+        let encoded = to_bencode_str(&t);
+        assert_eq!(
+            encoded,
+            "d8:announce8:http://b13:announce-listll8:http://b8:http://ael8:http://cee\
+             4:infod9:file treede12:meta versioni2e4:name1:n12:piece lengthi16384ee\
+             12:piece layersdee"
+        );
+        assert_eq!(
+            Torrent::from_bencode(encoded.as_bytes())
+                .unwrap()
+                .announce_list,
+            t.announce_list
+        );
+    }
+
+    #[test]
+    fn torrent_encode_comment() {
+        let mut t = Torrent::new(
+            "http://a".to_string(),
+            "n".to_string(),
+            PieceLength { layers: 0 },
+        );
+        t.announce_list = vec![vec!["http://a".to_owned()]];
+        t.comment = Some("hello".to_owned());
+
+        // comment is a top level key that sorts after announce-list
+        let encoded = to_bencode_str(&t);
+        assert_eq!(
+            encoded,
+            "d8:announce8:http://a13:announce-listll8:http://aee7:comment5:hello\
+             4:infod9:file treede12:meta versioni2e4:name1:n12:piece lengthi16384ee\
+             12:piece layersdee"
+        );
+        assert_eq!(
+            Torrent::from_bencode(encoded.as_bytes()).unwrap().comment,
+            t.comment
+        );
+    }
+
+    #[test]
+    fn torrent_encode_created_by() {
+        let mut t = Torrent::new(
+            "http://a".to_string(),
+            "n".to_string(),
+            PieceLength { layers: 0 },
+        );
+        t.comment = Some("c".to_owned());
+        t.created_by = Some("mktorrent-rs 0.1.0".to_owned());
+        t.creation_date = Some(1700000000);
+        t.httpseeds = vec!["http://seed".to_owned()];
+
+        // created by and creation date sort between comment and httpseeds
+        let encoded = to_bencode_str(&t);
+        assert_eq!(
+            encoded,
+            "d8:announce8:http://a7:comment1:c10:created by18:mktorrent-rs 0.1.0\
+             13:creation datei1700000000e9:httpseedsl11:http://seede\
+             4:infod9:file treede12:meta versioni2e4:name1:n\
+             12:piece lengthi16384ee12:piece layersdee"
+        );
+        let decoded = Torrent::from_bencode(encoded.as_bytes()).unwrap();
+        assert_eq!(decoded.created_by, t.created_by);
+        assert_eq!(decoded.creation_date, t.creation_date);
+    }
+
+    #[test]
+    fn info_encode_private() {
+        let mut t = Torrent::new(
+            "http://a".to_string(),
+            "n".to_string(),
+            PieceLength { layers: 0 },
+        );
+        let public = to_bencode_str(&t.info);
+        assert_eq!(
+            public,
+            "d9:file treede12:meta versioni2e4:name1:n12:piece lengthi16384ee"
+        );
+
+        // private sorts after piece length
+        t.info.private = true;
+        let encoded = to_bencode_str(&t.info);
+        assert_eq!(
+            encoded,
+            "d9:file treede12:meta versioni2e4:name1:n12:piece lengthi16384e7:privatei1ee"
+        );
+        assert!(Info::from_bencode(encoded.as_bytes()).unwrap().private);
+        assert!(!Info::from_bencode(public.as_bytes()).unwrap().private);
+    }
+
+    #[test]
+    fn info_encode_source() {
+        let mut t = Torrent::new(
+            "http://a".to_string(),
+            "n".to_string(),
+            PieceLength { layers: 0 },
+        );
+        t.info.private = true;
+        t.info.source = Some("A".to_owned());
+        let a = to_bencode_str(&t.info);
+        assert_eq!(
+            a,
+            "d9:file treede12:meta versioni2e4:name1:n12:piece lengthi16384e\
+             7:privatei1e6:source1:Ae"
+        );
+        assert_eq!(
+            Info::from_bencode(a.as_bytes()).unwrap().source,
+            t.info.source
+        );
+
+        // only the source differs but the infohash does too
+        t.info.source = Some("B".to_owned());
+        assert_ne!(to_bencode_str(&t.info), a);
+        let b = t.info.infohash().unwrap();
+        t.info.source = Some("A".to_owned());
+        assert_ne!(t.info.infohash().unwrap(), b);
+    }
+
+    #[test]
+    fn torrent_encode_httpseeds() {
+        let mut t = Torrent::new(
+            "http://a".to_string(),
+            "n".to_string(),
+            PieceLength { layers: 0 },
+        );
+        t.httpseeds = vec!["http://seed2".to_owned(), "http://seed1".to_owned()];
+
+        // httpseeds sorts between announce and info and keeps its order
+        let encoded = to_bencode_str(&t);
+        assert_eq!(
+            encoded,
+            "d8:announce8:http://a9:httpseedsl12:http://seed212:http://seed1e4:infod9:file treede\
+             12:meta versioni2e4:name1:n12:piece lengthi16384ee12:piece layersdee"
+        );
+        assert_eq!(
+            Torrent::from_bencode(encoded.as_bytes()).unwrap().httpseeds,
+            t.httpseeds
+        );
+    }
+
+    #[test]
+    fn torrent_encode_url_list() {
+        let mut t = Torrent::new(
+            "http://a".to_string(),
+            "n".to_string(),
+            PieceLength { layers: 0 },
+        );
+
+        // a single url is a string and url-list sorts after piece layers
+        t.url_list = vec!["http://seed/".to_owned()];
+        let encoded = to_bencode_str(&t);
+        assert_eq!(
+            encoded,
+            "d8:announce8:http://a4:infod9:file treede12:meta versioni2e4:name1:n\
+             12:piece lengthi16384ee12:piece layersde8:url-list12:http://seed/e"
+        );
+        assert_eq!(
+            Torrent::from_bencode(encoded.as_bytes()).unwrap().url_list,
+            t.url_list
+        );
+
+        t.url_list = vec!["http://seed2/".to_owned(), "ftp://seed1/".to_owned()];
+        let encoded = to_bencode_str(&t);
+        assert!(
+            encoded.ends_with("8:url-listl13:http://seed2/12:ftp://seed1/ee"),
+            "{}",
+            encoded
+        );
+        assert_eq!(
+            Torrent::from_bencode(encoded.as_bytes()).unwrap().url_list,
+            t.url_list
+        );
+    }
+
+    #[test]
+    fn torrent_encode_nodes() {
+        let mut t = Torrent::new(
+            "http://a".to_string(),
+            "n".to_string(),
+            PieceLength { layers: 0 },
+        );
+
+        // nodes sorts between info and piece layers and keeps its order
+        t.nodes = vec![
+            ("router.example.com".to_owned(), 6881),
+            ("::1".to_owned(), 6882),
+        ];
+        let encoded = to_bencode_str(&t);
+        assert_eq!(
+            encoded,
+            "d8:announce8:http://a4:infod9:file treede12:meta versioni2e4:name1:n\
+             12:piece lengthi16384ee5:nodesll18:router.example.comi6881eel3:::1i6882eee\
+             12:piece layersdee"
+        );
+        assert_eq!(
+            Torrent::from_bencode(encoded.as_bytes()).unwrap().nodes,
+            t.nodes
+        );
+
+        // a trackerless torrent has no announce key
+        t.announce = String::new();
+        let encoded = to_bencode_str(&t);
+        assert!(encoded.starts_with("d4:infod"), "{}", encoded);
+        assert_eq!(
+            Torrent::from_bencode(encoded.as_bytes()).unwrap().announce,
+            ""
+        );
+    }
+
+    #[test]
+    fn encode_with_infohash() {
+        let mut t = Torrent::new(
+            "http://a".to_string(),
+            "n".to_string(),
+            PieceLength { layers: 0 },
+        );
+        t.add_file(
+            "a/b",
+            File {
+                length: 1,
+                pieces_root: [b'a'; 32].into(),
+                crc32: None,
+            },
+            vec![],
+        );
+
+        let (data, infohash) = t.encode_with_infohash().unwrap();
+        assert_eq!(data, t.to_bencode().unwrap());
+        assert_eq!(infohash, t.info.infohash().unwrap());
+
+        // keys on both sides of info
+        t.comment = Some("c".to_owned());
+        t.nodes = vec![("n".to_owned(), 1)];
+        t.url_list = vec!["http://seed/".to_owned()];
+        t.add_file(
+            "big",
+            File {
+                length: 32 << 10,
+                pieces_root: [b'b'; 32].into(),
+                crc32: None,
+            },
+            vec![[b'c'; 32].into(), [b'd'; 32].into()],
+        );
+        let (data, infohash) = t.encode_with_infohash().unwrap();
+        assert_eq!(data, t.to_bencode().unwrap());
+        assert_eq!(infohash, t.info.infohash().unwrap());
+    }
+
+    #[test]
+    fn torrent_from_entries() {
+        let entries = vec![
+            FileEntry {
+                path: "b/c".to_owned(),
+                file: File {
+                    length: 1,
+                    pieces_root: [b'a'; 32].into(),
+                    crc32: None,
+                },
+                pieces_layer: vec![[b'b'; 32].into(), [b'c'; 32].into()],
+            },
+            FileEntry {
+                path: "a".to_owned(),
+                ..Default::default()
+            },
+        ];
+
+        let t = Torrent::from_entries(
+            "".to_string(),
+            "".to_string(),
+            PieceLength { layers: 0 },
+            entries.clone(),
+        )
+        .unwrap();
+
+        let mut expected = Torrent::new("".to_string(), "".to_string(), PieceLength { layers: 0 });
+        for e in entries.iter().cloned() {
+            assert!(expected.add_file(&e.path, e.file, e.pieces_layer));
+        }
+        assert_eq!(t.to_bencode().unwrap(), expected.to_bencode().unwrap());
+
+        // conflicts return the conflicting path and what it collides with
+        let mut conflicting = entries.clone();
+        conflicting.push(FileEntry {
+            path: "b/c/d".to_owned(),
+            ..Default::default()
+        });
+        assert_eq!(
+            Torrent::from_entries(
+                "".to_string(),
+                "".to_string(),
+                PieceLength { layers: 0 },
+                conflicting
+            )
+            .unwrap_err(),
+            "b/c/d: `b/c` is a file and cannot also be a directory"
+        );
+
+        let mut conflicting = entries;
+        conflicting.push(FileEntry {
+            path: "b".to_owned(),
+            ..Default::default()
+        });
+        assert_eq!(
+            Torrent::from_entries(
+                "".to_string(),
+                "".to_string(),
+                PieceLength { layers: 0 },
+                conflicting
+            )
+            .unwrap_err(),
+            "b: a directory with the same path already exists"
+        );
+    }
+
+    #[test]
+    fn conflict_with_prefix() {
+        // a prefix added to some paths collides with a file of the same name
+        let mut t = Torrent::new("".to_string(), "".to_string(), PieceLength { layers: 0 });
+        assert!(t.add_file("extra", File::default(), Vec::new()));
+        assert!(!t.add_file("extra/a", File::default(), Vec::new()));
+        assert_eq!(
+            t.conflict("extra/a"),
+            "`extra` is a file and cannot also be a directory"
+        );
+        assert!(!t.add_file("extra", File::default(), Vec::new()));
+        assert_eq!(t.conflict("extra"), "added more than once");
+    }
+
+    #[test]
+    fn torrent_files_preserve_order() {
+        let mut t = Torrent::new("".to_string(), "".to_string(), PieceLength { layers: 0 });
+        assert!(t.add_file("b", File::default(), Vec::new()));
+        assert!(t.add_file("a/d", File::default(), Vec::new()));
+        assert!(t.add_file("a/c", File::default(), Vec::new()));
+
+        let paths = |t: &Torrent| t.files().into_iter().map(|(p, _)| p).collect::<Vec<_>>();
+        assert_eq!(paths(&t), vec!["a/c", "a/d", "b"]);
+
+        t.preserve_order = true;
+        assert_eq!(paths(&t), vec!["b", "a/d", "a/c"]);
+
+        // the file tree is encoded sorted either way
+        assert_eq!(
+            to_bencode_str(&t.info.file_tree),
+            "d1:ad1:cd0:d6:lengthi0eee1:dd0:d6:lengthi0eeee1:bd0:d6:lengthi0eeee",
+        );
+    }
+
     #[test]
     fn piece_length() {
         let tests = [14, 15, 25];
@@ -429,48 +1535,206 @@ mod tests {
         );
     }
 
+    #[test]
+    fn piece_length_for_content() {
+        let tests = [
+            (0, 15),
+            (2000 << 14, 15),
+            (2000 << 15, 15),
+            ((2000 << 15) + 1, 16),
+            (2000 << 20, 20),
+            ((2000 << 20) + 1, 21),
+            (2000 << 24, 24),
+            (1 << 50, 24),
+        ];
+
+        for (total, exponent) in tests {
+            let pl = PieceLength::for_content(total);
+            assert_eq!(pl.layers + 14, exponent, "{}", total);
+            if (1000 << 15..=2000 << 24).contains(&total) {
+                assert!((1000..=2000).contains(&total.div_ceil(pl.bytes())));
+            }
+        }
+    }
+
     #[test]
     fn torrent_add_file() {
         let mut torrent = Torrent::new("".to_string(), "".to_string(), PieceLength { layers: 0 });
-        assert_eq!(true, torrent.add_file("a.txt", File::default(), Vec::new()));
+        assert!(torrent.add_file("a.txt", File::default(), Vec::new()));
         assert_eq!(torrent.piece_layers.len(), 0); // empty pieces_layer results in it not being added
 
         // adding the same file results in a conflict
-        assert_eq!(
-            false,
-            torrent.add_file("a.txt", File::default(), Vec::new())
-        );
+        assert!(!torrent.add_file("a.txt", File::default(), Vec::new()));
 
         // adding a different file does not
-        assert_eq!(true, torrent.add_file("b.txt", File::default(), Vec::new()));
+        assert!(torrent.add_file("b.txt", File::default(), Vec::new()));
 
         // directories work
-        assert_eq!(
-            true,
-            torrent.add_file("c/d.txt", File::default(), Vec::new())
-        );
+        assert!(torrent.add_file("c/d.txt", File::default(), Vec::new()));
 
         // cannot use an existing file as a directory
+        assert!(!torrent.add_file("c/d.txt/e", File::default(), Vec::new()));
+
+        // non-empty pieces_layer is added to pieces_layers
+        assert!(torrent.add_file(
+            "c/f.txt",
+            File {
+                pieces_root: [b'a'; 32].into(),
+                length: 1,
+                crc32: None,
+            },
+            vec![sha256::Digest::default(), sha256::Digest::default()]
+        ));
         assert_eq!(
-            false,
-            torrent.add_file("c/d.txt/e", File::default(), Vec::new())
+            torrent.piece_layers.get(&[b'a'; 32].into()).unwrap(),
+            &vec![sha256::Digest::default(), sha256::Digest::default()]
         );
+    }
 
-        // non-empty pieces_layer is added to pieces_layers
+    #[test]
+    fn torrent_decode_roundtrip() {
+        let t = Torrent::from_entries(
+            "http://example.com".to_string(),
+            "name".to_string(),
+            PieceLength { layers: 0 },
+            vec![
+                FileEntry {
+                    path: "dir/a".to_owned(),
+                    file: File {
+                        length: 1 << 15,
+                        pieces_root: [b'a'; 32].into(),
+                        crc32: None,
+                    },
+                    pieces_layer: vec![[b'b'; 32].into(), [b'c'; 32].into()],
+                },
+                FileEntry {
+                    path: "empty".to_owned(),
+                    ..Default::default()
+                },
+            ],
+        )
+        .unwrap();
+
+        let encoded = t.to_bencode().unwrap();
+        let decoded = Torrent::from_bencode(&encoded).unwrap();
+        assert_eq!(decoded.to_bencode().unwrap(), encoded);
+        assert_eq!(decoded.piece_layers, t.piece_layers);
+
+        // v1 torrents are rejected
+        assert!(Torrent::from_bencode(b"d4:infod6:lengthi1e4:name1:aee").is_err());
+    }
+
+    #[test]
+    fn validate_json() {
+        let piece_length = PieceLength { layers: 0 };
+        let layer: Vec<sha256::Digest> = vec![[b'b'; 32].into(), [b'c'; 32].into()];
+        let root = merkle::root_hash(0, &layer);
+        let file = |pieces_root| File {
+            length: 1 << 15,
+            pieces_root,
+            crc32: None,
+        };
+
+        let mut t = Torrent::new("".to_string(), "".to_string(), piece_length);
+        assert!(t.add_file("good", file(root), layer.clone()));
+        assert!(t.add_file("missing", file([b'm'; 32].into()), Vec::new()));
+        assert!(t.add_file("short", file([b's'; 32].into()), vec![[b'b'; 32].into()]));
+        assert!(t.add_file("wrong", file([b'w'; 32].into()), layer));
+        // single piece files need no layer
+        assert!(t.add_file("small", File::default(), Vec::new()));
+
+        let problems = t.validate();
         assert_eq!(
-            true,
-            torrent.add_file(
-                "c/f.txt",
-                File {
-                    pieces_root: ['a' as u8; 32].into(),
-                    length: 1
+            serde_json::to_value(&problems).unwrap(),
+            serde_json::json!([
+                {
+                    "file": "missing",
+                    "kind": "missing_layer",
+                    "detail": format!("no piece layer for pieces root {}", "6d".repeat(32)),
                 },
-                vec![sha256::Digest::default(), sha256::Digest::default()]
-            )
+                {
+                    "file": "short",
+                    "kind": "size_mismatch",
+                    "detail": "expected 2 piece hashes, found 1",
+                },
+                {
+                    "file": "wrong",
+                    "kind": "root_mismatch",
+                    "detail": format!(
+                        "piece layer hashes to {}, expected {}",
+                        root,
+                        "77".repeat(32)
+                    ),
+                },
+            ])
         );
+    }
+
+    #[test]
+    fn content_fingerprint() {
+        let build = |name: &str| {
+            let mut t = Torrent::new("".to_string(), name.to_string(), PieceLength { layers: 0 });
+            let f = File {
+                length: 1,
+                pieces_root: [b'a'; 32].into(),
+                crc32: None,
+            };
+            assert!(t.add_file("dir/a", f, Vec::new()));
+            t
+        };
+
+        let a = build("one");
+        let b = build("two");
+        assert_ne!(a.info.infohash().unwrap(), b.info.infohash().unwrap());
         assert_eq!(
-            torrent.piece_layers.get(&['a' as u8; 32].into()).unwrap(),
-            &vec![sha256::Digest::default(), sha256::Digest::default()]
+            a.info.content_fingerprint().unwrap(),
+            b.info.content_fingerprint().unwrap()
+        );
+
+        let mut c = build("one");
+        assert!(c.add_file("dir/b", File::default(), Vec::new()));
+        assert_ne!(
+            a.info.content_fingerprint().unwrap(),
+            c.info.content_fingerprint().unwrap()
         );
     }
+
+    #[test]
+    fn content_id() {
+        let build = |order: &[&str], preserve_order: bool| {
+            let mut t = Torrent::new(
+                "".to_string(),
+                "name".to_string(),
+                PieceLength { layers: 0 },
+            );
+            t.preserve_order = preserve_order;
+            for &path in order {
+                let f = File {
+                    length: 1,
+                    pieces_root: [path.as_bytes()[0]; 32].into(),
+                    crc32: None,
+                };
+                assert!(t.add_file(path, f, Vec::new()));
+            }
+            t
+        };
+
+        let a = build(&["a", "b/c", "d"], false);
+        let b = build(&["d", "b/c", "a"], true);
+        assert_eq!(a.content_id(), b.content_id());
+        assert_eq!(
+            a.content_id(),
+            merkle::root_hash(
+                0,
+                &[[b'a'; 32].into(), [b'b'; 32].into(), [b'd'; 32].into()]
+            )
+        );
+
+        let c = build(&["a", "b/c"], false);
+        assert_ne!(a.content_id(), c.content_id());
+
+        // renaming a file without changing the order keeps the id
+        let d = build(&["a", "bb/c", "d"], false);
+        assert_eq!(a.content_id(), d.content_id());
+    }
 }
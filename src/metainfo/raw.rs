@@ -0,0 +1,440 @@
+use std::collections::{BTreeMap, HashSet};
+
+use bendy::decoding::{self, Decoder, FromBencode, Object};
+use bendy::encoding::{self, ToBencode};
+use bendy::value::Value;
+use url::Url;
+
+// A torrent's top level dictionary with every value kept as its bencoded
+// bytes. Encoding it again reproduces values that were not changed, such as
+// info and therefore the infohash, byte for byte.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RawTorrent {
+    entries: BTreeMap<Vec<u8>, Vec<u8>>,
+}
+
+impl RawTorrent {
+    pub fn decode(b: &[u8]) -> Result<Self, decoding::Error> {
+        let entries = decode_dict(b)?;
+        if !entries.contains_key(b"info".as_slice()) {
+            return Err(decoding::Error::missing_field("info"));
+        }
+
+        Ok(RawTorrent { entries })
+    }
+
+    // Returns the bencoded value of key.
+    pub fn get(&self, key: &[u8]) -> Option<&[u8]> {
+        self.entries.get(key).map(Vec::as_slice)
+    }
+
+    // Sets key to value, which must already be bencoded.
+    pub fn set_raw(&mut self, key: &[u8], value: Vec<u8>) {
+        self.entries.insert(key.to_vec(), value);
+    }
+
+    // Sets key in the info dictionary to value, which must already be
+    // bencoded. Every other key of info is kept byte for byte. This changes
+    // the infohash.
+    pub fn set_info_raw(&mut self, key: &[u8], value: Vec<u8>) -> Result<(), decoding::Error> {
+        // decode checked that info is present.
+        let mut info = decode_dict(self.get(b"info").unwrap())?;
+        info.insert(key.to_vec(), value);
+        self.entries.insert(b"info".to_vec(), encode_dict(&info));
+        Ok(())
+    }
+
+    // Returns the bencoded value of key in the info dictionary.
+    pub fn get_info(&self, key: &[u8]) -> Result<Option<Vec<u8>>, decoding::Error> {
+        let mut info = decode_dict(self.get(b"info").unwrap())?;
+        Ok(info.remove(key))
+    }
+
+    // Sets key to the bencoded value.
+    pub fn set(&mut self, key: &[u8], value: impl ToBencode) -> Result<(), encoding::Error> {
+        self.entries.insert(key.to_vec(), value.to_bencode()?);
+        Ok(())
+    }
+
+    // Adds an announce url. It becomes the announce url if the torrent has
+    // none, otherwise it is added as a new tier of the announce-list as
+    // defined in bep_0012.
+    pub fn add_announce(&mut self, url: String) -> Result<(), decoding::Error> {
+        let announce = match self.get(b"announce") {
+            Some(a) => String::from_bencode(a)?,
+            None => {
+                self.set(b"announce", url).unwrap();
+                return Ok(());
+            }
+        };
+
+        let mut tiers = match self.get(b"announce-list") {
+            Some(l) => Vec::<Vec<String>>::from_bencode(l)?,
+            None => vec![vec![announce]],
+        };
+        tiers.push(vec![url]);
+        self.set(b"announce-list", tiers).unwrap();
+
+        Ok(())
+    }
+
+    // Replaces every announce url equal to old with new in both announce and
+    // announce-list. Returns the number of urls replaced.
+    pub fn replace_announce(&mut self, old: &str, new: &str) -> Result<usize, decoding::Error> {
+        let mut replaced = 0;
+
+        if let Some(a) = self.get(b"announce") {
+            if String::from_bencode(a)? == old {
+                self.set(b"announce", new).unwrap();
+                replaced += 1;
+            }
+        }
+
+        if let Some(l) = self.get(b"announce-list") {
+            let mut tiers = Vec::<Vec<String>>::from_bencode(l)?;
+            for url in tiers.iter_mut().flatten().filter(|u| *u == old) {
+                *url = new.to_owned();
+                replaced += 1;
+            }
+            self.set(b"announce-list", tiers).unwrap();
+        }
+
+        Ok(replaced)
+    }
+
+    // Normalizes every announce url with normalize_url and removes urls
+    // already seen in an earlier or the same tier. Tiers left empty are
+    // dropped. Urls that do not parse are kept as they are.
+    pub fn normalize_announce(&mut self) -> Result<(), decoding::Error> {
+        let normalize = |u: String| normalize_url(&u).unwrap_or(u);
+
+        if let Some(a) = self.get(b"announce") {
+            let url = normalize(String::from_bencode(a)?);
+            self.set(b"announce", url).unwrap();
+        }
+
+        if let Some(l) = self.get(b"announce-list") {
+            let tiers: Vec<Vec<String>> = normalize_tiers(Vec::from_bencode(l)?)
+                .into_iter()
+                .filter(|tier| !tier.is_empty())
+                .collect();
+            self.set(b"announce-list", tiers).unwrap();
+        }
+
+        Ok(())
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        encode_dict(&self.entries)
+    }
+}
+
+// Returns a nonstandard container with the encoded torrent under key and each
+// of fields as a string beside it. A field named key is replaced by the
+// torrent.
+pub fn wrap(torrent: &[u8], key: &str, fields: &[(String, String)]) -> Vec<u8> {
+    let mut entries = BTreeMap::new();
+    for (k, v) in fields {
+        entries.insert(k.as_bytes().to_vec(), v.to_bencode().unwrap());
+    }
+    entries.insert(key.as_bytes().to_vec(), torrent.to_vec());
+    encode_dict(&entries)
+}
+
+// Checks that b is a single value in canonical bencode: dictionary keys are
+// unique and sorted, and integers and string lengths have no leading zeros or
+// negative zero. bendy's decoder rejects anything else, so every token is read
+// and nothing may follow the value.
+pub fn check_canonical(b: &[u8]) -> Result<(), decoding::Error> {
+    let mut decoder = Decoder::new(b);
+    match decoder.next_object()? {
+        Some(Object::List(l)) => {
+            l.into_raw()?;
+        }
+        Some(Object::Dict(d)) => {
+            d.into_raw()?;
+        }
+        Some(_) => {}
+        None => return Err(decoding::Error::unexpected_token("a value", "end of input")),
+    }
+
+    if decoder.next_object()?.is_some() {
+        return Err(decoding::Error::unexpected_token(
+            "end of input",
+            "another value",
+        ));
+    }
+    Ok(())
+}
+
+// Experimental and nonstandard: cuts every pieces root in the file tree and
+// every hash in piece layers of the encoded torrent to their first n bytes.
+// The hashes must already have been computed truncated to n bytes; this only
+// drops the zero padding. Every other value is kept as is.
+pub fn truncate_digests(torrent: &[u8], n: usize) -> Result<Vec<u8>, decoding::Error> {
+    let mut t = RawTorrent::decode(torrent)?;
+
+    let mut info = Value::from_bencode(t.get(b"info").unwrap())?;
+    if let Value::Dict(info) = &mut info {
+        if let Some(tree) = info.get_mut(b"file tree".as_slice()) {
+            truncate_roots(tree, n);
+        }
+    }
+    t.set_raw(b"info", info.to_bencode().unwrap());
+
+    if let Some(layers) = t.get(b"piece layers") {
+        let layers = match Value::from_bencode(layers)? {
+            Value::Dict(layers) => Value::Dict(
+                layers
+                    .into_iter()
+                    .map(|(root, layer)| {
+                        let root = root[..n.min(root.len())].to_vec();
+                        let layer = match layer {
+                            Value::Bytes(b) => Value::Bytes(
+                                b.chunks(32)
+                                    .flat_map(|h| &h[..n.min(h.len())])
+                                    .copied()
+                                    .collect::<Vec<_>>()
+                                    .into(),
+                            ),
+                            v => v,
+                        };
+                        (root.into(), layer)
+                    })
+                    .collect(),
+            ),
+            v => v,
+        };
+        t.set_raw(b"piece layers", layers.to_bencode().unwrap());
+    }
+
+    Ok(t.encode())
+}
+
+// Cuts the pieces root of every file below tree to n bytes. Files are the
+// dictionaries under the empty key.
+fn truncate_roots(tree: &mut Value, n: usize) {
+    let Value::Dict(dict) = tree else {
+        return;
+    };
+    for (k, v) in dict.iter_mut() {
+        if !k.is_empty() {
+            truncate_roots(v, n);
+            continue;
+        }
+        if let Value::Dict(file) = v {
+            if let Some(Value::Bytes(root)) = file.get_mut(b"pieces root".as_slice()) {
+                let len = n.min(root.len());
+                root.to_mut().truncate(len);
+            }
+        }
+    }
+}
+
+// Decodes a dictionary, keeping each value as its bencoded bytes.
+fn decode_dict(b: &[u8]) -> Result<BTreeMap<Vec<u8>, Vec<u8>>, decoding::Error> {
+    let mut decoder = Decoder::new(b);
+    let mut dict = match decoder.next_object()? {
+        Some(o) => o.try_into_dictionary()?,
+        None => return Err(decoding::Error::missing_field("info")),
+    };
+
+    let mut entries = BTreeMap::new();
+    while let Some((k, v)) = dict.next_pair()? {
+        let raw = match v {
+            Object::List(l) => l.into_raw()?.to_vec(),
+            Object::Dict(d) => d.into_raw()?.to_vec(),
+            Object::Integer(i) => format!("i{}e", i).into_bytes(),
+            Object::Bytes(b) => {
+                let mut raw = format!("{}:", b.len()).into_bytes();
+                raw.extend_from_slice(b);
+                raw
+            }
+        };
+        entries.insert(k.to_vec(), raw);
+    }
+
+    Ok(entries)
+}
+
+fn encode_dict(entries: &BTreeMap<Vec<u8>, Vec<u8>>) -> Vec<u8> {
+    let mut ret = b"d".to_vec();
+    // BTreeMap iterates keys in the byte order bencode requires.
+    for (k, v) in entries {
+        ret.extend_from_slice(format!("{}:", k.len()).as_bytes());
+        ret.extend_from_slice(k);
+        ret.extend_from_slice(v);
+    }
+    ret.push(b'e');
+    ret
+}
+
+// Returns the canonical form of an announce url: the scheme and host are
+// lowercased, a default port is removed and the path is normalized. Urls
+// that differ only in these ways are the same tracker.
+pub fn normalize_url(s: &str) -> Result<String, url::ParseError> {
+    Url::parse(s).map(String::from)
+}
+
+// Normalizes every url in tiers with normalize_url and removes urls already
+// seen in an earlier or the same tier, which may leave tiers empty. Urls that
+// do not parse are kept as they are.
+pub fn normalize_tiers(tiers: Vec<Vec<String>>) -> Vec<Vec<String>> {
+    let mut seen = HashSet::new();
+    tiers
+        .into_iter()
+        .map(|tier| {
+            tier.into_iter()
+                .map(|u| normalize_url(&u).unwrap_or(u))
+                .filter(|u| seen.insert(u.clone()))
+                .collect()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn truncate_digests() {
+        let root = [b'r'; 32];
+        let layer: Vec<u8> = [[b'x'; 32], [b'y'; 32]].concat();
+        let mut data = b"d4:infod9:file treed1:ad0:d6:lengthi1e11:pieces root32:".to_vec();
+        data.extend_from_slice(&root);
+        data.extend_from_slice(b"eee4:name1:ae12:piece layersd32:");
+        data.extend_from_slice(&root);
+        data.extend_from_slice(b"64:");
+        data.extend_from_slice(&layer);
+        data.extend_from_slice(b"ee");
+
+        assert_eq!(super::truncate_digests(&data, 32).unwrap(), data);
+        assert_eq!(
+            String::from_utf8(super::truncate_digests(&data, 2).unwrap()).unwrap(),
+            "d4:infod9:file treed1:ad0:d6:lengthi1e11:pieces root2:rreee4:name1:ae\
+             12:piece layersd2:rr4:xxyyee"
+        );
+    }
+
+    #[test]
+    fn raw_roundtrip() {
+        // info has keys in an order and a value this crate would never
+        // produce so it can only survive by being copied.
+        let data = b"d8:announce3:one4:infod4:name1:a7:privatei1e3:zzz0:ee";
+        let mut t = RawTorrent::decode(data).unwrap();
+        assert_eq!(t.encode(), data);
+
+        t.set(b"comment", "fixed").unwrap();
+        t.add_announce("two".to_owned()).unwrap();
+        t.add_announce("three".to_owned()).unwrap();
+        assert_eq!(
+            String::from_utf8(t.encode()).unwrap(),
+            "d8:announce3:one13:announce-listll3:oneel3:twoel5:threeee\
+             7:comment5:fixed4:infod4:name1:a7:privatei1e3:zzz0:ee"
+        );
+
+        let mut t = RawTorrent::decode(b"d4:infodee").unwrap();
+        t.add_announce("one".to_owned()).unwrap();
+        assert_eq!(t.encode(), b"d8:announce3:one4:infodee");
+
+        assert!(RawTorrent::decode(b"d8:announce3:onee").is_err());
+    }
+
+    #[test]
+    fn set_info_raw() {
+        let data = b"d4:infod4:name1:a7:privatei1e3:zzz0:ee";
+        let mut t = RawTorrent::decode(data).unwrap();
+        assert_eq!(t.get_info(b"name").unwrap().unwrap(), b"1:a");
+        assert_eq!(t.get_info(b"missing").unwrap(), None);
+
+        t.set_info_raw(b"file tree", b"d1:bdee".to_vec()).unwrap();
+        t.set_raw(b"piece layers", b"de".to_vec());
+        assert_eq!(
+            String::from_utf8(t.encode()).unwrap(),
+            "d4:infod9:file treed1:bdee4:name1:a7:privatei1e3:zzz0:e12:piece layersdee"
+        );
+    }
+
+    #[test]
+    fn wrap() {
+        let torrent = b"d8:announce3:one4:infod4:name1:aee";
+        let fields = [
+            ("z".to_owned(), "last".to_owned()),
+            ("a".to_owned(), "first".to_owned()),
+        ];
+        let wrapped = super::wrap(torrent, "torrent", &fields);
+        assert_eq!(
+            String::from_utf8(wrapped.clone()).unwrap(),
+            "d1:a5:first7:torrentd8:announce3:one4:infod4:name1:aee1:z4:laste"
+        );
+
+        let entries = decode_dict(&wrapped).unwrap();
+        let inner = RawTorrent::decode(&entries[b"torrent".as_slice()]).unwrap();
+        assert_eq!(inner.encode(), torrent);
+    }
+
+    #[test]
+    fn check_canonical() {
+        super::check_canonical(b"d1:ai1e1:bli-1ei0e2:xyee").unwrap();
+        super::check_canonical(b"i0e").unwrap();
+
+        for bad in [
+            b"d1:bi1e1:ai1ee".as_slice(),
+            b"d1:ai1e1:ai1ee",
+            b"i01e",
+            b"i-0e",
+            b"02:ab",
+            b"lee",
+            b"i1ei2e",
+            b"",
+        ] {
+            assert!(
+                super::check_canonical(bad).is_err(),
+                "{}",
+                String::from_utf8_lossy(bad)
+            );
+        }
+    }
+
+    #[test]
+    fn replace_announce() {
+        let data = b"d8:announce3:old13:announce-listll3:old3:aaael3:bbb3:oldee4:infod4:name1:aee";
+        let mut t = RawTorrent::decode(data).unwrap();
+        assert_eq!(t.replace_announce("old", "new").unwrap(), 3);
+        assert_eq!(
+            String::from_utf8(t.encode()).unwrap(),
+            "d8:announce3:new13:announce-listll3:new3:aaael3:bbb3:newee4:infod4:name1:aee"
+        );
+        assert_eq!(
+            t.get(b"info"),
+            RawTorrent::decode(data).unwrap().get(b"info")
+        );
+
+        assert_eq!(t.replace_announce("missing", "new").unwrap(), 0);
+    }
+
+    #[test]
+    fn normalize_announce() {
+        assert_eq!(
+            normalize_url("http://TRACKER:80/").unwrap(),
+            "http://tracker/"
+        );
+        assert_eq!(
+            normalize_url("HTTPS://Tracker:443/a/./b/../announce").unwrap(),
+            "https://tracker/a/announce"
+        );
+        assert_eq!(
+            normalize_url("udp://tracker:6969").unwrap(),
+            "udp://tracker:6969"
+        );
+        assert!(normalize_url("not a url").is_err());
+
+        let data = b"d8:announce18:http://TRACKER:80/13:announce-listll18:http://TRACKER:80/15:http://tracker/el15:http://tracker/el9:not a urlee4:infod4:name1:aee";
+        let mut t = RawTorrent::decode(data).unwrap();
+        t.normalize_announce().unwrap();
+        assert_eq!(
+            t.encode(),
+            b"d8:announce15:http://tracker/13:announce-listll15:http://tracker/el9:not a urlee4:infod4:name1:aee"
+        );
+    }
+}
@@ -0,0 +1,295 @@
+use bendy::encoding::{AsString, Error, SingleItemEncoder, ToBencode};
+
+use crate::checksum::sha1;
+
+// A v1 only torrent metainfo file defined in bep_0003.
+#[derive(Clone, Debug)]
+pub struct Torrent {
+    // Omitted when empty, for a trackerless torrent.
+    pub announce: String,
+    // Tiers of announce urls as defined in bep_0012. Omitted when empty.
+    pub announce_list: Vec<Vec<String>>,
+    pub comment: Option<String>,
+    pub created_by: Option<String>,
+    // Unix time in seconds.
+    pub creation_date: Option<i64>,
+    // HTTP seed URLs as defined in bep_0017. Omitted when empty.
+    pub httpseeds: Vec<String>,
+    pub info: Info,
+    // DHT bootstrap nodes as host and port, defined in bep_0005. Omitted when
+    // empty.
+    pub nodes: Vec<(String, u16)>,
+    // Web seed URLs as defined in bep_0019. Omitted when empty and encoded as
+    // a single string when there is only one.
+    pub url_list: Vec<String>,
+}
+
+impl ToBencode for Torrent {
+    const MAX_DEPTH: usize = Info::MAX_DEPTH + 1;
+
+    fn encode(&self, encoder: SingleItemEncoder) -> Result<(), Error> {
+        encoder.emit_dict(|mut e| {
+            if !self.announce.is_empty() {
+                e.emit_pair(b"announce", &self.announce)?;
+            }
+            if !self.announce_list.is_empty() {
+                e.emit_pair(b"announce-list", &self.announce_list)?;
+            }
+            if let Some(comment) = &self.comment {
+                e.emit_pair(b"comment", comment)?;
+            }
+            if let Some(created_by) = &self.created_by {
+                e.emit_pair(b"created by", created_by)?;
+            }
+            if let Some(date) = self.creation_date {
+                e.emit_pair(b"creation date", date)?;
+            }
+            if !self.httpseeds.is_empty() {
+                e.emit_pair(b"httpseeds", &self.httpseeds)?;
+            }
+            e.emit_pair(b"info", &self.info)?;
+            if !self.nodes.is_empty() {
+                e.emit_pair_with(b"nodes", |e| {
+                    e.emit_list(|e| {
+                        for (host, port) in &self.nodes {
+                            e.emit_with(|e| {
+                                e.emit_list(|e| {
+                                    e.emit_str(host)?;
+                                    e.emit_int(*port)
+                                })
+                            })?;
+                        }
+                        Ok(())
+                    })
+                })?;
+            }
+            match self.url_list.as_slice() {
+                [] => {}
+                [url] => e.emit_pair(b"url-list", url)?,
+                _ => e.emit_pair(b"url-list", &self.url_list)?,
+            }
+            Ok(())
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Info {
+    pub name: String,
+    pub piece_length: u64,
+    pub pieces: Vec<sha1::Digest>,
+    pub files: Files,
+    // Set for torrents of private trackers as defined in bep_0027.
+    pub private: bool,
+    pub source: Option<String>,
+}
+
+impl Info {
+    // Returns the v1 infohash: the SHA-1 of the bencoded info dictionary.
+    pub fn infohash(&self) -> Result<sha1::Digest, Error> {
+        let mut h = sha1::Hasher::default();
+        h.update(&self.to_bencode()?);
+        Ok(h.into_digest())
+    }
+}
+
+impl ToBencode for Info {
+    const MAX_DEPTH: usize = File::MAX_DEPTH + 2;
+
+    fn encode(&self, encoder: SingleItemEncoder) -> Result<(), Error> {
+        let mut pieces = Vec::with_capacity(self.pieces.len() * sha1::Digest::LENGTH);
+        self.pieces
+            .iter()
+            .for_each(|d| pieces.extend_from_slice(d.as_ref()));
+
+        encoder.emit_dict(|mut e| {
+            match &self.files {
+                Files::Single { length } => e.emit_pair(b"length", length)?,
+                Files::Multi(files) => e.emit_pair(b"files", files)?,
+            }
+            e.emit_pair(b"name", &self.name)?;
+            e.emit_pair(b"piece length", self.piece_length)?;
+            e.emit_pair(b"pieces", AsString(&pieces))?;
+            if self.private {
+                e.emit_pair(b"private", 1)?;
+            }
+            if let Some(source) = &self.source {
+                e.emit_pair(b"source", source)?;
+            }
+            Ok(())
+        })
+    }
+}
+
+// The file layout of a v1 torrent. A single file torrent stores its length
+// directly in info while a multifile torrent has a list of files.
+#[derive(Clone, Debug)]
+pub enum Files {
+    Single { length: u64 },
+    Multi(Vec<File>),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct File {
+    pub length: u64,
+    pub path: Vec<String>,
+}
+
+impl ToBencode for File {
+    const MAX_DEPTH: usize = 2;
+
+    fn encode(&self, encoder: SingleItemEncoder) -> Result<(), Error> {
+        encoder.emit_dict(|mut e| {
+            e.emit_pair(b"length", self.length)?;
+            e.emit_pair(b"path", &self.path)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode_single_file() {
+        let t = Torrent {
+            announce: "http://example.com".to_owned(),
+            announce_list: Vec::new(),
+            comment: None,
+            created_by: None,
+            creation_date: None,
+            httpseeds: Vec::new(),
+            info: Info {
+                name: "a".to_owned(),
+                piece_length: 16384,
+                pieces: vec![[b'p'; sha1::Digest::LENGTH].into()],
+                files: Files::Single { length: 1 },
+                private: false,
+                source: None,
+            },
+            nodes: Vec::new(),
+            url_list: Vec::new(),
+        };
+        let encoded = t.to_bencode().unwrap();
+        assert_eq!(
+            String::from_utf8(encoded).unwrap(),
+            format!(
+                "d8:announce18:http://example.com4:infod6:lengthi1e4:name1:a\
+                 12:piece lengthi16384e6:pieces20:{}ee",
+                "p".repeat(20)
+            )
+        );
+    }
+
+    #[test]
+    fn encode_httpseeds() {
+        let t = Torrent {
+            announce: String::new(),
+            announce_list: Vec::new(),
+            comment: None,
+            created_by: None,
+            creation_date: Some(1),
+            httpseeds: vec!["http://seed2".to_owned(), "http://seed1".to_owned()],
+            info: Info {
+                name: "a".to_owned(),
+                piece_length: 16384,
+                pieces: Vec::new(),
+                files: Files::Single { length: 0 },
+                private: false,
+                source: None,
+            },
+            nodes: Vec::new(),
+            url_list: Vec::new(),
+        };
+
+        // httpseeds sorts between creation date and info and keeps its order
+        let encoded = t.to_bencode().unwrap();
+        assert_eq!(
+            String::from_utf8(encoded).unwrap(),
+            "d13:creation datei1e9:httpseedsl12:http://seed212:http://seed1e\
+             4:infod6:lengthi0e4:name1:a12:piece lengthi16384e6:pieces0:ee"
+        );
+    }
+
+    #[test]
+    fn encode_url_list() {
+        let mut t = Torrent {
+            announce: String::new(),
+            announce_list: Vec::new(),
+            comment: None,
+            created_by: None,
+            creation_date: None,
+            httpseeds: Vec::new(),
+            info: Info {
+                name: "a".to_owned(),
+                piece_length: 16384,
+                pieces: Vec::new(),
+                files: Files::Single { length: 0 },
+                private: false,
+                source: None,
+            },
+            nodes: Vec::new(),
+            url_list: vec!["http://seed/".to_owned()],
+        };
+
+        // a single url is a string and url-list sorts after info
+        let info = "4:infod6:lengthi0e4:name1:a12:piece lengthi16384e6:pieces0:e";
+        assert_eq!(
+            String::from_utf8(t.to_bencode().unwrap()).unwrap(),
+            format!("d{}8:url-list12:http://seed/e", info)
+        );
+
+        t.url_list = vec!["http://seed2/".to_owned(), "ftp://seed1/".to_owned()];
+        assert_eq!(
+            String::from_utf8(t.to_bencode().unwrap()).unwrap(),
+            format!("d{}8:url-listl13:http://seed2/12:ftp://seed1/ee", info)
+        );
+    }
+
+    #[test]
+    fn encode_nodes() {
+        let t = Torrent {
+            announce: String::new(),
+            announce_list: Vec::new(),
+            comment: None,
+            created_by: None,
+            creation_date: None,
+            httpseeds: Vec::new(),
+            info: Info {
+                name: "a".to_owned(),
+                piece_length: 16384,
+                pieces: Vec::new(),
+                files: Files::Single { length: 0 },
+                private: false,
+                source: None,
+            },
+            nodes: vec![("::1".to_owned(), 6881), ("b".to_owned(), 1)],
+            url_list: vec!["http://seed/".to_owned()],
+        };
+
+        // nodes sorts between info and url-list
+        assert_eq!(
+            String::from_utf8(t.to_bencode().unwrap()).unwrap(),
+            "d4:infod6:lengthi0e4:name1:a12:piece lengthi16384e6:pieces0:e\
+             5:nodesll3:::1i6881eel1:bi1eee8:url-list12:http://seed/e"
+        );
+    }
+
+    #[test]
+    fn infohash() {
+        let mut h = sha1::Hasher::default();
+        h.update(b"a");
+        let info = Info {
+            name: "a".to_owned(),
+            piece_length: 16384,
+            pieces: vec![h.into_digest()],
+            files: Files::Single { length: 1 },
+            private: false,
+            source: None,
+        };
+        assert_eq!(
+            info.infohash().unwrap().to_string(),
+            "bb98a674de9c3f85217ff32504fcc359cd5ffed3"
+        );
+    }
+}
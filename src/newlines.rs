@@ -0,0 +1,137 @@
+use std::io::{self, Read};
+
+// Wraps a reader, converting CRLF line endings to LF. A lone CR is kept.
+pub struct CrlfToLf<R> {
+    inner: R,
+    buf: Box<[u8]>,
+    pos: usize,
+    end: usize,
+    // A CR was read but the next byte is not known yet.
+    pending_cr: bool,
+}
+
+impl<R: Read> CrlfToLf<R> {
+    pub fn new(inner: R) -> Self {
+        CrlfToLf {
+            inner,
+            buf: vec![0; 64 << 10].into_boxed_slice(),
+            pos: 0,
+            end: 0,
+            pending_cr: false,
+        }
+    }
+}
+
+impl<R: Read> Read for CrlfToLf<R> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        let mut n = 0;
+        while n < out.len() {
+            if self.pos == self.end {
+                // Return what we have rather than block on another read.
+                if n > 0 {
+                    break;
+                }
+
+                self.end = self.inner.read(&mut self.buf)?;
+                self.pos = 0;
+                if self.end == 0 {
+                    if self.pending_cr {
+                        self.pending_cr = false;
+                        out[n] = b'\r';
+                        n += 1;
+                    }
+                    break;
+                }
+            }
+
+            let b = self.buf[self.pos];
+            self.pos += 1;
+
+            if self.pending_cr {
+                self.pending_cr = false;
+                if b == b'\n' {
+                    out[n] = b'\n';
+                    n += 1;
+                    continue;
+                }
+
+                out[n] = b'\r';
+                n += 1;
+                if n == out.len() {
+                    // No room for b, read it again next time.
+                    self.pos -= 1;
+                    break;
+                }
+            }
+
+            if b == b'\r' {
+                self.pending_cr = true;
+                continue;
+            }
+
+            out[n] = b;
+            n += 1;
+        }
+
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Reads all of r using reads of at most n bytes.
+    fn read_all(mut r: impl Read, n: usize) -> Vec<u8> {
+        let mut ret = Vec::new();
+        let mut buf = vec![0; n];
+        loop {
+            match r.read(&mut buf).unwrap() {
+                0 => return ret,
+                m => ret.extend_from_slice(&buf[..m]),
+            }
+        }
+    }
+
+    #[test]
+    fn crlf_to_lf() {
+        let cases: [(&[u8], &[u8]); 6] = [
+            (b"", b""),
+            (b"a\r\nb\r\n", b"a\nb\n"),
+            (b"a\rb\r", b"a\rb\r"),
+            (b"\r\r\n\n", b"\r\n\n"),
+            (b"\r\n\r\n", b"\n\n"),
+            (b"no newlines", b"no newlines"),
+        ];
+
+        for (input, expected) in cases {
+            for n in [1, 2, 3, 1024] {
+                assert_eq!(
+                    read_all(CrlfToLf::new(input), n),
+                    expected,
+                    "input {:?}, reads of {}",
+                    input,
+                    n
+                );
+            }
+        }
+    }
+
+    // A reader that returns one byte at a time.
+    struct OneByte<'a>(&'a [u8]);
+
+    impl Read for OneByte<'_> {
+        fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+            let n = self.0.len().min(out.len()).min(1);
+            out[..n].copy_from_slice(&self.0[..n]);
+            self.0 = &self.0[n..];
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn crlf_split_across_reads() {
+        let r = CrlfToLf::new(OneByte(b"a\r\nb\r\r\n"));
+        assert_eq!(read_all(r, 2), b"a\nb\r\n");
+    }
+}
@@ -0,0 +1,714 @@
+use std::fmt;
+use std::fs;
+use std::io::{self, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use anyhow::{Context, Error, Result};
+use bendy::encoding::ToBencode;
+
+use crate::metainfo::raw::{check_canonical, truncate_digests, wrap};
+use crate::metainfo::{v1, Torrent};
+use crate::{base32, Cli, InfohashEncoding};
+
+// Writes the infohash of torrent on its own line.
+pub fn print_infohash(
+    mut w: impl Write,
+    torrent: &Torrent,
+    encoding: InfohashEncoding,
+) -> Result<()> {
+    let infohash = torrent
+        .info
+        .infohash()
+        .map_err(|e| Error::msg(e.to_string()))?;
+    writeln!(w, "{}", format_infohash(&infohash, encoding))?;
+    Ok(())
+}
+
+// Same as print_infohash but for v1 torrents.
+pub fn print_infohash_v1(
+    mut w: impl Write,
+    torrent: &v1::Torrent,
+    encoding: InfohashEncoding,
+) -> Result<()> {
+    let infohash = torrent
+        .info
+        .infohash()
+        .map_err(|e| Error::msg(e.to_string()))?;
+    writeln!(w, "{}", format_infohash(&infohash, encoding))?;
+    Ok(())
+}
+
+// Formats a v1 or v2 infohash for printing.
+pub fn format_infohash<D>(infohash: &D, encoding: InfohashEncoding) -> String
+where
+    D: fmt::Display + AsRef<[u8]>,
+{
+    match encoding {
+        InfohashEncoding::Hex => infohash.to_string(),
+        InfohashEncoding::Base32 => base32::encode(&infohash.as_ref()[..20]),
+    }
+}
+
+// Returns the path to write the torrent to. If output is an existing directory
+// the torrent is written inside it as <name>.torrent, with name passed through
+// sanitize_file_name if sanitize is set.
+pub fn output_path(output: &Path, name: &str, sanitize: bool) -> PathBuf {
+    if fs::metadata(output).is_ok_and(|m| m.is_dir()) {
+        output.join(torrent_file_name(name, sanitize))
+    } else {
+        output.to_path_buf()
+    }
+}
+
+// Returns <name>.torrent.
+pub fn torrent_file_name(name: &str, sanitize: bool) -> String {
+    if sanitize {
+        format!("{}.torrent", sanitize_file_name(name))
+    } else {
+        format!("{}.torrent", name)
+    }
+}
+
+// Replaces characters that are unsafe in file names on common filesystems
+// with '_'.
+fn sanitize_file_name(name: &str) -> String {
+    name.chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect()
+}
+
+// Returns p if nothing exists there. Otherwise returns the first unused path
+// with " (2)", " (3)", etc. added to the file stem.
+pub fn unique_path(p: &Path) -> PathBuf {
+    if fs::symlink_metadata(p).is_err() {
+        return p.to_path_buf();
+    }
+
+    let stem = p.file_stem().unwrap_or_default().to_string_lossy();
+    let ext = p
+        .extension()
+        .map(|e| format!(".{}", e.to_string_lossy()))
+        .unwrap_or_default();
+
+    (2..)
+        .map(|i| p.with_file_name(format!("{} ({}){}", stem, i, ext)))
+        .find(|p| fs::symlink_metadata(p).is_err())
+        .unwrap()
+}
+
+// Writes a built torrent to every path in outputs, or stdout if outputs is
+// empty, wrapped under --wrap-key if it is set.
+pub fn emit_torrent(cli: &Cli, outputs: &[PathBuf], torrent: &impl ToBencode) -> Result<()> {
+    emit_encoded(cli, outputs, &torrent.to_bencode().unwrap())
+}
+
+// Same as emit_torrent for a torrent that has already been encoded. With
+// --digest-bytes the hashes are cut to their truncated length first. The
+// encoding is checked to be canonical with --validate-canonical.
+pub fn emit_encoded(cli: &Cli, outputs: &[PathBuf], data: &[u8]) -> Result<()> {
+    check_outputs(cli, outputs)?;
+    if cli.clean_temps {
+        for p in outputs {
+            for tmp in clean_temps(p).context("failed to clean temporary files")? {
+                eprintln!("removed `{}`", tmp.to_string_lossy());
+            }
+        }
+    }
+    let truncated;
+    let data = match cli.digest_bytes {
+        Some(n) => {
+            truncated = truncate_digests(data, n.into())
+                .map_err(|e| Error::msg(format!("failed to truncate digests: {}", e)))?;
+            &truncated
+        }
+        None => data,
+    };
+    if cli.validate_canonical {
+        check_canonical(data)
+            .map_err(|e| Error::msg(format!("encoded torrent is not canonical: {}", e)))?;
+    }
+    let wrapped;
+    let data = match &cli.wrap_key {
+        Some(key) => {
+            wrapped = wrap(data, key, &cli.wrap_field);
+            &wrapped
+        }
+        None => data,
+    };
+    if outputs.is_empty() {
+        write_output(None, data, cli.fsync)
+    } else {
+        write_outputs(outputs, data, cli.fsync)
+    }
+}
+
+// Runs check_output for every path in outputs, or for stdout if outputs is
+// empty.
+pub fn check_outputs(cli: &Cli, outputs: &[PathBuf]) -> Result<()> {
+    if outputs.is_empty() {
+        return check_output(cli, None);
+    }
+    outputs.iter().try_for_each(|p| check_output(cli, Some(p)))
+}
+
+// Unless --force is set, returns an error if output already exists or if
+// output is None and stdout is a terminal.
+fn check_output(cli: &Cli, output: Option<&Path>) -> Result<()> {
+    if cli.force {
+        return Ok(());
+    }
+    match output {
+        Some(p) if p.exists() => Err(Error::msg(format!(
+            "`{}` already exists, use --force to replace it",
+            p.to_string_lossy()
+        ))),
+        Some(_) => Ok(()),
+        None if io::stdout().is_terminal() => Err(Error::msg(
+            "refusing to write a torrent to a terminal, use --output or --force",
+        )),
+        None => Ok(()),
+    }
+}
+
+// Writes data to output, or stdout if output is None. The file is written to a
+// temporary path and renamed into place so a partial torrent is never left at
+// output. If fsync is set the file is synced to disk before the rename.
+pub fn write_output(output: Option<&Path>, data: &[u8], fsync: bool) -> Result<()> {
+    match output {
+        Some(p) => write_atomic(p, data, fsync)
+            .context(format!("failed to write `{}`", p.to_string_lossy()))?,
+        None => io::stdout().write_all(data).unwrap(),
+    }
+
+    Ok(())
+}
+
+// Writes data to a temporary file next to p and renames it to p. With fsync
+// the directory is synced too so the rename is not lost.
+fn write_atomic(p: &Path, data: &[u8], fsync: bool) -> io::Result<()> {
+    let tmp = write_temp(p, data, fsync)?;
+    fs::rename(&tmp, p).inspect_err(|_| {
+        let _ = fs::remove_file(&tmp);
+    })?;
+    if fsync {
+        sync_parent(p)?;
+    }
+    Ok(())
+}
+
+// Syncs the directory containing p to disk, which makes a rename to p
+// durable.
+#[cfg(unix)]
+fn sync_parent(p: &Path) -> io::Result<()> {
+    let dir = match p.parent() {
+        Some(d) if !d.as_os_str().is_empty() => d,
+        _ => Path::new("."),
+    };
+    fs::File::open(dir)?.sync_all()
+}
+
+// Directories cannot be opened to be synced elsewhere.
+#[cfg(not(unix))]
+fn sync_parent(_p: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+// Writes data to a new temporary file next to p and returns its path. The
+// temporary file is removed if writing fails.
+fn write_temp(p: &Path, data: &[u8], fsync: bool) -> io::Result<PathBuf> {
+    let tmp = temp_path(p);
+    let write = || -> io::Result<()> {
+        let mut f = fs::File::create(&tmp)?;
+        f.write_all(data)?;
+        if fsync {
+            f.sync_all()?;
+        }
+        Ok(())
+    };
+    write().inspect_err(|_| {
+        let _ = fs::remove_file(&tmp);
+    })?;
+    Ok(tmp)
+}
+
+// Writes data to every path in outputs as write_output does, but only renames
+// the temporary files into place once all of them are written. Files already
+// at the outputs, replaced with --force, are first moved aside. If any write
+// or rename fails the temporary files and the outputs already renamed are
+// removed and the replaced files are moved back.
+fn write_outputs(outputs: &[PathBuf], data: &[u8], fsync: bool) -> Result<()> {
+    let mut temps = Vec::with_capacity(outputs.len());
+    for p in outputs {
+        match write_temp(p, data, fsync) {
+            Ok(tmp) => temps.push(tmp),
+            Err(e) => {
+                temps.iter().for_each(|tmp| {
+                    let _ = fs::remove_file(tmp);
+                });
+                return Err(
+                    Error::from(e).context(format!("failed to write `{}`", p.to_string_lossy()))
+                );
+            }
+        }
+    }
+
+    let mut aside = Vec::new();
+    let mut renamed = 0;
+    let mut replace = || -> Result<()> {
+        for p in outputs {
+            // A directory in the way makes the rename below fail.
+            match fs::symlink_metadata(p) {
+                Ok(m) if !m.is_dir() => {
+                    let old = temp_path(p);
+                    fs::rename(p, &old)
+                        .context(format!("failed to move `{}` aside", p.to_string_lossy()))?;
+                    aside.push((old, p));
+                }
+                _ => {}
+            }
+        }
+        for (tmp, p) in temps.iter().zip(outputs) {
+            fs::rename(tmp, p).context(format!("failed to write `{}`", p.to_string_lossy()))?;
+            renamed += 1;
+        }
+        Ok(())
+    };
+
+    if let Err(e) = replace() {
+        temps[renamed..]
+            .iter()
+            .chain(&outputs[..renamed])
+            .for_each(|p| {
+                let _ = fs::remove_file(p);
+            });
+        for (old, p) in &aside {
+            let _ = fs::rename(old, p);
+        }
+        return Err(e);
+    }
+
+    for (old, _) in &aside {
+        let _ = fs::remove_file(old);
+    }
+    if fsync {
+        for p in outputs {
+            sync_parent(p).context(format!("failed to sync `{}`", p.to_string_lossy()))?;
+        }
+    }
+    Ok(())
+}
+
+// Counts the temporary files made by this process.
+static TEMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+// Returns a temporary path next to p named <p>.<pid>.<counter>.tmp, which no
+// other write in this or any other running process uses.
+fn temp_path(p: &Path) -> PathBuf {
+    let n = TEMP_COUNTER.fetch_add(1, Ordering::Relaxed);
+    let mut tmp = p.as_os_str().to_owned();
+    tmp.push(format!(".{}.{}.tmp", process::id(), n));
+    PathBuf::from(tmp)
+}
+
+// Returns the pid in file_name if it is a temporary file name for a file named
+// name.
+fn temp_pid(file_name: &str, name: &str) -> Option<u32> {
+    let rest = file_name
+        .strip_prefix(name)?
+        .strip_prefix('.')?
+        .strip_suffix(".tmp")?;
+    let (pid, counter) = rest.split_once('.')?;
+    counter.parse::<u64>().ok()?;
+    pid.parse().ok()
+}
+
+// Sending signal 0 checks whether a process exists without signalling it.
+// EPERM means it exists but belongs to another user.
+#[cfg(unix)]
+fn process_running(pid: u32) -> bool {
+    if pid == process::id() {
+        return true;
+    }
+    match libc::pid_t::try_from(pid) {
+        Ok(pid) if pid > 0 => {
+            let r = unsafe { libc::kill(pid, 0) };
+            r == 0 || io::Error::last_os_error().raw_os_error() != Some(libc::ESRCH)
+        }
+        _ => false,
+    }
+}
+
+// Without a way to tell whether another process is running every process is
+// assumed to be, so no temporary files are removed.
+#[cfg(not(unix))]
+fn process_running(_pid: u32) -> bool {
+    true
+}
+
+// Removes the temporary files for p left by processes that are no longer
+// running and returns their paths.
+pub fn clean_temps(p: &Path) -> io::Result<Vec<PathBuf>> {
+    let name = match p.file_name() {
+        Some(n) => n.to_string_lossy().into_owned(),
+        None => return Ok(Vec::new()),
+    };
+    let dir = match p.parent() {
+        Some(d) if !d.as_os_str().is_empty() => d,
+        _ => Path::new("."),
+    };
+
+    let mut removed = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let pid = entry.file_name().to_str().and_then(|n| temp_pid(n, &name));
+        match pid {
+            Some(pid) if !process_running(pid) => {
+                fs::remove_file(entry.path())?;
+                removed.push(entry.path());
+            }
+            _ => {}
+        }
+    }
+    removed.sort_unstable();
+
+    Ok(removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::build::{build_torrent, build_torrent_v1};
+    use crate::checksum::sha256;
+    use crate::hash::HashOptions;
+    use crate::metainfo::PieceLength;
+    use crate::test_util::{get_file_list, write_torrent};
+    use bendy::decoding::FromBencode;
+    use clap::Parser;
+
+    #[test]
+    fn output_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        let out = dir.path().join("some_dir");
+        fs::create_dir(&out).unwrap();
+
+        let mut with_slash = out.clone().into_os_string();
+        with_slash.push("/");
+        assert_eq!(
+            output_path(Path::new(&with_slash), "name", true),
+            out.join("name.torrent")
+        );
+
+        // other paths are used as-is
+        let file = dir.path().join("file.torrent");
+        assert_eq!(output_path(&file, "name", true), file);
+        fs::write(&file, "").unwrap();
+        assert_eq!(output_path(&file, "name", true), file);
+    }
+
+    #[test]
+    fn sanitize_output_name() {
+        let dir = tempfile::tempdir().unwrap();
+        let name = "a/b: c\u{7}?";
+
+        let p = output_path(dir.path(), name, true);
+        assert_eq!(p, dir.path().join("a_b_ c__.torrent"));
+        assert_eq!(
+            output_path(dir.path(), name, false),
+            dir.path().join(format!("{}.torrent", name))
+        );
+
+        let t = Torrent::new("".to_string(), name.to_string(), PieceLength { layers: 0 });
+        write_torrent(Some(&p), &t, false).unwrap();
+        let data = fs::read(&p).unwrap();
+        check_canonical(&data).unwrap();
+        let written = Torrent::from_bencode(&data).unwrap();
+        assert_eq!(written.info.name, name);
+
+        let cli = |args: &[&str]| {
+            let mut argv = vec!["mktorrent-rs", "--announce=", "--piece-length=14"];
+            argv.extend_from_slice(args);
+            argv.push("root");
+            Cli::parse_from(argv)
+        };
+        assert!(cli(&[]).sanitize_output_name);
+        assert!(!cli(&["--sanitize-output-name=false"]).sanitize_output_name);
+    }
+
+    #[test]
+    fn multiple_outputs() {
+        let dir = tempfile::tempdir().unwrap();
+        let a = dir.path().join("a.torrent");
+        let b = dir.path().join("b.torrent");
+
+        write_outputs(&[a.clone(), b.clone()], b"d1:ai1ee", false).unwrap();
+        assert_eq!(fs::read(&a).unwrap(), b"d1:ai1ee");
+        assert_eq!(fs::read(&a).unwrap(), fs::read(&b).unwrap());
+        fs::remove_file(&a).unwrap();
+        fs::remove_file(&b).unwrap();
+
+        // the second temporary file cannot be created
+        let missing = dir.path().join("missing/b.torrent");
+        assert!(write_outputs(&[a.clone(), missing.clone()], b"x", false).is_err());
+        assert!(!a.exists());
+        assert!(!missing.exists());
+
+        // the second rename fails since a directory is in the way
+        let blocked = dir.path().join("blocked");
+        fs::create_dir(&blocked).unwrap();
+        let err = write_outputs(&[a.clone(), blocked.clone()], b"x", false).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!("failed to write `{}`", blocked.to_string_lossy())
+        );
+        assert!(!a.exists());
+        assert!(blocked.is_dir());
+
+        // files replaced with --force are restored
+        fs::write(&a, "old").unwrap();
+        assert!(write_outputs(&[a.clone(), blocked.clone()], b"x", false).is_err());
+        assert_eq!(fs::read(&a).unwrap(), b"old");
+        write_outputs(std::slice::from_ref(&a), b"new", false).unwrap();
+        assert_eq!(fs::read(&a).unwrap(), b"new");
+        fs::remove_file(&a).unwrap();
+
+        // no temporary files are left behind
+        let names: Vec<_> = fs::read_dir(dir.path())
+            .unwrap()
+            .map(|e| e.unwrap().file_name())
+            .collect();
+        assert_eq!(names, ["blocked"]);
+    }
+
+    #[test]
+    fn concurrent_writes() {
+        let dir = tempfile::tempdir().unwrap();
+        let outputs = [dir.path().join("a.torrent"), dir.path().join("b.torrent")];
+
+        std::thread::scope(|s| {
+            for (i, p) in outputs.iter().enumerate() {
+                s.spawn(move || {
+                    for j in 0..100 {
+                        write_atomic(p, format!("{} {}", i, j).as_bytes(), false).unwrap();
+                    }
+                });
+            }
+        });
+
+        assert_eq!(fs::read_to_string(&outputs[0]).unwrap(), "0 99");
+        assert_eq!(fs::read_to_string(&outputs[1]).unwrap(), "1 99");
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 2);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn clean_temps() {
+        let dir = tempfile::tempdir().unwrap();
+        let p = dir.path().join("out.torrent");
+
+        let ours = temp_path(&p);
+        assert_eq!(
+            temp_pid(&ours.file_name().unwrap().to_string_lossy(), "out.torrent"),
+            Some(process::id())
+        );
+        assert_eq!(temp_pid("out.torrent.tmp", "out.torrent"), None);
+        assert_eq!(temp_pid("out.torrent.1.x.tmp", "out.torrent"), None);
+        assert_eq!(temp_pid("other.torrent.1.0.tmp", "out.torrent"), None);
+
+        // pids are positive i32s, so this process can't exist
+        let stale = dir.path().join(format!("out.torrent.{}.0.tmp", u32::MAX));
+        let unrelated = dir.path().join("out.torrent.tmp");
+        for f in [&ours, &stale, &unrelated] {
+            fs::write(f, "").unwrap();
+        }
+
+        assert_eq!(super::clean_temps(&p).unwrap(), vec![stale.clone()]);
+        assert!(ours.exists());
+        assert!(!stale.exists());
+        assert!(unrelated.exists());
+        assert!(super::clean_temps(&p).unwrap().is_empty());
+    }
+
+    #[test]
+    fn output_unique() {
+        let dir = tempfile::tempdir().unwrap();
+        let t = Torrent::new(
+            "".to_string(),
+            "name".to_string(),
+            PieceLength { layers: 0 },
+        );
+
+        let mut written = Vec::new();
+        for _ in 0..3 {
+            let p = unique_path(&output_path(dir.path(), "name", true));
+            write_torrent(Some(&p), &t, false).unwrap();
+            written.push(p.file_name().unwrap().to_str().unwrap().to_owned());
+        }
+        assert_eq!(
+            written,
+            vec!["name.torrent", "name (2).torrent", "name (3).torrent"]
+        );
+    }
+
+    #[test]
+    fn infohash_encoding() {
+        let d: sha256::Digest = [0xab; sha256::Digest::LENGTH].into();
+        assert_eq!(format_infohash(&d, InfohashEncoding::Hex), "ab".repeat(32));
+        assert_eq!(
+            format_infohash(&d, InfohashEncoding::Base32),
+            "VOV2XK5LVOV2XK5LVOV2XK5LVOV2XK5L"
+        );
+    }
+
+    #[test]
+    fn print_infohash_v1() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("a"), "a").unwrap();
+        let argv = [
+            "mktorrent-rs",
+            "--announce=",
+            "--piece-length=14",
+            "--print-infohash",
+            "--v1",
+            "--output=a.torrent",
+            "a",
+        ];
+        let cli = Cli::parse_from(argv);
+        let (t, _) = build_torrent_v1(
+            &cli,
+            PieceLength { layers: 0 },
+            "a".to_owned(),
+            dir.path(),
+            vec![("a".to_owned(), 1)],
+            true,
+        )
+        .unwrap();
+
+        let mut out = Vec::new();
+        super::print_infohash_v1(&mut out, &t, InfohashEncoding::Hex).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "bb98a674de9c3f85217ff32504fcc359cd5ffed3\n"
+        );
+
+        let without: Vec<_> = argv
+            .into_iter()
+            .filter(|a| *a != "--print-infohash")
+            .collect();
+        assert!(Cli::try_parse_from(without).is_err());
+        let mut v2 = argv.to_vec();
+        v2.insert(1, "--meta-version=v2");
+        assert!(Cli::try_parse_from(v2).is_err());
+    }
+
+    #[test]
+    fn check_output_force() {
+        let dir = tempfile::tempdir().unwrap();
+        let out = dir.path().join("out");
+        let cli = |args: &[&str]| {
+            let mut argv = vec!["mktorrent-rs", "--announce=", "--piece-length=14"];
+            argv.extend_from_slice(args);
+            argv.push("root");
+            Cli::parse_from(argv)
+        };
+
+        check_output(&cli(&[]), Some(&out)).unwrap();
+        fs::write(&out, b"").unwrap();
+        assert!(check_output(&cli(&[]), Some(&out)).is_err());
+        check_output(&cli(&["--force"]), Some(&out)).unwrap();
+        check_output(&cli(&["--force"]), None).unwrap();
+
+        assert!(Cli::try_parse_from([
+            "mktorrent-rs",
+            "--announce=",
+            "--piece-length=14",
+            "--force",
+            "--output-unique",
+            "root",
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn wrap_key() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("a"), vec![1u8; 40 << 10]).unwrap();
+        let out = dir.path().join("out");
+        let cli = Cli::parse_from([
+            "mktorrent-rs",
+            "--announce=http://example.com",
+            "--piece-length=14",
+            "--wrap-key=payload",
+            "--wrap-field=owner=archive",
+            "root",
+        ]);
+        let (t, _, _) = build_torrent(
+            &cli,
+            &HashOptions::new(PieceLength { layers: 0 }),
+            "a".to_owned(),
+            dir.path(),
+            get_file_list(dir.path()).unwrap(),
+            false,
+        )
+        .unwrap();
+        emit_torrent(&cli, std::slice::from_ref(&out), &t).unwrap();
+
+        #[derive(Debug)]
+        struct Wrapped {
+            owner: String,
+            payload: Torrent,
+        }
+        impl FromBencode for Wrapped {
+            fn decode_bencode_object(
+                object: bendy::decoding::Object,
+            ) -> Result<Self, bendy::decoding::Error> {
+                let mut dict = object.try_into_dictionary()?;
+                let (mut owner, mut payload) = (None, None);
+                while let Some((k, v)) = dict.next_pair()? {
+                    match k {
+                        b"owner" => owner = Some(String::decode_bencode_object(v)?),
+                        b"payload" => payload = Some(Torrent::decode_bencode_object(v)?),
+                        _ => panic!("unexpected key"),
+                    }
+                }
+                Ok(Wrapped {
+                    owner: owner.unwrap(),
+                    payload: payload.unwrap(),
+                })
+            }
+        }
+
+        let data = fs::read(&out).unwrap();
+        check_canonical(&data).unwrap();
+        let w = Wrapped::from_bencode(&data).unwrap();
+        assert_eq!(w.owner, "archive");
+        assert_eq!(w.payload.to_bencode().unwrap(), t.to_bencode().unwrap());
+        assert_eq!(
+            w.payload.info.infohash().unwrap(),
+            t.info.infohash().unwrap()
+        );
+    }
+
+    #[test]
+    fn fsync() {
+        let dir = tempfile::tempdir().unwrap();
+        let p = dir.path().join("a.torrent");
+        let t = Torrent::new("".to_string(), "a".to_string(), PieceLength { layers: 0 });
+
+        write_torrent(Some(&p), &t, true).unwrap();
+        assert_eq!(fs::read(&p).unwrap(), t.to_bencode().unwrap());
+        // the temporary file was renamed into place
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+
+        // a relative path is synced in the current directory
+        sync_parent(Path::new("a.torrent")).unwrap();
+        let outputs = [dir.path().join("b.torrent"), dir.path().join("c.torrent")];
+        write_outputs(&outputs, b"x", true).unwrap();
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 3);
+    }
+}
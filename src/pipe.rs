@@ -0,0 +1,169 @@
+use std::fs;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+
+use serde::Serialize;
+
+// A piece of a file finished hashing. done is the number of bytes of the file
+// hashed so far.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub struct PieceEvent {
+    pub piece: u64,
+    pub done: u64,
+}
+
+// The number of events queued for the writer. Events sent while the queue is
+// full are dropped.
+const QUEUE_LEN: usize = 1024;
+
+// Writes piece events as JSON lines to a named pipe from a separate thread so
+// a slow or missing reader never holds up hashing. Writing stops if the reader
+// goes away.
+pub struct ProgressPipe {
+    tx: mpsc::SyncSender<PieceEvent>,
+    opened: Arc<AtomicBool>,
+    writer: thread::JoinHandle<()>,
+}
+
+impl ProgressPipe {
+    pub fn spawn(path: PathBuf) -> Self {
+        let (tx, rx) = mpsc::sync_channel::<PieceEvent>(QUEUE_LEN);
+        let opened = Arc::new(AtomicBool::new(false));
+
+        let writer = {
+            let opened = opened.clone();
+            thread::spawn(move || {
+                // Opening a pipe blocks until a reader opens it.
+                let f = match fs::OpenOptions::new().write(true).open(&path) {
+                    Ok(f) => f,
+                    Err(e) => {
+                        eprintln!(
+                            "warning: failed to open `{}`: {}",
+                            path.to_string_lossy(),
+                            e
+                        );
+                        return;
+                    }
+                };
+                opened.store(true, Ordering::Release);
+
+                let mut w = io::LineWriter::new(f);
+                for event in rx {
+                    let line = serde_json::to_string(&event).unwrap();
+                    if writeln!(w, "{}", line).is_err() {
+                        break;
+                    }
+                }
+            })
+        };
+
+        ProgressPipe { tx, opened, writer }
+    }
+
+    // Returns a sender for events. Events should be sent with try_send, so
+    // they are dropped rather than waited on when the reader falls behind.
+    // Events sent after the reader went away are dropped too.
+    pub fn sender(&self) -> mpsc::SyncSender<PieceEvent> {
+        self.tx.clone()
+    }
+
+    // Waits for every event to be written once all senders are dropped. If no
+    // reader ever opened the pipe there is nothing to wait for.
+    pub fn finish(self) {
+        drop(self.tx);
+        if self.opened.load(Ordering::Acquire) {
+            let _ = self.writer.join();
+        }
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+
+    use std::ffi::CString;
+    use std::io::{BufRead, BufReader};
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::Path;
+    use std::time::Duration;
+
+    use crate::checksum::checksum_file_progress;
+    use crate::metainfo::PieceLength;
+
+    fn mkfifo(p: &Path) {
+        let c = CString::new(p.as_os_str().as_bytes()).unwrap();
+        assert_eq!(unsafe { libc::mkfifo(c.as_ptr(), 0o600) }, 0);
+    }
+
+    #[test]
+    fn progress_pipe() {
+        let dir = tempfile::tempdir().unwrap();
+        let p = dir.path().join("fifo");
+        mkfifo(&p);
+
+        let reader = {
+            let p = p.clone();
+            thread::spawn(move || {
+                let f = fs::File::open(p).unwrap();
+                BufReader::new(f)
+                    .lines()
+                    .map(|l| l.unwrap())
+                    .collect::<Vec<_>>()
+            })
+        };
+
+        // Hash a file of 10 pieces, sending an event for each.
+        let data = vec![7u8; 10 << 14];
+        let pipe = ProgressPipe::spawn(p);
+        let tx = pipe.sender();
+        checksum_file_progress(
+            PieceLength { layers: 0 },
+            data.len() as u64,
+            &data,
+            |piece, done| tx.try_send(PieceEvent { piece, done }).unwrap(),
+        )
+        .unwrap();
+        drop(tx);
+        // Wait for the writer to open the pipe so finish waits for it.
+        while !pipe.opened.load(Ordering::Acquire) {
+            thread::sleep(Duration::from_millis(1));
+        }
+        pipe.finish();
+
+        let events: Vec<(u64, u64)> = reader
+            .join()
+            .unwrap()
+            .iter()
+            .map(|l| {
+                let v: serde_json::Value = serde_json::from_str(l).unwrap();
+                (v["piece"].as_u64().unwrap(), v["done"].as_u64().unwrap())
+            })
+            .collect();
+        let mut pieces: Vec<_> = events.iter().map(|&(p, _)| p).collect();
+        pieces.sort_unstable();
+        assert_eq!(pieces, (0..10).collect::<Vec<_>>());
+        assert_eq!(events.iter().map(|&(_, d)| d).max(), Some(10 << 14));
+    }
+
+    #[test]
+    fn reader_disconnects() {
+        let dir = tempfile::tempdir().unwrap();
+        let p = dir.path().join("fifo");
+        mkfifo(&p);
+
+        let pipe = ProgressPipe::spawn(p.clone());
+        drop(fs::File::open(&p).unwrap());
+
+        // Sending never fails the hash or blocks, even once the writer has
+        // stopped and the queue is full.
+        let tx = pipe.sender();
+        for piece in 0..(QUEUE_LEN as u64 * 2) {
+            let _ = tx.try_send(PieceEvent { piece, done: piece });
+        }
+        drop(tx);
+        pipe.finish();
+    }
+}
@@ -0,0 +1,141 @@
+use std::path::PathBuf;
+
+use anyhow::Result;
+use indicatif::ProgressBar;
+use positioned_io::{RandomAccessFile, ReadAt};
+
+// Somewhere torrent content is read from. Anything that can list its files
+// and read them at an offset, such as an object store, can be hashed the same
+// way as a directory.
+pub trait ContentSource {
+    type Reader: ReadAt + Sync;
+
+    // Returns the path, relative to the root of the content with '/'
+    // separators, and length of every file.
+    fn file_list(&self) -> Result<Vec<(String, u64)>>;
+
+    // Opens the file at path as returned by file_list.
+    fn open(&self, path: &str) -> Result<Self::Reader>;
+}
+
+// The files under a directory on the local filesystem.
+pub struct Filesystem {
+    root: PathBuf,
+    dereference: bool,
+    progress: ProgressBar,
+}
+
+impl Filesystem {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Filesystem {
+            root: root.into(),
+            dereference: false,
+            progress: ProgressBar::hidden(),
+        }
+    }
+
+    // Makes file_list include symlinks to files with the length of their
+    // target.
+    pub fn dereference(mut self, dereference: bool) -> Self {
+        self.dereference = dereference;
+        self
+    }
+
+    // Makes file_list increment progress for each file found.
+    pub fn progress(mut self, progress: ProgressBar) -> Self {
+        self.progress = progress;
+        self
+    }
+}
+
+impl ContentSource for Filesystem {
+    type Reader = RandomAccessFile;
+
+    fn file_list(&self) -> Result<Vec<(String, u64)>> {
+        crate::files::get_file_list_progress(&self.root, self.dereference, &self.progress)
+    }
+
+    fn open(&self, path: &str) -> Result<RandomAccessFile> {
+        Ok(RandomAccessFile::open(self.root.join(path))?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+
+    use anyhow::Error;
+
+    use crate::hash::{hash_source, HashOptions};
+    use crate::metainfo::{FileEntry, PieceLength, Torrent};
+
+    // Files held in memory.
+    struct Memory(Vec<(String, Vec<u8>)>);
+
+    impl ContentSource for Memory {
+        type Reader = Vec<u8>;
+
+        fn file_list(&self) -> Result<Vec<(String, u64)>> {
+            Ok(self
+                .0
+                .iter()
+                .map(|(p, data)| (p.clone(), data.len() as u64))
+                .collect())
+        }
+
+        fn open(&self, path: &str) -> Result<Vec<u8>> {
+            self.0
+                .iter()
+                .find(|(p, _)| p == path)
+                .map(|(_, data)| data.clone())
+                .ok_or_else(|| Error::msg(format!("no such file: {}", path)))
+        }
+    }
+
+    fn build(source: &impl ContentSource) -> Torrent {
+        let opts = HashOptions::new(PieceLength { layers: 0 });
+        let entries: Vec<_> = source
+            .file_list()
+            .unwrap()
+            .into_iter()
+            .map(|(path, l)| {
+                let (file, pieces_layer) = hash_source(source, &opts, &path, l).unwrap();
+                FileEntry {
+                    path,
+                    file,
+                    pieces_layer,
+                }
+            })
+            .collect();
+        Torrent::from_entries(
+            "".to_owned(),
+            "root".to_owned(),
+            PieceLength { layers: 0 },
+            entries,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn memory_matches_filesystem() {
+        let files = vec![
+            ("a".to_owned(), vec![1u8; 40 << 10]),
+            ("b/c".to_owned(), b"c".to_vec()),
+        ];
+
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("b")).unwrap();
+        for (p, data) in &files {
+            fs::write(dir.path().join(p), data).unwrap();
+        }
+
+        let memory = Memory(files);
+        assert!(memory.open("missing").is_err());
+        assert_eq!(
+            build(&memory).info.infohash().unwrap(),
+            build(&Filesystem::new(dir.path())).info.infohash().unwrap()
+        );
+    }
+}
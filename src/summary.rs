@@ -0,0 +1,447 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, Write};
+use std::path::PathBuf;
+
+use serde::Serialize;
+
+use crate::checksum::sha256;
+use crate::metainfo::{Directory, File, PathElement, PieceLength, Torrent};
+
+// A machine readable description of a built torrent.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Summary {
+    // Where the torrent was written. None means stdout.
+    pub output: Option<PathBuf>,
+    // The v2 infohash in hex.
+    pub infohash: String,
+    pub name: String,
+    pub file_count: usize,
+    pub total_size: u64,
+    pub piece_length: u64,
+    pub piece_count: u64,
+}
+
+impl Summary {
+    pub fn new(torrent: &Torrent, output: Option<PathBuf>) -> Result<Self, bendy::encoding::Error> {
+        let piece_length = torrent.info.piece_length.bytes();
+        let files = torrent.files();
+
+        Ok(Summary {
+            output,
+            infohash: torrent.info.infohash()?.to_string(),
+            name: torrent.info.name.clone(),
+            file_count: files.len(),
+            total_size: files.iter().map(|(_, f)| f.length).sum(),
+            piece_count: files
+                .iter()
+                .map(|(_, f)| f.length.div_ceil(piece_length))
+                .sum(),
+            piece_length,
+        })
+    }
+}
+
+// The piece hashes of a single file, in hex.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct FilePieces {
+    pub path: String,
+    pub pieces_root: String,
+    // Empty for files with at most one piece since they have no piece layer.
+    pub pieces: Vec<String>,
+}
+
+// Returns the piece layer of every file in the torrent.
+pub fn file_pieces(torrent: &Torrent) -> Vec<FilePieces> {
+    torrent
+        .files()
+        .into_iter()
+        .map(|(path, f)| FilePieces {
+            path,
+            pieces_root: f.pieces_root.to_string(),
+            pieces: torrent
+                .piece_layers
+                .get(&f.pieces_root)
+                .map(|l| l.iter().map(|d| d.to_string()).collect())
+                .unwrap_or_default(),
+        })
+        .collect()
+}
+
+// Statistics about the piece layers of a torrent.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct LayerStats {
+    // Number of distinct pieces roots with a piece layer.
+    pub roots: usize,
+    // Number of piece hashes across all distinct layers.
+    pub piece_hashes: usize,
+    // Size of the encoded piece layers dictionary.
+    pub encoded_bytes: u64,
+    // Number of files with a piece layer that another file also has.
+    pub shared_files: usize,
+}
+
+impl LayerStats {
+    pub fn new(torrent: &Torrent) -> Self {
+        let mut stats = LayerStats {
+            // "d" and "e"
+            encoded_bytes: 2,
+            ..Default::default()
+        };
+
+        for (root, layer) in &torrent.piece_layers {
+            if layer.is_empty() {
+                continue;
+            }
+            stats.roots += 1;
+            stats.piece_hashes += layer.len();
+            stats.encoded_bytes += encoded_string_len(root.as_ref().len())
+                + encoded_string_len(layer.len() * sha256::Digest::LENGTH);
+        }
+
+        let mut users: HashMap<&sha256::Digest, usize> = HashMap::new();
+        for (_, f) in torrent.files() {
+            if torrent
+                .piece_layers
+                .get(&f.pieces_root)
+                .is_some_and(|l| !l.is_empty())
+            {
+                *users.entry(&f.pieces_root).or_default() += 1;
+            }
+        }
+        stats.shared_files = users.values().filter(|&&n| n > 1).sum();
+
+        stats
+    }
+}
+
+impl fmt::Display for LayerStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "distinct roots: {}", self.roots)?;
+        writeln!(f, "piece hashes: {}", self.piece_hashes)?;
+        writeln!(f, "encoded size: {} bytes", self.encoded_bytes)?;
+        write!(f, "files sharing a layer: {}", self.shared_files)
+    }
+}
+
+// The shape of a torrent's file tree.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TreeStats {
+    // Number of path components of the deepest file.
+    pub max_depth: usize,
+    // The first deepest file in path order.
+    pub deepest_path: String,
+    // Number of directories, including the top level one.
+    pub directories: usize,
+    // Entries in the largest directory.
+    pub max_fanout: usize,
+    // Mean number of entries per directory.
+    pub avg_fanout: f64,
+    // The path and number of entries of every directory in path order. The
+    // top level directory's path is empty.
+    pub fanouts: Vec<(String, usize)>,
+}
+
+impl TreeStats {
+    pub fn new(torrent: &Torrent) -> Self {
+        let mut stats = TreeStats::default();
+        let mut entries = 0;
+        stats.walk(&torrent.info.file_tree, "", 1, &mut entries);
+        if stats.directories > 0 {
+            stats.avg_fanout = entries as f64 / stats.directories as f64;
+        }
+        stats
+    }
+
+    // Same as new for a torrent of the files at paths, which is known before
+    // any content is hashed. Paths that conflict are ignored.
+    pub fn from_paths(paths: impl IntoIterator<Item = String>) -> Self {
+        let mut t = Torrent::new(String::new(), String::new(), PieceLength { layers: 0 });
+        for p in paths {
+            t.add_file(&p, File::default(), Vec::new());
+        }
+        TreeStats::new(&t)
+    }
+
+    // Returns the directories with more than n entries.
+    pub fn wide_dirs(&self, n: usize) -> Vec<(String, usize)> {
+        self.fanouts
+            .iter()
+            .filter(|&&(_, entries)| entries > n)
+            .cloned()
+            .collect()
+    }
+
+    // Adds dir, whose files are depth components deep, and everything under
+    // it. entries counts the entries of every directory seen.
+    fn walk(&mut self, dir: &Directory, prefix: &str, depth: usize, entries: &mut usize) {
+        self.directories += 1;
+        self.max_fanout = self.max_fanout.max(dir.entries.len());
+        self.fanouts.push((prefix.to_owned(), dir.entries.len()));
+        *entries += dir.entries.len();
+
+        let mut sorted: Vec<_> = dir.entries.iter().collect();
+        sorted.sort_unstable_by_key(|&(name, _)| name);
+        for (name, e) in sorted {
+            let path = if prefix.is_empty() {
+                name.clone()
+            } else {
+                format!("{}/{}", prefix, name)
+            };
+            match e {
+                PathElement::Directory(d) => self.walk(d, &path, depth + 1, entries),
+                PathElement::File(_) if depth > self.max_depth => {
+                    self.max_depth = depth;
+                    self.deepest_path = path;
+                }
+                PathElement::File(_) => {}
+            }
+        }
+    }
+}
+
+impl fmt::Display for TreeStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "max depth: {} ({})", self.max_depth, self.deepest_path)?;
+        writeln!(f, "directories: {}", self.directories)?;
+        writeln!(f, "max fanout: {}", self.max_fanout)?;
+        write!(f, "average fanout: {:.1}", self.avg_fanout)
+    }
+}
+
+// Length of a bencoded string of n bytes.
+fn encoded_string_len(n: usize) -> u64 {
+    (n.to_string().len() + 1 + n) as u64
+}
+
+// Writes a table of each file's pieces root, length and path.
+pub fn write_file_table(mut w: impl Write, torrent: &Torrent) -> io::Result<()> {
+    let files = torrent.files();
+    let width = files
+        .iter()
+        .map(|(_, f)| f.length.to_string().len())
+        .max()
+        .unwrap_or(0);
+
+    for (path, f) in files {
+        writeln!(w, "{}  {:>width$}  {}", f.pieces_root, f.length, path)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::checksum::sha256;
+
+    #[test]
+    fn summary_json() {
+        let mut t = Torrent::new(
+            "http://announce.example.com".to_string(),
+            "name".to_string(),
+            PieceLength { layers: 0 },
+        );
+        t.add_file(
+            "a",
+            File {
+                length: 20 << 10,
+                pieces_root: [b'a'; 32].into(),
+                crc32: None,
+            },
+            Vec::new(),
+        );
+        t.add_file(
+            "b/c",
+            File {
+                length: 1,
+                pieces_root: [b'b'; 32].into(),
+                crc32: None,
+            },
+            Vec::new(),
+        );
+
+        let s = Summary::new(&t, Some("out.torrent".into())).unwrap();
+        let v: serde_json::Value = serde_json::to_value(&s).unwrap();
+
+        assert_eq!(v["output"], "out.torrent");
+        assert_eq!(v["infohash"], t.info.infohash().unwrap().to_string());
+        assert_eq!(v["infohash"].as_str().unwrap().len(), 64);
+        assert_eq!(v["name"], "name");
+        assert_eq!(v["file_count"], 2);
+        assert_eq!(v["total_size"], (20 << 10) + 1);
+        assert_eq!(v["piece_length"], 16 << 10);
+        assert_eq!(v["piece_count"], 3);
+    }
+
+    #[test]
+    fn file_table() {
+        let mut t = Torrent::new("".to_string(), "".to_string(), PieceLength { layers: 0 });
+        t.add_file(
+            "b/c",
+            File {
+                length: 1,
+                pieces_root: [0xbb; 32].into(),
+                crc32: None,
+            },
+            Vec::new(),
+        );
+        t.add_file(
+            "a",
+            File {
+                length: 1024,
+                pieces_root: [0xaa; 32].into(),
+                crc32: None,
+            },
+            Vec::new(),
+        );
+
+        let mut out = Vec::new();
+        write_file_table(&mut out, &t).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            format!(
+                "{}  1024  a\n{}     1  b/c\n",
+                "aa".repeat(32),
+                "bb".repeat(32)
+            ),
+        );
+    }
+
+    #[test]
+    fn pieces_sidecar() {
+        let layer: Vec<sha256::Digest> = vec![[b'b'; 32].into(), [b'c'; 32].into()];
+        let mut t = Torrent::new(
+            "".to_string(),
+            "name".to_string(),
+            PieceLength { layers: 0 },
+        );
+        t.add_file(
+            "big",
+            File {
+                length: 20 << 10,
+                pieces_root: [b'a'; 32].into(),
+                crc32: None,
+            },
+            layer.clone(),
+        );
+        t.add_file("small", File::default(), Vec::new());
+
+        let pieces = file_pieces(&t);
+        assert_eq!(pieces.len(), 2);
+        assert_eq!(pieces[0].path, "big");
+        assert_eq!(
+            pieces[0].pieces,
+            t.piece_layers[&[b'a'; 32].into()]
+                .iter()
+                .map(|d| d.to_string())
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(pieces[0].pieces, vec!["62".repeat(32), "63".repeat(32)]);
+        assert_eq!(pieces[1].path, "small");
+        assert!(pieces[1].pieces.is_empty());
+    }
+
+    #[test]
+    fn layer_stats() {
+        let mut t = Torrent::new(
+            "".to_string(),
+            "name".to_string(),
+            PieceLength { layers: 0 },
+        );
+        let layer: Vec<sha256::Digest> = vec![[b'b'; 32].into(), [b'c'; 32].into()];
+        for path in ["a", "b/a", "c/a"] {
+            t.add_file(
+                path,
+                File {
+                    length: 20 << 10,
+                    pieces_root: [b'a'; 32].into(),
+                    crc32: None,
+                },
+                layer.clone(),
+            );
+        }
+        t.add_file(
+            "d",
+            File {
+                length: 20 << 10,
+                pieces_root: [b'd'; 32].into(),
+                crc32: None,
+            },
+            layer.clone(),
+        );
+        t.add_file("small", File::default(), Vec::new());
+
+        let stats = LayerStats::new(&t);
+        assert_eq!(
+            stats,
+            LayerStats {
+                roots: 2,
+                piece_hashes: 4,
+                encoded_bytes: 2 + 2 * (35 + 67),
+                shared_files: 3,
+            }
+        );
+
+        // matches the encoded dictionary
+        let encoded = bendy::encoding::ToBencode::to_bencode(&t).unwrap();
+        let start = encoded
+            .windows(15)
+            .position(|w| w == b"12:piece layers")
+            .map(|i| i + 15);
+        assert_eq!(
+            encoded.len() - 1 - start.unwrap(),
+            stats.encoded_bytes as usize
+        );
+    }
+
+    #[test]
+    fn tree_stats() {
+        let paths = ["a", "b/c", "b/d", "b/e/f", "b/e/g/h", "z/y"];
+        let mut t = Torrent::new("".to_string(), "".to_string(), PieceLength { layers: 0 });
+        for p in paths {
+            assert!(t.add_file(p, File::default(), Vec::new()));
+        }
+
+        let stats = TreeStats::new(&t);
+        assert_eq!(stats.max_depth, 4);
+        assert_eq!(stats.deepest_path, "b/e/g/h");
+        // the top level, b, b/e, b/e/g and z
+        assert_eq!(stats.directories, 5);
+        assert_eq!(stats.max_fanout, 3);
+        // 3 + 3 + 2 + 1 + 1 entries
+        assert_eq!(stats.avg_fanout, 2.0);
+        assert_eq!(
+            stats.to_string(),
+            "max depth: 4 (b/e/g/h)\ndirectories: 5\nmax fanout: 3\naverage fanout: 2.0"
+        );
+        assert_eq!(TreeStats::from_paths(paths.map(String::from)), stats);
+
+        let empty = TreeStats::new(&Torrent::new(
+            "".to_string(),
+            "".to_string(),
+            PieceLength { layers: 0 },
+        ));
+        assert_eq!(empty.max_depth, 0);
+        assert_eq!(empty.directories, 1);
+    }
+
+    #[test]
+    fn wide_dirs() {
+        let mut t = Torrent::new("".to_string(), "".to_string(), PieceLength { layers: 0 });
+        for i in 0..50 {
+            assert!(t.add_file(&format!("b/wide/{}", i), File::default(), Vec::new()));
+        }
+        for p in ["a", "b/c", "d/e"] {
+            assert!(t.add_file(p, File::default(), Vec::new()));
+        }
+
+        let stats = TreeStats::new(&t);
+        assert_eq!(stats.wide_dirs(10), vec![("b/wide".to_owned(), 50)]);
+        assert_eq!(
+            stats.wide_dirs(2),
+            vec![("".to_owned(), 3), ("b/wide".to_owned(), 50)]
+        );
+        assert!(stats.wide_dirs(50).is_empty());
+    }
+}
@@ -0,0 +1,19 @@
+// Helpers shared by the tests of several modules.
+
+use std::path::Path;
+
+use anyhow::Result;
+use bendy::encoding::ToBencode;
+
+use crate::output::write_output;
+use crate::source::{ContentSource, Filesystem};
+
+// Returns the relative path from the root for each file in the root.
+pub fn get_file_list(root: &Path) -> Result<Vec<(String, u64)>> {
+    Filesystem::new(root).file_list()
+}
+
+// Writes the encoded torrent to output, or stdout if output is None.
+pub fn write_torrent(output: Option<&Path>, torrent: &impl ToBencode, fsync: bool) -> Result<()> {
+    write_output(output, &torrent.to_bencode().unwrap(), fsync)
+}